@@ -0,0 +1,196 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Backpressure of slow key server listeners.
+//!
+//! Key server accepts service tasks through registered listener. If the listener is
+//! slow to accept tasks, we stop dispatching tasks to the key server instead of
+//! buffering them. Skipped tasks are still pending on chain, so they're dispatched
+//! by the pending tasks scan that is forced when listener recovers.
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use parity_secretstore_primitives::service::{
+	ServiceTask, ServiceTasksListener, ServiceTasksListenerRegistrar,
+};
+use crate::KeyServerHandle;
+
+/// Listener backpressure configuration.
+#[derive(Debug, Clone)]
+pub struct BackpressureConfiguration {
+	/// Dispatching is throttled when listener is processing this number of tasks
+	/// concurrently.
+	pub max_in_flight_tasks: usize,
+	/// Dispatching is throttled when listener has spent this time processing the
+	/// last task.
+	pub slow_task_threshold: Duration,
+}
+
+impl Default for BackpressureConfiguration {
+	fn default() -> Self {
+		BackpressureConfiguration {
+			max_in_flight_tasks: 64,
+			slow_task_threshold: Duration::from_secs(1),
+		}
+	}
+}
+
+/// Throttle state of single key server listener.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ThrottleState {
+	/// True if tasks dispatching is currently throttled.
+	pub is_throttled: bool,
+	/// Number of tasks that listener is currently processing.
+	pub in_flight_tasks: usize,
+	/// Time listener has spent processing the last task.
+	pub last_task_duration: Duration,
+	/// Number of blocks which tasks haven't been dispatched because of throttling.
+	pub throttled_blocks: u64,
+}
+
+/// Backpressure action for the new block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockBackpressure {
+	/// Tasks are dispatched as usual.
+	None,
+	/// Tasks of the block must not be dispatched.
+	Throttled,
+	/// Listener has recovered after throttling. Skipped tasks need to be read by
+	/// pending tasks scan.
+	Recovered,
+}
+
+/// Backpressure of all key server listeners.
+pub struct ListenerBackpressure {
+	/// Backpressure configuration. If `None`, dispatching is never throttled.
+	config: Option<BackpressureConfiguration>,
+	/// Throttle state of every key server route (`None` is the shadow key server).
+	states: Mutex<BTreeMap<Option<KeyServerHandle>, ThrottleState>>,
+}
+
+/// Listener registrar that tracks how fast listeners are accepting tasks.
+pub struct BackpressureListenerRegistrar {
+	/// Inner registrar.
+	registrar: Arc<dyn ServiceTasksListenerRegistrar>,
+	/// Shared backpressure state.
+	backpressure: Arc<ListenerBackpressure>,
+	/// Key server route of the registrar.
+	route: Option<KeyServerHandle>,
+}
+
+/// Listener that tracks how fast inner listener is accepting tasks.
+struct BackpressureListener {
+	/// Inner listener.
+	listener: Arc<dyn ServiceTasksListener>,
+	/// Shared backpressure state.
+	backpressure: Arc<ListenerBackpressure>,
+	/// Key server route of the listener.
+	route: Option<KeyServerHandle>,
+}
+
+impl ListenerBackpressure {
+	/// Create new backpressure state.
+	pub fn new(config: Option<BackpressureConfiguration>) -> Self {
+		ListenerBackpressure {
+			config,
+			states: Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	/// Returns true if dispatching could be throttled.
+	pub fn is_enabled(&self) -> bool {
+		self.config.is_some()
+	}
+
+	/// Called when new block is processed by given route.
+	pub fn on_new_block(&self, route: Option<KeyServerHandle>) -> BlockBackpressure {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return BlockBackpressure::None,
+		};
+
+		let mut states = self.states.lock().expect("never panics under lock; qed");
+		let state = states.entry(route).or_default();
+		let is_throttled = state.in_flight_tasks >= config.max_in_flight_tasks
+			|| state.last_task_duration >= config.slow_task_threshold;
+		let was_throttled = state.is_throttled;
+		state.is_throttled = is_throttled;
+		match (was_throttled, is_throttled) {
+			(_, true) => {
+				state.throttled_blocks += 1;
+				BlockBackpressure::Throttled
+			},
+			(true, false) => BlockBackpressure::Recovered,
+			(false, false) => BlockBackpressure::None,
+		}
+	}
+
+	/// Returns throttle state of every route.
+	pub fn snapshot(&self) -> BTreeMap<Option<KeyServerHandle>, ThrottleState> {
+		self.states.lock().expect("never panics under lock; qed").clone()
+	}
+
+	/// Called when listener of given route starts processing task.
+	fn on_task_started(&self, route: Option<KeyServerHandle>) {
+		let mut states = self.states.lock().expect("never panics under lock; qed");
+		states.entry(route).or_default().in_flight_tasks += 1;
+	}
+
+	/// Called when listener of given route has completed processing task.
+	fn on_task_completed(&self, route: Option<KeyServerHandle>, duration: Duration) {
+		let mut states = self.states.lock().expect("never panics under lock; qed");
+		let state = states.entry(route).or_default();
+		state.in_flight_tasks = state.in_flight_tasks.saturating_sub(1);
+		state.last_task_duration = duration;
+	}
+}
+
+impl BackpressureListenerRegistrar {
+	/// Create new registrar.
+	pub fn new(
+		registrar: Arc<dyn ServiceTasksListenerRegistrar>,
+		backpressure: Arc<ListenerBackpressure>,
+		route: Option<KeyServerHandle>,
+	) -> Self {
+		BackpressureListenerRegistrar {
+			registrar,
+			backpressure,
+			route,
+		}
+	}
+}
+
+impl ServiceTasksListenerRegistrar for BackpressureListenerRegistrar {
+	fn register_listener(&self, listener: Arc<dyn ServiceTasksListener>) {
+		self.registrar.register_listener(Arc::new(BackpressureListener {
+			listener,
+			backpressure: self.backpressure.clone(),
+			route: self.route,
+		}));
+	}
+}
+
+impl ServiceTasksListener for BackpressureListener {
+	fn process_task(&self, task: ServiceTask) {
+		self.backpressure.on_task_started(self.route);
+		let started_at = Instant::now();
+		self.listener.process_task(task);
+		self.backpressure.on_task_completed(self.route, started_at.elapsed());
+	}
+}
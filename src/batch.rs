@@ -0,0 +1,75 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Batching of responses.
+//!
+//! When many sessions are completed at once, submitting single transaction per
+//! response is expensive. With batching enabled, responses are collected while block
+//! is processed and are submitted (e.g. using `utility.batch` call) when next block is
+//! processed or when batch is full. Responses of different origins (or submitted by
+//! different accounts) are never batched together. Batches are ordered using configured
+//! `ResponseOrdering` and high priority responses (errors) are submitted in their own
+//! batches, before routine responses.
+
+use parity_secretstore_primitives::Address;
+use crate::identity::AccountId32;
+
+/// Responses with the same key could be batched together.
+type BatchKey = (Address, Option<AccountId32>);
+
+/// Responses batching configuration.
+#[derive(Debug, Clone)]
+pub struct BatchingConfiguration {
+	/// Max number of responses in single batch. Batch is submitted as soon as it is full.
+	pub max_batch_size: usize,
+}
+
+impl Default for BatchingConfiguration {
+	fn default() -> Self {
+		BatchingConfiguration {
+			max_batch_size: 16,
+		}
+	}
+}
+
+/// Split collected responses into batches. Responses are grouped by origin and
+/// submitter account, and order of responses within group is preserved.
+pub fn split_into_batches<T>(
+	responses: Vec<T>,
+	max_batch_size: usize,
+	batch_key: impl Fn(&T) -> BatchKey,
+) -> Vec<Vec<T>> {
+	let max_batch_size = std::cmp::max(max_batch_size, 1);
+	let mut groups: Vec<(BatchKey, Vec<Vec<T>>)> = Vec::new();
+	for response in responses {
+		let key = batch_key(&response);
+		let group_index = match groups.iter().position(|(group_key, _)| *group_key == key) {
+			Some(group_index) => group_index,
+			None => {
+				groups.push((key, vec![Vec::new()]));
+				groups.len() - 1
+			},
+		};
+
+		let batches = &mut groups[group_index].1;
+		if batches.last().map(|batch| batch.len() >= max_batch_size).unwrap_or(true) {
+			batches.push(Vec::new());
+		}
+		batches.last_mut().expect("pushed above if empty; qed").push(response);
+	}
+
+	groups.into_iter().flat_map(|(_, batches)| batches).collect()
+}
@@ -0,0 +1,132 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Time-sliced processing of blocks.
+//!
+//! On resource-constrained validators, the service must not spend more than given
+//! time processing single block. When the budget is exhausted, remaining new tasks
+//! are deferred to the next block and pending tasks scan is interrupted (pending
+//! tasks will be read again by the next scan).
+
+use std::{
+	collections::{BTreeMap, VecDeque},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+use parity_secretstore_blockchain_service::BlockchainServiceTask;
+use crate::{KeyServerHandle, TaskOriginBlock};
+
+/// Processing budget of single block.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockBudget {
+	/// Time when block processing has started.
+	started_at: Instant,
+	/// Max block processing time. If `None`, processing time isn't limited.
+	limit: Option<Duration>,
+}
+
+/// Processing budget statistics.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BudgetStatistics {
+	/// Number of new tasks that are currently deferred to next blocks.
+	pub deferred_tasks: usize,
+	/// Number of blocks which processing budget has been exhausted.
+	pub exhausted_blocks: u64,
+	/// Number of pending tasks scans (of single task kind) that have been interrupted.
+	pub interrupted_scans: u64,
+}
+
+/// New tasks that have been deferred because processing budget has been exhausted.
+pub struct DeferredWork<Hash> {
+	/// Max block processing time. If `None`, processing time isn't limited.
+	limit: Option<Duration>,
+	/// Deferred work state.
+	state: Mutex<DeferredWorkState<Hash>>,
+}
+
+/// Deferred work state.
+struct DeferredWorkState<Hash> {
+	/// Deferred tasks of every key server route (`None` is the shadow key server).
+	tasks: BTreeMap<Option<KeyServerHandle>, VecDeque<(TaskOriginBlock<Hash>, BlockchainServiceTask)>>,
+	/// Statistics.
+	statistics: BudgetStatistics,
+}
+
+impl BlockBudget {
+	/// Returns true if processing budget is exhausted.
+	pub fn is_exhausted(&self) -> bool {
+		self.limit.map(|limit| self.started_at.elapsed() >= limit).unwrap_or(false)
+	}
+}
+
+impl<Hash> DeferredWork<Hash> {
+	/// Create new deferred work queue.
+	pub fn new(limit: Option<Duration>) -> Self {
+		DeferredWork {
+			limit,
+			state: Mutex::new(DeferredWorkState {
+				tasks: BTreeMap::new(),
+				statistics: BudgetStatistics::default(),
+			}),
+		}
+	}
+
+	/// Returns true if block processing time is limited.
+	pub fn is_enabled(&self) -> bool {
+		self.limit.is_some()
+	}
+
+	/// Start processing new block.
+	pub fn start_block(&self) -> BlockBudget {
+		BlockBudget {
+			started_at: Instant::now(),
+			limit: self.limit,
+		}
+	}
+
+	/// Take tasks that have been deferred by given route.
+	pub fn take(&self, route: Option<KeyServerHandle>) -> VecDeque<(TaskOriginBlock<Hash>, BlockchainServiceTask)> {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let tasks = state.tasks.remove(&route).unwrap_or_default();
+		state.statistics.deferred_tasks -= tasks.len();
+		tasks
+	}
+
+	/// Defer tasks of given route to the next block.
+	pub fn defer(
+		&self,
+		route: Option<KeyServerHandle>,
+		tasks: impl IntoIterator<Item = (TaskOriginBlock<Hash>, BlockchainServiceTask)>,
+	) {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let state = &mut *state;
+		let route_tasks = state.tasks.entry(route).or_default();
+		let route_tasks_count = route_tasks.len();
+		route_tasks.extend(tasks);
+		state.statistics.deferred_tasks += route_tasks.len() - route_tasks_count;
+		state.statistics.exhausted_blocks += 1;
+	}
+
+	/// Called when pending tasks scan is interrupted.
+	pub fn on_scan_interrupted(&self) {
+		self.state.lock().expect("never panics under lock; qed").statistics.interrupted_scans += 1;
+	}
+
+	/// Returns processing budget statistics.
+	pub fn statistics(&self) -> BudgetStatistics {
+		self.state.lock().expect("never panics under lock; qed").statistics.clone()
+	}
+}
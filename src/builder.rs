@@ -0,0 +1,169 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use futures::{Stream, StreamExt, stream::BoxStream};
+use parity_secretstore_primitives::{
+	error::Error,
+	executor::Executor,
+	key_server::KeyServer,
+	service::ServiceTasksListenerRegistrar,
+};
+use crate::{
+	Blockchain, Configuration, KeyServerRoute, ServiceConfiguration, ServiceHandle, TaskRouter,
+	TransactionPool, start_routed_service,
+	dedup::SubmittedResponses,
+};
+
+/// Secret Store service builder.
+pub struct ServiceBuilder<B: Blockchain, E, TP, KS> {
+	/// Key servers that are processing tasks.
+	routes: Vec<KeyServerRoute<KS>>,
+	/// Task router.
+	router: Option<TaskRouter>,
+	/// Shadow key server.
+	shadow: Option<KeyServerRoute<KS>>,
+	/// Blockchain.
+	blockchain: Option<Arc<B>>,
+	/// Executor.
+	executor: Option<Arc<E>>,
+	/// Transaction pool.
+	transaction_pool: Option<Arc<TP>>,
+	/// Service configuration.
+	service_config: ServiceConfiguration,
+	/// New blocks stream.
+	new_blocks_stream: Option<BoxStream<'static, B::BlockHash>>,
+}
+
+impl<B, E, TP, KS> ServiceBuilder<B, E, TP, KS>
+	where
+		B: Blockchain,
+		E: Executor,
+		TP: TransactionPool,
+		KS: KeyServer,
+{
+	/// Create new builder with default service configuration.
+	pub fn new() -> Self {
+		ServiceBuilder {
+			routes: Vec::new(),
+			router: None,
+			shadow: None,
+			blockchain: None,
+			executor: None,
+			transaction_pool: None,
+			service_config: ServiceConfiguration::default(),
+			new_blocks_stream: None,
+		}
+	}
+
+	/// Add key server that will process tasks. If several key servers are added,
+	/// router must be set too.
+	pub fn with_key_server(
+		mut self,
+		key_server: Arc<KS>,
+		listener_registrar: Arc<dyn ServiceTasksListenerRegistrar>,
+		config: Configuration,
+	) -> Self {
+		self.routes.push(KeyServerRoute {
+			key_server,
+			listener_registrar,
+			config,
+		});
+		self
+	}
+
+	/// Set router that selects key server for every task.
+	pub fn with_router(mut self, router: TaskRouter) -> Self {
+		self.router = Some(router);
+		self
+	}
+
+	/// Set shadow (candidate) key server.
+	pub fn with_shadow_key_server(mut self, shadow: KeyServerRoute<KS>) -> Self {
+		self.shadow = Some(shadow);
+		self
+	}
+
+	/// Set blockchain.
+	pub fn with_blockchain(mut self, blockchain: Arc<B>) -> Self {
+		self.blockchain = Some(blockchain);
+		self
+	}
+
+	/// Set executor.
+	pub fn with_executor(mut self, executor: Arc<E>) -> Self {
+		self.executor = Some(executor);
+		self
+	}
+
+	/// Set transaction pool.
+	pub fn with_transaction_pool(mut self, transaction_pool: Arc<TP>) -> Self {
+		self.transaction_pool = Some(transaction_pool);
+		self
+	}
+
+	/// Set service configuration.
+	pub fn with_configuration(mut self, service_config: ServiceConfiguration) -> Self {
+		self.service_config = service_config;
+		self
+	}
+
+	/// Set persistent record of submitted responses.
+	pub fn with_submitted_responses(mut self, submitted_responses: Arc<SubmittedResponses>) -> Self {
+		self.service_config.submitted_responses = Some(submitted_responses);
+		self
+	}
+
+	/// Set new blocks stream.
+	pub fn with_new_blocks_stream(
+		mut self,
+		new_blocks_stream: impl Stream<Item = B::BlockHash> + Send + 'static,
+	) -> Self {
+		self.new_blocks_stream = Some(new_blocks_stream.boxed());
+		self
+	}
+
+	/// Start the service.
+	pub fn build_and_spawn(self) -> Result<ServiceHandle, Error> {
+		start_routed_service(
+			self.routes,
+			self.router,
+			self.shadow,
+			self.blockchain.ok_or_else(|| missing("blockchain"))?,
+			self.executor.ok_or_else(|| missing("executor"))?,
+			self.transaction_pool.ok_or_else(|| missing("transaction pool"))?,
+			self.service_config,
+			self.new_blocks_stream.ok_or_else(|| missing("new blocks stream"))?,
+		)
+	}
+}
+
+impl<B, E, TP, KS> Default for ServiceBuilder<B, E, TP, KS>
+	where
+		B: Blockchain,
+		E: Executor,
+		TP: TransactionPool,
+		KS: KeyServer,
+{
+	fn default() -> Self {
+		ServiceBuilder::new()
+	}
+}
+
+/// Returns error for missing builder parameter.
+fn missing(parameter: &str) -> Error {
+	Error::Internal(format!("{} is required to start Secret Store service", parameter))
+}
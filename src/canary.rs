@@ -0,0 +1,239 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{
+	Arc,
+	atomic::{AtomicUsize, Ordering},
+};
+use parity_crypto::Keccak256;
+use parity_secretstore_primitives::{Address, ServerKeyId};
+use crate::{
+	DroppedTransaction, KeyServerHandle, SecretStoreCall, SubmissionPriority, SubmitError, TaskRouter,
+	TransactionPool,
+	task_kind_and_key_id,
+	health::HealthReport,
+	identity::AccountId32,
+	pipeline::PipelineConfiguration,
+};
+
+/// Max canary percentage.
+const MAX_PERCENTAGE: u8 = 100;
+
+/// Selects slice of tasks that are processed using new (canary) code path. Selection
+/// is deterministic by key id, so all tasks and responses for the same key always
+/// follow the same path.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CanaryRollout {
+	/// Percentage of key ids that are processed using canary path. Values above 100 are
+	/// treated as 100.
+	pub percentage: u8,
+}
+
+/// Canary rollout configuration of the service.
+#[derive(Clone)]
+pub struct CanaryConfiguration {
+	/// Rollout.
+	pub rollout: CanaryRollout,
+	/// Key server that processes stable tasks.
+	pub stable_route: KeyServerHandle,
+	/// Key server that processes canary tasks.
+	pub canary_route: KeyServerHandle,
+	/// Responses submission pipeline of the canary key server. If `None`, service
+	/// pipeline is used.
+	pub canary_pipeline: Option<PipelineConfiguration>,
+	/// Statistics of `CanaryTransactionPool`, used by the service. If set, statistics
+	/// are exported in metrics.
+	pub stats: Option<Arc<CanaryStats>>,
+}
+
+/// Number of responses submitted using every path.
+#[derive(Debug, Default)]
+pub struct CanaryStats {
+	/// Number of responses submitted using stable path.
+	pub stable_submitted: AtomicUsize,
+	/// Number of responses that have failed to submit using stable path.
+	pub stable_failed: AtomicUsize,
+	/// Number of responses submitted using canary path.
+	pub canary_submitted: AtomicUsize,
+	/// Number of responses that have failed to submit using canary path.
+	pub canary_failed: AtomicUsize,
+}
+
+/// Transaction pool that submits canary slice of responses using canary pool.
+pub struct CanaryTransactionPool<S, C> {
+	/// Rollout configuration.
+	rollout: CanaryRollout,
+	/// Stable transaction pool.
+	stable: S,
+	/// Canary transaction pool.
+	canary: C,
+	/// Submission statistics.
+	stats: Arc<CanaryStats>,
+}
+
+impl CanaryRollout {
+	/// Returns true if tasks with given key id must be processed using canary path.
+	pub fn is_canary(&self, key_id: &ServerKeyId) -> bool {
+		match self.percentage {
+			0 => false,
+			percentage if percentage >= MAX_PERCENTAGE => true,
+			percentage => {
+				// key ids could be chosen by users => hash to get uniform distribution
+				let hash: [u8; 32] = key_id.as_bytes().keccak256();
+				let mut bucket = [0u8; 8];
+				bucket.copy_from_slice(&hash[..8]);
+				u64::from_be_bytes(bucket) % u64::from(MAX_PERCENTAGE) < u64::from(percentage)
+			},
+		}
+	}
+
+	/// Returns router that selects `canary` key server for canary tasks and `stable`
+	/// key server for all other tasks. Tasks that are not bound to key id are always
+	/// routed to stable key server.
+	pub fn router(self, stable: KeyServerHandle, canary: KeyServerHandle) -> TaskRouter {
+		Arc::new(move |task| match task_kind_and_key_id(task) {
+			Some((_, key_id)) if self.is_canary(&key_id) => canary,
+			_ => stable,
+		})
+	}
+}
+
+impl<S, C> CanaryTransactionPool<S, C> {
+	/// Create new transaction pool.
+	pub fn new(rollout: CanaryRollout, stable: S, canary: C) -> Self {
+		CanaryTransactionPool {
+			rollout,
+			stable,
+			canary,
+			stats: Arc::new(CanaryStats::default()),
+		}
+	}
+
+	/// Returns submission statistics.
+	pub fn stats(&self) -> Arc<CanaryStats> {
+		self.stats.clone()
+	}
+}
+
+impl<S, C> TransactionPool for CanaryTransactionPool<S, C>
+	where
+		S: TransactionPool,
+		C: TransactionPool<TransactionHash = S::TransactionHash>,
+{
+	type TransactionHash = S::TransactionHash;
+
+	fn submit_transaction(&self, call: SecretStoreCall) -> Result<Self::TransactionHash, SubmitError> {
+		self.submit_transaction_from(None, call)
+	}
+
+	fn submit_transaction_from(
+		&self,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		let priority = call.priority();
+		self.submit_transaction_with_priority(submitter, call, priority)
+	}
+
+	fn submit_transaction_with_priority(
+		&self,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+		priority: SubmissionPriority,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		self.submit_transaction_for_origin(None, submitter, call, priority)
+	}
+
+	fn submit_transaction_for_origin(
+		&self,
+		origin: Option<&Address>,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+		priority: SubmissionPriority,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		let (result, submitted, failed) = match self.rollout.is_canary(&call.key_id()) {
+			true => (
+				self.canary.submit_transaction_for_origin(origin, submitter, call, priority),
+				&self.stats.canary_submitted,
+				&self.stats.canary_failed,
+			),
+			false => (
+				self.stable.submit_transaction_for_origin(origin, submitter, call, priority),
+				&self.stats.stable_submitted,
+				&self.stats.stable_failed,
+			),
+		};
+
+		match result {
+			Ok(_) => submitted.fetch_add(1, Ordering::Relaxed),
+			Err(_) => failed.fetch_add(1, Ordering::Relaxed),
+		};
+
+		result
+	}
+
+	fn submit_batch_for_origin(
+		&self,
+		origin: Option<&Address>,
+		submitter: Option<&AccountId32>,
+		calls: Vec<SecretStoreCall>,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		// the service never batches canary and stable responses together
+		let is_canary = calls.first().map(|call| self.rollout.is_canary(&call.key_id())).unwrap_or(false);
+		if calls.iter().any(|call| self.rollout.is_canary(&call.key_id()) != is_canary) {
+			return Err(SubmitError::Invalid("batch contains both canary and stable responses".into()));
+		}
+
+		let calls_count = calls.len();
+		let (result, submitted, failed) = match is_canary {
+			true => (
+				self.canary.submit_batch_for_origin(origin, submitter, calls),
+				&self.stats.canary_submitted,
+				&self.stats.canary_failed,
+			),
+			false => (
+				self.stable.submit_batch_for_origin(origin, submitter, calls),
+				&self.stats.stable_submitted,
+				&self.stats.stable_failed,
+			),
+		};
+
+		match result {
+			Ok(_) => submitted.fetch_add(calls_count, Ordering::Relaxed),
+			Err(_) => failed.fetch_add(calls_count, Ordering::Relaxed),
+		};
+
+		result
+	}
+
+	fn routes_by_origin(&self) -> bool {
+		self.stable.routes_by_origin() || self.canary.routes_by_origin()
+	}
+
+	fn submitter_account(&self) -> Option<AccountId32> {
+		self.stable.submitter_account()
+	}
+
+	fn submit_health_report(&self, report: &HealthReport) -> Result<Self::TransactionHash, SubmitError> {
+		self.stable.submit_health_report(report)
+	}
+
+	fn take_dropped_transactions(&self) -> Vec<DroppedTransaction> {
+		let mut dropped_transactions = self.stable.take_dropped_transactions();
+		dropped_transactions.extend(self.canary.take_dropped_transactions());
+		dropped_transactions
+	}
+}
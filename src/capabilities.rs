@@ -0,0 +1,120 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::BTreeSet,
+	fmt,
+};
+use parity_secretstore_primitives::Address;
+use crate::{TaskKind, identity::AccountId32};
+
+/// All known task kinds.
+pub const ALL_TASK_KINDS: [TaskKind; 4] = [
+	TaskKind::ServerKeyGeneration,
+	TaskKind::ServerKeyRetrieval,
+	TaskKind::DocumentKeyStore,
+	TaskKind::DocumentKeyShadowRetrieval,
+];
+
+/// Capabilities of running service instance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityReport {
+	/// Label of the service instance.
+	pub instance_label: Option<String>,
+	/// Addresses of key servers that are processing tasks.
+	pub key_servers: Vec<Address>,
+	/// Address of shadow (candidate) key server.
+	pub shadow_key_server: Option<Address>,
+	/// Kinds of tasks that are served for origins without custom policy.
+	pub enabled_task_kinds: BTreeSet<TaskKind>,
+	/// Number of origins with custom serving policy.
+	pub tenants: usize,
+	/// Number of key id namespaces served. Zero means that all key ids are served.
+	pub key_id_namespaces: usize,
+	/// Runtime interface version, if detected.
+	pub runtime_interface_version: Option<u32>,
+	/// Default account that submits responses, if known.
+	pub submitter_account: Option<AccountId32>,
+	/// Pending tasks are read from every `pending_scan_interval` block.
+	pub pending_scan_interval: u32,
+	/// True if pending scans are adaptively throttled.
+	pub pending_scan_throttling: bool,
+	/// True if requests from non-finalized blocks are processed speculatively.
+	pub speculative_processing: bool,
+	/// Kinds of tasks which latency is tracked.
+	pub sla_tracked_task_kinds: BTreeSet<TaskKind>,
+	/// True if identifying data is redacted.
+	pub confidential_logging: bool,
+	/// True if responses are mirrored to secondary publication target.
+	pub secondary_publisher: bool,
+	/// Compile-time features of the crate.
+	pub features: Vec<&'static str>,
+}
+
+/// Returns compile-time features of the crate.
+pub fn enabled_features() -> Vec<&'static str> {
+	let mut features = Vec::new();
+	if cfg!(feature = "golden-vectors") {
+		features.push("golden-vectors");
+	}
+	if cfg!(feature = "ledger") {
+		features.push("ledger");
+	}
+	if cfg!(feature = "metrics") {
+		features.push("metrics");
+	}
+	features
+}
+
+impl fmt::Display for CapabilityReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if let Some(ref instance_label) = self.instance_label {
+			writeln!(f, "instance: {}", instance_label)?;
+		}
+		writeln!(f, "key servers: {:?}", self.key_servers)?;
+		match self.shadow_key_server {
+			Some(ref shadow_key_server) => writeln!(f, "shadow key server: {:?}", shadow_key_server)?,
+			None => writeln!(f, "shadow key server: none")?,
+		}
+		writeln!(f, "enabled task kinds: {:?}", self.enabled_task_kinds)?;
+		writeln!(f, "custom tenant policies: {}", self.tenants)?;
+		match self.key_id_namespaces {
+			0 => writeln!(f, "key id namespaces: all")?,
+			key_id_namespaces => writeln!(f, "key id namespaces: {}", key_id_namespaces)?,
+		}
+		match self.runtime_interface_version {
+			Some(version) => writeln!(f, "runtime interface version: {}", version)?,
+			None => writeln!(f, "runtime interface version: unknown")?,
+		}
+		match self.submitter_account {
+			Some(ref account) => {
+				write!(f, "submitter account: 0x")?;
+				for byte in account {
+					write!(f, "{:02x}", byte)?;
+				}
+				writeln!(f)?;
+			},
+			None => writeln!(f, "submitter account: unknown")?,
+		}
+		writeln!(f, "pending scan interval: {}", self.pending_scan_interval)?;
+		writeln!(f, "pending scan throttling: {}", self.pending_scan_throttling)?;
+		writeln!(f, "speculative processing: {}", self.speculative_processing)?;
+		writeln!(f, "SLA tracked task kinds: {:?}", self.sla_tracked_task_kinds)?;
+		writeln!(f, "confidential logging: {}", self.confidential_logging)?;
+		writeln!(f, "secondary publisher: {}", self.secondary_publisher)?;
+		write!(f, "features: {:?}", self.features)
+	}
+}
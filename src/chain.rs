@@ -0,0 +1,132 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Typed facade over common SecretStore runtime module queries.
+//!
+//! Admin tools built around the service are frequently asking the same questions:
+//! is the request still pending, which key servers have already responded, which key
+//! server has given index. This module answers them using `Blockchain` primitives.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	sync::Arc,
+};
+use parity_secretstore_primitives::{Address, KeyServerId, ServerKeyId};
+use crate::{
+	Blockchain, TaskKind, task_kind_and_key_id,
+	capabilities::ALL_TASK_KINDS,
+	error::ServiceError,
+	pending::pending_tasks,
+};
+
+/// Max number of pending tasks that are read by single query.
+const PENDING_TASKS_PAGE_SIZE: usize = 64;
+
+/// Status of requests with given key id.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestStatus {
+	/// Kinds of requests that are pending on chain.
+	pub pending_task_kinds: BTreeSet<TaskKind>,
+}
+
+impl RequestStatus {
+	/// Returns true if there's at least one pending request.
+	pub fn is_pending(&self) -> bool {
+		!self.pending_task_kinds.is_empty()
+	}
+}
+
+/// Typed facade over SecretStore runtime module.
+pub struct SecretStoreChain<B> {
+	/// Blockchain reference.
+	blockchain: Arc<B>,
+}
+
+impl<B: Blockchain> SecretStoreChain<B> {
+	/// Create new facade.
+	pub fn new(blockchain: Arc<B>) -> Self {
+		SecretStoreChain { blockchain }
+	}
+
+	/// Returns reference to the underlying blockchain.
+	pub fn blockchain(&self) -> &Arc<B> {
+		&self.blockchain
+	}
+
+	/// Returns status of requests with given key id at given block.
+	pub fn request_status(&self, block_hash: B::BlockHash, key_id: ServerKeyId) -> RequestStatus {
+		RequestStatus {
+			pending_task_kinds: pending_tasks(self.blockchain.clone(), block_hash, PENDING_TASKS_PAGE_SIZE)
+				.filter_map(|task| task_kind_and_key_id(&task))
+				.filter(|(_, task_key_id)| *task_key_id == key_id)
+				.map(|(task_kind, _)| task_kind)
+				.collect(),
+		}
+	}
+
+	/// Returns kinds of requests with given key id that every key server of the current
+	/// set has responded to. Document key shadow retrieval responses are only checked if
+	/// requester is provided. Key servers without responses are omitted.
+	pub fn responses_for(
+		&self,
+		key_id: ServerKeyId,
+		requester: Option<Address>,
+	) -> Result<BTreeMap<KeyServerId, BTreeSet<TaskKind>>, ServiceError> {
+		let mut responses = BTreeMap::new();
+		for key_server_id in self.blockchain.current_key_servers_set() {
+			let mut key_server_responses = BTreeSet::new();
+			for task_kind in ALL_TASK_KINDS.iter().cloned() {
+				if self.has_response(task_kind, key_id, requester, key_server_id)? {
+					key_server_responses.insert(task_kind);
+				}
+			}
+
+			if !key_server_responses.is_empty() {
+				responses.insert(key_server_id, key_server_responses);
+			}
+		}
+
+		Ok(responses)
+	}
+
+	/// Returns key server with given index within current key servers set.
+	pub fn server_of_index(&self, index: usize) -> Option<KeyServerId> {
+		self.blockchain.current_key_servers_set().into_iter().nth(index)
+	}
+
+	/// Returns true if key server has responded to the request of given kind.
+	fn has_response(
+		&self,
+		task_kind: TaskKind,
+		key_id: ServerKeyId,
+		requester: Option<Address>,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		match task_kind {
+			TaskKind::ServerKeyGeneration =>
+				self.blockchain.has_server_key_generation_response(key_id, key_server_id),
+			TaskKind::ServerKeyRetrieval =>
+				self.blockchain.has_server_key_retrieval_response(key_id, key_server_id),
+			TaskKind::DocumentKeyStore =>
+				self.blockchain.has_document_key_store_response(key_id, key_server_id),
+			TaskKind::DocumentKeyShadowRetrieval => match requester {
+				Some(requester) => self.blockchain
+					.has_document_key_shadow_retrieval_response(key_id, requester, key_server_id),
+				None => Ok(false),
+			},
+		}
+	}
+}
@@ -0,0 +1,287 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Persistent checkpoint of the last processed block.
+//!
+//! Together with the persistent record of submitted responses, it lets restarted
+//! service resume from where it has stopped: blocks that have been imported while
+//! service has been down are replayed (see `replay` module) and requests that have
+//! already been answered are not executed again.
+//!
+//! Block is checkpointed when all key server routes have finished its processing.
+//! Hashes of the recently processed blocks are stored too, so if some of these blocks
+//! have been retracted while service has been down, processing is resumed from the
+//! last block that is still canonical.
+
+use std::{
+	collections::VecDeque,
+	convert::TryInto,
+	sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+};
+use log::warn;
+use crate::{persistence::Persistence, shutdown::ShutdownReporter};
+
+/// Key of the last processed block number and hash, written by older versions of the service.
+const LAST_PROCESSED_BLOCK_KEY: &[u8] = b"secretstore:checkpoint:last_block";
+/// Key of the recently processed blocks numbers and hashes.
+const RECENT_BLOCKS_KEY: &[u8] = b"secretstore:checkpoint:recent_blocks";
+/// Max number of recently processed blocks in the checkpoint.
+const MAX_RECENT_BLOCKS: usize = 64;
+
+/// Checkpointed block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointedBlock {
+	/// Block number.
+	pub number: u64,
+	/// Encoded block hash. Empty if blockchain doesn't support hashes encoding or if
+	/// checkpoint has been written by older version of the service.
+	pub hash: Vec<u8>,
+}
+
+/// Persistent checkpoint of the last processed block.
+pub struct BlockCheckpoint {
+	/// Underlying persistence.
+	persistence: Arc<dyn Persistence>,
+	/// Recently processed blocks, the last processed block first.
+	recent_blocks: Mutex<VecDeque<CheckpointedBlock>>,
+}
+
+impl BlockCheckpoint {
+	/// Create new checkpoint.
+	pub fn new(persistence: Arc<dyn Persistence>) -> Self {
+		let recent_blocks = read_recent_blocks(&*persistence);
+		BlockCheckpoint {
+			persistence,
+			recent_blocks: Mutex::new(recent_blocks),
+		}
+	}
+
+	/// Returns recently processed blocks, the last processed block first.
+	pub fn recent_blocks(&self) -> Vec<CheckpointedBlock> {
+		self.recent_blocks.lock().expect("never panics under lock; qed").iter().cloned().collect()
+	}
+
+	/// Called when block is processed.
+	pub fn on_block_processed(&self, block_number: u64, block_hash: &[u8]) {
+		let mut recent_blocks = self.recent_blocks.lock().expect("never panics under lock; qed");
+		// blocks of the retracted fork are replaced
+		while recent_blocks.front().map(|block| block.number >= block_number).unwrap_or(false) {
+			recent_blocks.pop_front();
+		}
+		recent_blocks.push_front(CheckpointedBlock {
+			number: block_number,
+			hash: block_hash.to_vec(),
+		});
+		recent_blocks.truncate(MAX_RECENT_BLOCKS);
+
+		if let Err(error) = self.persistence.put(RECENT_BLOCKS_KEY, encode_blocks(&recent_blocks)) {
+			warn!(
+				target: "secretstore",
+				"Failed to write last processed block: {}",
+				error,
+			);
+		}
+	}
+}
+
+/// Block that is being processed by key server routes. Block is recorded as processed
+/// when all routes have finished its processing.
+pub struct ProcessedBlock {
+	/// Checkpoint to write.
+	checkpoint: Option<Arc<BlockCheckpoint>>,
+	/// Shutdown reporter that tracks the last processed block.
+	shutdown: Arc<ShutdownReporter>,
+	/// Block number.
+	number: u64,
+	/// Encoded block hash.
+	hash: Vec<u8>,
+	/// Number of routes that are still processing the block.
+	remaining_routes: AtomicUsize,
+}
+
+impl ProcessedBlock {
+	/// Create new processed block.
+	pub fn new(
+		checkpoint: Option<Arc<BlockCheckpoint>>,
+		shutdown: Arc<ShutdownReporter>,
+		number: u64,
+		hash: Vec<u8>,
+	) -> Self {
+		ProcessedBlock {
+			checkpoint,
+			shutdown,
+			number,
+			hash,
+			remaining_routes: AtomicUsize::new(0),
+		}
+	}
+
+	/// Called when block is passed to given number of routes.
+	pub fn on_routes_started(&self, routes: usize) {
+		self.remaining_routes.store(routes, Ordering::SeqCst);
+	}
+
+	/// Called when route has finished processing the block.
+	pub fn on_route_processed(&self) {
+		if self.remaining_routes.fetch_sub(1, Ordering::SeqCst) != 1 {
+			return;
+		}
+
+		if let Some(ref checkpoint) = self.checkpoint {
+			checkpoint.on_block_processed(self.number, &self.hash);
+		}
+		self.shutdown.on_block_processed(self.number);
+	}
+}
+
+/// Read recently processed blocks from the persistence.
+fn read_recent_blocks(persistence: &dyn Persistence) -> VecDeque<CheckpointedBlock> {
+	let read_result = persistence.get(RECENT_BLOCKS_KEY)
+		.and_then(|encoded| match encoded {
+			Some(encoded) => decode_blocks(&encoded)
+				.map(Some)
+				.ok_or_else(|| String::from("invalid encoding of recent blocks")),
+			None => Ok(None),
+		})
+		.and_then(|recent_blocks| match recent_blocks {
+			Some(recent_blocks) => Ok(recent_blocks),
+			None => read_legacy_block(persistence).map(|block| block.into_iter().collect()),
+		});
+
+	match read_result {
+		Ok(recent_blocks) => recent_blocks,
+		Err(error) => {
+			warn!(
+				target: "secretstore",
+				"Failed to read last processed block: {}",
+				error,
+			);
+			VecDeque::new()
+		},
+	}
+}
+
+/// Read the last processed block, written by older version of the service.
+fn read_legacy_block(persistence: &dyn Persistence) -> Result<Option<CheckpointedBlock>, String> {
+	let encoded = match persistence.get(LAST_PROCESSED_BLOCK_KEY)? {
+		Some(encoded) => encoded,
+		None => return Ok(None),
+	};
+
+	match encoded.get(..8).and_then(|number| number.try_into().ok()) {
+		Some(number) => Ok(Some(CheckpointedBlock {
+			number: u64::from_be_bytes(number),
+			hash: encoded[8..].to_vec(),
+		})),
+		None => Err(format!("invalid length of the last processed block: {}", encoded.len())),
+	}
+}
+
+/// Encode blocks as sequence of numbers and length-prefixed hashes.
+fn encode_blocks(blocks: &VecDeque<CheckpointedBlock>) -> Vec<u8> {
+	let mut encoded = Vec::new();
+	for block in blocks {
+		encoded.extend_from_slice(&block.number.to_be_bytes());
+		encoded.extend_from_slice(&(block.hash.len() as u32).to_be_bytes());
+		encoded.extend_from_slice(&block.hash);
+	}
+	encoded
+}
+
+/// Decode blocks, encoded with `encode_blocks`.
+fn decode_blocks(mut encoded: &[u8]) -> Option<VecDeque<CheckpointedBlock>> {
+	let mut blocks = VecDeque::new();
+	while !encoded.is_empty() {
+		let number = u64::from_be_bytes(encoded.get(..8)?.try_into().ok()?);
+		let hash_len = u32::from_be_bytes(encoded.get(8..12)?.try_into().ok()?) as usize;
+		let hash = encoded.get(12..12usize.checked_add(hash_len)?)?;
+		blocks.push_back(CheckpointedBlock {
+			number,
+			hash: hash.to_vec(),
+		});
+		encoded = &encoded[12 + hash_len..];
+	}
+	Some(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::persistence::InMemoryPersistence;
+	use super::*;
+
+	fn block(number: u64, hash: u8) -> CheckpointedBlock {
+		CheckpointedBlock {
+			number,
+			hash: vec![hash; 32],
+		}
+	}
+
+	#[test]
+	fn recent_blocks_are_persisted() {
+		let persistence = Arc::new(InMemoryPersistence::default());
+		let checkpoint = BlockCheckpoint::new(persistence.clone());
+		checkpoint.on_block_processed(1, &[1; 32]);
+		checkpoint.on_block_processed(2, &[2; 32]);
+
+		let checkpoint = BlockCheckpoint::new(persistence);
+		assert_eq!(checkpoint.recent_blocks(), vec![block(2, 2), block(1, 1)]);
+	}
+
+	#[test]
+	fn retracted_blocks_are_replaced() {
+		let checkpoint = BlockCheckpoint::new(Arc::new(InMemoryPersistence::default()));
+		checkpoint.on_block_processed(1, &[1; 32]);
+		checkpoint.on_block_processed(2, &[2; 32]);
+		checkpoint.on_block_processed(3, &[3; 32]);
+		checkpoint.on_block_processed(2, &[4; 32]);
+
+		assert_eq!(checkpoint.recent_blocks(), vec![block(2, 4), block(1, 1)]);
+	}
+
+	#[test]
+	fn number_of_recent_blocks_is_limited() {
+		let checkpoint = BlockCheckpoint::new(Arc::new(InMemoryPersistence::default()));
+		for number in 0..MAX_RECENT_BLOCKS as u64 + 10 {
+			checkpoint.on_block_processed(number, &[0; 32]);
+		}
+
+		let recent_blocks = checkpoint.recent_blocks();
+		assert_eq!(recent_blocks.len(), MAX_RECENT_BLOCKS);
+		assert_eq!(recent_blocks[0].number, MAX_RECENT_BLOCKS as u64 + 9);
+	}
+
+	#[test]
+	fn legacy_checkpoint_is_read() {
+		let persistence = Arc::new(InMemoryPersistence::default());
+		let mut encoded = 42u64.to_be_bytes().to_vec();
+		encoded.extend_from_slice(&[1; 32]);
+		persistence.put(LAST_PROCESSED_BLOCK_KEY, encoded).unwrap();
+		assert_eq!(BlockCheckpoint::new(persistence.clone()).recent_blocks(), vec![block(42, 1)]);
+
+		persistence.put(LAST_PROCESSED_BLOCK_KEY, 42u64.to_be_bytes().to_vec()).unwrap();
+		assert_eq!(
+			BlockCheckpoint::new(persistence).recent_blocks(),
+			vec![CheckpointedBlock { number: 42, hash: Vec::new() }],
+		);
+	}
+
+	#[test]
+	fn corrupted_checkpoint_is_ignored() {
+		let persistence = Arc::new(InMemoryPersistence::default());
+		persistence.put(RECENT_BLOCKS_KEY, vec![0; 10]).unwrap();
+		assert_eq!(BlockCheckpoint::new(persistence).recent_blocks(), Vec::new());
+	}
+}
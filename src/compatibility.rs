@@ -0,0 +1,171 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Compatibility of this build with the SecretStore runtime module.
+//!
+//! Deployment tooling verifies that the service understands the runtime before it
+//! is rolled out, rather than after the first failed decode. Events are described
+//! by tasks they're decoded into - the runtime-specific decoding is done by the
+//! `MaybeSecretStoreEvent` implementation.
+
+use std::{fmt, ops::RangeInclusive};
+use crate::{TaskKind, capabilities::enabled_features};
+
+/// Versions of the SecretStore runtime interface (see `Blockchain::runtime_interface_version`)
+/// which events and calls layouts are matching this build.
+pub const SUPPORTED_RUNTIME_INTERFACE_VERSIONS: RangeInclusive<u32> = 1..=1;
+
+/// Layout of the SecretStore runtime module event or call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+	/// Name of the event or call.
+	pub name: &'static str,
+	/// Kind of the task this event starts (or this call responds to).
+	pub task_kind: TaskKind,
+	/// Names of the fields, in order.
+	pub fields: &'static [&'static str],
+}
+
+/// Build/runtime compatibility matrix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompatibilityMatrix {
+	/// Version of the service crate.
+	pub service_version: &'static str,
+	/// Compile-time features of the crate.
+	pub features: Vec<&'static str>,
+	/// Supported versions of the SecretStore runtime interface.
+	pub runtime_interface_versions: RangeInclusive<u32>,
+	/// Task events that are understood by the service.
+	pub events: Vec<Layout>,
+	/// Calls that are submitted by the service.
+	pub calls: Vec<Layout>,
+}
+
+/// Returns compatibility matrix of this build.
+pub fn compatibility_matrix() -> CompatibilityMatrix {
+	CompatibilityMatrix {
+		service_version: env!("CARGO_PKG_VERSION"),
+		features: enabled_features(),
+		runtime_interface_versions: SUPPORTED_RUNTIME_INTERFACE_VERSIONS,
+		events: vec![
+			Layout {
+				name: "GenerateServerKey",
+				task_kind: TaskKind::ServerKeyGeneration,
+				fields: &["origin", "key_id", "requester", "threshold"],
+			},
+			Layout {
+				name: "RetrieveServerKey",
+				task_kind: TaskKind::ServerKeyRetrieval,
+				fields: &["origin", "key_id", "requester"],
+			},
+			Layout {
+				name: "StoreDocumentKey",
+				task_kind: TaskKind::DocumentKeyStore,
+				fields: &["origin", "key_id", "requester", "common_point", "encrypted_point"],
+			},
+			Layout {
+				name: "RetrieveShadowDocumentKeyCommon",
+				task_kind: TaskKind::DocumentKeyShadowRetrieval,
+				fields: &["origin", "key_id", "requester"],
+			},
+			Layout {
+				name: "RetrieveShadowDocumentKeyPersonal",
+				task_kind: TaskKind::DocumentKeyShadowRetrieval,
+				fields: &["origin", "key_id", "requester"],
+			},
+		],
+		calls: vec![
+			Layout {
+				name: "ServerKeyGenerated",
+				task_kind: TaskKind::ServerKeyGeneration,
+				fields: &["key_id", "server_key_public"],
+			},
+			Layout {
+				name: "ServerKeyGenerationError",
+				task_kind: TaskKind::ServerKeyGeneration,
+				fields: &["key_id"],
+			},
+			Layout {
+				name: "ServerKeyRetrieved",
+				task_kind: TaskKind::ServerKeyRetrieval,
+				fields: &["key_id", "server_key_public", "threshold"],
+			},
+			Layout {
+				name: "ServerKeyRetrievalError",
+				task_kind: TaskKind::ServerKeyRetrieval,
+				fields: &["key_id"],
+			},
+			Layout {
+				name: "DocumentKeyStored",
+				task_kind: TaskKind::DocumentKeyStore,
+				fields: &["key_id"],
+			},
+			Layout {
+				name: "DocumentKeyStoreError",
+				task_kind: TaskKind::DocumentKeyStore,
+				fields: &["key_id"],
+			},
+			Layout {
+				name: "DocumentKeyCommonRetrieved",
+				task_kind: TaskKind::DocumentKeyShadowRetrieval,
+				fields: &["key_id", "requester", "common_point", "threshold"],
+			},
+			Layout {
+				name: "DocumentKeyPersonalRetrieved",
+				task_kind: TaskKind::DocumentKeyShadowRetrieval,
+				fields: &["key_id", "requester", "participants", "decrypted_secret", "shadow"],
+			},
+			Layout {
+				name: "DocumentKeyShadowRetrievalError",
+				task_kind: TaskKind::DocumentKeyShadowRetrieval,
+				fields: &["key_id", "requester"],
+			},
+		],
+	}
+}
+
+impl CompatibilityMatrix {
+	/// Returns true if runtime with given interface version is supported. Runtime that
+	/// doesn't report its version is assumed to be compatible.
+	pub fn is_compatible(&self, runtime_interface_version: Option<u32>) -> bool {
+		runtime_interface_version
+			.map(|version| self.runtime_interface_versions.contains(&version))
+			.unwrap_or(true)
+	}
+}
+
+impl fmt::Display for CompatibilityMatrix {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "service version: {}", self.service_version)?;
+		writeln!(f, "features: {:?}", self.features)?;
+		writeln!(
+			f,
+			"runtime interface versions: {}..={}",
+			self.runtime_interface_versions.start(),
+			self.runtime_interface_versions.end(),
+		)?;
+		for event in &self.events {
+			writeln!(f, "event {}({})", event.name, event.fields.join(", "))?;
+		}
+		for (index, call) in self.calls.iter().enumerate() {
+			write!(f, "call {}({})", call.name, call.fields.join(", "))?;
+			if index + 1 != self.calls.len() {
+				writeln!(f)?;
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,69 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	fmt::{Debug, Display, Write},
+	sync::Arc,
+};
+use parity_crypto::Keccak256;
+
+/// Number of hash bytes that are used to identify redacted values.
+const REDACTED_HASH_LEN: usize = 8;
+
+/// Formats identifying data (key ids, requesters) before it leaves the service
+/// (logs, metrics labels, ...).
+///
+/// In confidential mode, values are replaced with salted hashes. The same value is
+/// always replaced with the same hash, so requests could still be traced through logs.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+	/// Salt. If `None`, values are not redacted.
+	salt: Option<Arc<Vec<u8>>>,
+}
+
+impl Redactor {
+	/// Create new redactor. If salt is `None`, values are formatted as is.
+	pub fn new(salt: Option<Vec<u8>>) -> Self {
+		Redactor {
+			salt: salt.map(Arc::new),
+		}
+	}
+
+	/// Returns true if confidential mode is enabled.
+	pub fn is_confidential(&self) -> bool {
+		self.salt.is_some()
+	}
+
+	/// Format identifying value.
+	pub fn redact<T: Debug + Display>(&self, value: &T) -> String {
+		let salt = match self.salt {
+			Some(ref salt) => salt,
+			None => return value.to_string(),
+		};
+
+		// Debug representation of all identifying types is not abbreviated
+		let mut salted_value = salt.as_ref().clone();
+		salted_value.extend_from_slice(format!("{:?}", value).as_bytes());
+		let hash: [u8; 32] = salted_value.keccak256();
+
+		let mut redacted = String::with_capacity(1 + 2 * REDACTED_HASH_LEN);
+		redacted.push('#');
+		for byte in &hash[..REDACTED_HASH_LEN] {
+			let _ = write!(redacted, "{:02x}", byte);
+		}
+		redacted
+	}
+}
@@ -0,0 +1,157 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Confirmations of high-value requests.
+//!
+//! Some requests are too valuable to be served speculatively: when the block
+//! where such request has been seen is retracted, the (irreversible) effects of
+//! the session are already there. Tasks of kinds that require confirmations are
+//! deferred until the block where they have been seen has enough blocks on top
+//! of it. This also works when new blocks stream is yielding best blocks.
+//!
+//! Confirmations only apply to tasks from block events. Pending tasks scans are
+//! still reading all pending requests, except for those that are deferred.
+
+use std::{
+	collections::{BTreeMap, VecDeque},
+	sync::Mutex,
+};
+use log::{error, trace, warn};
+use parity_secretstore_blockchain_service::BlockchainServiceTask;
+use crate::{
+	Blockchain, KeyServerHandle, TaskKind, TaskOriginBlock, task_kind_and_key_id,
+	dedup::ServedRequest,
+	speculative::BlockFinality,
+};
+
+/// Max number of deferred tasks of single key server route.
+const MAX_DEFERRED_TASKS: usize = 4096;
+
+/// Task that is waiting for confirmations.
+struct DeferredTask<Hash> {
+	/// Block where task has been seen.
+	origin: TaskOriginBlock<Hash>,
+	/// Deferred task.
+	task: BlockchainServiceTask,
+}
+
+/// Tasks that are waiting for confirmations.
+pub struct ConfirmationQueue<Hash> {
+	/// Number of blocks that must be built on top of the task block, by task kind.
+	depths: BTreeMap<TaskKind, u64>,
+	/// Deferred tasks of every key server route (`None` is the shadow key server).
+	queues: Mutex<BTreeMap<Option<KeyServerHandle>, VecDeque<DeferredTask<Hash>>>>,
+}
+
+impl<Hash: Clone> ConfirmationQueue<Hash> {
+	/// Create new confirmation queue.
+	pub fn new(depths: BTreeMap<TaskKind, u64>) -> Self {
+		ConfirmationQueue {
+			depths,
+			queues: Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	/// Returns true if tasks of some kinds require confirmations.
+	pub fn is_enabled(&self) -> bool {
+		self.depths.values().any(|depth| *depth != 0)
+	}
+
+	/// Returns number of confirmations that the task requires.
+	pub fn confirmation_depth(&self, task: &BlockchainServiceTask) -> u64 {
+		task_kind_and_key_id(task)
+			.and_then(|(task_kind, _)| self.depths.get(&task_kind).cloned())
+			.unwrap_or(0)
+	}
+
+	/// Defer task until its block gets enough confirmations.
+	pub fn defer(&self, route: Option<KeyServerHandle>, origin: TaskOriginBlock<Hash>, task: BlockchainServiceTask) {
+		let mut queues = self.queues.lock().expect("never panics under lock; qed");
+		let queue = queues.entry(route).or_default();
+		queue.push_back(DeferredTask { origin, task });
+
+		if queue.len() > MAX_DEFERRED_TASKS {
+			queue.pop_front();
+			warn!(
+				target: "secretstore",
+				"Too many tasks are waiting for confirmations. Dropping oldest task",
+			);
+		}
+	}
+
+	/// Returns true if the same request is waiting for confirmations.
+	pub fn is_deferred(&self, route: Option<KeyServerHandle>, task: &BlockchainServiceTask) -> bool {
+		let request = match ServedRequest::from_task(task) {
+			Some(request) => request,
+			None => return false,
+		};
+
+		self.queues
+			.lock()
+			.expect("never panics under lock; qed")
+			.get(&route)
+			.map(|queue| queue.iter().any(|deferred| ServedRequest::from_task(&deferred.task) == Some(request)))
+			.unwrap_or(false)
+	}
+
+	/// Returns tasks that have got enough confirmations at given best block. Tasks from
+	/// retracted blocks are dropped.
+	pub fn release<B: Blockchain<BlockHash = Hash>>(
+		&self,
+		blockchain: &B,
+		route: Option<KeyServerHandle>,
+		best_block_number: u64,
+	) -> Vec<(TaskOriginBlock<Hash>, BlockchainServiceTask)> {
+		let mut queues = self.queues.lock().expect("never panics under lock; qed");
+		let queue = match queues.get_mut(&route) {
+			Some(queue) => queue,
+			None => return Vec::new(),
+		};
+
+		let mut released = Vec::new();
+		let mut still_deferred = VecDeque::with_capacity(queue.len());
+		for deferred in queue.drain(..) {
+			let origin_block_number = deferred.origin.block_number.unwrap_or(best_block_number);
+			let confirmations = best_block_number.saturating_sub(origin_block_number);
+			if confirmations < self.confirmation_depth(&deferred.task) {
+				still_deferred.push_back(deferred);
+				continue;
+			}
+
+			match blockchain.block_finality(deferred.origin.block_hash.clone()) {
+				Ok(BlockFinality::Retracted) => trace!(
+					target: "secretstore",
+					"Dropping task from retracted block {}",
+					origin_block_number,
+				),
+				Ok(_) => released.push((deferred.origin, deferred.task)),
+				Err(error) => {
+					error!(
+						target: "secretstore",
+						"Failed to read finality of the block {}: {}",
+						origin_block_number,
+						error,
+					);
+
+					still_deferred.push_back(deferred);
+				},
+			}
+		}
+
+		*queue = still_deferred;
+		released
+	}
+}
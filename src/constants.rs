@@ -0,0 +1,70 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use log::info;
+use crate::{
+	ServiceConfiguration,
+	capabilities::ALL_TASK_KINDS,
+	verify::ArtifactsVerification,
+};
+
+/// Constants of the SecretStore runtime module.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SecretStoreConstants {
+	/// Max threshold of server keys.
+	pub max_threshold: Option<u8>,
+	/// Number of blocks the module is waiting for key servers responses.
+	pub response_timeout: Option<u64>,
+	/// Max size of encoded module call.
+	pub max_payload_size: Option<usize>,
+}
+
+/// Parameterize configuration with runtime module constants. Values that are explicitly
+/// configured are kept, unless they're contradicting the runtime.
+pub fn apply_constants(config: &mut ServiceConfiguration, constants: &SecretStoreConstants) {
+	if constants.max_threshold.is_some() || constants.max_payload_size.is_some() {
+		let artifacts_verification = config.artifacts_verification.get_or_insert_with(|| ArtifactsVerification {
+			verifier: None,
+			mismatch_handler: None,
+			max_encoded_sizes: Default::default(),
+			max_threshold: None,
+		});
+		if artifacts_verification.max_threshold.is_none() {
+			artifacts_verification.max_threshold = constants.max_threshold;
+		}
+		if let Some(max_payload_size) = constants.max_payload_size {
+			for task_kind in ALL_TASK_KINDS.iter() {
+				artifacts_verification.max_encoded_sizes.entry(*task_kind).or_insert(max_payload_size);
+			}
+		}
+	}
+
+	// all resubmissions must happen before the runtime stops waiting for the response
+	if let (Some(response_timeout), Some(reconciliation)) = (constants.response_timeout, config.reconciliation.as_mut()) {
+		let attempts = u64::from(reconciliation.max_resubmissions) + 1;
+		let max_confirmation_timeout = std::cmp::max(response_timeout / attempts, 1);
+		if reconciliation.confirmation_timeout > max_confirmation_timeout {
+			info!(
+				target: "secretstore",
+				"Decreasing responses confirmation timeout to {} blocks to fit runtime response timeout of {} blocks",
+				max_confirmation_timeout,
+				response_timeout,
+			);
+
+			reconciliation.confirmation_timeout = max_confirmation_timeout;
+		}
+	}
+}
@@ -0,0 +1,331 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use log::{trace, warn};
+use parity_secretstore_primitives::{Address, ServerKeyId};
+use crate::{
+	BlockchainServiceTask, SecretStoreCall, TaskKind, task_kind_and_key_id, task_origin,
+	identity::requester_address,
+	persistence::{GenesisBoundPersistence, Persistence},
+};
+
+/// Prefix of submitted response records keys.
+const SUBMITTED_RESPONSE_KEY_PREFIX: &[u8] = b"secretstore:submitted:";
+/// Number of blocks during which completed requests are not served again.
+const COMPLETED_REQUESTS_RETENTION: u64 = 16;
+
+/// Request that has been responded by this key server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ServedRequest {
+	/// Origin of the request. `None` if it is unknown (externally produced calls).
+	pub origin: Option<Address>,
+	/// Kind of the task.
+	pub task_kind: TaskKind,
+	/// Key id.
+	pub key_id: ServerKeyId,
+	/// Requester address (document key shadow retrieval only).
+	pub requester: Option<Address>,
+	/// True if this is personal part of document key shadow retrieval.
+	pub is_personal: bool,
+}
+
+/// Record of the submitted response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmittedResponse {
+	/// Hash of the transaction that has been submitted.
+	pub transaction_hash: String,
+	/// Submission time (seconds since unix epoch).
+	pub submitted_at: u64,
+}
+
+/// Persistent record of responses that have been submitted, but (probably) not yet
+/// included into the chain. It survives restarts, so requests that have already been
+/// answered aren't executed again while the responses are still in the transaction pool.
+pub struct SubmittedResponses {
+	/// Records storage.
+	persistence: Arc<dyn Persistence>,
+	/// Records older than this are considered lost (e.g. transaction has been dropped
+	/// from the pool) and the request is executed again.
+	ttl: Duration,
+}
+
+/// Requests that have been recently completed (by any key server).
+///
+/// When peer key server restarts and re-publishes its responses, the runtime module
+/// may emit events that look like new requests. Requests that are known to be completed
+/// are not served again for some blocks.
+#[derive(Default)]
+pub struct CompletedRequests {
+	/// Completed requests state.
+	state: Mutex<CompletedRequestsState>,
+}
+
+/// Completed requests state.
+#[derive(Default)]
+struct CompletedRequestsState {
+	/// Index of the current block.
+	current_block: u64,
+	/// Completed requests, mapped to the block where they have been completed.
+	requests: BTreeMap<ServedRequest, u64>,
+}
+
+impl CompletedRequests {
+	/// Called when new block is processed. Forgets old completed requests.
+	pub fn on_new_block(&self) {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		state.current_block += 1;
+
+		let current_block = state.current_block;
+		state.requests.retain(|_, completed_at| current_block - *completed_at <= COMPLETED_REQUESTS_RETENTION);
+	}
+
+	/// Called when request is known to be completed.
+	pub fn on_request_completed(&self, request: ServedRequest) {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let current_block = state.current_block;
+		state.requests.insert(request, current_block);
+	}
+
+	/// Returns true if request of the task has been recently completed.
+	pub fn is_task_completed(&self, task: &BlockchainServiceTask) -> bool {
+		let request = match ServedRequest::from_task(task) {
+			Some(request) => request,
+			None => return false,
+		};
+
+		let is_completed = self.state.lock().expect("never panics under lock; qed").requests.contains_key(&request);
+		if is_completed {
+			trace!(
+				target: "secretstore",
+				"Skipping {:?} task: request has been already completed",
+				request.task_kind,
+			);
+		}
+
+		is_completed
+	}
+}
+
+impl ServedRequest {
+	/// Returns request that the task is about to serve.
+	pub fn from_task(task: &BlockchainServiceTask) -> Option<Self> {
+		let (task_kind, key_id) = task_kind_and_key_id(task)?;
+		let (requester, is_personal) = match *task {
+			BlockchainServiceTask::RetrieveShadowDocumentKeyCommon(_, _, ref requester) =>
+				(requester_address(requester, &key_id).ok(), false),
+			BlockchainServiceTask::RetrieveShadowDocumentKeyPersonal(_, _, ref requester) =>
+				(requester_address(requester, &key_id).ok(), true),
+			_ => (None, false),
+		};
+
+		Some(ServedRequest {
+			origin: Some(task_origin(task)),
+			task_kind,
+			key_id,
+			requester,
+			is_personal,
+		})
+	}
+
+	/// Returns requests of given origin that are completed by given response, accepted
+	/// by the runtime module.
+	pub fn from_accepted_call(origin: Option<Address>, call: &SecretStoreCall) -> Vec<Self> {
+		let (task_kind, key_id) = (call.task_kind(), call.key_id());
+		let requests = match *call {
+			SecretStoreCall::DocumentKeyCommonRetrieved(_, requester, ..) =>
+				vec![(Some(requester), false)],
+			SecretStoreCall::DocumentKeyPersonalRetrieved(_, requester, ..) =>
+				vec![(Some(requester), true)],
+			// error could be reported at both common and personal retrieval stages
+			SecretStoreCall::DocumentKeyShadowRetrievalError(_, requester) =>
+				vec![(Some(requester), false), (Some(requester), true)],
+			_ => vec![(None, false)],
+		};
+
+		requests
+			.into_iter()
+			.map(|(requester, is_personal)| ServedRequest {
+				origin,
+				task_kind,
+				key_id,
+				requester,
+				is_personal,
+			})
+			.collect()
+	}
+
+	/// Returns key of the record in persistence.
+	fn record_key(&self) -> Vec<u8> {
+		self.prefixed_key(SUBMITTED_RESPONSE_KEY_PREFIX)
+	}
+
+	/// Returns key of the request-related record with given prefix.
+	pub fn prefixed_key(&self, prefix: &[u8]) -> Vec<u8> {
+		let mut key = prefix.to_vec();
+		key.push(self.task_kind as u8);
+		key.extend_from_slice(self.key_id.as_bytes());
+		key.push(self.is_personal as u8);
+		key.push(self.origin.is_some() as u8);
+		if let Some(ref origin) = self.origin {
+			key.extend_from_slice(origin.as_bytes());
+		}
+		if let Some(ref requester) = self.requester {
+			key.extend_from_slice(requester.as_bytes());
+		}
+		key
+	}
+}
+
+impl SubmittedResponse {
+	/// Encode record.
+	fn encode(&self) -> Vec<u8> {
+		let mut encoded = self.submitted_at.to_be_bytes().to_vec();
+		encoded.extend_from_slice(self.transaction_hash.as_bytes());
+		encoded
+	}
+
+	/// Decode record.
+	fn decode(encoded: &[u8]) -> Result<Self, String> {
+		if encoded.len() < 8 {
+			return Err(format!("submitted response record is too short: {} bytes", encoded.len()));
+		}
+
+		let mut submitted_at = [0u8; 8];
+		submitted_at.copy_from_slice(&encoded[..8]);
+		Ok(SubmittedResponse {
+			transaction_hash: String::from_utf8_lossy(&encoded[8..]).into_owned(),
+			submitted_at: u64::from_be_bytes(submitted_at),
+		})
+	}
+}
+
+impl SubmittedResponses {
+	/// Create new submitted responses record.
+	pub fn new(persistence: Arc<dyn Persistence>, ttl: Duration) -> Self {
+		SubmittedResponses {
+			persistence,
+			ttl,
+		}
+	}
+
+	/// Returns the same record, stored in the persistence that is bound to the chain
+	/// with given genesis hash.
+	pub fn bound_to_genesis(&self, genesis_hash: &[u8]) -> Result<Self, String> {
+		Ok(SubmittedResponses::new(
+			Arc::new(GenesisBoundPersistence::new(self.persistence.clone(), genesis_hash)?),
+			self.ttl,
+		))
+	}
+
+	/// Returns record of response to given request, if it has been submitted recently.
+	pub fn submitted_response(&self, request: &ServedRequest) -> Option<SubmittedResponse> {
+		let record_key = request.record_key();
+		let response = match self.persistence.get(&record_key) {
+			Ok(Some(encoded)) => SubmittedResponse::decode(&encoded),
+			Ok(None) => return None,
+			Err(error) => Err(error),
+		};
+
+		match response {
+			Ok(response) if now().saturating_sub(response.submitted_at) <= self.ttl.as_secs() =>
+				Some(response),
+			Ok(_) => {
+				self.remove(&record_key);
+				None
+			},
+			Err(error) => {
+				warn!(
+					target: "secretstore",
+					"Failed to read submitted response record: {}",
+					error,
+				);
+				None
+			},
+		}
+	}
+
+	/// Returns true if response to the task has been submitted recently.
+	pub fn is_task_served(&self, task: &BlockchainServiceTask) -> bool {
+		let request = match ServedRequest::from_task(task) {
+			Some(request) => request,
+			None => return false,
+		};
+
+		match self.submitted_response(&request) {
+			Some(response) => {
+				trace!(
+					target: "secretstore",
+					"Skipping {:?} task: response has been submitted in transaction {}",
+					request.task_kind,
+					response.transaction_hash,
+				);
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Called when response to the request has been submitted.
+	pub fn on_response_submitted(&self, request: &ServedRequest, transaction_hash: String) {
+		let response = SubmittedResponse {
+			transaction_hash,
+			submitted_at: now(),
+		};
+		if let Err(error) = self.persistence.put(&request.record_key(), response.encode()) {
+			warn!(
+				target: "secretstore",
+				"Failed to store submitted response record: {}",
+				error,
+			);
+		}
+	}
+
+	/// Called when request no longer requires our response.
+	pub fn on_request_completed(&self, request: &ServedRequest) {
+		self.remove(&request.record_key());
+	}
+
+	/// Called when our response has been accepted by the runtime module.
+	pub fn on_response_accepted(&self, origin: Address, call: &SecretStoreCall) {
+		for request in ServedRequest::from_accepted_call(Some(origin), call) {
+			self.on_request_completed(&request);
+		}
+	}
+
+	/// Remove record.
+	fn remove(&self, record_key: &[u8]) {
+		if let Err(error) = self.persistence.remove(record_key) {
+			warn!(
+				target: "secretstore",
+				"Failed to remove submitted response record: {}",
+				error,
+			);
+		}
+	}
+}
+
+/// Returns current time (seconds since unix epoch).
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|duration| duration.as_secs())
+		.unwrap_or(0)
+}
@@ -0,0 +1,41 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	sync::Arc,
+	time::Duration,
+};
+
+/// Key server cluster health probe.
+pub trait ClusterHealth: Send + Sync + 'static {
+	/// Returns true if key server cluster is formed and sessions could be completed.
+	fn is_available(&self) -> bool;
+}
+
+/// Degraded mode configuration.
+///
+/// When key server cluster is unavailable (e.g. it isn't formed yet), sessions are
+/// failing for reasons unrelated to requests. In degraded mode, error responses are
+/// not published while the cluster is down. If cluster recovers within buffer
+/// period, errors are dropped and requests are retried (they are still pending
+/// on chain). Otherwise errors are published when buffer period expires.
+#[derive(Clone)]
+pub struct DegradedModeConfiguration {
+	/// Cluster health probe.
+	pub health: Arc<dyn ClusterHealth>,
+	/// Max time error responses are buffered.
+	pub buffer_period: Duration,
+}
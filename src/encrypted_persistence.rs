@@ -0,0 +1,274 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use log::warn;
+use parity_crypto::publickey::{KeyPair, ecies};
+use crate::persistence::Persistence;
+
+/// Current version of encrypted value envelope.
+const ENVELOPE_VERSION: u8 = 1;
+/// Size of envelope header: envelope version + encryption key version.
+const ENVELOPE_HEADER_SIZE: usize = 5;
+
+/// Encryption keys provider (configuration, KMS, ...).
+pub trait EncryptionKeys: Send + Sync + 'static {
+	/// Returns version and key pair that must be used to encrypt new values.
+	fn current_key(&self) -> Result<(u32, KeyPair), String>;
+	/// Returns key pair of given version, if it is still known.
+	fn key(&self, version: u32) -> Result<Option<KeyPair>, String>;
+}
+
+/// Encryption keys read from the configuration.
+pub struct StaticEncryptionKeys {
+	/// Version of the key that is used to encrypt new values.
+	current_version: u32,
+	/// All known keys.
+	keys: BTreeMap<u32, KeyPair>,
+}
+
+/// Persistence wrapper that encrypts all stored values.
+///
+/// Every value is wrapped into versioned envelope, which also holds the version
+/// of the encryption key. So after key rotation, values that are encrypted with
+/// previous keys could still be read (as long as previous keys are provided). Such
+/// values are re-encrypted with the current key when read.
+pub struct EncryptedPersistence<P, K> {
+	/// Underlying persistence.
+	persistence: P,
+	/// Encryption keys.
+	keys: K,
+}
+
+impl StaticEncryptionKeys {
+	/// Create keys provider with single key.
+	pub fn new(version: u32, key: KeyPair) -> Self {
+		let mut keys = BTreeMap::new();
+		keys.insert(version, key);
+		StaticEncryptionKeys {
+			current_version: version,
+			keys,
+		}
+	}
+
+	/// Add previous key, that is used to decrypt old values.
+	pub fn with_previous_key(mut self, version: u32, key: KeyPair) -> Self {
+		self.keys.entry(version).or_insert(key);
+		self
+	}
+}
+
+impl EncryptionKeys for StaticEncryptionKeys {
+	fn current_key(&self) -> Result<(u32, KeyPair), String> {
+		self.keys
+			.get(&self.current_version)
+			.cloned()
+			.map(|key| (self.current_version, key))
+			.ok_or_else(|| format!("missing current encryption key {}", self.current_version))
+	}
+
+	fn key(&self, version: u32) -> Result<Option<KeyPair>, String> {
+		Ok(self.keys.get(&version).cloned())
+	}
+}
+
+impl<P, K> EncryptedPersistence<P, K>
+	where
+		P: Persistence,
+		K: EncryptionKeys,
+{
+	/// Create encrypted persistence.
+	pub fn new(persistence: P, keys: K) -> Self {
+		EncryptedPersistence {
+			persistence,
+			keys,
+		}
+	}
+
+	/// Encrypt value with current key. Storage key is used as shared MAC, so that
+	/// encrypted values can't be swapped.
+	fn encrypt(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>, String> {
+		let (key_version, key_pair) = self.keys.current_key()?;
+		let encrypted_value = ecies::encrypt(key_pair.public(), key, value)
+			.map_err(|error| format!("failed to encrypt value: {}", error))?;
+
+		let mut envelope = Vec::with_capacity(ENVELOPE_HEADER_SIZE + encrypted_value.len());
+		envelope.push(ENVELOPE_VERSION);
+		envelope.extend_from_slice(&key_version.to_be_bytes());
+		envelope.extend_from_slice(&encrypted_value);
+		Ok(envelope)
+	}
+
+	/// Decrypt value. Returns decrypted value and version of key it has been encrypted with.
+	fn decrypt(&self, key: &[u8], envelope: &[u8]) -> Result<(Vec<u8>, u32), String> {
+		if envelope.len() < ENVELOPE_HEADER_SIZE {
+			return Err("too short encrypted value".into());
+		}
+		if envelope[0] != ENVELOPE_VERSION {
+			return Err(format!("unsupported encrypted value version: {}", envelope[0]));
+		}
+
+		let key_version = u32::from_be_bytes([envelope[1], envelope[2], envelope[3], envelope[4]]);
+		let key_pair = self.keys
+			.key(key_version)?
+			.ok_or_else(|| format!("value is encrypted with unknown key {}", key_version))?;
+		let value = ecies::decrypt(key_pair.secret(), key, &envelope[ENVELOPE_HEADER_SIZE..])
+			.map_err(|error| format!("failed to decrypt value: {}", error))?;
+		Ok((value, key_version))
+	}
+}
+
+impl<P, K> Persistence for EncryptedPersistence<P, K>
+	where
+		P: Persistence,
+		K: EncryptionKeys,
+{
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+		let envelope = match self.persistence.get(key)? {
+			Some(envelope) => envelope,
+			None => return Ok(None),
+		};
+
+		let (value, key_version) = self.decrypt(key, &envelope)?;
+		let (current_key_version, _) = self.keys.current_key()?;
+		if key_version != current_key_version {
+			if let Err(error) = self.put(key, value.clone()) {
+				warn!(
+					target: "secretstore",
+					"Failed to re-encrypt value with current key {}: {}",
+					current_key_version,
+					error,
+				);
+			}
+		}
+
+		Ok(Some(value))
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), String> {
+		let envelope = self.encrypt(key, &value)?;
+		self.persistence.put(key, envelope)
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<(), String> {
+		self.persistence.remove(key)
+	}
+
+	fn clear(&self) -> Result<(), String> {
+		self.persistence.clear()
+	}
+
+	fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+		self.persistence.keys()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use parity_crypto::publickey::Secret;
+	use crate::persistence::InMemoryPersistence;
+	use super::*;
+
+	fn key_pair(secret: u8) -> KeyPair {
+		let mut secret_bytes = [0u8; 32];
+		secret_bytes[31] = secret;
+		KeyPair::from_secret(Secret::from_unsafe_slice(&secret_bytes).unwrap()).unwrap()
+	}
+
+	fn envelope_key_version(envelope: &[u8]) -> u32 {
+		u32::from_be_bytes([envelope[1], envelope[2], envelope[3], envelope[4]])
+	}
+
+	#[test]
+	fn values_are_encrypted() {
+		let storage = Arc::new(InMemoryPersistence::default());
+		let persistence = EncryptedPersistence::new(storage.clone(), StaticEncryptionKeys::new(1, key_pair(1)));
+		persistence.put(b"key", b"value".to_vec()).unwrap();
+
+		let envelope = storage.get(b"key").unwrap().unwrap();
+		assert_eq!(envelope[0], ENVELOPE_VERSION);
+		assert_eq!(envelope_key_version(&envelope), 1);
+		assert_ne!(&envelope[ENVELOPE_HEADER_SIZE..], b"value");
+		assert_eq!(persistence.get(b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(persistence.get(b"other-key").unwrap(), None);
+	}
+
+	#[test]
+	fn values_encrypted_with_previous_key_are_re_encrypted() {
+		let storage = Arc::new(InMemoryPersistence::default());
+		EncryptedPersistence::new(storage.clone(), StaticEncryptionKeys::new(1, key_pair(1)))
+			.put(b"key", b"value".to_vec()).unwrap();
+
+		let persistence = EncryptedPersistence::new(
+			storage.clone(),
+			StaticEncryptionKeys::new(2, key_pair(2)).with_previous_key(1, key_pair(1)),
+		);
+		assert_eq!(persistence.get(b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(envelope_key_version(&storage.get(b"key").unwrap().unwrap()), 2);
+
+		let persistence = EncryptedPersistence::new(storage, StaticEncryptionKeys::new(2, key_pair(2)));
+		assert_eq!(persistence.get(b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn values_encrypted_with_unknown_key_are_not_read() {
+		let storage = Arc::new(InMemoryPersistence::default());
+		EncryptedPersistence::new(storage.clone(), StaticEncryptionKeys::new(1, key_pair(1)))
+			.put(b"key", b"value".to_vec()).unwrap();
+
+		let persistence = EncryptedPersistence::new(storage, StaticEncryptionKeys::new(2, key_pair(2)));
+		assert_eq!(persistence.get(b"key"), Err("value is encrypted with unknown key 1".into()));
+	}
+
+	#[test]
+	fn values_with_unsupported_envelope_version_are_not_read() {
+		let storage = Arc::new(InMemoryPersistence::default());
+		let persistence = EncryptedPersistence::new(storage.clone(), StaticEncryptionKeys::new(1, key_pair(1)));
+		persistence.put(b"key", b"value".to_vec()).unwrap();
+
+		let mut envelope = storage.get(b"key").unwrap().unwrap();
+		envelope[0] = ENVELOPE_VERSION + 1;
+		storage.put(b"key", envelope).unwrap();
+		assert_eq!(
+			persistence.get(b"key"),
+			Err(format!("unsupported encrypted value version: {}", ENVELOPE_VERSION + 1)),
+		);
+	}
+
+	#[test]
+	fn too_short_envelopes_are_not_read() {
+		let storage = Arc::new(InMemoryPersistence::default());
+		let persistence = EncryptedPersistence::new(storage.clone(), StaticEncryptionKeys::new(1, key_pair(1)));
+		storage.put(b"key", vec![ENVELOPE_VERSION, 0, 0, 0]).unwrap();
+		assert_eq!(persistence.get(b"key"), Err("too short encrypted value".into()));
+	}
+
+	#[test]
+	fn swapped_values_are_not_read() {
+		let storage = Arc::new(InMemoryPersistence::default());
+		let persistence = EncryptedPersistence::new(storage.clone(), StaticEncryptionKeys::new(1, key_pair(1)));
+		persistence.put(b"key1", b"value1".to_vec()).unwrap();
+		persistence.put(b"key2", b"value2".to_vec()).unwrap();
+
+		let envelope1 = storage.get(b"key1").unwrap().unwrap();
+		let envelope2 = storage.get(b"key2").unwrap().unwrap();
+		storage.put(b"key1", envelope2).unwrap();
+		storage.put(b"key2", envelope1).unwrap();
+		assert!(persistence.get(b"key1").is_err());
+		assert!(persistence.get(b"key2").is_err());
+	}
+}
@@ -0,0 +1,117 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Structured errors of the blockchain queries.
+//!
+//! Errors are either transient (e.g. RPC connection has been lost) or permanent (e.g.
+//! runtime storage can't be decoded). Transient errors may disappear if the query is
+//! retried later, while retrying permanent errors won't help.
+
+use std::{error::Error, fmt, sync::Arc};
+use crate::SubmitError;
+
+/// Underlying cause of the error.
+pub type ErrorSource = Arc<dyn Error + Send + Sync>;
+
+/// Blockchain query error.
+#[derive(Debug, Clone)]
+pub enum ServiceError {
+	/// Error that may disappear if query is retried later.
+	Transient(String, Option<ErrorSource>),
+	/// Error that won't disappear if query is retried.
+	Permanent(String, Option<ErrorSource>),
+}
+
+impl ServiceError {
+	/// Create transient error.
+	pub fn transient(message: impl Into<String>) -> Self {
+		ServiceError::Transient(message.into(), None)
+	}
+
+	/// Create permanent error.
+	pub fn permanent(message: impl Into<String>) -> Self {
+		ServiceError::Permanent(message.into(), None)
+	}
+
+	/// Attach underlying cause to the error.
+	pub fn with_source(self, source: impl Error + Send + Sync + 'static) -> Self {
+		let source = Some(Arc::new(source) as ErrorSource);
+		match self {
+			ServiceError::Transient(message, _) => ServiceError::Transient(message, source),
+			ServiceError::Permanent(message, _) => ServiceError::Permanent(message, source),
+		}
+	}
+
+	/// Returns true if query may succeed if retried.
+	pub fn is_transient(&self) -> bool {
+		match *self {
+			ServiceError::Transient(..) => true,
+			ServiceError::Permanent(..) => false,
+		}
+	}
+
+	/// Returns error message (without sources).
+	pub fn message(&self) -> &str {
+		match *self {
+			ServiceError::Transient(ref message, _) | ServiceError::Permanent(ref message, _) => message,
+		}
+	}
+}
+
+impl fmt::Display for ServiceError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.message())?;
+
+		let mut source = self.source();
+		while let Some(error) = source {
+			write!(f, ": {}", error)?;
+			source = error.source();
+		}
+
+		Ok(())
+	}
+}
+
+impl Error for ServiceError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match *self {
+			ServiceError::Transient(_, ref source) | ServiceError::Permanent(_, ref source) =>
+				source.as_ref().map(|source| &**source as &(dyn Error + 'static)),
+		}
+	}
+}
+
+/// Errors of unknown nature are considered transient.
+impl From<String> for ServiceError {
+	fn from(error: String) -> Self {
+		ServiceError::transient(error)
+	}
+}
+
+impl From<&str> for ServiceError {
+	fn from(error: &str) -> Self {
+		ServiceError::transient(error)
+	}
+}
+
+impl From<ServiceError> for SubmitError {
+	fn from(error: ServiceError) -> Self {
+		match error.is_transient() {
+			true => SubmitError::Retryable(error.to_string()),
+			false => SubmitError::Invalid(error.to_string()),
+		}
+	}
+}
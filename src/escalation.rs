@@ -0,0 +1,198 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+};
+use log::{error, info, warn};
+use crate::error::ServiceError;
+
+/// Class of errors that may be escalated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorClass {
+	/// Transaction has been rejected by the pool as invalid.
+	InvalidTransaction,
+	/// Session artifacts have failed verification.
+	ArtifactsVerification,
+	/// Blockchain query has failed with transient error.
+	BlockchainQuery,
+	/// Blockchain query has failed with permanent error.
+	PermanentBlockchainQuery,
+	/// Blockchain service processing loop has failed.
+	BlockchainService,
+}
+
+/// What to do when error of some class happens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+	/// Log error and keep processing blocks.
+	LogAndContinue,
+	/// Stop processing blocks until service is resumed.
+	Pause,
+	/// Stop processing blocks forever.
+	Terminate,
+}
+
+/// State of the service.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServiceState {
+	/// Service is processing blocks.
+	Running,
+	/// Service is paused because of given error.
+	Paused(ErrorClass, String),
+	/// Service is terminated because of given error.
+	Terminated(ErrorClass, String),
+	/// Service has been stopped by the embedder.
+	Stopped,
+}
+
+/// Called when error is escalated (i.e. policy isn't `LogAndContinue`).
+pub type EscalationHandler = Arc<dyn Fn(ErrorClass, ErrorPolicy, &str) + Send + Sync>;
+
+/// Called (once) when service is terminated because of error.
+pub type TerminationHook = Box<dyn Fn(ErrorClass, &str) + Send + Sync>;
+
+/// Errors escalation configuration.
+#[derive(Clone, Default)]
+pub struct EscalationPolicy {
+	/// Policies by error class. Errors of missing classes are logged.
+	pub policies: BTreeMap<ErrorClass, ErrorPolicy>,
+	/// Called when error is escalated.
+	pub handler: Option<EscalationHandler>,
+}
+
+/// Applies escalation policy to service errors.
+pub struct Escalation {
+	/// Escalation policy.
+	policy: EscalationPolicy,
+	/// Current service state.
+	state: Mutex<ServiceState>,
+	/// Service termination hook.
+	termination_hook: Mutex<Option<TerminationHook>>,
+}
+
+impl Escalation {
+	/// Create new escalation.
+	pub fn new(policy: EscalationPolicy) -> Self {
+		Escalation {
+			policy,
+			state: Mutex::new(ServiceState::Running),
+			termination_hook: Mutex::new(None),
+		}
+	}
+
+	/// Set service termination hook.
+	pub fn set_termination_hook(&self, hook: TerminationHook) {
+		*self.termination_hook.lock().expect("never panics under lock; qed") = Some(hook);
+	}
+
+	/// Called when blockchain query has failed. Error is expected to be already logged.
+	pub fn on_query_error(&self, error: &ServiceError) {
+		let class = match error.is_transient() {
+			true => ErrorClass::BlockchainQuery,
+			false => ErrorClass::PermanentBlockchainQuery,
+		};
+		self.on_error(class, &error.to_string())
+	}
+
+	/// Called when error of given class happens. Error is expected to be already logged.
+	pub fn on_error(&self, class: ErrorClass, error: &str) {
+		let policy = self.policy.policies.get(&class).cloned().unwrap_or(ErrorPolicy::LogAndContinue);
+		{
+			let mut state = self.state.lock().expect("never panics under lock; qed");
+			match (policy, &*state) {
+				(ErrorPolicy::LogAndContinue, _)
+					| (_, ServiceState::Terminated(..))
+					| (_, ServiceState::Stopped) => return,
+				(ErrorPolicy::Pause, ServiceState::Paused(..)) => return,
+				(ErrorPolicy::Pause, _) => {
+					warn!(
+						target: "secretstore",
+						"Pausing Secret Store service because of {:?} error: {}",
+						class,
+						error,
+					);
+					*state = ServiceState::Paused(class, error.into());
+				},
+				(ErrorPolicy::Terminate, _) => {
+					error!(
+						target: "secretstore",
+						"Terminating Secret Store service because of {:?} error: {}",
+						class,
+						error,
+					);
+					*state = ServiceState::Terminated(class, error.into());
+				},
+			}
+		}
+
+		if let Some(ref handler) = self.policy.handler {
+			handler(class, policy, error);
+		}
+
+		if policy == ErrorPolicy::Terminate {
+			if let Some(ref hook) = *self.termination_hook.lock().expect("never panics under lock; qed") {
+				hook(class, error);
+			}
+		}
+	}
+
+	/// Returns true if blocks could be processed.
+	pub fn is_running(&self) -> bool {
+		*self.state.lock().expect("never panics under lock; qed") == ServiceState::Running
+	}
+
+	/// Returns current service state.
+	pub fn state(&self) -> ServiceState {
+		self.state.lock().expect("never panics under lock; qed").clone()
+	}
+
+	/// Stop the service. Returns false if service has been already stopped or terminated.
+	pub fn stop(&self) -> bool {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		match *state {
+			ServiceState::Terminated(..) | ServiceState::Stopped => false,
+			ServiceState::Running | ServiceState::Paused(..) => {
+				info!(
+					target: "secretstore",
+					"Stopping Secret Store service",
+				);
+				*state = ServiceState::Stopped;
+				true
+			},
+		}
+	}
+
+	/// Resume paused service. Terminated (or stopped) service can't be resumed.
+	pub fn resume(&self) -> Result<(), String> {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		match *state {
+			ServiceState::Running => Ok(()),
+			ServiceState::Paused(..) => {
+				info!(
+					target: "secretstore",
+					"Resuming Secret Store service",
+				);
+				*state = ServiceState::Running;
+				Ok(())
+			},
+			ServiceState::Terminated(class, ref error) =>
+				Err(format!("Secret Store service has been terminated because of {:?} error: {}", class, error)),
+			ServiceState::Stopped => Err("Secret Store service has been stopped".into()),
+		}
+	}
+}
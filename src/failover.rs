@@ -0,0 +1,121 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+use futures::{StreamExt, stream::BoxStream};
+use log::{info, warn};
+
+/// New blocks streams failover configuration.
+#[derive(Debug, Clone)]
+pub struct FailoverConfiguration {
+	/// If primary stream hasn't yielded any blocks for this period, blocks from
+	/// fallback streams are used.
+	pub primary_timeout: Duration,
+	/// Number of recent blocks that are remembered to deduplicate notifications
+	/// coming from different streams.
+	pub dedup_window: usize,
+}
+
+/// Failover state.
+struct FailoverState<Hash> {
+	/// Time when primary stream has yielded last block.
+	last_primary_block: Instant,
+	/// True if we're currently using fallback streams.
+	is_failed_over: bool,
+	/// Recently yielded blocks.
+	recent_blocks: VecDeque<Hash>,
+}
+
+impl Default for FailoverConfiguration {
+	fn default() -> Self {
+		FailoverConfiguration {
+			primary_timeout: Duration::from_secs(30),
+			dedup_window: 64,
+		}
+	}
+}
+
+/// Combine new blocks streams from several sources (e.g. RPC endpoints) into single
+/// stream. The first stream is the primary one. Blocks from fallback streams are only
+/// used when primary stream hasn't yielded blocks for configured period. The same
+/// block is never yielded twice (within dedup window).
+pub fn failover_blocks_stream<Hash>(
+	streams: Vec<BoxStream<'static, Hash>>,
+	config: FailoverConfiguration,
+) -> BoxStream<'static, Hash> where
+	Hash: Clone + PartialEq + Send + 'static,
+{
+	let mut state = FailoverState {
+		last_primary_block: Instant::now(),
+		is_failed_over: false,
+		recent_blocks: VecDeque::with_capacity(config.dedup_window),
+	};
+
+	futures::stream::select_all(
+		streams
+			.into_iter()
+			.enumerate()
+			.map(|(index, stream)| stream.map(move |block_hash| (index == 0, block_hash)).boxed())
+	)
+	.filter_map(move |(is_primary, block_hash)| futures::future::ready(
+		state.on_block(&config, is_primary, block_hash)
+	))
+	.boxed()
+}
+
+impl<Hash: Clone + PartialEq> FailoverState<Hash> {
+	/// Called when any stream yields block. Returns block if it needs to be processed.
+	fn on_block(&mut self, config: &FailoverConfiguration, is_primary: bool, block_hash: Hash) -> Option<Hash> {
+		if is_primary {
+			self.last_primary_block = Instant::now();
+			if self.is_failed_over {
+				self.is_failed_over = false;
+				info!(
+					target: "secretstore",
+					"Primary new blocks stream has recovered",
+				);
+			}
+		} else if !self.is_failed_over {
+			let primary_silence = self.last_primary_block.elapsed();
+			if primary_silence <= config.primary_timeout {
+				return None;
+			}
+
+			self.is_failed_over = true;
+			warn!(
+				target: "secretstore",
+				"Primary new blocks stream hasn't yielded blocks for {:?}. Switching to fallback streams",
+				primary_silence,
+			);
+		}
+
+		if self.recent_blocks.contains(&block_hash) {
+			return None;
+		}
+
+		if config.dedup_window != 0 {
+			if self.recent_blocks.len() == config.dedup_window {
+				self.recent_blocks.pop_front();
+			}
+			self.recent_blocks.push_back(block_hash.clone());
+		}
+
+		Some(block_hash)
+	}
+}
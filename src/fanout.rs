@@ -0,0 +1,120 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Deduplication of server key generation requests across origins.
+//!
+//! When several origins (e.g. different pallets) are requesting generation of the
+//! same server key, only the first request (leader) starts the session. Requests of
+//! other origins (followers) are not started - instead, result of the leader session
+//! is fanned out to them. Requests are only shared if they have the same author and
+//! threshold, and only if the transaction pool submits responses to different origins
+//! differently (otherwise fanned out response is just a duplicate transaction).
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	sync::Mutex,
+};
+use parity_secretstore_primitives::{Address, ServerKeyId, service::ServiceTask};
+use crate::{BlockchainServiceTask, identity::requester_address};
+
+/// Max number of server keys which generation is tracked.
+const MAX_TRACKED_GENERATIONS: usize = 4096;
+
+/// Server key generations that are shared by several origins.
+#[derive(Default)]
+pub struct GenerationFanOut {
+	/// True if generations are shared.
+	is_enabled: bool,
+	/// Generations by key id.
+	generations: Mutex<BTreeMap<ServerKeyId, SharedGeneration>>,
+}
+
+/// Generation that is shared by several origins.
+struct SharedGeneration {
+	/// Origin which request has started the session.
+	leader: Address,
+	/// Author of the leader request.
+	author: Address,
+	/// Threshold of the leader request.
+	threshold: usize,
+	/// Origins that are waiting for the leader session result.
+	followers: BTreeSet<Address>,
+}
+
+impl GenerationFanOut {
+	/// Create new fan out. If `is_enabled` is false, generations are never shared.
+	pub fn new(is_enabled: bool) -> Self {
+		GenerationFanOut {
+			is_enabled,
+			generations: Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	/// Returns false if task is server key generation that is already started by
+	/// request of another origin with the same author and threshold.
+	pub fn accepts_task(&self, task: &BlockchainServiceTask) -> bool {
+		if !self.is_enabled {
+			return true;
+		}
+
+		let (origin, key_id, author, threshold) = match *task {
+			BlockchainServiceTask::Regular(origin, ServiceTask::GenerateServerKey(key_id, ref requester, threshold)) =>
+				match requester_address(requester, &key_id) {
+					Ok(author) => (origin, key_id, author, threshold),
+					Err(_) => return true,
+				},
+			_ => return true,
+		};
+
+		let mut generations = self.generations.lock().expect("never panics under lock; qed");
+		let is_full = generations.len() >= MAX_TRACKED_GENERATIONS;
+		match generations.get_mut(&key_id) {
+			Some(generation) if generation.leader == origin => true,
+			Some(generation) if generation.author == author && generation.threshold == threshold => {
+				generation.followers.insert(origin);
+				false
+			},
+			Some(_) => true,
+			None if !is_full => {
+				generations.insert(key_id, SharedGeneration {
+					leader: origin,
+					author,
+					threshold,
+					followers: BTreeSet::new(),
+				});
+				true
+			},
+			None => true,
+		}
+	}
+
+	/// Called when session started by request of given origin is completed. Returns
+	/// origins that are waiting for its result.
+	pub fn on_generation_completed(&self, origin: Address, key_id: ServerKeyId) -> BTreeSet<Address> {
+		let mut generations = self.generations.lock().expect("never panics under lock; qed");
+		match generations.get(&key_id) {
+			Some(generation) if generation.leader == origin =>
+				generations.remove(&key_id).map(|generation| generation.followers).unwrap_or_default(),
+			_ => BTreeSet::new(),
+		}
+	}
+
+	/// Called when generation request no longer requires our response. Followers are
+	/// started (by next pending tasks scans) on their own.
+	pub fn on_request_completed(&self, key_id: ServerKeyId) {
+		self.generations.lock().expect("never panics under lock; qed").remove(&key_id);
+	}
+}
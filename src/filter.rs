@@ -0,0 +1,71 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use parity_secretstore_primitives::ServerKeyId;
+use crate::{BlockchainServiceTask, task_kind_and_key_id};
+
+/// Key id namespace pattern. Key id belongs to the namespace if
+/// `key_id & mask == value`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyIdPattern {
+	/// Mask of namespace bits.
+	pub mask: ServerKeyId,
+	/// Expected value of namespace bits.
+	pub value: ServerKeyId,
+}
+
+/// Filter of tasks by key id namespace. Empty filter accepts all tasks.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyIdFilter {
+	/// Namespaces served by this key server.
+	pub patterns: Vec<KeyIdPattern>,
+}
+
+impl KeyIdPattern {
+	/// Create pattern that matches all key ids starting with given prefix.
+	pub fn prefix(prefix: &[u8]) -> Self {
+		let mut mask = ServerKeyId::zero();
+		let mut value = ServerKeyId::zero();
+		let prefix_len = std::cmp::min(prefix.len(), ServerKeyId::len_bytes());
+		mask.as_bytes_mut()[..prefix_len].iter_mut().for_each(|byte| *byte = 0xFF);
+		value.as_bytes_mut()[..prefix_len].copy_from_slice(&prefix[..prefix_len]);
+		KeyIdPattern { mask, value }
+	}
+
+	/// Returns true if key id matches the pattern.
+	pub fn matches(&self, key_id: &ServerKeyId) -> bool {
+		key_id.as_bytes()
+			.iter()
+			.zip(self.mask.as_bytes())
+			.zip(self.value.as_bytes())
+			.all(|((key_byte, mask_byte), value_byte)| key_byte & mask_byte == *value_byte)
+	}
+}
+
+impl KeyIdFilter {
+	/// Returns true if key id belongs to one of served namespaces.
+	pub fn accepts(&self, key_id: &ServerKeyId) -> bool {
+		self.patterns.is_empty() || self.patterns.iter().any(|pattern| pattern.matches(key_id))
+	}
+
+	/// Returns true if task belongs to one of served namespaces. Tasks that are not
+	/// bound to key id are always accepted.
+	pub fn accepts_task(&self, task: &BlockchainServiceTask) -> bool {
+		task_kind_and_key_id(task)
+			.map(|(_, key_id)| self.accepts(&key_id))
+			.unwrap_or(true)
+	}
+}
@@ -0,0 +1,249 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Golden test vectors generation.
+//!
+//! Produces canonical samples of every `SecretStoreCall`, encoded by the runtime
+//! encoder and written to JSON fixture. The fixture is meant to be checked in to the
+//! runtime module and compared with its own encoding there, so both sides of the
+//! protocol are kept in lockstep.
+//!
+//! The fixture, generated with `PalletCallEncoder`, is checked in at
+//! `res/golden_calls.json` and is verified by this crate tests.
+
+use std::{fmt::Write, path::Path};
+use parity_secretstore_primitives::{Address, Public, ServerKeyId};
+use crate::SecretStoreCall;
+
+/// Encodes Secret Store module calls the same way the runtime does (SCALE).
+pub trait CallEncoder {
+	/// Encode the call.
+	fn encode_call(&self, call: &SecretStoreCall) -> Result<Vec<u8>, String>;
+}
+
+/// Encodes calls of the Secret Store pallet (i.e. without runtime module index) the
+/// way SCALE codec does.
+pub struct PalletCallEncoder;
+
+impl CallEncoder for PalletCallEncoder {
+	fn encode_call(&self, call: &SecretStoreCall) -> Result<Vec<u8>, String> {
+		let mut encoded = vec![call_index(call)];
+		match *call {
+			SecretStoreCall::ServerKeyGenerated(ref key_id, ref key) => {
+				encoded.extend_from_slice(key_id.as_bytes());
+				encoded.extend_from_slice(key.as_bytes());
+			},
+			SecretStoreCall::ServerKeyRetrieved(ref key_id, ref key, threshold) => {
+				encoded.extend_from_slice(key_id.as_bytes());
+				encoded.extend_from_slice(key.as_bytes());
+				encoded.push(threshold);
+			},
+			SecretStoreCall::ServerKeyGenerationError(ref key_id)
+				| SecretStoreCall::ServerKeyRetrievalError(ref key_id)
+				| SecretStoreCall::DocumentKeyStored(ref key_id)
+				| SecretStoreCall::DocumentKeyStoreError(ref key_id) => {
+				encoded.extend_from_slice(key_id.as_bytes());
+			},
+			SecretStoreCall::DocumentKeyCommonRetrieved(ref key_id, ref requester, ref common_point, threshold) => {
+				encoded.extend_from_slice(key_id.as_bytes());
+				encoded.extend_from_slice(requester.as_bytes());
+				encoded.extend_from_slice(common_point.as_bytes());
+				encoded.push(threshold);
+			},
+			SecretStoreCall::DocumentKeyPersonalRetrieved(
+				ref key_id,
+				ref requester,
+				ref participants,
+				ref decrypted_secret,
+				ref shadow,
+			) => {
+				encoded.extend_from_slice(key_id.as_bytes());
+				encoded.extend_from_slice(requester.as_bytes());
+				encode_compact_len(participants.len(), &mut encoded)?;
+				for participant in participants {
+					encoded.extend_from_slice(participant.as_bytes());
+				}
+				encoded.extend_from_slice(decrypted_secret.as_bytes());
+				encode_compact_len(shadow.len(), &mut encoded)?;
+				encoded.extend_from_slice(shadow);
+			},
+			SecretStoreCall::DocumentKeyShadowRetrievalError(ref key_id, ref requester) => {
+				encoded.extend_from_slice(key_id.as_bytes());
+				encoded.extend_from_slice(requester.as_bytes());
+			},
+		}
+		Ok(encoded)
+	}
+}
+
+/// Returns index of the call in the Secret Store pallet. Adding new variant breaks this
+/// match, reminding to add it to `sample_calls`.
+fn call_index(call: &SecretStoreCall) -> u8 {
+	match *call {
+		SecretStoreCall::ServerKeyGenerated(..) => 0,
+		SecretStoreCall::ServerKeyGenerationError(..) => 1,
+		SecretStoreCall::ServerKeyRetrieved(..) => 2,
+		SecretStoreCall::ServerKeyRetrievalError(..) => 3,
+		SecretStoreCall::DocumentKeyStored(..) => 4,
+		SecretStoreCall::DocumentKeyStoreError(..) => 5,
+		SecretStoreCall::DocumentKeyCommonRetrieved(..) => 6,
+		SecretStoreCall::DocumentKeyPersonalRetrieved(..) => 7,
+		SecretStoreCall::DocumentKeyShadowRetrievalError(..) => 8,
+	}
+}
+
+/// Append SCALE compact encoding of the length.
+fn encode_compact_len(len: usize, encoded: &mut Vec<u8>) -> Result<(), String> {
+	match len {
+		0..=0x3f => encoded.push((len as u8) << 2),
+		0x40..=0x3fff => encoded.extend_from_slice(&(((len as u16) << 2) | 0b01).to_le_bytes()),
+		0x4000..=0x3fff_ffff => encoded.extend_from_slice(&(((len as u32) << 2) | 0b10).to_le_bytes()),
+		_ => return Err(format!("length {} is too large for the golden sample", len)),
+	}
+	Ok(())
+}
+
+/// Returns canonical samples of every supported call.
+pub fn sample_calls() -> Vec<(&'static str, SecretStoreCall)> {
+	let key_id = ServerKeyId::repeat_byte(0x01);
+	let public = Public::repeat_byte(0x02);
+	let requester = Address::repeat_byte(0x03);
+	vec![
+		("ServerKeyGenerated", SecretStoreCall::ServerKeyGenerated(key_id, public)),
+		("ServerKeyGenerationError", SecretStoreCall::ServerKeyGenerationError(key_id)),
+		("ServerKeyRetrieved", SecretStoreCall::ServerKeyRetrieved(key_id, public, 1)),
+		("ServerKeyRetrievalError", SecretStoreCall::ServerKeyRetrievalError(key_id)),
+		("DocumentKeyStored", SecretStoreCall::DocumentKeyStored(key_id)),
+		("DocumentKeyStoreError", SecretStoreCall::DocumentKeyStoreError(key_id)),
+		("DocumentKeyCommonRetrieved", SecretStoreCall::DocumentKeyCommonRetrieved(key_id, requester, public, 1)),
+		(
+			"DocumentKeyPersonalRetrieved",
+			SecretStoreCall::DocumentKeyPersonalRetrieved(
+				key_id,
+				requester,
+				vec![Address::repeat_byte(0x04), Address::repeat_byte(0x05)],
+				public,
+				vec![0x06; 16],
+			),
+		),
+		("DocumentKeyShadowRetrievalError", SecretStoreCall::DocumentKeyShadowRetrievalError(key_id, requester)),
+	]
+}
+
+/// Encode all sample calls and return JSON fixture.
+pub fn generate_fixture(encoder: &dyn CallEncoder) -> Result<String, String> {
+	let samples = sample_calls();
+	let mut fixture = String::from("{\n\t\"calls\": [\n");
+	for (index, (name, call)) in samples.iter().enumerate() {
+		let encoded = encoder.encode_call(call)
+			.map_err(|error| format!("failed to encode {}: {}", name, error))?;
+		let mut encoded_hex = String::with_capacity(2 + encoded.len() * 2);
+		encoded_hex.push_str("0x");
+		for byte in encoded {
+			write!(encoded_hex, "{:02x}", byte).expect("writing to String never fails; qed");
+		}
+
+		let separator = if index + 1 == samples.len() { "" } else { "," };
+		writeln!(
+			fixture,
+			"\t\t{{ \"name\": \"{}\", \"encoded\": \"{}\" }}{}",
+			name,
+			encoded_hex,
+			separator,
+		).expect("writing to String never fails; qed");
+	}
+	fixture.push_str("\t]\n}\n");
+
+	Ok(fixture)
+}
+
+/// Encode all sample calls and write JSON fixture to the file.
+pub fn write_fixture(encoder: &dyn CallEncoder, path: &Path) -> Result<(), String> {
+	let fixture = generate_fixture(encoder)?;
+	std::fs::write(path, fixture)
+		.map_err(|error| format!("failed to write fixture to {}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Encoder that encodes every call as its variant index.
+	struct IndexEncoder;
+
+	impl CallEncoder for IndexEncoder {
+		fn encode_call(&self, call: &SecretStoreCall) -> Result<Vec<u8>, String> {
+			Ok(vec![call_index(call), 0xff])
+		}
+	}
+
+	/// Encoder that fails to encode any call.
+	struct FailingEncoder;
+
+	impl CallEncoder for FailingEncoder {
+		fn encode_call(&self, _call: &SecretStoreCall) -> Result<Vec<u8>, String> {
+			Err("not supported".into())
+		}
+	}
+
+	#[test]
+	fn sample_calls_cover_every_call() {
+		let indices = sample_calls().iter().map(|(_, call)| call_index(call)).collect::<Vec<_>>();
+		assert_eq!(indices, (0..9).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn fixture_is_generated() {
+		let fixture = generate_fixture(&IndexEncoder).unwrap();
+		let lines = fixture.lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 4 + sample_calls().len());
+		assert_eq!(lines[0], "{");
+		assert_eq!(lines[1], "\t\"calls\": [");
+		assert_eq!(lines[2], "\t\t{ \"name\": \"ServerKeyGenerated\", \"encoded\": \"0x00ff\" },");
+		assert_eq!(lines[10], "\t\t{ \"name\": \"DocumentKeyShadowRetrievalError\", \"encoded\": \"0x08ff\" }");
+		assert_eq!(lines[11], "\t]");
+		assert_eq!(lines[12], "}");
+	}
+
+	#[test]
+	fn checked_in_fixture_is_up_to_date() {
+		assert_eq!(
+			generate_fixture(&PalletCallEncoder).unwrap(),
+			include_str!("../res/golden_calls.json"),
+		);
+	}
+
+	#[test]
+	fn compact_length_is_encoded() {
+		let encode = |len| {
+			let mut encoded = Vec::new();
+			encode_compact_len(len, &mut encoded).map(|_| encoded)
+		};
+		assert_eq!(encode(0), Ok(vec![0x00]));
+		assert_eq!(encode(0x3f), Ok(vec![0xfc]));
+		assert_eq!(encode(0x40), Ok(vec![0x01, 0x01]));
+		assert_eq!(encode(0x4000), Ok(vec![0x02, 0x00, 0x01, 0x00]));
+		assert!(encode(0x4000_0000).is_err());
+	}
+
+	#[test]
+	fn fixture_generation_fails_when_call_encoding_fails() {
+		assert_eq!(
+			generate_fixture(&FailingEncoder),
+			Err("failed to encode ServerKeyGenerated: not supported".into()),
+		);
+	}
+}
@@ -0,0 +1,113 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Periodic on-chain reports of the service health.
+//!
+//! When enabled, aggregated statistics of the reporting period (number of served
+//! requests and number of failures) are periodically submitted to the chain, so
+//! that governance has on-chain visibility into service health.
+
+use std::sync::Mutex;
+use crate::origin_stats::{OriginCounters, OriginStatistics};
+
+/// Aggregated health statistics of the reporting period.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HealthReport {
+	/// Number of reporting period blocks.
+	pub blocks: u32,
+	/// Number of new tasks seen.
+	pub tasks: u64,
+	/// Number of submitted responses (including error responses).
+	pub responses: u64,
+	/// Number of submitted error responses.
+	pub error_responses: u64,
+}
+
+/// Health reports configuration.
+#[derive(Debug, Clone)]
+pub struct HealthReportConfiguration {
+	/// Report is submitted every `interval` blocks.
+	pub interval: u32,
+}
+
+/// Produces periodic health reports.
+pub struct HealthReporter {
+	/// Configuration. If `None`, reports are never produced.
+	config: Option<HealthReportConfiguration>,
+	/// Reporter state.
+	state: Mutex<HealthReporterState>,
+}
+
+/// Reporter state.
+struct HealthReporterState {
+	/// Number of blocks till next report.
+	blocks_till_report: u32,
+	/// Totals of all origins at the time of previous report.
+	reported_totals: OriginCounters,
+}
+
+impl Default for HealthReportConfiguration {
+	fn default() -> Self {
+		HealthReportConfiguration {
+			interval: 600,
+		}
+	}
+}
+
+impl HealthReporter {
+	/// Create new health reporter.
+	pub fn new(config: Option<HealthReportConfiguration>) -> Self {
+		let blocks_till_report = config.as_ref().map(|config| config.interval).unwrap_or(0);
+		HealthReporter {
+			config,
+			state: Mutex::new(HealthReporterState {
+				blocks_till_report,
+				reported_totals: OriginCounters::default(),
+			}),
+		}
+	}
+
+	/// Called when new block is processed. Returns report if it needs to be submitted.
+	pub fn on_new_block(&self, origin_statistics: &OriginStatistics) -> Option<HealthReport> {
+		let config = self.config.as_ref()?;
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		if state.blocks_till_report != 0 {
+			state.blocks_till_report -= 1;
+			return None;
+		}
+		state.blocks_till_report = std::cmp::max(config.interval, 1) - 1;
+
+		let totals = origin_statistics
+			.snapshot()
+			.values()
+			.fold(OriginCounters::default(), |mut totals, counters| {
+				totals.tasks += counters.tasks;
+				totals.responses += counters.responses;
+				totals.error_responses += counters.error_responses;
+				totals
+			});
+		let report = HealthReport {
+			blocks: std::cmp::max(config.interval, 1),
+			tasks: totals.tasks.saturating_sub(state.reported_totals.tasks),
+			responses: totals.responses.saturating_sub(state.reported_totals.responses),
+			error_responses: totals.error_responses.saturating_sub(state.reported_totals.error_responses),
+		};
+		state.reported_totals = totals;
+
+		Some(report)
+	}
+}
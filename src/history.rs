@@ -0,0 +1,92 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::VecDeque,
+	ops::Range,
+};
+use crate::{Blockchain, MaybeSecretStoreEvent, SecretStoreResponse};
+
+/// Secret Store request that has been answered.
+#[derive(Debug, Clone)]
+pub struct CompletedTask<Hash> {
+	/// Number of the block where response has been accepted.
+	pub block_number: u64,
+	/// Hash of the block where response has been accepted.
+	pub block_hash: Hash,
+	/// Accepted response.
+	pub response: SecretStoreResponse,
+}
+
+/// Iterator over requests that have been answered within given range of blocks.
+pub struct CompletedTasks<'a, B: Blockchain> {
+	/// Blockchain reference.
+	blockchain: &'a B,
+	/// Range of blocks that are not yet read.
+	blocks: Range<u64>,
+	/// Completed tasks of the last read block.
+	pending: VecDeque<CompletedTask<B::BlockHash>>,
+}
+
+/// Returns iterator over requests that have been answered within given range of blocks.
+/// Iteration stops at the best known block.
+pub fn completed_tasks<B: Blockchain>(blockchain: &B, blocks: Range<u64>) -> CompletedTasks<'_, B> {
+	CompletedTasks {
+		blockchain,
+		blocks,
+		pending: VecDeque::new(),
+	}
+}
+
+impl<'a, B: Blockchain> Iterator for CompletedTasks<'a, B> {
+	type Item = Result<CompletedTask<B::BlockHash>, String>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some(completed_task) = self.pending.pop_front() {
+				return Some(Ok(completed_task));
+			}
+
+			if self.blocks.start >= self.blocks.end {
+				return None;
+			}
+
+			let block_number = self.blocks.start;
+			self.blocks.start += 1;
+
+			let block_hash = match self.blockchain.block_hash(block_number) {
+				Ok(Some(block_hash)) => block_hash,
+				Ok(None) => {
+					self.blocks.start = self.blocks.end;
+					return None;
+				},
+				Err(error) => return Some(Err(error.to_string())),
+			};
+
+			self.pending.extend(
+				self.blockchain
+					.block_events(block_hash.clone())
+					.into_iter()
+					.filter_map(|event| event.as_secret_store_response())
+					.map(|response| CompletedTask {
+						block_number,
+						block_hash: block_hash.clone(),
+						response,
+					})
+			);
+		}
+	}
+}
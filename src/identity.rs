@@ -0,0 +1,135 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Conversions between Substrate and Ethereum identities.
+//!
+//! Secret Store identifies requesters and key servers by Ethereum-style addresses,
+//! while Substrate chains use 32-byte account ids. The mapping used here is the
+//! 'truncated' one: the address occupies the first 20 bytes of the account id and
+//! the remaining 12 bytes are zero. So every address has exactly one account id
+//! and converting it back yields the same address.
+
+use parity_crypto::Keccak256;
+use parity_secretstore_primitives::{
+	Address, Public, ServerKeyId,
+	requester::Requester,
+};
+
+/// Substrate 32-byte account id.
+pub type AccountId32 = [u8; 32];
+
+/// Convert Ethereum address to Substrate account id.
+pub fn address_to_account_id(address: &Address) -> AccountId32 {
+	let mut account_id = [0u8; 32];
+	account_id[..20].copy_from_slice(address.as_bytes());
+	account_id
+}
+
+/// Convert Substrate account id to Ethereum address. The account id is truncated to
+/// its first 20 bytes, so this is the reverse of `address_to_account_id`.
+pub fn account_id_to_address(account_id: &AccountId32) -> Address {
+	Address::from_slice(&account_id[..20])
+}
+
+/// Returns true if account id has been produced by `address_to_account_id`.
+pub fn is_address_account_id(account_id: &AccountId32) -> bool {
+	account_id[20..].iter().all(|byte| *byte == 0)
+}
+
+/// Derive Ethereum address from (uncompressed, without prefix) secp256k1 public key.
+pub fn public_to_address(public: &Public) -> Address {
+	let hash: [u8; 32] = public.as_bytes().keccak256();
+	Address::from_slice(&hash[12..])
+}
+
+/// Derive Substrate account id from (uncompressed, without prefix) secp256k1 public key.
+pub fn public_to_account_id(public: &Public) -> AccountId32 {
+	address_to_account_id(&public_to_address(public))
+}
+
+/// Returns address of the requester. This is the only way the service shall use to
+/// convert requesters to addresses.
+pub fn requester_address(requester: &Requester, key_id: &ServerKeyId) -> Result<Address, String> {
+	requester.address(key_id).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+	use parity_crypto::publickey::{KeyPair, Secret};
+	use super::*;
+
+	fn address(hex: &str) -> Address {
+		let bytes = (0..hex.len())
+			.step_by(2)
+			.map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+			.collect::<Vec<_>>();
+		Address::from_slice(&bytes)
+	}
+
+	fn key_pair(secret: u8) -> KeyPair {
+		let mut secret_bytes = [0u8; 32];
+		secret_bytes[31] = secret;
+		KeyPair::from_secret(Secret::from_unsafe_slice(&secret_bytes).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn address_to_account_id_round_trip() {
+		let address = address("7e5f4552091a69125d5dfcb7b8c2659029395bdf");
+		let account_id = address_to_account_id(&address);
+		assert_eq!(&account_id[..20], address.as_bytes());
+		assert_eq!(account_id[20..], [0u8; 12]);
+		assert_eq!(account_id_to_address(&account_id), address);
+	}
+
+	#[test]
+	fn account_id_to_address_truncates_account_id() {
+		let mut account_id = [0x42u8; 32];
+		account_id[..20].copy_from_slice(&[0x01; 20]);
+		assert_eq!(account_id_to_address(&account_id), Address::repeat_byte(0x01));
+	}
+
+	#[test]
+	fn is_address_account_id_works() {
+		assert!(is_address_account_id(&address_to_account_id(&Address::repeat_byte(0x01))));
+		assert!(is_address_account_id(&[0u8; 32]));
+		assert!(!is_address_account_id(&[0x01u8; 32]));
+
+		let mut account_id = address_to_account_id(&Address::repeat_byte(0x01));
+		account_id[31] = 1;
+		assert!(!is_address_account_id(&account_id));
+	}
+
+	#[test]
+	fn public_to_address_works() {
+		let vectors = [
+			(1, "7e5f4552091a69125d5dfcb7b8c2659029395bdf"),
+			(2, "2b5ad5c4795c026514f8317c7a215e218dccd6cf"),
+		];
+		for (secret, expected_address) in vectors.iter() {
+			let key_pair = key_pair(*secret);
+			assert_eq!(public_to_address(key_pair.public()), address(expected_address));
+			assert_eq!(public_to_address(key_pair.public()), key_pair.address());
+		}
+	}
+
+	#[test]
+	fn public_to_account_id_works() {
+		let key_pair = key_pair(1);
+		let account_id = public_to_account_id(key_pair.public());
+		assert!(is_address_account_id(&account_id));
+		assert_eq!(account_id_to_address(&account_id), address("7e5f4552091a69125d5dfcb7b8c2659029395bdf"));
+	}
+}
@@ -0,0 +1,467 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reading requests from the chain indexer.
+//!
+//! On high-volume chains, pending tasks scans over node RPC are too expensive. This
+//! module provides `Blockchain` adapter that reads pending requests from the chain
+//! indexer (e.g. SubQuery or Subsquid), while everything else (block events, responses
+//! queries, ...) is still read from the node. Transactions are still submitted
+//! through the node transaction pool.
+//!
+//! Indexer is only trusted when its last indexed block is finalized and is on the
+//! canonical chain of the node. Otherwise (and on any indexer error) pending tasks
+//! are read from the node. To protect against compromised (or buggy) indexer, some
+//! of pages it serves could also be read from the node and compared.
+
+use std::{
+	collections::BTreeSet,
+	ops::Range,
+	sync::{Arc, Mutex},
+};
+use log::{error, trace, warn};
+use parity_secretstore_blockchain_service::BlockchainServiceTask;
+use parity_secretstore_primitives::{Address, KeyServerId, ServerKeyId};
+use crate::{
+	Blockchain, MaybeSecretStoreEvent, RawSecretStoreEvent, SecretStoreResponse, TaskKind, TaskOriginBlock,
+	constants::SecretStoreConstants,
+	dedup::ServedRequest,
+	error::ServiceError,
+	prewarm::ScheduledRequest,
+	speculative::BlockFinality,
+};
+
+/// Chain indexer that serves decoded SecretStore requests.
+pub trait RequestsIndexer: Send + Sync + 'static {
+	/// Block hash type.
+	type BlockHash;
+
+	/// Get number and hash of the last block that has been processed by the indexer.
+	fn indexed_block(&self) -> Result<(u64, Self::BlockHash), String>;
+	/// Get range of tasks of given kind that are pending at given block.
+	fn pending_tasks(
+		&self,
+		task_kind: TaskKind,
+		block_number: u64,
+		range: Range<usize>,
+	) -> Result<Vec<BlockchainServiceTask>, String>;
+}
+
+/// Called when tasks served by the indexer differ from tasks read from the node.
+/// Arguments are task kind and range of pending tasks.
+pub type DivergenceHandler = Arc<dyn Fn(TaskKind, &Range<usize>) + Send + Sync>;
+
+/// Dual-read verification configuration.
+#[derive(Clone)]
+pub struct DualReadConfiguration {
+	/// Every `sample_interval` page served by the indexer is also read from the node.
+	pub sample_interval: u32,
+	/// Divergence handler.
+	pub divergence_handler: Option<DivergenceHandler>,
+}
+
+/// Event that is either read from the node, or decoded by the indexer.
+pub enum IndexedEvent<E> {
+	/// Event read from the node.
+	Node(E),
+	/// Task that has already been decoded (by the indexer or during verification).
+	Decoded(Box<BlockchainServiceTask>),
+}
+
+/// Blockchain that reads pending requests from the indexer.
+pub struct IndexerBlockchain<B, I> {
+	/// Node blockchain.
+	node: B,
+	/// Requests indexer.
+	indexer: I,
+	/// Dual-read verification. If `None`, indexer pages are never verified.
+	verification: Option<DualReadConfiguration>,
+	/// Number of indexer pages till next verification.
+	pages_till_verification: Mutex<u32>,
+}
+
+impl Default for DualReadConfiguration {
+	fn default() -> Self {
+		DualReadConfiguration {
+			sample_interval: 10,
+			divergence_handler: None,
+		}
+	}
+}
+
+impl<B, I> IndexerBlockchain<B, I>
+	where
+		B: Blockchain,
+		B::BlockHash: PartialEq,
+		I: RequestsIndexer<BlockHash = B::BlockHash>,
+{
+	/// Create new indexer-backed blockchain.
+	pub fn new(node: B, indexer: I) -> Self {
+		IndexerBlockchain {
+			node,
+			indexer,
+			verification: None,
+			pages_till_verification: Mutex::new(0),
+		}
+	}
+
+	/// Enable dual-read verification of indexer pages.
+	pub fn with_verification(mut self, verification: DualReadConfiguration) -> Self {
+		self.verification = Some(verification);
+		self
+	}
+
+	/// Returns reference to the node blockchain.
+	pub fn node(&self) -> &B {
+		&self.node
+	}
+
+	/// Returns reference to the indexer.
+	pub fn indexer(&self) -> &I {
+		&self.indexer
+	}
+
+	/// Read pending tasks from the indexer. Returns `Ok(None)` if indexer hasn't yet
+	/// indexed given block (that is expected for blocks that are not yet finalized) and
+	/// error if indexer can't be trusted to serve tasks at given block.
+	pub fn indexed_pending_tasks(
+		&self,
+		task_kind: TaskKind,
+		block_hash: &B::BlockHash,
+		range: Range<usize>,
+	) -> Result<Option<Vec<BlockchainServiceTask>>, String> {
+		let block_number = self.node.block_number(block_hash.clone()).map_err(|error| error.to_string())?;
+		let (indexed_block_number, indexed_block_hash) = self.indexer.indexed_block()?;
+		if indexed_block_number < block_number {
+			return Ok(None);
+		}
+		let canonical_block_hash = self.node.block_hash(indexed_block_number).map_err(|error| error.to_string())?;
+		if canonical_block_hash.as_ref() != Some(&indexed_block_hash) {
+			return Err(format!("indexed block {} is not on the canonical chain", indexed_block_number));
+		}
+		let indexed_block_finality = self.node.block_finality(indexed_block_hash).map_err(|error| error.to_string())?;
+		if indexed_block_finality != BlockFinality::Finalized {
+			return Err(format!("indexed block {} is not finalized", indexed_block_number));
+		}
+
+		self.indexer.pending_tasks(task_kind, block_number, range).map(Some)
+	}
+
+	/// Read pending tasks from the indexer, falling back to the node.
+	fn pending_tasks(
+		&self,
+		task_kind: TaskKind,
+		block_hash: &B::BlockHash,
+		range: Range<usize>,
+		read_from_node: impl FnOnce(&B, &B::BlockHash, Range<usize>) -> Result<B::PendingEvents, ServiceError>,
+	) -> Result<Vec<IndexedEvent<B::Event>>, ServiceError> {
+		match self.indexed_pending_tasks(task_kind, block_hash, range.clone()) {
+			Ok(Some(tasks)) => match self.is_verification_required() {
+				true => self.verify_pending_tasks(task_kind, block_hash, range, tasks, read_from_node),
+				false => Ok(tasks.into_iter().map(|task| IndexedEvent::Decoded(Box::new(task))).collect()),
+			},
+			Ok(None) => {
+				trace!(
+					target: "secretstore",
+					"Indexer is lagging behind the requested block. Reading pending {:?} tasks from node",
+					task_kind,
+				);
+
+				Ok(read_from_node(&self.node, block_hash, range)?.into_iter().map(IndexedEvent::Node).collect())
+			},
+			Err(error) => {
+				warn!(
+					target: "secretstore",
+					"Failed to read pending {:?} tasks from indexer: {}. Reading from node",
+					task_kind,
+					error,
+				);
+
+				Ok(read_from_node(&self.node, block_hash, range)?.into_iter().map(IndexedEvent::Node).collect())
+			},
+		}
+	}
+}
+
+impl<B: Blockchain, I> IndexerBlockchain<B, I> {
+	/// Returns true if next indexer page needs to be verified.
+	fn is_verification_required(&self) -> bool {
+		let verification = match self.verification {
+			Some(ref verification) => verification,
+			None => return false,
+		};
+
+		let mut pages_till_verification = self.pages_till_verification
+			.lock()
+			.expect("never panics under lock; qed");
+		if *pages_till_verification != 0 {
+			*pages_till_verification -= 1;
+			return false;
+		}
+		*pages_till_verification = std::cmp::max(verification.sample_interval, 1) - 1;
+		true
+	}
+
+	/// Read the same page from the node and compare it with the indexer page. Node
+	/// page is returned if pages differ.
+	fn verify_pending_tasks(
+		&self,
+		task_kind: TaskKind,
+		block_hash: &B::BlockHash,
+		range: Range<usize>,
+		indexer_tasks: Vec<BlockchainServiceTask>,
+		read_from_node: impl FnOnce(&B, &B::BlockHash, Range<usize>) -> Result<B::PendingEvents, ServiceError>,
+	) -> Result<Vec<IndexedEvent<B::Event>>, ServiceError> {
+		let node_tasks = read_from_node(&self.node, block_hash, range.clone())?
+			.into_iter()
+			.filter_map(MaybeSecretStoreEvent::as_secret_store_event)
+			.collect::<Vec<_>>();
+		let is_divergent = indexer_tasks.len() != node_tasks.len()
+			|| indexer_tasks.iter().zip(node_tasks.iter()).any(|(indexer_task, node_task)|
+				!is_same_task(indexer_task, node_task)
+			);
+		if !is_divergent {
+			return Ok(indexer_tasks.into_iter().map(|task| IndexedEvent::Decoded(Box::new(task))).collect());
+		}
+
+		error!(
+			target: "secretstore",
+			"Pending {:?} tasks {:?} served by indexer differ from node: {} tasks vs {} tasks",
+			task_kind,
+			range,
+			indexer_tasks.len(),
+			node_tasks.len(),
+		);
+
+		let divergence_handler = self.verification
+			.as_ref()
+			.and_then(|verification| verification.divergence_handler.as_ref());
+		if let Some(divergence_handler) = divergence_handler {
+			divergence_handler(task_kind, &range);
+		}
+
+		Ok(node_tasks.into_iter().map(|task| IndexedEvent::Decoded(Box::new(task))).collect())
+	}
+}
+
+impl<E: MaybeSecretStoreEvent> MaybeSecretStoreEvent for IndexedEvent<E> {
+	fn as_secret_store_event(self) -> Option<BlockchainServiceTask> {
+		match self {
+			IndexedEvent::Node(event) => event.as_secret_store_event(),
+			IndexedEvent::Decoded(task) => Some(*task),
+		}
+	}
+
+	fn as_secret_store_response(&self) -> Option<SecretStoreResponse> {
+		match *self {
+			IndexedEvent::Node(ref event) => event.as_secret_store_response(),
+			IndexedEvent::Decoded(_) => None,
+		}
+	}
+
+	fn as_unknown_secret_store_event(&self) -> Option<RawSecretStoreEvent> {
+		match *self {
+			IndexedEvent::Node(ref event) => event.as_unknown_secret_store_event(),
+			IndexedEvent::Decoded(_) => None,
+		}
+	}
+
+	fn as_scheduled_request(&self) -> Option<ScheduledRequest> {
+		match *self {
+			IndexedEvent::Node(ref event) => event.as_scheduled_request(),
+			IndexedEvent::Decoded(_) => None,
+		}
+	}
+
+	fn as_completed_request(&self) -> Option<ServedRequest> {
+		match *self {
+			IndexedEvent::Node(ref event) => event.as_completed_request(),
+			IndexedEvent::Decoded(_) => None,
+		}
+	}
+}
+
+impl<B, I> Blockchain for IndexerBlockchain<B, I>
+	where
+		B: Blockchain,
+		B::BlockHash: PartialEq,
+		I: RequestsIndexer<BlockHash = B::BlockHash>,
+{
+	type BlockHash = B::BlockHash;
+	type Event = IndexedEvent<B::Event>;
+	type BlockEvents = std::iter::Map<
+		<B::BlockEvents as IntoIterator>::IntoIter,
+		fn(B::Event) -> IndexedEvent<B::Event>,
+	>;
+	type PendingEvents = Vec<IndexedEvent<B::Event>>;
+
+	fn block_events(&self, block_hash: Self::BlockHash) -> Self::BlockEvents {
+		self.node.block_events(block_hash).into_iter().map(IndexedEvent::Node)
+	}
+
+	fn block_hash(&self, block_number: u64) -> Result<Option<Self::BlockHash>, ServiceError> {
+		self.node.block_hash(block_number)
+	}
+
+	fn has_secret_store_activity(&self, block_hash: Self::BlockHash) -> bool {
+		self.node.has_secret_store_activity(block_hash)
+	}
+
+	fn on_task_forwarded(&self, task: &BlockchainServiceTask, origin: &TaskOriginBlock<Self::BlockHash>) {
+		self.node.on_task_forwarded(task, origin)
+	}
+
+	fn block_number(&self, block_hash: Self::BlockHash) -> Result<u64, ServiceError> {
+		self.node.block_number(block_hash)
+	}
+
+	fn block_finality(&self, block_hash: Self::BlockHash) -> Result<BlockFinality, ServiceError> {
+		self.node.block_finality(block_hash)
+	}
+
+	fn secret_store_constants(&self) -> Result<SecretStoreConstants, ServiceError> {
+		self.node.secret_store_constants()
+	}
+
+	fn runtime_interface_version(&self) -> Option<u32> {
+		self.node.runtime_interface_version()
+	}
+
+	fn current_key_servers_set(&self) -> BTreeSet<KeyServerId> {
+		self.node.current_key_servers_set()
+	}
+
+	fn server_key_generation_tasks(
+		&self,
+		block_hash: &Self::BlockHash,
+		range: Range<usize>,
+	) -> Result<Self::PendingEvents, ServiceError> {
+		self.pending_tasks(TaskKind::ServerKeyGeneration, block_hash, range, B::server_key_generation_tasks)
+	}
+
+	fn is_server_key_generation_response_required(
+		&self,
+		key_id: ServerKeyId,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.is_server_key_generation_response_required(key_id, key_server_id)
+	}
+
+	fn has_server_key_generation_response(
+		&self,
+		key_id: ServerKeyId,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.has_server_key_generation_response(key_id, key_server_id)
+	}
+
+	fn server_key_retrieval_tasks(
+		&self,
+		block_hash: &Self::BlockHash,
+		range: Range<usize>,
+	) -> Result<Self::PendingEvents, ServiceError> {
+		self.pending_tasks(TaskKind::ServerKeyRetrieval, block_hash, range, B::server_key_retrieval_tasks)
+	}
+
+	fn is_server_key_retrieval_response_required(
+		&self,
+		key_id: ServerKeyId,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.is_server_key_retrieval_response_required(key_id, key_server_id)
+	}
+
+	fn has_server_key_retrieval_response(
+		&self,
+		key_id: ServerKeyId,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.has_server_key_retrieval_response(key_id, key_server_id)
+	}
+
+	fn document_key_store_tasks(
+		&self,
+		block_hash: &Self::BlockHash,
+		range: Range<usize>,
+	) -> Result<Self::PendingEvents, ServiceError> {
+		self.pending_tasks(TaskKind::DocumentKeyStore, block_hash, range, B::document_key_store_tasks)
+	}
+
+	fn is_document_key_store_response_required(
+		&self,
+		key_id: ServerKeyId,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.is_document_key_store_response_required(key_id, key_server_id)
+	}
+
+	fn has_document_key_store_response(
+		&self,
+		key_id: ServerKeyId,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.has_document_key_store_response(key_id, key_server_id)
+	}
+
+	fn document_key_shadow_retrieval_tasks(
+		&self,
+		block_hash: &Self::BlockHash,
+		range: Range<usize>,
+	) -> Result<Self::PendingEvents, ServiceError> {
+		self.pending_tasks(
+			TaskKind::DocumentKeyShadowRetrieval,
+			block_hash,
+			range,
+			B::document_key_shadow_retrieval_tasks,
+		)
+	}
+
+	fn is_document_key_shadow_retrieval_response_required(
+		&self,
+		key_id: ServerKeyId,
+		requester: Address,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.is_document_key_shadow_retrieval_response_required(key_id, requester, key_server_id)
+	}
+
+	fn has_document_key_shadow_retrieval_response(
+		&self,
+		key_id: ServerKeyId,
+		requester: Address,
+		key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		self.node.has_document_key_shadow_retrieval_response(key_id, requester, key_server_id)
+	}
+}
+
+/// Returns true if both tasks are the same, including origin and all task parameters.
+fn is_same_task(first: &BlockchainServiceTask, second: &BlockchainServiceTask) -> bool {
+	match (first, second) {
+		(
+			BlockchainServiceTask::Regular(first_origin, first_task),
+			BlockchainServiceTask::Regular(second_origin, second_task),
+		) => first_origin == second_origin && first_task == second_task,
+		(
+			BlockchainServiceTask::RetrieveShadowDocumentKeyCommon(first_origin, first_key_id, first_requester),
+			BlockchainServiceTask::RetrieveShadowDocumentKeyCommon(second_origin, second_key_id, second_requester),
+		) | (
+			BlockchainServiceTask::RetrieveShadowDocumentKeyPersonal(first_origin, first_key_id, first_requester),
+			BlockchainServiceTask::RetrieveShadowDocumentKeyPersonal(second_origin, second_key_id, second_requester),
+		) => first_origin == second_origin && first_key_id == second_key_id && first_requester == second_requester,
+		_ => false,
+	}
+}
@@ -0,0 +1,129 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex};
+use log::debug;
+use crate::{
+	reconcile::Reconciler,
+	shadow::ShadowComparator,
+	sla::SlaTracker,
+};
+
+/// Called when garbage collection round is completed.
+pub type JanitorHandler = Arc<dyn Fn(&JanitorReport) + Send + Sync>;
+
+/// Garbage collection configuration. All retention periods are in blocks.
+#[derive(Debug, Clone)]
+pub struct JanitorConfiguration {
+	/// Garbage is collected every `interval` blocks.
+	pub interval: u32,
+	/// Retention of requests that have violated SLA and are still not completed.
+	pub sla_retention: u64,
+	/// Retention of submitted responses that are waiting for reconciliation.
+	pub in_flight_retention: u64,
+	/// Retention of shadow mode responses that are waiting for the response of other
+	/// key server.
+	pub shadow_retention: u64,
+}
+
+/// Number of entries evicted from every structure at single garbage collection round.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JanitorReport {
+	/// Number of evicted SLA tracker requests.
+	pub sla_requests: usize,
+	/// Number of evicted in-flight responses.
+	pub in_flight_responses: usize,
+	/// Number of evicted unmatched shadow mode responses.
+	pub shadow_responses: usize,
+}
+
+/// Periodically evicts stale entries from internal caches and queues, so that they
+/// do not grow unbounded on long-running servers.
+pub struct Janitor {
+	/// Configuration. If `None`, garbage is never collected.
+	config: Option<JanitorConfiguration>,
+	/// Garbage collection report handler.
+	handler: Option<JanitorHandler>,
+	/// Number of blocks till next garbage collection round.
+	blocks_till_collection: Mutex<u32>,
+}
+
+impl Default for JanitorConfiguration {
+	fn default() -> Self {
+		JanitorConfiguration {
+			interval: 100,
+			sla_retention: 1_000,
+			in_flight_retention: 1_000,
+			shadow_retention: 100,
+		}
+	}
+}
+
+impl Janitor {
+	/// Create new janitor.
+	pub fn new(config: Option<JanitorConfiguration>, handler: Option<JanitorHandler>) -> Self {
+		let blocks_till_collection = config.as_ref().map(|config| config.interval).unwrap_or(0);
+		Janitor {
+			config,
+			handler,
+			blocks_till_collection: Mutex::new(blocks_till_collection),
+		}
+	}
+
+	/// Called when new block is processed. Collects garbage if required.
+	pub fn on_new_block(
+		&self,
+		sla: &SlaTracker,
+		reconciler: &Reconciler,
+		shadow: Option<&ShadowComparator>,
+	) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+
+		{
+			let mut blocks_till_collection = self.blocks_till_collection
+				.lock()
+				.expect("janitor never panics under lock; qed");
+			if *blocks_till_collection != 0 {
+				*blocks_till_collection -= 1;
+				return;
+			}
+			*blocks_till_collection = std::cmp::max(config.interval, 1) - 1;
+		}
+
+		let report = JanitorReport {
+			sla_requests: sla.collect_garbage(config.sla_retention),
+			in_flight_responses: reconciler.collect_garbage(config.in_flight_retention),
+			shadow_responses: shadow
+				.map(|shadow| shadow.collect_garbage(config.shadow_retention))
+				.unwrap_or(0),
+		};
+
+		debug!(
+			target: "secretstore",
+			"Collected garbage: {} SLA requests, {} in-flight responses, {} shadow responses",
+			report.sla_requests,
+			report.in_flight_responses,
+			report.shadow_responses,
+		);
+
+		if let Some(ref handler) = self.handler {
+			handler(&report);
+		}
+	}
+}
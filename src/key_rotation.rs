@@ -0,0 +1,258 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	sync::{Arc, Condvar, Mutex, RwLock},
+	time::{Duration, Instant},
+};
+use log::{error, info};
+use crate::{SecretStoreCall, SubmitError, TransactionPool};
+
+/// Submitter session keys management.
+pub trait SubmitterKeys: Send + Sync + 'static {
+	/// Submitter session key.
+	type Key: Clone + Send + Sync + std::fmt::Display;
+	/// Transaction hash.
+	type TransactionHash: std::fmt::Display;
+
+	/// Generate new session key.
+	fn generate_key(&self) -> Result<Self::Key, String>;
+	/// Register and fund the new key using configured rotation call, signed by the
+	/// current key. Shall return once the new key may be used to sign transactions.
+	fn register_key(&self, current: &Self::Key, new: &Self::Key) -> Result<(), String>;
+	/// Retire old key (unregister it, withdraw funds, remove it from the keystore).
+	fn retire_key(&self, current: &Self::Key, old: &Self::Key) -> Result<(), String>;
+	/// Submit transaction signed by given key.
+	fn submit_transaction(
+		&self,
+		key: &Self::Key,
+		call: SecretStoreCall,
+	) -> Result<Self::TransactionHash, SubmitError>;
+}
+
+/// Transaction pool that is periodically rotating submitter session key.
+///
+/// Keys are rotated by the background thread, so submissions are never blocked by
+/// the key registration. The old key is retired after the grace period, giving
+/// transactions signed by this key time to be included into the chain. The thread is
+/// stopped when the pool is dropped.
+///
+/// All transactions are signed by the session key, so transactions from tenant
+/// submitter accounts are rejected with `SubmitError::Invalid`.
+pub struct RotatingTransactionPool<K: SubmitterKeys> {
+	/// Shared rotator state.
+	rotator: Arc<KeyRotator<K>>,
+}
+
+/// Rotator state, shared with the background thread.
+struct KeyRotator<K: SubmitterKeys> {
+	/// Submitter keys.
+	keys: K,
+	/// Interval between key rotations.
+	rotation_interval: Duration,
+	/// Time given to transactions, signed by the old key, to be included into the chain.
+	retirement_delay: Duration,
+	/// Current submitter key.
+	current_key: RwLock<K::Key>,
+	/// Guards key rotation.
+	rotation: Mutex<()>,
+	/// Rotation schedule.
+	schedule: Mutex<RotationSchedule<K::Key>>,
+	/// Notified when schedule is updated.
+	schedule_updated: Condvar,
+}
+
+/// Schedule of key rotations and retirements.
+struct RotationSchedule<Key> {
+	/// Time of last rotation attempt.
+	last_rotation: Instant,
+	/// Old keys and times when they may be retired.
+	retiring_keys: Vec<(Key, Instant)>,
+	/// True if the rotator thread must stop.
+	is_stopped: bool,
+}
+
+impl<K: SubmitterKeys> RotatingTransactionPool<K> {
+	/// Create new transaction pool and start rotator thread.
+	pub fn new(
+		keys: K,
+		initial_key: K::Key,
+		rotation_interval: Duration,
+		retirement_delay: Duration,
+	) -> Result<Self, String> {
+		let rotator = Arc::new(KeyRotator {
+			keys,
+			rotation_interval,
+			retirement_delay,
+			current_key: RwLock::new(initial_key),
+			rotation: Mutex::new(()),
+			schedule: Mutex::new(RotationSchedule {
+				last_rotation: Instant::now(),
+				retiring_keys: Vec::new(),
+				is_stopped: false,
+			}),
+			schedule_updated: Condvar::new(),
+		});
+		let thread_rotator = rotator.clone();
+		std::thread::Builder::new()
+			.name("secretstore-key-rotation".into())
+			.spawn(move || thread_rotator.run())
+			.map_err(|error| format!("failed to start key rotation thread: {}", error))?;
+
+		Ok(RotatingTransactionPool { rotator })
+	}
+
+	/// Returns current submitter key.
+	pub fn current_key(&self) -> K::Key {
+		self.rotator.current_key()
+	}
+
+	/// Rotate submitter key right now.
+	pub fn rotate(&self) -> Result<K::Key, String> {
+		self.rotator.schedule
+			.lock()
+			.expect("poisoned only on rotator thread panic; qed")
+			.last_rotation = Instant::now();
+		self.rotator.rotate()
+	}
+}
+
+impl<K: SubmitterKeys> TransactionPool for RotatingTransactionPool<K> {
+	type TransactionHash = K::TransactionHash;
+
+	fn submit_transaction(&self, call: SecretStoreCall) -> Result<Self::TransactionHash, SubmitError> {
+		let current_key = self.rotator.current_key
+			.read()
+			.expect("poisoned only on panic while switching keys; qed");
+		self.rotator.keys.submit_transaction(&*current_key, call)
+	}
+}
+
+impl<K: SubmitterKeys> Drop for RotatingTransactionPool<K> {
+	fn drop(&mut self) {
+		if let Ok(mut schedule) = self.rotator.schedule.lock() {
+			schedule.is_stopped = true;
+			self.rotator.schedule_updated.notify_one();
+		}
+	}
+}
+
+impl<K: SubmitterKeys> KeyRotator<K> {
+	/// Rotate and retire keys until stopped.
+	fn run(&self) {
+		loop {
+			let (is_rotation_required, retired_keys) = {
+				let mut schedule = match self.schedule.lock() {
+					Ok(schedule) => schedule,
+					Err(_) => return,
+				};
+				loop {
+					if schedule.is_stopped {
+						return;
+					}
+
+					let now = Instant::now();
+					let next_deadline = schedule.retiring_keys
+						.iter()
+						.map(|(_, retire_at)| *retire_at)
+						.fold(schedule.last_rotation + self.rotation_interval, std::cmp::min);
+					if next_deadline <= now {
+						break;
+					}
+
+					schedule = match self.schedule_updated.wait_timeout(schedule, next_deadline - now) {
+						Ok((schedule, _)) => schedule,
+						Err(_) => return,
+					};
+				}
+
+				// if rotation fails, we'll retry after next interval
+				let now = Instant::now();
+				let is_rotation_required = schedule.last_rotation + self.rotation_interval <= now;
+				if is_rotation_required {
+					schedule.last_rotation = now;
+				}
+
+				let (retired_keys, retiring_keys) = schedule.retiring_keys
+					.drain(..)
+					.partition::<Vec<_>, _>(|(_, retire_at)| *retire_at <= now);
+				schedule.retiring_keys = retiring_keys;
+
+				(is_rotation_required, retired_keys)
+			};
+
+			for (old_key, _) in retired_keys {
+				self.retire(old_key);
+			}
+
+			if is_rotation_required {
+				if let Err(error) = self.rotate() {
+					error!(
+						target: "secretstore",
+						"Failed to rotate submitter key: {}",
+						error,
+					);
+				}
+			}
+		}
+	}
+
+	/// Returns current submitter key.
+	fn current_key(&self) -> K::Key {
+		self.current_key.read().expect("poisoned only on panic while switching keys; qed").clone()
+	}
+
+	/// Rotate submitter key and schedule retirement of the old key.
+	fn rotate(&self) -> Result<K::Key, String> {
+		let _rotation = self.rotation.lock().expect("poisoned only on panic while rotating; qed");
+		let old_key = self.current_key();
+		let new_key = self.keys.generate_key()?;
+		self.keys.register_key(&old_key, &new_key)?;
+
+		*self.current_key.write().expect("poisoned only on panic while switching keys; qed") = new_key.clone();
+		info!(
+			target: "secretstore",
+			"Switched submitter key: {} -> {}",
+			old_key,
+			new_key,
+		);
+
+		let mut schedule = self.schedule.lock().expect("poisoned only on rotator thread panic; qed");
+		schedule.retiring_keys.push((old_key, Instant::now() + self.retirement_delay));
+		self.schedule_updated.notify_one();
+
+		Ok(new_key)
+	}
+
+	/// Retire old submitter key.
+	fn retire(&self, old_key: K::Key) {
+		let _rotation = self.rotation.lock().expect("poisoned only on panic while rotating; qed");
+		let current_key = self.current_key();
+		match self.keys.retire_key(&current_key, &old_key) {
+			Ok(()) => info!(
+				target: "secretstore",
+				"Retired old submitter key {}",
+				old_key,
+			),
+			Err(error) => error!(
+				target: "secretstore",
+				"Failed to retire old submitter key {}: {}",
+				old_key,
+				error,
+			),
+		}
+	}
+}
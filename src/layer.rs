@@ -0,0 +1,55 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Custom processing layers.
+//!
+//! Tasks are processed by the service in stages: events are decoded, tasks are
+//! filtered (routing, key id namespaces, already served requests, tenant quotas),
+//! dispatched to the key server and responses are published. Embedders may plug
+//! their own layers between built-in stages, without forking the crate.
+
+use std::sync::Arc;
+use crate::{BlockchainServiceTask, SecretStoreCall};
+
+/// Layer that is applied to tasks after built-in filters, before dispatching them
+/// to the key server.
+pub trait TaskLayer: Send + Sync + 'static {
+	/// Process the task. Returning `None` drops the task.
+	fn on_task(&self, task: BlockchainServiceTask) -> Option<BlockchainServiceTask>;
+}
+
+/// Layer that is applied to responses before they're published.
+pub trait ResponseLayer: Send + Sync + 'static {
+	/// Process the response. Returning `None` drops the response. The request stays
+	/// pending on chain, so it may be retried later.
+	fn on_response(&self, call: SecretStoreCall) -> Option<SecretStoreCall>;
+}
+
+/// Apply task layers in order.
+pub fn apply_task_layers(
+	layers: &[Arc<dyn TaskLayer>],
+	task: BlockchainServiceTask,
+) -> Option<BlockchainServiceTask> {
+	layers.iter().try_fold(task, |task, layer| layer.on_task(task))
+}
+
+/// Apply response layers in order.
+pub fn apply_response_layers(
+	layers: &[Arc<dyn ResponseLayer>],
+	call: SecretStoreCall,
+) -> Option<SecretStoreCall> {
+	layers.iter().try_fold(call, |call, layer| layer.on_response(call))
+}
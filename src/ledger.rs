@@ -0,0 +1,512 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Condvar, Mutex},
+	time::Duration,
+};
+use log::{error, trace, warn};
+use crate::{DroppedTransaction, SecretStoreCall, SubmitError, TransactionPool};
+
+/// Instruction to sign transaction payload.
+const INS_SIGN: u8 = 0x02;
+/// First sign chunk, containing derivation path.
+const P1_INIT: u8 = 0x00;
+/// Intermediate sign chunk, containing part of the payload.
+const P1_ADD: u8 = 0x01;
+/// Last sign chunk, containing the rest of the payload.
+const P1_LAST: u8 = 0x02;
+/// Max size of payload chunk.
+const CHUNK_SIZE: usize = 250;
+/// Max size of APDU data (it is prefixed with single byte length).
+const MAX_APDU_DATA_SIZE: usize = 255;
+/// Status word of successful command.
+const SW_OK: u16 = 0x9000;
+/// Status word returned when user has rejected the transaction.
+const SW_REJECTED: u16 = 0x6986;
+
+/// Ledger device transport (USB HID, speculos, ...).
+pub trait LedgerTransport: Send + Sync + 'static {
+	/// Send APDU command to the device and wait for the response. If there's no
+	/// response within `timeout`, `LedgerError::Timeout` must be returned.
+	fn exchange(&self, command: &[u8], timeout: Duration) -> Result<Vec<u8>, LedgerError>;
+}
+
+/// Builder of transactions that are signed by the Ledger device.
+pub trait LedgerTransactionBuilder: Send + Sync + 'static {
+	/// Transaction hash.
+	type TransactionHash: std::fmt::Display;
+
+	/// Encode signing payload of the transaction that is calling Secret Store module.
+	fn signing_payload(&self, call: SecretStoreCall) -> Result<Vec<u8>, String>;
+	/// Submit transaction with given payload and signature to the pool. The signature
+	/// is exactly what device has returned (i.e. it is prefixed with scheme byte).
+	fn submit_signed_transaction(
+		&self,
+		payload: Vec<u8>,
+		signature: Vec<u8>,
+	) -> Result<Self::TransactionHash, String>;
+}
+
+/// Ledger communication error.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedgerError {
+	/// Device has not responded in time (most likely user has not confirmed transaction).
+	Timeout,
+	/// User has rejected the transaction.
+	Rejected,
+	/// Device has returned an error status word.
+	Status(u16),
+	/// Transport error.
+	Transport(String),
+}
+
+/// Signature scheme used by the Ledger Substrate app.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedgerScheme {
+	/// Ed25519 signatures.
+	Ed25519,
+	/// Sr25519 signatures.
+	Sr25519,
+}
+
+/// What to do when user has not confirmed transaction in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfirmationTimeoutPolicy {
+	/// Drop the transaction.
+	Drop,
+	/// Ask for confirmation again, at most given number of times.
+	Retry(usize),
+}
+
+/// Ledger signer configuration.
+#[derive(Debug, Clone)]
+pub struct LedgerConfiguration {
+	/// Class of the Substrate app installed on the device (0x90 for Polkadot, 0x99 for Kusama, ...).
+	pub app_class: u8,
+	/// BIP44 derivation path of the submitter account (hardened components must have high bit set).
+	pub derivation_path: [u32; 5],
+	/// Signature scheme.
+	pub scheme: LedgerScheme,
+	/// Timeout of device exchanges that do not require user confirmation.
+	pub exchange_timeout: Duration,
+	/// Time given to user to confirm the transaction on the device.
+	pub confirmation_timeout: Duration,
+	/// What to do when the transaction is not confirmed in time.
+	pub confirmation_timeout_policy: ConfirmationTimeoutPolicy,
+	/// Max number of transactions waiting for confirmation.
+	pub max_queue_size: usize,
+}
+
+/// Ticket of the transaction that has been queued for signing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LedgerTicket(pub u64);
+
+/// Transaction pool that signs transactions using Ledger device.
+///
+/// Signing requires user confirmation, which could take a while. So transactions are
+/// queued and signed (one by one) by the background thread. The thread is stopped
+/// when the pool is dropped. Transactions that have failed to be signed or submitted
+/// are reported back to the service by `take_dropped_transactions`.
+///
+/// All transactions are signed by the device account, so transactions from tenant
+/// submitter accounts are rejected with `SubmitError::Invalid`.
+pub struct LedgerTransactionPool<T, B> {
+	/// Shared signer state.
+	signer: Arc<LedgerSigner<T, B>>,
+}
+
+/// Signer state, shared with the background thread.
+struct LedgerSigner<T, B> {
+	/// Device transport.
+	transport: T,
+	/// Transactions builder.
+	builder: B,
+	/// Signer configuration.
+	config: LedgerConfiguration,
+	/// Signing queue.
+	queue: Mutex<LedgerQueue>,
+	/// Notified when queue is updated.
+	queue_updated: Condvar,
+}
+
+/// Queue of transactions waiting for signing.
+#[derive(Default)]
+struct LedgerQueue {
+	/// Queued transactions.
+	transactions: VecDeque<(LedgerTicket, SecretStoreCall)>,
+	/// Transactions that have failed to be signed or submitted.
+	dropped_transactions: Vec<DroppedTransaction>,
+	/// Next ticket.
+	next_ticket: u64,
+	/// True if the signer thread must stop.
+	is_stopped: bool,
+}
+
+impl<T, B> LedgerTransactionPool<T, B>
+	where
+		T: LedgerTransport,
+		B: LedgerTransactionBuilder,
+{
+	/// Create new transaction pool and start signer thread.
+	pub fn new(
+		transport: T,
+		builder: B,
+		config: LedgerConfiguration,
+	) -> Result<Self, String> {
+		let signer = Arc::new(LedgerSigner {
+			transport,
+			builder,
+			config,
+			queue: Mutex::new(LedgerQueue::default()),
+			queue_updated: Condvar::new(),
+		});
+		let thread_signer = signer.clone();
+		std::thread::Builder::new()
+			.name("secretstore-ledger".into())
+			.spawn(move || thread_signer.run())
+			.map_err(|error| format!("failed to start Ledger signer thread: {}", error))?;
+
+		Ok(LedgerTransactionPool { signer })
+	}
+}
+
+impl<T, B> TransactionPool for LedgerTransactionPool<T, B>
+	where
+		T: LedgerTransport,
+		B: LedgerTransactionBuilder,
+{
+	type TransactionHash = LedgerTicket;
+
+	fn submit_transaction(&self, call: SecretStoreCall) -> Result<Self::TransactionHash, SubmitError> {
+		let mut queue = self.signer.queue.lock().expect("poisoned only on signer thread panic; qed");
+		if queue.transactions.len() >= self.signer.config.max_queue_size {
+			return Err(SubmitError::Retryable(format!(
+				"Ledger signing queue is full ({} transactions)",
+				queue.transactions.len(),
+			)));
+		}
+
+		let ticket = LedgerTicket(queue.next_ticket);
+		queue.next_ticket += 1;
+		queue.transactions.push_back((ticket, call));
+		self.signer.queue_updated.notify_one();
+
+		Ok(ticket)
+	}
+
+	fn take_dropped_transactions(&self) -> Vec<DroppedTransaction> {
+		let mut queue = self.signer.queue.lock().expect("poisoned only on signer thread panic; qed");
+		std::mem::take(&mut queue.dropped_transactions)
+	}
+}
+
+impl<T, B> Drop for LedgerTransactionPool<T, B> {
+	fn drop(&mut self) {
+		if let Ok(mut queue) = self.signer.queue.lock() {
+			queue.is_stopped = true;
+			self.signer.queue_updated.notify_one();
+
+			for (ticket, call) in queue.transactions.drain(..) {
+				warn!(
+					target: "secretstore",
+					"Ledger signer is stopped. Dropping unsigned {:?} transaction {}",
+					call.task_kind(),
+					ticket,
+				);
+			}
+		}
+	}
+}
+
+impl<T, B> LedgerSigner<T, B>
+	where
+		T: LedgerTransport,
+		B: LedgerTransactionBuilder,
+{
+	/// Sign and submit queued transactions until stopped.
+	fn run(&self) {
+		loop {
+			let (ticket, call) = {
+				let mut queue = match self.queue.lock() {
+					Ok(queue) => queue,
+					Err(_) => return,
+				};
+				loop {
+					if queue.is_stopped {
+						return;
+					}
+					if let Some(transaction) = queue.transactions.pop_front() {
+						break transaction;
+					}
+					queue = match self.queue_updated.wait(queue) {
+						Ok(queue) => queue,
+						Err(_) => return,
+					};
+				}
+			};
+
+			match self.sign_and_submit(call.clone()) {
+				Ok(transaction_hash) => trace!(
+					target: "secretstore",
+					"Submitted Ledger-signed transaction {}: {}",
+					ticket,
+					transaction_hash,
+				),
+				Err(error) => {
+					error!(
+						target: "secretstore",
+						"Failed to submit Ledger-signed transaction {}: {}",
+						ticket,
+						error,
+					);
+
+					if let Ok(mut queue) = self.queue.lock() {
+						queue.dropped_transactions.push(DroppedTransaction { call, error });
+					}
+				},
+			}
+		}
+	}
+
+	/// Sign transaction with the device and submit it.
+	fn sign_and_submit(&self, call: SecretStoreCall) -> Result<B::TransactionHash, String> {
+		let payload = self.builder.signing_payload(call)?;
+
+		let mut attempt = 0;
+		let signature = loop {
+			attempt += 1;
+			match self.sign(&payload) {
+				Ok(signature) => break signature,
+				Err(LedgerError::Timeout) => match self.config.confirmation_timeout_policy {
+					ConfirmationTimeoutPolicy::Retry(max_attempts) if attempt <= max_attempts => warn!(
+						target: "secretstore",
+						"Transaction has not been confirmed on Ledger device in time. Retrying ({}/{})",
+						attempt,
+						max_attempts,
+					),
+					_ => return Err("transaction has not been confirmed on Ledger device in time".into()),
+				},
+				Err(error) => return Err(format!("Ledger signing has failed: {:?}", error)),
+			}
+		};
+
+		self.builder.submit_signed_transaction(payload, signature)
+	}
+
+	/// Sign payload using the device.
+	fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, LedgerError> {
+		let scheme = match self.config.scheme {
+			LedgerScheme::Ed25519 => 0x00,
+			LedgerScheme::Sr25519 => 0x01,
+		};
+
+		let path = self.config.derivation_path
+			.iter()
+			.flat_map(|component| component.to_le_bytes().to_vec())
+			.collect::<Vec<_>>();
+		self.exchange(P1_INIT, scheme, &path, self.config.exchange_timeout)?;
+
+		let mut chunks = payload.chunks(CHUNK_SIZE).peekable();
+		while let Some(chunk) = chunks.next() {
+			let is_last = chunks.peek().is_none();
+			let (p1, timeout) = match is_last {
+				true => (P1_LAST, self.config.confirmation_timeout),
+				false => (P1_ADD, self.config.exchange_timeout),
+			};
+			let response = self.exchange(p1, scheme, chunk, timeout)?;
+			if is_last {
+				return Ok(response);
+			}
+		}
+
+		Err(LedgerError::Transport("empty transaction payload".into()))
+	}
+
+	/// Send single sign APDU to the device.
+	fn exchange(&self, p1: u8, p2: u8, data: &[u8], timeout: Duration) -> Result<Vec<u8>, LedgerError> {
+		if data.len() > MAX_APDU_DATA_SIZE {
+			return Err(LedgerError::Transport(format!("too large APDU data: {} bytes", data.len())));
+		}
+
+		let mut command = Vec::with_capacity(5 + data.len());
+		command.extend_from_slice(&[self.config.app_class, INS_SIGN, p1, p2, data.len() as u8]);
+		command.extend_from_slice(data);
+
+		let mut response = self.transport.exchange(&command, timeout)?;
+		if response.len() < 2 {
+			return Err(LedgerError::Transport("too short device response".into()));
+		}
+
+		let status_offset = response.len() - 2;
+		let status = u16::from_be_bytes([response[status_offset], response[status_offset + 1]]);
+		response.truncate(status_offset);
+		match status {
+			SW_OK => Ok(response),
+			SW_REJECTED => Err(LedgerError::Rejected),
+			_ => Err(LedgerError::Status(status)),
+		}
+	}
+}
+
+impl std::fmt::Display for LedgerTicket {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "ledger#{}", self.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Transport that records all commands and replies with prepared responses.
+	#[derive(Default)]
+	struct FakeTransport {
+		/// Received commands.
+		commands: Mutex<Vec<(Vec<u8>, Duration)>>,
+		/// Responses to send back.
+		responses: Mutex<VecDeque<Result<Vec<u8>, LedgerError>>>,
+	}
+
+	impl LedgerTransport for FakeTransport {
+		fn exchange(&self, command: &[u8], timeout: Duration) -> Result<Vec<u8>, LedgerError> {
+			self.commands.lock().unwrap().push((command.to_vec(), timeout));
+			self.responses.lock().unwrap().pop_front().unwrap_or_else(|| Ok(vec![0x90, 0x00]))
+		}
+	}
+
+	/// Builder that is never called by the signer.
+	struct UnusedBuilder;
+
+	impl LedgerTransactionBuilder for UnusedBuilder {
+		type TransactionHash = u64;
+
+		fn signing_payload(&self, _call: SecretStoreCall) -> Result<Vec<u8>, String> {
+			Err("not supported".into())
+		}
+
+		fn submit_signed_transaction(&self, _payload: Vec<u8>, _signature: Vec<u8>) -> Result<u64, String> {
+			Err("not supported".into())
+		}
+	}
+
+	fn signer(responses: Vec<Result<Vec<u8>, LedgerError>>) -> LedgerSigner<FakeTransport, UnusedBuilder> {
+		LedgerSigner {
+			transport: FakeTransport {
+				commands: Mutex::new(Vec::new()),
+				responses: Mutex::new(responses.into()),
+			},
+			builder: UnusedBuilder,
+			config: LedgerConfiguration {
+				app_class: 0x90,
+				derivation_path: [0x8000_002c, 0x8000_0162, 0x8000_0000, 0, 0],
+				scheme: LedgerScheme::Sr25519,
+				exchange_timeout: Duration::from_secs(1),
+				confirmation_timeout: Duration::from_secs(60),
+				confirmation_timeout_policy: ConfirmationTimeoutPolicy::Drop,
+				max_queue_size: 16,
+			},
+			queue: Mutex::new(LedgerQueue::default()),
+			queue_updated: Condvar::new(),
+		}
+	}
+
+	fn commands(signer: &LedgerSigner<FakeTransport, UnusedBuilder>) -> Vec<(Vec<u8>, Duration)> {
+		signer.transport.commands.lock().unwrap().clone()
+	}
+
+	#[test]
+	fn payload_is_sent_in_chunks() {
+		let signer = signer(vec![
+			Ok(vec![0x90, 0x00]),
+			Ok(vec![0x90, 0x00]),
+			Ok(vec![0x01, 0x02, 0x90, 0x00]),
+		]);
+		let payload = (0..CHUNK_SIZE + 10).map(|index| index as u8).collect::<Vec<_>>();
+		assert_eq!(signer.sign(&payload), Ok(vec![0x01, 0x02]));
+
+		let commands = commands(&signer);
+		assert_eq!(commands.len(), 3);
+
+		let (ref init, init_timeout) = commands[0];
+		assert_eq!(init[..5], [0x90, INS_SIGN, P1_INIT, 0x01, 20]);
+		assert_eq!(init[5..9], 0x8000_002cu32.to_le_bytes());
+		assert_eq!(init.len(), 5 + 20);
+		assert_eq!(init_timeout, Duration::from_secs(1));
+
+		let (ref add, add_timeout) = commands[1];
+		assert_eq!(add[..5], [0x90, INS_SIGN, P1_ADD, 0x01, CHUNK_SIZE as u8]);
+		assert_eq!(add[5..], payload[..CHUNK_SIZE]);
+		assert_eq!(add_timeout, Duration::from_secs(1));
+
+		let (ref last, last_timeout) = commands[2];
+		assert_eq!(last[..5], [0x90, INS_SIGN, P1_LAST, 0x01, 10]);
+		assert_eq!(last[5..], payload[CHUNK_SIZE..]);
+		assert_eq!(last_timeout, Duration::from_secs(60));
+	}
+
+	#[test]
+	fn payload_of_single_chunk_is_sent_as_last_chunk() {
+		let signer = signer(vec![Ok(vec![0x90, 0x00]), Ok(vec![0x01, 0x90, 0x00])]);
+		assert_eq!(signer.sign(&[0x42; CHUNK_SIZE]), Ok(vec![0x01]));
+
+		let commands = commands(&signer);
+		assert_eq!(commands.len(), 2);
+		assert_eq!(commands[1].0[..5], [0x90, INS_SIGN, P1_LAST, 0x01, CHUNK_SIZE as u8]);
+	}
+
+	#[test]
+	fn empty_payload_is_not_signed() {
+		let signer = signer(vec![]);
+		assert_eq!(signer.sign(&[]), Err(LedgerError::Transport("empty transaction payload".into())));
+	}
+
+	#[test]
+	fn status_word_is_parsed() {
+		let signer = signer(vec![
+			Ok(vec![0x01, 0x90, 0x00]),
+			Ok(vec![0x69, 0x86]),
+			Ok(vec![0x6a, 0x80]),
+			Err(LedgerError::Timeout),
+		]);
+		let timeout = Duration::from_secs(1);
+		assert_eq!(signer.exchange(P1_INIT, 0, &[], timeout), Ok(vec![0x01]));
+		assert_eq!(signer.exchange(P1_INIT, 0, &[], timeout), Err(LedgerError::Rejected));
+		assert_eq!(signer.exchange(P1_INIT, 0, &[], timeout), Err(LedgerError::Status(0x6a80)));
+		assert_eq!(signer.exchange(P1_INIT, 0, &[], timeout), Err(LedgerError::Timeout));
+	}
+
+	#[test]
+	fn too_short_response_is_rejected() {
+		let signer = signer(vec![Ok(vec![0x90])]);
+		assert_eq!(
+			signer.exchange(P1_INIT, 0, &[], Duration::from_secs(1)),
+			Err(LedgerError::Transport("too short device response".into())),
+		);
+	}
+
+	#[test]
+	fn too_large_data_is_not_sent() {
+		let signer = signer(vec![]);
+		assert_eq!(
+			signer.exchange(P1_INIT, 0, &[0; MAX_APDU_DATA_SIZE + 1], Duration::from_secs(1)),
+			Err(LedgerError::Transport(format!("too large APDU data: {} bytes", MAX_APDU_DATA_SIZE + 1))),
+		);
+		assert!(commands(&signer).is_empty());
+		assert!(signer.exchange(P1_INIT, 0, &[0; MAX_APDU_DATA_SIZE], Duration::from_secs(1)).is_ok());
+	}
+}
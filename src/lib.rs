@@ -15,30 +15,147 @@
 // along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::{
-	collections::{BTreeSet, VecDeque},
+	collections::{BTreeMap, BTreeSet, VecDeque},
 	ops::Range,
-	sync::Arc,
+	sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+	time::{Duration, Instant},
 };
-use futures::{FutureExt, Stream, StreamExt};
-use log::error;
+use futures::{FutureExt, Stream, StreamExt, channel::mpsc::UnboundedSender, stream::BoxStream};
+use log::{error, info, trace, warn};
 use parity_secretstore_primitives::{
 	Address, KeyServerId, Public, ServerKeyId,
 	error::Error,
 	executor::Executor,
 	key_server::KeyServer,
-	service::ServiceTasksListenerRegistrar,
+	service::{ServiceTask, ServiceTasksListenerRegistrar},
 };
 use crate::{
+	backpressure::{
+		BackpressureConfiguration, BackpressureListenerRegistrar, BlockBackpressure,
+		ListenerBackpressure, ThrottleState,
+	},
+	batch::BatchingConfiguration,
+	canary::{CanaryConfiguration, CanaryRollout},
+	budget::{BlockBudget, BudgetStatistics, DeferredWork},
+	capabilities::{ALL_TASK_KINDS, CapabilityReport, enabled_features},
+	checkpoint::{BlockCheckpoint, CheckpointedBlock, ProcessedBlock},
+	compatibility::compatibility_matrix,
+	confidential::Redactor,
+	confirmations::ConfirmationQueue,
+	constants::{SecretStoreConstants, apply_constants},
+	dedup::{CompletedRequests, ServedRequest, SubmittedResponses},
+	degraded::{ClusterHealth, DegradedModeConfiguration},
+	error::ServiceError,
+	escalation::{ErrorClass, Escalation, EscalationPolicy, ServiceState},
+	fanout::GenerationFanOut,
+	filter::KeyIdFilter,
+	health::{HealthReport, HealthReportConfiguration, HealthReporter},
+	janitor::{Janitor, JanitorConfiguration, JanitorHandler},
+	layer::{ResponseLayer, TaskLayer, apply_task_layers},
+	migration::{KeyServersSetMigration, KeyServersSetMonitor},
+	identity::AccountId32,
+	origin_stats::{OriginCounters, OriginStatistics},
+	pending::PendingRequests,
+	persistence::{GenesisBoundPersistence, Persistence},
+	pipeline::{PipelineConfiguration, PipelinedTransactionPool},
+	poison::{FailureStage, PoisonQuarantine, PoisonQuarantineConfiguration, PoisonedItem, PoisonedItemId},
+	prewarm::{PrewarmConfiguration, ScheduledRequest, ScheduledRequests},
+	queue::{QueuedResponse, QueuedResponseId, SubmissionQueue},
+	readiness::{CapacityGate, CapacityStatus, ClusterConnectivity, ReadinessGate},
+	reconcile::{ReconciliationConfiguration, ReconciliationHandler, Reconciler},
+	reorg::{ReorgConfiguration, ReorgStatistics, ReorgTracker},
+	replay::{BlockReplay, ReplayConfiguration, ReplayStatistics},
+	restart::RestartableListenerRegistrar,
+	safe_mode::{SafeMode, SafeModeConfiguration},
+	schedule::fair_order_by,
+	session_limits::{SessionLimiter, SessionLimitsConfiguration},
+	shadow::{ShadowComparator, ShadowMismatchHandler, ShadowRole},
+	shutdown::{ShutdownHandler, ShutdownReason, ShutdownReport, ShutdownReporter},
+	sla::{SlaTracker, SlaViolationHandler},
+	speculative::{BlockFinality, SpeculativeTasks},
+	summary::{BlockSummary, Informant, InformantSummary, SkipReason},
+	tenant::{TenantQuotas, Tenants},
+	throttle::{ScanThrottle, ThrottleConfiguration},
 	transaction_pool::SubstrateTransactionPool,
+	unrequired::{UnrequiredResponses, UnrequiredResponsesConfiguration},
+	verify::ArtifactsVerification,
+	watchdog::{Watchdog, WatchdogConfiguration},
+	withholding::{WithholdingConfiguration, WithholdingMonitor, WithholdingReport},
 };
+#[cfg(feature = "metrics")]
+use crate::metrics::{Metrics, TaskSource};
 
 // hide blockchain-service dependency
 pub use parity_secretstore_blockchain_service::Configuration;
 
 pub type BlockchainServiceTask = parity_secretstore_blockchain_service::BlockchainServiceTask;
 
+pub mod backpressure;
+pub mod batch;
+pub mod budget;
+pub mod builder;
+pub mod canary;
+pub mod chain;
+pub mod checkpoint;
+pub mod capabilities;
+pub mod compatibility;
+pub mod confidential;
+pub mod confirmations;
+pub mod constants;
+pub mod dedup;
+pub mod degraded;
+pub mod encrypted_persistence;
+pub mod error;
+pub mod escalation;
+pub mod failover;
+pub mod fanout;
+pub mod filter;
+#[cfg(feature = "golden-vectors")]
+pub mod golden;
+pub mod health;
+pub mod history;
+pub mod identity;
+pub mod indexer;
+pub mod janitor;
+pub mod key_rotation;
+pub mod layer;
+#[cfg(feature = "ledger")]
+pub mod ledger;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod migration;
+pub mod origin_pool;
+pub mod origin_stats;
+pub mod pending;
+pub mod persistence;
+pub mod pipeline;
+pub mod poison;
+pub mod prewarm;
+pub mod queue;
+pub mod readiness;
+pub mod reconcile;
+pub mod reorg;
+pub mod replay;
+pub mod restart;
+pub mod safe_mode;
+pub mod schedule;
+pub mod session_limits;
+pub mod shadow;
+pub mod shutdown;
+pub mod sla;
+pub mod speculative;
+pub mod summary;
+pub mod tenant;
+pub mod throttle;
+pub mod unrequired;
+pub mod verify;
+pub mod watchdog;
+pub mod withholding;
 mod transaction_pool;
 
+/// Default number of pending tasks that are read by single query.
+const DEFAULT_PENDING_RANGE_LENGTH: usize = 16;
+
 /// Substrate block id.
 pub enum BlockId<Hash> {
 	/// Use block referenced by the hash.
@@ -51,9 +168,80 @@ pub enum BlockId<Hash> {
 pub trait MaybeSecretStoreEvent {
 	/// Try convert to secret store event.
 	fn as_secret_store_event(self) -> Option<BlockchainServiceTask>;
+	/// Try convert to key server response that has been accepted by the runtime module.
+	fn as_secret_store_response(&self) -> Option<SecretStoreResponse> {
+		None
+	}
+	/// Try convert to event that is emitted by the SecretStore runtime module, but is
+	/// not understood by this crate (e.g. runtime-specific extension).
+	fn as_unknown_secret_store_event(&self) -> Option<RawSecretStoreEvent> {
+		None
+	}
+	/// Try convert to announcement of request that will be generated at given block.
+	fn as_scheduled_request(&self) -> Option<ScheduledRequest> {
+		None
+	}
+	/// Try convert to signal that request has already been completed (e.g. when quorum
+	/// of key servers has responded, or when peer key server has re-published response
+	/// to completed request).
+	fn as_completed_request(&self) -> Option<ServedRequest> {
+		None
+	}
+}
+
+/// Raw event of the SecretStore runtime module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawSecretStoreEvent {
+	/// Index of the module in the runtime.
+	pub module_index: u8,
+	/// Index of the event in the module.
+	pub event_index: u8,
+	/// Name of the event, if known from metadata.
+	pub name: Option<String>,
+	/// Encoded event data.
+	pub data: Vec<u8>,
+}
+
+/// Called when unknown SecretStore runtime module event is found in the block.
+pub type UnknownEventHandler = Arc<dyn Fn(&RawSecretStoreEvent) + Send + Sync>;
+
+/// On-chain origin of the task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskOriginBlock<Hash> {
+	/// Hash of the block where task has been requested.
+	pub block_hash: Hash,
+	/// Number of the block, if known.
+	pub block_number: Option<u64>,
+	/// Index of the task event in the block events.
+	pub event_index: usize,
+}
+
+/// Kind of Secret Store task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TaskKind {
+	/// Server key generation.
+	ServerKeyGeneration,
+	/// Server key retrieval.
+	ServerKeyRetrieval,
+	/// Document key store.
+	DocumentKeyStore,
+	/// Document key shadow retrieval.
+	DocumentKeyShadowRetrieval,
+}
+
+/// Key server response that has been accepted by the runtime module.
+#[derive(Debug, Clone)]
+pub struct SecretStoreResponse {
+	/// Origin of the request that has been responded.
+	pub origin: Address,
+	/// Key server that has submitted the response.
+	pub key_server: KeyServerId,
+	/// The response itself.
+	pub call: SecretStoreCall,
 }
 
 /// Substrate Secret Store module calls.
+#[derive(Debug, Clone, PartialEq)]
 pub enum SecretStoreCall {
 	/// Called when server key is generated.
 	ServerKeyGenerated(ServerKeyId, Public),
@@ -75,10 +263,106 @@ pub enum SecretStoreCall {
 	DocumentKeyShadowRetrievalError(ServerKeyId, Address),
 }
 
+/// Returns origin of the blockchain service task.
+pub fn task_origin(task: &BlockchainServiceTask) -> Address {
+	match *task {
+		BlockchainServiceTask::Regular(origin, _)
+			| BlockchainServiceTask::RetrieveShadowDocumentKeyCommon(origin, _, _)
+			| BlockchainServiceTask::RetrieveShadowDocumentKeyPersonal(origin, _, _) => origin,
+	}
+}
+
+/// Returns kind and key id of the blockchain service task.
+pub fn task_kind_and_key_id(task: &BlockchainServiceTask) -> Option<(TaskKind, ServerKeyId)> {
+	match *task {
+		BlockchainServiceTask::Regular(_, ServiceTask::GenerateServerKey(key_id, ..)) =>
+			Some((TaskKind::ServerKeyGeneration, key_id)),
+		BlockchainServiceTask::Regular(_, ServiceTask::RetrieveServerKey(key_id, ..)) =>
+			Some((TaskKind::ServerKeyRetrieval, key_id)),
+		BlockchainServiceTask::Regular(_, ServiceTask::StoreDocumentKey(key_id, ..)) =>
+			Some((TaskKind::DocumentKeyStore, key_id)),
+		BlockchainServiceTask::RetrieveShadowDocumentKeyCommon(_, key_id, _)
+			| BlockchainServiceTask::RetrieveShadowDocumentKeyPersonal(_, key_id, _) =>
+			Some((TaskKind::DocumentKeyShadowRetrieval, key_id)),
+		_ => None,
+	}
+}
+
+impl SecretStoreCall {
+	/// Returns kind of the task this call is responding to.
+	pub fn task_kind(&self) -> TaskKind {
+		match *self {
+			SecretStoreCall::ServerKeyGenerated(..)
+				| SecretStoreCall::ServerKeyGenerationError(..) => TaskKind::ServerKeyGeneration,
+			SecretStoreCall::ServerKeyRetrieved(..)
+				| SecretStoreCall::ServerKeyRetrievalError(..) => TaskKind::ServerKeyRetrieval,
+			SecretStoreCall::DocumentKeyStored(..)
+				| SecretStoreCall::DocumentKeyStoreError(..) => TaskKind::DocumentKeyStore,
+			SecretStoreCall::DocumentKeyCommonRetrieved(..)
+				| SecretStoreCall::DocumentKeyPersonalRetrieved(..)
+				| SecretStoreCall::DocumentKeyShadowRetrievalError(..) => TaskKind::DocumentKeyShadowRetrieval,
+		}
+	}
+
+	/// Returns id of the key this call is responding to.
+	pub fn key_id(&self) -> ServerKeyId {
+		match *self {
+			SecretStoreCall::ServerKeyGenerated(key_id, ..)
+				| SecretStoreCall::ServerKeyGenerationError(key_id)
+				| SecretStoreCall::ServerKeyRetrieved(key_id, ..)
+				| SecretStoreCall::ServerKeyRetrievalError(key_id)
+				| SecretStoreCall::DocumentKeyStored(key_id)
+				| SecretStoreCall::DocumentKeyStoreError(key_id)
+				| SecretStoreCall::DocumentKeyCommonRetrieved(key_id, ..)
+				| SecretStoreCall::DocumentKeyPersonalRetrieved(key_id, ..)
+				| SecretStoreCall::DocumentKeyShadowRetrievalError(key_id, ..) => key_id,
+		}
+	}
+
+	/// Returns default submission priority of this call. Error responses outrank
+	/// routine key publications, because requesters are waiting for them too.
+	pub fn priority(&self) -> SubmissionPriority {
+		match self.is_error() {
+			true => SubmissionPriority::High,
+			false => SubmissionPriority::Normal,
+		}
+	}
+
+	/// Returns true if this call is reporting an error.
+	pub fn is_error(&self) -> bool {
+		matches!(
+			*self,
+			SecretStoreCall::ServerKeyGenerationError(..)
+				| SecretStoreCall::ServerKeyRetrievalError(..)
+				| SecretStoreCall::DocumentKeyStoreError(..)
+				| SecretStoreCall::DocumentKeyShadowRetrievalError(..)
+		)
+	}
+
+	/// Returns error response to the same request.
+	pub fn to_error(&self) -> SecretStoreCall {
+		match *self {
+			SecretStoreCall::ServerKeyGenerated(key_id, ..)
+				| SecretStoreCall::ServerKeyGenerationError(key_id) =>
+				SecretStoreCall::ServerKeyGenerationError(key_id),
+			SecretStoreCall::ServerKeyRetrieved(key_id, ..)
+				| SecretStoreCall::ServerKeyRetrievalError(key_id) =>
+				SecretStoreCall::ServerKeyRetrievalError(key_id),
+			SecretStoreCall::DocumentKeyStored(key_id)
+				| SecretStoreCall::DocumentKeyStoreError(key_id) =>
+				SecretStoreCall::DocumentKeyStoreError(key_id),
+			SecretStoreCall::DocumentKeyCommonRetrieved(key_id, requester, ..)
+				| SecretStoreCall::DocumentKeyPersonalRetrieved(key_id, requester, ..)
+				| SecretStoreCall::DocumentKeyShadowRetrievalError(key_id, requester) =>
+				SecretStoreCall::DocumentKeyShadowRetrievalError(key_id, requester),
+		}
+	}
+}
+
 /// Substrate blockchain.
 pub trait Blockchain: 'static + Send + Sync {
 	/// Block hash type.
-	type BlockHash: Clone + Send + Sync;
+	type BlockHash: Clone + PartialEq + Send + Sync;
 	/// Blockchain event type.
 	type Event: MaybeSecretStoreEvent;
 	/// Block events iterator type.
@@ -88,6 +372,50 @@ pub trait Blockchain: 'static + Send + Sync {
 
 	/// Get block events.
 	fn block_events(&self, block_hash: Self::BlockHash) -> Self::BlockEvents;
+	/// Get hash of the canonical block with given number.
+	fn block_hash(&self, _block_number: u64) -> Result<Option<Self::BlockHash>, ServiceError> {
+		Err(ServiceError::permanent("block hashes are not supported by the blockchain"))
+	}
+	/// Encode block hash. Encoded hashes are stored in the processed blocks checkpoint
+	/// and used to bind persisted state to the chain. If `None`, retracted checkpointed
+	/// blocks can't be detected and persisted state isn't bound to the chain.
+	fn encode_block_hash(&self, _block_hash: &Self::BlockHash) -> Option<Vec<u8>> {
+		None
+	}
+	/// Returns false if block has no events of the SecretStore runtime module. Used to
+	/// skip empty blocks cheaply. Blockchains that can't answer this cheaply (e.g. without
+	/// reading all block events) should keep the default implementation.
+	fn has_secret_store_activity(&self, _block_hash: Self::BlockHash) -> bool {
+		true
+	}
+	/// Called when new task is forwarded to the key server. Could be used to link key
+	/// server sessions with on-chain events that have triggered them.
+	fn on_task_forwarded(&self, _task: &BlockchainServiceTask, _origin: &TaskOriginBlock<Self::BlockHash>) {}
+	/// Get number of the block.
+	fn block_number(&self, _block_hash: Self::BlockHash) -> Result<u64, ServiceError> {
+		Err(ServiceError::permanent("block numbers are not supported by the blockchain"))
+	}
+	/// Get finality status of the block. Only used if speculative processing is enabled.
+	fn block_finality(&self, _block_hash: Self::BlockHash) -> Result<BlockFinality, ServiceError> {
+		Ok(BlockFinality::Finalized)
+	}
+	/// Get constants of the SecretStore runtime module.
+	fn secret_store_constants(&self) -> Result<SecretStoreConstants, ServiceError> {
+		Err(ServiceError::permanent("runtime module constants are not supported by the blockchain"))
+	}
+	/// Get version of the Secret Store runtime interface at the best block, if known.
+	fn runtime_interface_version(&self) -> Option<u32> {
+		None
+	}
+	/// Get number of pending tasks of given kind at given block, if it is known. Pending
+	/// tasks scans use it to avoid reading past the last pending task.
+	fn pending_tasks_count(
+		&self,
+		_block_hash: &Self::BlockHash,
+		_task_kind: TaskKind,
+	) -> Result<Option<usize>, ServiceError> {
+		Ok(None)
+	}
 	/// Get current key servers set. This should return current key servers set at the best
 	/// known (finalized) block. That's because we use this to determine key server which
 	/// will should start corresponding session AND the session starts at the time when
@@ -97,55 +425,186 @@ pub trait Blockchain: 'static + Send + Sync {
 	/// Get pending server key generation tasks range at given block.
 	fn server_key_generation_tasks(
 		&self,
-		block_hash: Self::BlockHash,
+		block_hash: &Self::BlockHash,
 		range: Range<usize>,
-	) -> Result<Self::PendingEvents, String>;
+	) -> Result<Self::PendingEvents, ServiceError>;
 	/// Is server key generation request response required?
 	fn is_server_key_generation_response_required(
 		&self,
 		key_id: ServerKeyId,
 		key_server_id: KeyServerId,
-	) -> Result<bool, String>;
+	) -> Result<bool, ServiceError>;
+	/// Has key server already responded to server key generation request? Unlike
+	/// `is_server_key_generation_response_required`, this doesn't depend on whether the
+	/// request is still open.
+	fn has_server_key_generation_response(
+		&self,
+		_key_id: ServerKeyId,
+		_key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		Err(ServiceError::permanent("responses queries are not supported by the blockchain"))
+	}
 
 	/// Get pending server key retrieval tasks range at given block.
 	fn server_key_retrieval_tasks(
 		&self,
-		block_hash: Self::BlockHash,
+		block_hash: &Self::BlockHash,
 		range: Range<usize>,
-	) -> Result<Self::PendingEvents, String>;
+	) -> Result<Self::PendingEvents, ServiceError>;
 	/// Is server key retrieval request response required?
 	fn is_server_key_retrieval_response_required(
 		&self,
 		key_id: ServerKeyId,
 		key_server_id: KeyServerId,
-	) -> Result<bool, String>;
+	) -> Result<bool, ServiceError>;
+	/// Has key server already responded to server key retrieval request? Unlike
+	/// `is_server_key_retrieval_response_required`, this doesn't depend on whether the
+	/// request is still open.
+	fn has_server_key_retrieval_response(
+		&self,
+		_key_id: ServerKeyId,
+		_key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		Err(ServiceError::permanent("responses queries are not supported by the blockchain"))
+	}
 
 	/// Get pending document key store tasks range at given block.
 	fn document_key_store_tasks(
 		&self,
-		block_hash: Self::BlockHash,
+		block_hash: &Self::BlockHash,
 		range: Range<usize>,
-	) -> Result<Self::PendingEvents, String>;
+	) -> Result<Self::PendingEvents, ServiceError>;
 	/// Is document key store request response required?
 	fn is_document_key_store_response_required(
 		&self,
 		key_id: ServerKeyId,
 		key_server_id: KeyServerId,
-	) -> Result<bool, String>;
+	) -> Result<bool, ServiceError>;
+	/// Has key server already responded to document key store request? Unlike
+	/// `is_document_key_store_response_required`, this doesn't depend on whether the
+	/// request is still open.
+	fn has_document_key_store_response(
+		&self,
+		_key_id: ServerKeyId,
+		_key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		Err(ServiceError::permanent("responses queries are not supported by the blockchain"))
+	}
 
 	/// Get pending document key shadow retrieval tasks range at given block.
 	fn document_key_shadow_retrieval_tasks(
 		&self,
-		block_hash: Self::BlockHash,
+		block_hash: &Self::BlockHash,
 		range: Range<usize>,
-	) -> Result<Self::PendingEvents, String>;
+	) -> Result<Self::PendingEvents, ServiceError>;
 	/// Is document key shadow retrieval request response required?
 	fn is_document_key_shadow_retrieval_response_required(
 		&self,
 		key_id: ServerKeyId,
 		requester: Address,
 		key_server_id: KeyServerId,
-	) -> Result<bool, String>;
+	) -> Result<bool, ServiceError>;
+	/// Has key server already responded to document key shadow retrieval request? Unlike
+	/// `is_document_key_shadow_retrieval_response_required`, this doesn't depend on whether
+	/// the request is still open.
+	fn has_document_key_shadow_retrieval_response(
+		&self,
+		_key_id: ServerKeyId,
+		_requester: Address,
+		_key_server_id: KeyServerId,
+	) -> Result<bool, ServiceError> {
+		Err(ServiceError::permanent("responses queries are not supported by the blockchain"))
+	}
+}
+
+/// Priority of the submitted transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SubmissionPriority {
+	/// Transactions that may wait.
+	Low,
+	/// Routine transactions (e.g. key publications).
+	Normal,
+	/// Transactions that should outrank routine transactions (e.g. error responses).
+	High,
+}
+
+/// Order in which deferred responses (held until origin block is finalized or buffered
+/// while key server cluster is unavailable) are submitted when they are released together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseOrdering {
+	/// Responses are submitted in order they have been deferred.
+	Fifo,
+	/// Error responses are submitted before success responses.
+	ErrorsFirst,
+	/// Success responses are submitted before error responses.
+	SuccessesFirst,
+	/// Responses are grouped by key id.
+	ByKeyId,
+}
+
+/// Transaction submission error.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubmitError {
+	/// Transaction has been rejected for temporary reason (pool is full, priority is
+	/// too low, ...). Submission may be retried later.
+	Retryable(String),
+	/// Transaction nonce has been rejected. Submission may be retried right away - pool
+	/// must reassign the nonce.
+	InvalidNonce(String),
+	/// Transaction is invalid. Retrying won't help.
+	Invalid(String),
+}
+
+impl SubmitError {
+	/// Returns true if submission may ever succeed if retried.
+	pub fn is_retryable(&self) -> bool {
+		match *self {
+			SubmitError::Retryable(_) | SubmitError::InvalidNonce(_) => true,
+			SubmitError::Invalid(_) => false,
+		}
+	}
+}
+
+impl std::fmt::Display for SubmitError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match *self {
+			SubmitError::Retryable(ref error) => write!(f, "{}", error),
+			SubmitError::InvalidNonce(ref error) => write!(f, "invalid nonce: {}", error),
+			SubmitError::Invalid(ref error) => write!(f, "invalid transaction: {}", error),
+		}
+	}
+}
+
+/// Transaction that has been accepted by the pool, but has been dropped before it has
+/// reached the chain (e.g. because it hasn't been signed in time).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DroppedTransaction {
+	/// The call.
+	pub call: SecretStoreCall,
+	/// Why transaction has been dropped.
+	pub error: String,
+}
+
+/// Submit transaction to the pool. If nonce is rejected, submission is retried once
+/// (pool is expected to reassign the nonce).
+fn submit_call<TP: TransactionPool + ?Sized>(
+	transaction_pool: &TP,
+	origin: Option<&Address>,
+	submitter: Option<&AccountId32>,
+	call: SecretStoreCall,
+) -> Result<TP::TransactionHash, SubmitError> {
+	let priority = call.priority();
+	match transaction_pool.submit_transaction_for_origin(origin, submitter, call.clone(), priority) {
+		Err(SubmitError::InvalidNonce(error)) => {
+			trace!(
+				target: "secretstore",
+				"Transaction nonce has been rejected: {}. Retrying",
+				error,
+			);
+			transaction_pool.submit_transaction_for_origin(origin, submitter, call, priority)
+		},
+		result => result,
+	}
 }
 
 /// Transaction pool API.
@@ -154,20 +613,735 @@ pub trait TransactionPool: Send + Sync + 'static {
 	type TransactionHash: std::fmt::Display;
 
 	/// Submit transaction to the pool.
-	fn submit_transaction(&self, call: SecretStoreCall) -> Result<Self::TransactionHash, String>;
+	fn submit_transaction(&self, call: SecretStoreCall) -> Result<Self::TransactionHash, SubmitError>;
+	/// Submit transaction to the pool, signed by given account. If account is `None`,
+	/// default account is used. Pools that support multiple accounts must override this.
+	fn submit_transaction_from(
+		&self,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		match submitter {
+			Some(_) => Err(SubmitError::Invalid(
+				"submitting transactions from non-default account is not supported".into(),
+			)),
+			None => self.submit_transaction(call),
+		}
+	}
+	/// Submit transaction to the pool, signed by given account, with given priority.
+	/// Pools may map priority to tip levels or pool priorities. By default, priority
+	/// is ignored.
+	fn submit_transaction_with_priority(
+		&self,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+		_priority: SubmissionPriority,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		self.submit_transaction_from(submitter, call)
+	}
+	/// Submit response to the request of given origin. Origin is `None` for externally
+	/// produced calls. Pools that submit responses to different origins differently
+	/// (e.g. using different pallets) must override this. By default, origin is ignored.
+	fn submit_transaction_for_origin(
+		&self,
+		_origin: Option<&Address>,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+		priority: SubmissionPriority,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		self.submit_transaction_with_priority(submitter, call, priority)
+	}
+	/// Submit several responses to requests of given origin in single transaction (e.g.
+	/// using `utility.batch` call). Only called if responses batching is enabled. If it
+	/// fails, responses are submitted one by one.
+	fn submit_batch_for_origin(
+		&self,
+		_origin: Option<&Address>,
+		_submitter: Option<&AccountId32>,
+		_calls: Vec<SecretStoreCall>,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		Err(SubmitError::Invalid("batches are not supported by the transaction pool".into()))
+	}
+	/// Returns true if responses to different origins are submitted differently (see
+	/// `submit_transaction_for_origin`). Server key generations are only shared by
+	/// origins if this returns true.
+	fn routes_by_origin(&self) -> bool {
+		false
+	}
+	/// Get default account that submits transactions, if known.
+	fn submitter_account(&self) -> Option<AccountId32> {
+		None
+	}
+	/// Submit service health report to the pool. Only called if health reports are enabled.
+	fn submit_health_report(&self, _report: &HealthReport) -> Result<Self::TransactionHash, SubmitError> {
+		Err(SubmitError::Invalid("health reports are not supported by the transaction pool".into()))
+	}
+	/// Take transactions that have been dropped since last call. Pools that are submitting
+	/// transactions asynchronously (i.e. when submission has succeeded, transaction could
+	/// still fail) must override this. Called on every new block. Dropped responses are
+	/// reported to the poison quarantine and resubmitted by the reconciler.
+	fn take_dropped_transactions(&self) -> Vec<DroppedTransaction> {
+		Vec::new()
+	}
+}
+
+/// Secondary publication target (e.g. Ethereum service contract), where responses
+/// are mirrored after they're submitted to the Substrate chain.
+pub trait SecondaryPublisher: Send + Sync + 'static {
+	/// Publish response that has been submitted to the Substrate chain.
+	fn publish(&self, call: SecretStoreCall) -> Result<String, String>;
+}
+
+/// Named configuration preset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigurationPreset {
+	/// Local development chain: react to everything as fast as possible. Responses are
+	/// neither batched nor reconciled.
+	Dev,
+	/// Staging parachain: moderate read load. Blocks are processed speculatively,
+	/// because parachain blocks are finalized with a delay.
+	StagingParachain,
+	/// Production chain: minimize load on the node. Tasks that create keys are only
+	/// started from blocks that are deep enough.
+	Production,
+}
+
+/// Substrate-specific service configuration.
+#[derive(Clone)]
+pub struct ServiceConfiguration {
+	/// Pending tasks are only read from every `pending_scan_interval` block.
+	pub pending_scan_interval: u32,
+	/// Confidential logging salt. When set, all identifying data (key ids, requesters)
+	/// is replaced with salted hashes before it leaves the service.
+	pub confidential_logging_salt: Option<Vec<u8>>,
+	/// Secondary publication target. If set, every response that is submitted to the
+	/// Substrate chain is also published there.
+	pub secondary_publisher: Option<Arc<dyn SecondaryPublisher>>,
+	/// Target latency (in blocks) of requests, by task kind. Requests of kinds that
+	/// are missing from this map are not tracked.
+	pub sla_targets: BTreeMap<TaskKind, u64>,
+	/// Called when request is not answered within target latency.
+	pub sla_violation_handler: Option<SlaViolationHandler>,
+	/// Adaptive pending scans throttling. If `None`, scans are never throttled.
+	pub pending_scan_throttle: Option<ThrottleConfiguration>,
+	/// Max number of tasks dispatched by single pending tasks scan. Remaining tasks
+	/// are dispatched by next scans. If `None`, number of tasks isn't limited.
+	pub pending_scan_max_items: Option<usize>,
+	/// Number of pending tasks read by single query. Larger pages mean fewer queries,
+	/// but every query is heavier. Pending scans throttling may reduce the page size.
+	pub pending_page_size: usize,
+	/// Key id namespaces served by this key server. Tasks from other namespaces
+	/// are ignored.
+	pub key_id_filter: KeyIdFilter,
+	/// Per-origin serving policies.
+	pub tenants: Tenants,
+	/// Called when responses of primary and shadow (candidate) key servers differ.
+	pub shadow_mismatch_handler: Option<ShadowMismatchHandler>,
+	/// Persistent record of submitted responses. If set, requests that have been
+	/// answered recently (even before restart) are not executed again.
+	pub submitted_responses: Option<Arc<SubmittedResponses>>,
+	/// Persistence of the last processed block. If set (and `replay` is configured),
+	/// blocks that have been imported while service has been down are replayed on
+	/// restart.
+	pub checkpoint: Option<Arc<dyn Persistence>>,
+	/// Called (once) when service is stopped or terminated because of error.
+	pub shutdown_handler: Option<ShutdownHandler>,
+	/// What to do with computed responses to requests that have been already answered
+	/// by other key servers. By default, such responses are dropped.
+	pub unrequired_responses: UnrequiredResponsesConfiguration,
+	/// Starts key shares migration when on-chain key servers set changes. If `None`,
+	/// key servers set changes are ignored.
+	pub key_servers_set_migration: Option<Arc<dyn KeyServersSetMigration>>,
+	/// Quarantine of tasks that crash the service repeatedly. If `None`, crashes
+	/// are not tracked.
+	pub safe_mode: Option<SafeModeConfiguration>,
+	/// Quarantine of tasks that fail repeatedly. If `None`, failing tasks are retried
+	/// forever.
+	pub poison_quarantine: Option<PoisonQuarantineConfiguration>,
+	/// Limits of concurrently running key server sessions. If `None`, all tasks are
+	/// dispatched immediately.
+	pub session_limits: Option<SessionLimitsConfiguration>,
+	/// Periodic reconciliation of submitted responses with chain state. If `None`,
+	/// responses are never resubmitted.
+	pub reconciliation: Option<ReconciliationConfiguration>,
+	/// Called when reconciliation round is completed.
+	pub reconciliation_handler: Option<ReconciliationHandler>,
+	/// Garbage collection of internal caches and queues. If `None`, garbage is
+	/// never collected.
+	pub janitor: Option<JanitorConfiguration>,
+	/// Called when garbage collection round is completed.
+	pub janitor_handler: Option<JanitorHandler>,
+	/// Set this if new blocks stream is yielding best (not yet finalized) blocks. Sessions
+	/// for requests from new blocks are started immediately, but responses are held
+	/// until the block is finalized (see `Blockchain::block_finality`).
+	pub speculative_processing: bool,
+	/// Called when SecretStore runtime module event that is not understood by this crate
+	/// is found in the block. If `None`, such events are dropped.
+	pub unknown_event_handler: Option<UnknownEventHandler>,
+	/// Degraded mode configuration. If `None`, error responses are published
+	/// immediately, even if key server cluster is unavailable.
+	pub degraded_mode: Option<DegradedModeConfiguration>,
+	/// Key server cluster readiness probe. If set, blocks are not processed until
+	/// the cluster is ready.
+	pub readiness_probe: Option<Arc<dyn ClusterHealth>>,
+	/// Key server cluster connectivity probe. If set, server key generation tasks
+	/// that require more key servers than currently reachable are deferred.
+	pub connectivity_probe: Option<Arc<dyn ClusterConnectivity>>,
+	/// Sessions pre-warming for scheduled requests. If `None`, announcements of
+	/// scheduled requests are ignored.
+	pub prewarm: Option<PrewarmConfiguration>,
+	/// Max number of origins that have their own statistics. Tasks and responses of
+	/// other origins are counted together.
+	pub max_origins_in_statistics: usize,
+	/// Responses submission pipeline. If `None`, responses are submitted by threads
+	/// that are completing sessions.
+	pub pipeline: Option<PipelineConfiguration>,
+	/// Order in which deferred responses are submitted when they are released together.
+	pub response_ordering: ResponseOrdering,
+	/// Responses batching. If `None`, every response is submitted in its own transaction.
+	pub response_batching: Option<BatchingConfiguration>,
+	/// Canary rollout. If set, tasks are routed to the stable or canary key server
+	/// by key id. Must not be used together with custom task router.
+	pub canary: Option<CanaryConfiguration>,
+	/// Verification of session artifacts before publication. If `None`, artifacts
+	/// are published as is.
+	pub artifacts_verification: Option<ArtifactsVerification>,
+	/// Label of the service instance. Must be set (and be unique) when several
+	/// instances are running in the same process, so that their statistics, logs and
+	/// threads could be told apart.
+	pub instance_label: Option<String>,
+	/// What to do when errors of different classes happen. By default, all errors are
+	/// logged and blocks processing continues.
+	pub escalation_policy: EscalationPolicy,
+	/// Custom layers that are applied (in order) to tasks that have passed built-in filters.
+	pub task_layers: Vec<Arc<dyn TaskLayer>>,
+	/// Custom layers that are applied (in order) to responses before publication.
+	pub response_layers: Vec<Arc<dyn ResponseLayer>>,
+	/// Replay of blocks that have been missed by the new blocks stream (e.g. because
+	/// connection to the node has been lost). If `None`, missed blocks are ignored.
+	pub replay: Option<ReplayConfiguration>,
+	/// Reorgs tracking. When recent block is retracted, new canonical blocks are
+	/// replayed and pending tasks are scanned. If `None`, reorgs are not tracked. Use
+	/// `confirmation_depths` to only start tasks from blocks that are deep enough.
+	pub reorg_tracking: Option<ReorgConfiguration>,
+	/// Number of blocks that must be built on top of the block where task has been seen
+	/// before the task is started, by task kind. Tasks of kinds that are missing from
+	/// this map are started immediately.
+	pub confirmation_depths: BTreeMap<TaskKind, u64>,
+	/// Watchdog of key server sessions. If `None`, sessions are never aborted.
+	pub watchdog: Option<WatchdogConfiguration>,
+	/// Periodic on-chain health reports. If `None`, health is never reported.
+	pub health_reports: Option<HealthReportConfiguration>,
+	/// Max time spent processing single block by every key server. When it is exceeded,
+	/// remaining new tasks are deferred to the next block and pending tasks scan is
+	/// interrupted. If `None`, processing time isn't limited.
+	pub block_processing_budget: Option<Duration>,
+	/// Backpressure of slow key server listeners. If `None`, tasks are always
+	/// dispatched to key servers.
+	pub listener_backpressure: Option<BackpressureConfiguration>,
+	/// Detection of key servers that are withholding responses. If `None`, responses
+	/// of other key servers are not tracked.
+	pub withholding_detection: Option<WithholdingConfiguration>,
+}
+
+impl ConfigurationPreset {
+	/// Apply preset to the configuration.
+	pub fn apply(&self, config: &mut ServiceConfiguration) {
+		match *self {
+			ConfigurationPreset::Dev => {
+				config.pending_scan_interval = 1;
+				config.pending_scan_throttle = None;
+				config.reconciliation = None;
+				config.speculative_processing = false;
+				config.confirmation_depths = BTreeMap::new();
+				config.response_batching = None;
+			},
+			ConfigurationPreset::StagingParachain => {
+				config.pending_scan_interval = 4;
+				config.pending_scan_throttle = Some(ThrottleConfiguration::default());
+				config.reconciliation = Some(ReconciliationConfiguration::default());
+				config.speculative_processing = true;
+				config.confirmation_depths = BTreeMap::new();
+				config.response_batching = Some(BatchingConfiguration::default());
+			},
+			ConfigurationPreset::Production => {
+				config.pending_scan_interval = 10;
+				config.pending_scan_throttle = Some(ThrottleConfiguration::default());
+				config.reconciliation = Some(ReconciliationConfiguration {
+					interval: 20,
+					confirmation_timeout: 40,
+					..Default::default()
+				});
+				config.speculative_processing = false;
+				config.confirmation_depths = vec![
+					(TaskKind::ServerKeyGeneration, 2),
+					(TaskKind::DocumentKeyStore, 2),
+				].into_iter().collect();
+				config.response_batching = Some(BatchingConfiguration {
+					max_batch_size: 32,
+				});
+			},
+		}
+	}
+}
+
+impl std::str::FromStr for ConfigurationPreset {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"dev" => Ok(ConfigurationPreset::Dev),
+			"staging-parachain" => Ok(ConfigurationPreset::StagingParachain),
+			"production" => Ok(ConfigurationPreset::Production),
+			_ => Err(format!("unknown configuration preset: {}", s)),
+		}
+	}
+}
+
+impl ServiceConfiguration {
+	/// Create configuration from named preset.
+	pub fn with_preset(preset: ConfigurationPreset) -> Self {
+		let mut config = ServiceConfiguration::default();
+		preset.apply(&mut config);
+		config
+	}
+}
+
+impl Default for ServiceConfiguration {
+	fn default() -> Self {
+		ServiceConfiguration {
+			pending_scan_interval: 1,
+			confidential_logging_salt: None,
+			secondary_publisher: None,
+			sla_targets: BTreeMap::new(),
+			sla_violation_handler: None,
+			pending_scan_throttle: None,
+			pending_scan_max_items: None,
+			pending_page_size: DEFAULT_PENDING_RANGE_LENGTH,
+			key_id_filter: KeyIdFilter::default(),
+			tenants: Tenants::default(),
+			shadow_mismatch_handler: None,
+			submitted_responses: None,
+			checkpoint: None,
+			shutdown_handler: None,
+			unrequired_responses: UnrequiredResponsesConfiguration::default(),
+			key_servers_set_migration: None,
+			safe_mode: None,
+			poison_quarantine: None,
+			session_limits: None,
+			reconciliation: None,
+			reconciliation_handler: None,
+			janitor: None,
+			janitor_handler: None,
+			speculative_processing: false,
+			unknown_event_handler: None,
+			degraded_mode: None,
+			readiness_probe: None,
+			connectivity_probe: None,
+			prewarm: None,
+			max_origins_in_statistics: 16,
+			pipeline: None,
+			response_ordering: ResponseOrdering::Fifo,
+			response_batching: None,
+			canary: None,
+			artifacts_verification: None,
+			instance_label: None,
+			escalation_policy: EscalationPolicy::default(),
+			task_layers: Vec::new(),
+			response_layers: Vec::new(),
+			replay: None,
+			reorg_tracking: None,
+			confirmation_depths: BTreeMap::new(),
+			watchdog: None,
+			health_reports: None,
+			block_processing_budget: None,
+			listener_backpressure: None,
+			withholding_detection: None,
+		}
+	}
+}
+
+/// Key server that executes routed tasks.
+pub struct KeyServerRoute<KS> {
+	/// Key server.
+	pub key_server: Arc<KS>,
+	/// Service tasks listener registrar of the key server.
+	pub listener_registrar: Arc<dyn ServiceTasksListenerRegistrar>,
+	/// Blockchain service configuration of the key server.
+	pub config: Configuration,
+}
+
+/// Handle of the running service.
+#[derive(Clone)]
+pub struct ServiceHandle {
+	/// Capabilities of the service.
+	capabilities: Arc<CapabilityReport>,
+	/// Externally produced calls sender.
+	external_calls: UnboundedSender<SecretStoreCall>,
+	/// Per-origin statistics.
+	origin_statistics: Arc<OriginStatistics>,
+	/// Number of requests that are pending on chain.
+	pending_requests: Arc<PendingRequests>,
+	/// Service restart requests sender.
+	restart: UnboundedSender<()>,
+	/// Errors escalation.
+	escalation: Arc<Escalation>,
+	/// Missed blocks replay.
+	block_replay: Arc<BlockReplay>,
+	/// Responses that are queued, but not yet submitted.
+	submission_queue: Arc<SubmissionQueue>,
+	/// Backpressure of key server listeners.
+	backpressure: Arc<ListenerBackpressure>,
+	/// Responses withholding monitor.
+	withholding: Arc<WithholdingMonitor>,
+	/// Key server cluster capacity gate.
+	capacity: Arc<CapacityGate>,
+	/// Quarantine of tasks that fail repeatedly.
+	poison: Arc<PoisonQuarantine>,
+	/// Tasks that have crashed the service.
+	safe_mode: Arc<SafeMode>,
+	/// Last block summaries for the node informant line.
+	informant: Arc<Informant>,
+	/// Reorgs tracking statistics.
+	reorg_statistics: Arc<dyn Fn() -> ReorgStatistics + Send + Sync>,
+	/// Block processing budget statistics.
+	budget_statistics: Arc<dyn Fn() -> BudgetStatistics + Send + Sync>,
+	/// Number of tasks that are queued by the sessions limiter.
+	queued_tasks: Arc<dyn Fn() -> usize + Send + Sync>,
+	/// Graceful service shutdown.
+	shutdown: Arc<dyn Fn() -> ShutdownReport + Send + Sync>,
+	/// Tasks processing and transactions submission metrics.
+	#[cfg(feature = "metrics")]
+	metrics: Arc<Metrics>,
+}
+
+impl ServiceHandle {
+	/// Returns capabilities of the service.
+	pub fn capabilities(&self) -> &CapabilityReport {
+		&self.capabilities
+	}
+
+	/// Returns sink for externally produced calls. Calls are submitted using the same
+	/// transaction pool (and reconciled the same way) as responses of key servers.
+	pub fn calls_sink(&self) -> UnboundedSender<SecretStoreCall> {
+		self.external_calls.clone()
+	}
+
+	/// Returns label of the service instance. Statistics of the instance must be
+	/// exported under this label.
+	pub fn instance_label(&self) -> Option<&str> {
+		self.capabilities.instance_label.as_deref()
+	}
+
+	/// Returns tasks and responses counters by origin label.
+	pub fn origin_statistics(&self) -> BTreeMap<String, OriginCounters> {
+		self.origin_statistics.snapshot()
+	}
+
+	/// Returns number of requests that are pending on chain, by task kind, as seen by
+	/// the last pending tasks scan.
+	pub fn pending_requests(&self) -> BTreeMap<TaskKind, usize> {
+		self.pending_requests.snapshot()
+	}
+
+	/// Returns responses that are queued, but not yet submitted.
+	pub fn queued_responses(&self) -> BTreeMap<QueuedResponseId, QueuedResponse> {
+		self.submission_queue.snapshot()
+	}
+
+	/// Returns throttle state of every key server listener. `None` key is the shadow
+	/// key server.
+	pub fn listener_throttle_states(&self) -> BTreeMap<Option<KeyServerHandle>, ThrottleState> {
+		self.backpressure.snapshot()
+	}
+
+	/// Returns capacity of the key server cluster. Server key generation tasks are
+	/// deferred while capacity is degraded.
+	pub fn capacity_status(&self) -> CapacityStatus {
+		self.capacity.status()
+	}
+
+	/// Returns responses statistics of key servers, including flagged ones.
+	pub fn withholding_reports(&self) -> Vec<WithholdingReport> {
+		self.withholding.reports()
+	}
+
+	/// Drop queued response, so that it is never submitted. Request stays pending on
+	/// chain, so it could be served again later.
+	pub fn drop_queued_response(&self, id: QueuedResponseId) -> Result<QueuedResponse, String> {
+		let response = self.submission_queue
+			.remove(id)
+			.ok_or_else(|| format!("response {} is not queued", id))?;
+		info!(
+			target: "secretstore",
+			"Dropped queued response {}",
+			response.description,
+		);
+		Ok(response)
+	}
+
+	/// Returns compact summary of the last processed block (e.g. "3 SS tasks, 2 responses
+	/// submitted"), designed for inclusion in the node informant line.
+	pub fn informant_summary(&self) -> InformantSummary {
+		self.informant.summary()
+	}
+
+	/// Returns items that are quarantined because they have failed too many times.
+	pub fn poisoned_items(&self) -> BTreeMap<PoisonedItemId, PoisonedItem> {
+		self.poison.items()
+	}
+
+	/// Release item from quarantine. Task is dispatched again by the next pending tasks
+	/// scan (task that has crashed the service is also released from the safe mode).
+	/// Block with undecodable event is replayed when next block is imported.
+	pub fn retry_poisoned_item(&self, id: PoisonedItemId) -> Result<PoisonedItem, String> {
+		let item = self.poison
+			.retry(id)
+			.ok_or_else(|| format!("item {} is not quarantined", id))?;
+		if let Some(block_number) = item.block_number {
+			self.block_replay.request_replay(block_number);
+		}
+		if let (FailureStage::Crash, Some((task_kind, key_id))) = (item.stage, item.task) {
+			self.safe_mode.release(task_kind, key_id);
+		}
+		info!(
+			target: "secretstore",
+			"Retrying quarantined item {}: {}",
+			id,
+			item.last_error,
+		);
+		Ok(item)
+	}
+
+	/// Drop quarantined item. Task is still skipped until request is completed.
+	pub fn discard_poisoned_item(&self, id: PoisonedItemId) -> Result<PoisonedItem, String> {
+		let item = self.poison
+			.discard(id)
+			.ok_or_else(|| format!("item {} is not quarantined", id))?;
+		info!(
+			target: "secretstore",
+			"Discarded quarantined item {}: {}",
+			id,
+			item.last_error,
+		);
+		Ok(item)
+	}
+
+	/// Returns block processing budget statistics (including amount of deferred work).
+	pub fn budget_statistics(&self) -> BudgetStatistics {
+		(self.budget_statistics)()
+	}
+
+	/// Returns number of tasks that are waiting for running sessions to complete.
+	pub fn queued_tasks(&self) -> usize {
+		(self.queued_tasks)()
+	}
+
+	/// Returns reorgs tracking statistics.
+	pub fn reorg_statistics(&self) -> ReorgStatistics {
+		(self.reorg_statistics)()
+	}
+
+	/// Returns number of missed blocks that have been replayed and skipped.
+	pub fn replay_statistics(&self) -> ReplayStatistics {
+		self.block_replay.statistics()
+	}
+
+	/// Re-process events of the past block with given number. Block is replayed when
+	/// next block is imported. Tasks that have been already served (or requests that
+	/// have been completed) are still skipped.
+	pub fn replay_block(&self, block_number: u64) {
+		self.block_replay.request_replay(block_number)
+	}
+
+	/// Returns metrics in Prometheus text exposition format.
+	#[cfg(feature = "metrics")]
+	pub fn render_metrics(&self) -> String {
+		self.metrics.render()
+	}
+
+	/// Restart processing loops of all key servers. Caches, queues and persisted state are
+	/// preserved. Restart doesn't resume paused service. Fails if service has been stopped
+	/// or terminated.
+	pub fn restart(&self) -> Result<(), String> {
+		match self.state() {
+			ServiceState::Stopped => return Err(String::from("Secret Store service has been stopped")),
+			ServiceState::Terminated(_, error) =>
+				return Err(format!("Secret Store service has been terminated: {}", error)),
+			ServiceState::Running | ServiceState::Paused(_, _) => (),
+		}
+
+		self.restart
+			.unbounded_send(())
+			.map_err(|_| String::from("Secret Store service has been stopped"))
+	}
+
+	/// Returns current state of the service.
+	pub fn state(&self) -> ServiceState {
+		self.escalation.state()
+	}
+
+	/// Resume service that has been paused because of error.
+	pub fn resume(&self) -> Result<(), String> {
+		self.escalation.resume()
+	}
+
+	/// Stop processing blocks and return report of the interrupted work. Stopped
+	/// service can't be resumed.
+	pub fn shutdown(&self) -> ShutdownReport {
+		(self.shutdown)()
+	}
+
+	/// Queue externally produced call for submission. Fails only if service has been stopped.
+	pub fn submit(&self, call: SecretStoreCall) -> Result<(), String> {
+		self.external_calls
+			.unbounded_send(call)
+			.map_err(|_| String::from("Secret Store service has been stopped"))
+	}
+}
+
+/// Index of the key server route.
+pub type KeyServerHandle = usize;
+
+/// Selects key server that will execute the task.
+pub type TaskRouter = Arc<dyn Fn(&BlockchainServiceTask) -> KeyServerHandle + Send + Sync>;
+
+/// Starts processing loop of the key server, fed by given new blocks stream.
+type RouteStarter<Hash> = Box<dyn Fn(BoxStream<'static, NewBlock<Hash>>) + Send>;
+
+/// Service state, shared by all processed blocks.
+struct ServiceContext<B: Blockchain> {
+	/// Shared blockchain reference.
+	blockchain: Arc<B>,
+	/// SLA tracker.
+	sla: Arc<SlaTracker>,
+	/// Pending scans throttle.
+	throttle: Arc<ScanThrottle>,
+	/// Max number of tasks dispatched by single pending tasks scan.
+	pending_scan_max_items: Option<usize>,
+	/// Key id namespaces filter.
+	key_id_filter: KeyIdFilter,
+	/// Per-origin serving policies.
+	tenants: Arc<Tenants>,
+	/// Task router. If `None`, all tasks are executed by the single key server.
+	router: Option<TaskRouter>,
+	/// Persistent record of submitted responses.
+	submitted_responses: Option<Arc<SubmittedResponses>>,
+	/// Local and chain state reconciler.
+	reconciler: Arc<Reconciler>,
+	/// Shadow mode comparator. `None` if there's no shadow key server.
+	shadow: Option<Arc<ShadowComparator>>,
+	/// Internal caches and queues garbage collector.
+	janitor: Janitor,
+	/// Speculatively started tasks. `None` if speculative processing is disabled.
+	speculative: Option<SpeculativeTasks<B::BlockHash>>,
+	/// Unknown SecretStore runtime module events handler.
+	unknown_event_handler: Option<UnknownEventHandler>,
+	/// Degraded mode configuration.
+	degraded_mode: Option<DegradedModeConfiguration>,
+	/// Scheduled requests that are waiting for pre-warming.
+	scheduled_requests: ScheduledRequests,
+	/// Per-origin statistics.
+	origin_statistics: Arc<OriginStatistics>,
+	/// Number of requests that are pending on chain.
+	pending_requests: Arc<PendingRequests>,
+	/// Order of deferred responses submission.
+	response_ordering: ResponseOrdering,
+	/// Responses batching configuration.
+	response_batching: Option<BatchingConfiguration>,
+	/// Canary rollout. Canary and stable responses are never batched together.
+	canary_rollout: Option<CanaryRollout>,
+	/// Session artifacts verification.
+	artifacts_verification: Option<ArtifactsVerification>,
+	/// Errors escalation.
+	escalation: Arc<Escalation>,
+	/// Custom tasks layers.
+	task_layers: Vec<Arc<dyn TaskLayer>>,
+	/// Custom responses layers.
+	response_layers: Vec<Arc<dyn ResponseLayer>>,
+	/// Tasks that are waiting for confirmations.
+	confirmations: ConfirmationQueue<B::BlockHash>,
+	/// Key server sessions watchdog.
+	watchdog: Watchdog,
+	/// Responses that are queued, but not yet submitted.
+	submission_queue: Arc<SubmissionQueue>,
+	/// Recently completed requests.
+	completed_requests: CompletedRequests,
+	/// Work that is deferred because block processing budget has been exhausted.
+	deferred_work: Arc<DeferredWork<B::BlockHash>>,
+	/// Backpressure of key server listeners.
+	backpressure: Arc<ListenerBackpressure>,
+	/// Responses withholding monitor.
+	withholding: Arc<WithholdingMonitor>,
+	/// Key server cluster capacity gate.
+	capacity: Arc<CapacityGate>,
+	/// Sink for error responses to requests that violate origin policy.
+	policy_rejections: UnboundedSender<SecretStoreCall>,
+	/// Server key generations that are shared by several origins.
+	generation_fan_out: GenerationFanOut,
+	/// Service shutdown reporter.
+	shutdown: Arc<ShutdownReporter>,
+	/// Computed responses that are not required anymore.
+	unrequired_responses: UnrequiredResponses,
+	/// Key servers set changes monitor.
+	key_servers_set: KeyServersSetMonitor,
+	/// Quarantine of tasks that crash the service.
+	safe_mode: Arc<SafeMode>,
+	/// Quarantine of tasks that fail repeatedly.
+	poison: Arc<PoisonQuarantine>,
+	/// Last block summaries for the node informant line.
+	informant: Arc<Informant>,
+	/// Limits of concurrently running sessions.
+	session_limiter: SessionLimiter<B::BlockHash>,
+	/// Tasks processing and transactions submission metrics.
+	#[cfg(feature = "metrics")]
+	metrics: Arc<Metrics>,
+}
+
+/// Block from the new blocks stream.
+#[derive(Clone)]
+struct NewBlock<Hash> {
+	/// Block hash.
+	block_hash: Hash,
+	/// True if pending tasks need to be read from this block.
+	scan_pending_tasks: bool,
+	/// Number of tasks accepted from every origin at this block.
+	tenant_quotas: Arc<TenantQuotas>,
+	/// Processing tracker of the new block. `None` for replayed blocks.
+	processed: Option<Arc<ProcessedBlock>>,
 }
 
 /// Substrate block passed to the blockchain service.
 struct SubstrateBlock<B: Blockchain> {
 	/// Origin block.
-	pub block_hash: B::BlockHash,
-	/// Shared blockchain reference.
-	pub blockchain: Arc<B>,
+	pub block: NewBlock<B::BlockHash>,
+	/// Shared service state.
+	pub context: Arc<ServiceContext<B>>,
 	/// This server key address.
 	pub key_server_address: Address,
+	/// Key server route that is processing this block. `None` for shadow key server,
+	/// which processes all tasks.
+	pub route: Option<KeyServerHandle>,
+	/// Block processing budget.
+	pub budget: BlockBudget,
+	/// True if tasks of this block must not be dispatched, because key server
+	/// listener is slow to accept tasks.
+	pub is_throttled: bool,
+	/// Block processing summary. It is logged when the block (and all its tasks
+	/// iterators) is dropped.
+	pub summary: Arc<BlockSummary>,
+}
+
+impl<B: Blockchain> Drop for SubstrateBlock<B> {
+	fn drop(&mut self) {
+		if let Some(ref processed) = self.block.processed {
+			processed.on_route_processed();
+		}
+	}
 }
 
 /// Start listening requests from given contract.
+#[allow(clippy::too_many_arguments)]
 pub fn start_service<B, E, TP, KS>(
 	key_server: Arc<KS>,
 	listener_registrar: Arc<dyn ServiceTasksListenerRegistrar>,
@@ -175,42 +1349,603 @@ pub fn start_service<B, E, TP, KS>(
 	executor: Arc<E>,
 	transaction_pool: Arc<TP>,
 	config: Configuration,
+	service_config: ServiceConfiguration,
 	new_blocks_stream: impl Stream<Item = B::BlockHash> + Send + 'static,
-) -> Result<(), Error> where
+) -> Result<ServiceHandle, Error> where
 	B: Blockchain,
 	E: Executor,
 	TP: TransactionPool,
 	KS: KeyServer,
 {
-//	let config = Arc::new(config);
-	let key_server_address = config.self_id;
-	let transaction_pool = Arc::new(SubstrateTransactionPool::new(
-		blockchain.clone(),
+	start_routed_service(
+		vec![KeyServerRoute {
+			key_server,
+			listener_registrar,
+			config,
+		}],
+		None,
+		None,
+		blockchain,
+		executor,
 		transaction_pool,
-		key_server_address.clone(),
-	));
-	let new_blocks_future = parity_secretstore_blockchain_service::start_service(
-		key_server,
-		listener_registrar,
-		executor.clone(),
-		transaction_pool,
-		config,
-		new_blocks_stream
-			.map(move |block_hash| SubstrateBlock {
+		service_config,
+		new_blocks_stream,
+	)
+}
+
+/// Start listening requests from given contract. Every task is executed by the key
+/// server, selected by the router. Responses of all key servers are submitted to the
+/// same transaction pool.
+///
+/// If shadow key server is given, it executes all tasks too. Its responses are never
+/// submitted - they're only compared with responses of primary key servers.
+#[allow(clippy::too_many_arguments)]
+pub fn start_routed_service<B, E, TP, KS>(
+	routes: Vec<KeyServerRoute<KS>>,
+	router: Option<TaskRouter>,
+	shadow: Option<KeyServerRoute<KS>>,
+	blockchain: Arc<B>,
+	executor: Arc<E>,
+	transaction_pool: Arc<TP>,
+	mut service_config: ServiceConfiguration,
+	new_blocks_stream: impl Stream<Item = B::BlockHash> + Send + 'static,
+) -> Result<ServiceHandle, Error> where
+	B: Blockchain,
+	E: Executor,
+	TP: TransactionPool,
+	KS: KeyServer,
+{
+	if routes.is_empty() {
+		return Err(Error::Internal("at least one key server is required".into()));
+	}
+
+	let router = match (router, service_config.canary.as_ref()) {
+		(Some(_), Some(_)) => return Err(Error::Internal("task router can't be used with canary rollout".into())),
+		(None, Some(canary)) if canary.stable_route >= routes.len() || canary.canary_route >= routes.len() =>
+			return Err(Error::Internal("canary rollout refers to unknown key server".into())),
+		(None, Some(canary)) => Some(canary.rollout.router(canary.stable_route, canary.canary_route)),
+		(router, None) => router,
+	};
+
+	match blockchain.secret_store_constants() {
+		Ok(constants) => apply_constants(&mut service_config, &constants),
+		Err(error) => info!(
+			target: "secretstore",
+			"Runtime module constants are unknown: {}. Using local configuration",
+			error,
+		),
+	}
+
+	match blockchain.block_hash(0).map(|genesis_hash| genesis_hash.map(|hash| blockchain.encode_block_hash(&hash))) {
+		Ok(Some(Some(genesis_hash))) => bind_persistence_to_genesis(&mut service_config, &genesis_hash)
+			.map_err(Error::Internal)?,
+		Ok(Some(None)) => warn!(
+			target: "secretstore",
+			"Block hashes can't be encoded. Persisted state isn't bound to the chain",
+		),
+		Ok(None) => warn!(
+			target: "secretstore",
+			"Genesis block is unknown. Persisted state isn't bound to the chain",
+		),
+		Err(error) => warn!(
+			target: "secretstore",
+			"Failed to read genesis block hash: {}. Persisted state isn't bound to the chain",
+			error,
+		),
+	}
+
+	let runtime_interface_version = blockchain.runtime_interface_version();
+	if !compatibility_matrix().is_compatible(runtime_interface_version) {
+		warn!(
+			target: "secretstore",
+			"Runtime interface version {:?} isn't supported by this build. Events may fail to decode",
+			runtime_interface_version,
+		);
+	}
+
+	let capabilities = Arc::new(CapabilityReport {
+		instance_label: service_config.instance_label.clone(),
+		key_servers: routes.iter().map(|route| route.config.self_id).collect(),
+		shadow_key_server: shadow.as_ref().map(|route| route.config.self_id),
+		enabled_task_kinds: service_config.tenants.default.enabled_task_kinds
+			.clone()
+			.unwrap_or_else(|| ALL_TASK_KINDS.iter().cloned().collect()),
+		tenants: service_config.tenants.tenants.len(),
+		key_id_namespaces: service_config.key_id_filter.patterns.len(),
+		runtime_interface_version,
+		submitter_account: transaction_pool.submitter_account(),
+		pending_scan_interval: service_config.pending_scan_interval,
+		pending_scan_throttling: service_config.pending_scan_throttle.is_some(),
+		speculative_processing: service_config.speculative_processing,
+		sla_tracked_task_kinds: service_config.sla_targets.keys().cloned().collect(),
+		confidential_logging: service_config.confidential_logging_salt.is_some(),
+		secondary_publisher: service_config.secondary_publisher.is_some(),
+		features: enabled_features(),
+	});
+	info!(
+		target: "secretstore",
+		"Starting Secret Store service{}. Capabilities:\n{}",
+		instance_suffix(&service_config.instance_label),
+		capabilities,
+	);
+
+	let pending_scan_interval = std::cmp::max(service_config.pending_scan_interval, 1);
+	let mut blocks_till_pending_scan = 0u32;
+	let redactor = Redactor::new(service_config.confidential_logging_salt);
+	let poison = Arc::new(PoisonQuarantine::new(service_config.poison_quarantine));
+	let (external_calls, external_calls_receiver) = futures::channel::mpsc::unbounded();
+	let (pending_requests, submission_queue) = (Arc::new(PendingRequests::default()), Arc::new(SubmissionQueue::default()));
+	let context = Arc::new(ServiceContext {
+		blockchain: blockchain.clone(),
+		sla: Arc::new(SlaTracker::new(
+			service_config.sla_targets,
+			service_config.sla_violation_handler,
+			redactor.clone(),
+		)),
+		throttle: Arc::new(ScanThrottle::new(
+			service_config.pending_scan_throttle,
+			std::cmp::max(service_config.pending_page_size, 1),
+		)),
+		pending_scan_max_items: service_config.pending_scan_max_items,
+		key_id_filter: service_config.key_id_filter,
+		tenants: Arc::new(service_config.tenants),
+		router,
+		submitted_responses: service_config.submitted_responses,
+		reconciler: Arc::new(Reconciler::new(
+			service_config.reconciliation,
+			service_config.reconciliation_handler,
+			redactor.clone(),
+		)),
+		shadow: match shadow {
+			Some(_) => Some(Arc::new(ShadowComparator::new(
+				service_config.shadow_mismatch_handler,
+				redactor.clone(),
+			))),
+			None => None,
+		},
+		janitor: Janitor::new(service_config.janitor, service_config.janitor_handler),
+		speculative: match service_config.speculative_processing {
+			true => Some(SpeculativeTasks::new()),
+			false => None,
+		},
+		unknown_event_handler: service_config.unknown_event_handler,
+		degraded_mode: service_config.degraded_mode,
+		scheduled_requests: ScheduledRequests::new(service_config.prewarm, redactor.clone()),
+		origin_statistics: Arc::new(OriginStatistics::new(service_config.max_origins_in_statistics)),
+		#[cfg(feature = "metrics")]
+		metrics: Arc::new(Metrics::new(
+			service_config.instance_label.clone(),
+			pending_requests.clone(),
+			submission_queue.clone(),
+			service_config.canary.as_ref().and_then(|canary| canary.stats.clone()),
+		)),
+		pending_requests,
+		response_ordering: service_config.response_ordering,
+		response_batching: service_config.response_batching,
+		canary_rollout: service_config.canary.as_ref().map(|canary| canary.rollout),
+		artifacts_verification: service_config.artifacts_verification,
+		escalation: Arc::new(Escalation::new(service_config.escalation_policy)),
+		task_layers: service_config.task_layers,
+		response_layers: service_config.response_layers,
+		confirmations: ConfirmationQueue::new(service_config.confirmation_depths),
+		watchdog: Watchdog::new(service_config.watchdog, redactor.clone()),
+		submission_queue,
+		completed_requests: CompletedRequests::default(),
+		deferred_work: Arc::new(DeferredWork::new(service_config.block_processing_budget)),
+		backpressure: Arc::new(ListenerBackpressure::new(service_config.listener_backpressure)),
+		withholding: Arc::new(WithholdingMonitor::new(service_config.withholding_detection)),
+		capacity: Arc::new(CapacityGate::new(service_config.connectivity_probe)),
+		policy_rejections: external_calls.clone(),
+		generation_fan_out: GenerationFanOut::new(transaction_pool.routes_by_origin()),
+		shutdown: Arc::new(ShutdownReporter::new(service_config.shutdown_handler)),
+		unrequired_responses: UnrequiredResponses::new(service_config.unrequired_responses),
+		key_servers_set: KeyServersSetMonitor::new(service_config.key_servers_set_migration),
+		safe_mode: Arc::new(SafeMode::new(service_config.safe_mode, &redactor, &poison)),
+		poison,
+		informant: Arc::new(Informant::default()),
+		session_limiter: SessionLimiter::new(service_config.session_limits),
+	});
+
+	let readiness_gate = ReadinessGate::new(service_config.readiness_probe);
+	let block_replay = Arc::new(BlockReplay::new(service_config.replay));
+	let stream_block_replay = block_replay.clone();
+	let checkpoint = service_config.checkpoint.clone().map(|checkpoint| Arc::new(BlockCheckpoint::new(checkpoint)));
+	let recent_blocks = checkpoint.as_ref().map(|checkpoint| checkpoint.recent_blocks()).unwrap_or_default();
+	if let Some(resume_block_number) = resume_block_number(&*context.blockchain, &recent_blocks) {
+		block_replay.resume_from(resume_block_number);
+	}
+	let reorg_tracker = Arc::new(ReorgTracker::new(service_config.reorg_tracking));
+	let stream_reorg_tracker = reorg_tracker.clone();
+	let reorg_statistics = Arc::new(move || reorg_tracker.statistics());
+	let health_reporter = HealthReporter::new(service_config.health_reports);
+	let escalation = context.escalation.clone();
+	let (block_context, block_transaction_pool) = (context.clone(), transaction_pool.clone());
+	let mut is_block_number_supported = true;
+	let new_blocks_stream = new_blocks_stream
+		.filter(move |_| futures::future::ready(escalation.is_running() && readiness_gate.is_open()))
+		.map(move |block_hash| {
+			block_context.sla.on_new_block();
+			block_context.completed_requests.on_new_block();
+			block_context.key_servers_set.on_new_block(&*block_context.blockchain);
+			for dropped_transaction in block_transaction_pool.take_dropped_transactions() {
+				let call = dropped_transaction.call;
+				warn!(
+					target: "secretstore",
+					"{:?} response has been dropped by the transaction pool: {}",
+					call.task_kind(),
+					dropped_transaction.error,
+				);
+
+				block_context.poison.on_task_failure(
+					FailureStage::Submit,
+					call.task_kind(),
+					call.key_id(),
+					&dropped_transaction.error,
+				);
+				block_context.reconciler.on_response_dropped(&call);
+			}
+			// requests with abandoned responses are served again by the pending tasks scan
+			let reconciliation_report = block_context.reconciler
+				.on_new_block(&*block_context.blockchain, &*block_transaction_pool);
+			let has_abandoned_responses = match reconciliation_report {
+				Some(ref report) if !report.abandoned_requests.is_empty() => {
+					for request in &report.abandoned_requests {
+						if let Some(ref submitted_responses) = block_context.submitted_responses {
+							submitted_responses.on_request_completed(request);
+						}
+					}
+					true
+				},
+				_ => false,
+			};
+			if let Some(ref shadow) = block_context.shadow {
+				shadow.on_new_block();
+			}
+			block_context.janitor.on_new_block(
+				&block_context.sla,
+				&block_context.reconciler,
+				block_context.shadow.as_deref(),
+			);
+			if let Some(health_report) = health_reporter.on_new_block(&block_context.origin_statistics) {
+				match block_transaction_pool.submit_health_report(&health_report) {
+					Ok(transaction_hash) => trace!(
+						target: "secretstore",
+						"Submitted health report {:?}: {}",
+						health_report,
+						transaction_hash,
+					),
+					Err(error) => error!(
+						target: "secretstore",
+						"Failed to submit health report {:?}: {}",
+						health_report,
+						error,
+					),
+				}
+			}
+			if block_context.scheduled_requests.is_enabled() {
+				match block_context.blockchain.block_number(block_hash.clone()) {
+					Ok(block_number) => block_context.scheduled_requests.on_new_block(block_number),
+					Err(error) => error!(
+						target: "secretstore",
+						"Failed to read number of the block: {}",
+						error,
+					),
+				}
+			}
+
+			// events of replayed blocks are processed before events of the new block. Blocks
+			// enacted by reorg are older than missed blocks. Blocks requested by operator
+			// are replayed first
+			let requested_blocks = stream_block_replay.take_requested_blocks(&*block_context.blockchain);
+			let enacted_blocks = stream_reorg_tracker.on_new_block(&*block_context.blockchain, &block_hash);
+			let missed_blocks = stream_block_replay.on_new_block(&*block_context.blockchain, &block_hash);
+			let mut new_blocks = requested_blocks
+				.into_iter()
+				.chain(enacted_blocks.replayed)
+				.chain(missed_blocks.replayed)
+				.map(|block_hash| NewBlock {
+					block_hash,
+					scan_pending_tasks: false,
+					tenant_quotas: Arc::new(TenantQuotas::default()),
+					processed: None,
+				})
+				.collect::<Vec<_>>();
+
+			// tasks of aborted sessions are re-dispatched by the pending tasks scan
+			let aborted_sessions = block_context.watchdog.on_new_block();
+			for (task_kind, key_id) in &aborted_sessions {
+				block_context.poison.on_task_failure(FailureStage::Execute, *task_kind, *key_id, "session has been wedged");
+			}
+			let has_aborted_sessions = !aborted_sessions.is_empty();
+			let scan_pending_tasks = blocks_till_pending_scan == 0
+				|| missed_blocks.is_pending_scan_required
+				|| enacted_blocks.is_pending_scan_required
+				|| has_aborted_sessions
+				|| has_abandoned_responses;
+			blocks_till_pending_scan = match scan_pending_tasks {
+				true => block_context.throttle.scan_interval(pending_scan_interval) - 1,
+				false => blocks_till_pending_scan - 1,
+			};
+			let is_processed_block_tracked = is_block_number_supported
+				&& (checkpoint.is_some() || block_context.shutdown.is_tracking_blocks());
+			let processed = match is_processed_block_tracked {
+				true => match block_context.blockchain.block_number(block_hash.clone()) {
+					Ok(block_number) => Some(Arc::new(ProcessedBlock::new(
+						checkpoint.clone(),
+						block_context.shutdown.clone(),
+						block_number,
+						block_context.blockchain.encode_block_hash(&block_hash).unwrap_or_default(),
+					))),
+					Err(error) if !error.is_transient() => {
+						warn!(
+							target: "secretstore",
+							"Processed blocks aren't tracked: {}",
+							error,
+						);
+						is_block_number_supported = false;
+						None
+					},
+					Err(error) => {
+						error!(
+							target: "secretstore",
+							"Failed to read number of the new block: {}",
+							error,
+						);
+						None
+					},
+				},
+				false => None,
+			};
+			new_blocks.push(NewBlock {
 				block_hash,
-				blockchain: blockchain.clone(),
-				key_server_address: key_server_address.clone(),
-			})
+				scan_pending_tasks,
+				tenant_quotas: Arc::new(TenantQuotas::default()),
+				processed,
+			});
+			new_blocks
+		});
+
+	// shadow key server is the last route and it isn't selected by router
+	let shadow_index = shadow.as_ref().map(|_| routes.len());
+	let routes = routes
+		.into_iter()
+		.enumerate()
+		.map(|(route_index, route)| (route, Some(route_index), ShadowRole::Primary))
+		.chain(shadow.map(|route| (route, None, ShadowRole::Candidate)))
+		.collect::<Vec<_>>();
+
+	// every key server needs its own copy of new blocks stream. Senders are replaced
+	// when service is restarted
+	let routes_senders = Arc::new(Mutex::new(Vec::<UnboundedSender<NewBlock<B::BlockHash>>>::new()));
+	let broadcast_routes_senders = routes_senders.clone();
+	#[cfg(feature = "metrics")]
+	let broadcast_metrics = context.metrics.clone();
+	executor.spawn(new_blocks_stream
+		.for_each(move |new_blocks| {
+			let routes_senders = broadcast_routes_senders.lock().expect("never panics under lock; qed");
+			for new_block in new_blocks {
+				#[cfg(feature = "metrics")]
+				broadcast_metrics.on_block_received();
+				if let Some(ref processed) = new_block.processed {
+					processed.on_routes_started(routes_senders.len());
+				}
+				for (index, sender) in routes_senders.iter().enumerate() {
+					// shadow key server must not consume tenant quotas of primary key servers
+					let new_block = match Some(index) == shadow_index {
+						true => NewBlock {
+							tenant_quotas: Arc::new(TenantQuotas::default()),
+							..new_block.clone()
+						},
+						false => new_block.clone(),
+					};
+					let _ = sender.unbounded_send(new_block);
+				}
+			}
+			futures::future::ready(())
+		})
+		.boxed()
 	);
-	executor.spawn(new_blocks_future
-		.map(|err| error!(
-			target: "secretstore",
-			"Blockhain service future failed: {:?}",
-			err,
-		))
+
+	let mut routes_starters: Vec<RouteStarter<B::BlockHash>> = Vec::with_capacity(routes.len());
+	for (route, route_index, shadow_role) in routes {
+		let key_server_address = route.config.self_id;
+		let transaction_pool = Arc::new(SubstrateTransactionPool::new(
+			context.clone(),
+			transaction_pool.clone(),
+			key_server_address,
+			redactor.clone(),
+			service_config.secondary_publisher.clone(),
+			context.shadow.clone().map(|shadow_comparator| (shadow_comparator, shadow_role)),
+		));
+		let (route_context, route_transaction_pool) = (context.clone(), transaction_pool.clone());
+		let transaction_pool = Arc::new(
+			PipelinedTransactionPool::new(
+				transaction_pool,
+				route_pipeline(&service_config.pipeline, service_config.canary.as_ref(), route_index),
+				service_config.instance_label.as_deref(),
+			)
+				.map_err(Error::Internal)?
+		);
+		let (key_server, route_config) = (route.key_server, route.config);
+		let listener_registrar = match context.backpressure.is_enabled() {
+			true => Arc::new(BackpressureListenerRegistrar::new(
+				route.listener_registrar,
+				context.backpressure.clone(),
+				route_index,
+			)),
+			false => route.listener_registrar,
+		};
+		let listener_registrar = Arc::new(RestartableListenerRegistrar::new(listener_registrar));
+		let (route_executor, instance_suffix) = (executor.clone(), instance_suffix(&service_config.instance_label));
+		let route_escalation = context.escalation.clone();
+		routes_starters.push(Box::new(move |route_stream| {
+			let (route_context, route_transaction_pool) = (route_context.clone(), route_transaction_pool.clone());
+			let new_blocks_future = parity_secretstore_blockchain_service::start_service(
+				key_server.clone(),
+				listener_registrar.clone(),
+				route_executor.clone(),
+				transaction_pool.clone(),
+				route_config.clone(),
+				route_stream
+					.map(move |mut block| {
+						let summary = Arc::new(BlockSummary::new(
+							route_index,
+							route_context.informant.clone(),
+							route_transaction_pool.take_submitted_responses_count(),
+						));
+						route_transaction_pool.on_new_block();
+						#[cfg(feature = "metrics")]
+						route_context.metrics.on_block_processed(route_index);
+						let is_throttled = match route_context.backpressure.on_new_block(route_index) {
+							BlockBackpressure::None => false,
+							BlockBackpressure::Throttled => true,
+							// tasks that have been skipped while throttled are still pending on chain
+							BlockBackpressure::Recovered => {
+								block.scan_pending_tasks = true;
+								false
+							},
+						};
+						SubstrateBlock {
+							block,
+							budget: route_context.deferred_work.start_block(),
+							is_throttled,
+							summary,
+							context: route_context.clone(),
+							key_server_address,
+							route: route_index,
+						}
+					})
+			);
+			let (instance_suffix, route_escalation) = (instance_suffix.clone(), route_escalation.clone());
+			route_executor.spawn(new_blocks_future
+				.map(move |err| {
+					error!(
+						target: "secretstore",
+						"Blockhain service future{} failed: {:?}",
+						instance_suffix,
+						err,
+					);
+
+					route_escalation.on_error(ErrorClass::BlockchainService, &format!("{:?}", err));
+				})
+				.boxed()
+			);
+		}));
+	}
+
+	// (re)start processing loops of all key servers. Old loops are stopped when their
+	// new blocks streams are closed
+	let start_routes = move || {
+		let mut routes_senders = routes_senders.lock().expect("never panics under lock; qed");
+		routes_senders.clear();
+		for route_starter in &routes_starters {
+			let (sender, receiver) = futures::channel::mpsc::unbounded();
+			routes_senders.push(sender);
+			route_starter(receiver.boxed());
+		}
+	};
+	start_routes();
+
+	let (restart, restart_receiver) = futures::channel::mpsc::unbounded();
+	let restart_instance_suffix = instance_suffix(&service_config.instance_label);
+	executor.spawn(restart_receiver
+		.for_each(move |()| {
+			info!(
+				target: "secretstore",
+				"Restarting Secret Store service{}",
+				restart_instance_suffix,
+			);
+
+			start_routes();
+			futures::future::ready(())
+		})
 		.boxed()
 	);
-	Ok(())
+
+	let (origin_statistics, pending_requests) = (context.origin_statistics.clone(), context.pending_requests.clone());
+	let (escalation, submission_queue) = (context.escalation.clone(), context.submission_queue.clone());
+	let (deferred_work, backpressure) = (context.deferred_work.clone(), context.backpressure.clone());
+	let (withholding, capacity) = (context.withholding.clone(), context.capacity.clone());
+	let (poison, informant) = (context.poison.clone(), context.informant.clone());
+	let safe_mode = context.safe_mode.clone();
+	let budget_statistics = Arc::new(move || deferred_work.statistics());
+
+	// service context holds escalation => use weak reference to avoid cycle
+	let termination_context = Arc::downgrade(&context);
+	context.escalation.set_termination_hook(Box::new(move |class, error| {
+		if let Some(context) = termination_context.upgrade() {
+			report_shutdown(&context, ShutdownReason::Fatal(class, error.into()));
+		}
+	}));
+	let queued_tasks_context = context.clone();
+	let queued_tasks = Arc::new(move || queued_tasks_context.session_limiter.queued_tasks());
+	let shutdown_context = context.clone();
+	let shutdown = Arc::new(move || {
+		shutdown_context.escalation.stop();
+		shutdown_context.safe_mode.on_graceful_shutdown();
+		report_shutdown(&shutdown_context, ShutdownReason::Graceful)
+	});
+	#[cfg(feature = "metrics")]
+	let metrics = context.metrics.clone();
+
+	// externally produced calls (and policy rejections) are reconciled as if they were
+	// submitted by the first key server
+	let external_key_server_address = capabilities.key_servers[0];
+	executor.spawn(external_calls_receiver
+		.for_each(move |call: SecretStoreCall| {
+			match submit_call(&*transaction_pool, None, None, call.clone()) {
+				Ok(transaction_hash) => {
+					trace!(
+						target: "secretstore",
+						"Submitted external call {:?}: {}",
+						call.task_kind(),
+						transaction_hash,
+					);
+
+					if let Some(request) = ServedRequest::from_accepted_call(None, &call).into_iter().next() {
+						context.reconciler.on_response_submitted(
+							external_key_server_address,
+							request,
+							None,
+							None,
+							call.clone(),
+						);
+					}
+				},
+				Err(error) => error!(
+					target: "secretstore",
+					"Failed to submit external call {:?}: {}",
+					call.task_kind(),
+					error,
+				),
+			}
+			futures::future::ready(())
+		})
+		.boxed()
+	);
+
+	Ok(ServiceHandle {
+		capabilities,
+		external_calls,
+		origin_statistics,
+		pending_requests,
+		restart,
+		escalation,
+		block_replay,
+		reorg_statistics,
+		submission_queue,
+		backpressure,
+		withholding,
+		capacity,
+		poison,
+		safe_mode,
+		informant,
+		budget_statistics,
+		queued_tasks,
+		shutdown,
+		#[cfg(feature = "metrics")]
+		metrics,
+	})
 }
 
 impl<B: Blockchain> parity_secretstore_blockchain_service::Block for SubstrateBlock<B> {
@@ -218,89 +1953,730 @@ impl<B: Blockchain> parity_secretstore_blockchain_service::Block for SubstrateBl
 	type PendingBlocksIterator = Box<dyn Iterator<Item = BlockchainServiceTask>>;
 
 	fn new_tasks(&mut self) -> Self::NewBlocksIterator {
+		let has_secret_store_activity = self.context.blockchain
+			.has_secret_store_activity(self.block.block_hash.clone());
+		let has_deferred_work = self.context.deferred_work.is_enabled()
+			|| self.context.confirmations.is_enabled()
+			|| self.context.session_limiter.is_enabled();
+		if !has_secret_store_activity && !has_deferred_work {
+			return Box::new(std::iter::empty());
+		}
+
+		let new_tasks = match has_secret_store_activity {
+			true => self.decode_block_events(
+				self.context.blockchain.block_events(self.block.block_hash.clone()),
+			),
+			false => Vec::new(),
+		};
+
+		#[cfg(feature = "metrics")]
+		if let Some(metrics) = self.task_metrics() {
+			for (_, task) in &new_tasks {
+				metrics.on_task_seen(task, TaskSource::NewBlock);
+			}
+		}
+
+		// responses have been processed while decoding events, but tasks are read later
+		// by the pending tasks scan
+		if self.is_throttled {
+			return Box::new(std::iter::empty());
+		}
+
+		// tasks that have got enough confirmations are started before new tasks
+		let (new_tasks, confirmed_tasks) = self.confirm_tasks(new_tasks);
+
+		// tasks of tenants with larger priority are started (and counted against quotas) first
+		let new_tasks = fair_order_by(new_tasks, &self.context.tenants, |(_, task)| task);
+
+		// origin is only reported for tasks that are forwarded to primary key servers
+		let (accept_task, layers_context) = (self.accept_task(), self.context.clone());
+		let (blockchain, route, summary) = (self.context.blockchain.clone(), self.route, self.summary.clone());
+		let block_hash = self.block.block_hash.clone();
+		let mut block_number = None;
+		// tasks that have been queued or deferred at previous blocks are started first
+		let new_tasks = self.context.session_limiter
+			.take(route)
+			.into_iter()
+			.chain(self.context.deferred_work.take(route))
+			.chain(confirmed_tasks)
+			.map(|(task_origin_block, task)| (false, task_origin_block, task))
+			.chain(new_tasks.into_iter().map(move |(event_index, task)| (
+				true,
+				TaskOriginBlock {
+					block_hash: block_hash.clone(),
+					block_number: None,
+					event_index,
+				},
+				task,
+			)))
+			.collect::<Vec<_>>();
+		let new_tasks = BudgetedTasks {
+			tasks: new_tasks.into_iter(),
+			budget: self.budget,
+			deferred_work: self.context.deferred_work.clone(),
+			route,
+		}
+			.filter_map(move |(is_current_block, mut task_origin_block, task)| {
+				if !accept_task(&task) {
+					return None;
+				}
+
+				// shadow key server isn't limited, because it isn't dispatching its sessions
+				if let Some(route) = route {
+					if !layers_context.session_limiter.accepts_task(&task) {
+						summary.on_task_skipped(SkipReason::SessionLimit);
+						layers_context.session_limiter.queue(route, task_origin_block, task);
+						return None;
+					}
+				}
+
+				let task = apply_task_layers(&layers_context.task_layers, task)?;
+				if route.is_some() {
+					if task_origin_block.block_number.is_none() {
+						let read_block_number = || blockchain.block_number(task_origin_block.block_hash.clone()).ok();
+						task_origin_block.block_number = match is_current_block {
+							true => *block_number.get_or_insert_with(read_block_number),
+							false => read_block_number(),
+						};
+					}
+
+					blockchain.on_task_forwarded(&task, &task_origin_block);
+				}
+
+				Some(task)
+			});
+
+		let (context, block_hash) = (self.context.clone(), self.block.block_hash.clone());
+		let origin_statistics = self.context.origin_statistics.clone();
 		Box::new(
-			self.blockchain
-				.block_events(self.block_hash.clone())
-				.into_iter()
-				.filter_map(MaybeSecretStoreEvent::as_secret_store_event)
+			new_tasks
+				.inspect(track_seen_task(self.context.sla.clone()))
+				.inspect(self.watch_dispatched_task())
+				.inspect(move |task| if route.is_some() {
+					origin_statistics.on_task_seen(&task_origin(task));
+				})
+				.inspect(move |task| if let Some(ref speculative) = context.speculative {
+					if let Some(request) = ServedRequest::from_task(task) {
+						speculative.on_task_seen(request, block_hash.clone());
+					}
+				})
 		)
 	}
 
 	fn pending_tasks(&mut self) -> Self::PendingBlocksIterator {
-		let (blockchain, block_hash) = (self.blockchain.clone(), self.block_hash.clone());
+		if !self.block.scan_pending_tasks || self.is_throttled {
+			return Box::new(std::iter::empty());
+		}
+
+		// block hash is shared by all pending tasks queries
+		let shared_block_hash = Arc::new(self.block.block_hash.clone());
+
+		let (blockchain, block_hash) = (self.context.blockchain.clone(), shared_block_hash.clone());
 		let server_key_generation_tasks = move |tasks: &mut VecDeque<BlockchainServiceTask>, range|
 			Ok(tasks.extend(
 				blockchain
-					.server_key_generation_tasks(block_hash.clone(), range)?
+					.server_key_generation_tasks(&block_hash, range)?
 					.into_iter()
 					.filter_map(MaybeSecretStoreEvent::as_secret_store_event)
 			));
-		let (blockchain, block_hash) = (self.blockchain.clone(), self.block_hash.clone());
+		let (blockchain, block_hash) = (self.context.blockchain.clone(), shared_block_hash.clone());
 		let server_key_retrieval_tasks = move |tasks: &mut VecDeque<BlockchainServiceTask>, range|
 			Ok(tasks.extend(
 				blockchain
-					.server_key_retrieval_tasks(block_hash.clone(), range)?
+					.server_key_retrieval_tasks(&block_hash, range)?
 					.into_iter()
 					.filter_map(MaybeSecretStoreEvent::as_secret_store_event)
 			));
-		let (blockchain, block_hash) = (self.blockchain.clone(), self.block_hash.clone());
+		let (blockchain, block_hash) = (self.context.blockchain.clone(), shared_block_hash.clone());
 		let document_key_store_tasks = move |tasks: &mut VecDeque<BlockchainServiceTask>, range|
 			Ok(tasks.extend(
 				blockchain
-					.document_key_store_tasks(block_hash.clone(), range)?
+					.document_key_store_tasks(&block_hash, range)?
 					.into_iter()
 					.filter_map(MaybeSecretStoreEvent::as_secret_store_event)
 			));
-		let (blockchain, block_hash) = (self.blockchain.clone(), self.block_hash.clone());
+		let (blockchain, block_hash) = (self.context.blockchain.clone(), shared_block_hash.clone());
 		let document_key_shadow_retrieval_tasks = move |tasks: &mut VecDeque<BlockchainServiceTask>, range|
 			Ok(tasks.extend(
 				blockchain
-					.document_key_shadow_retrieval_tasks(block_hash.clone(), range)?
+					.document_key_shadow_retrieval_tasks(&block_hash, range)?
 					.into_iter()
 					.filter_map(MaybeSecretStoreEvent::as_secret_store_event)
 			));
 
+		let (confirmations_context, route) = (self.context.clone(), self.route);
+		let (layers_context, summary) = (self.context.clone(), self.summary.clone());
+		let dispatched_tasks = Arc::new(AtomicUsize::new(0));
+		let max_dispatched_tasks = self.context.pending_scan_max_items.unwrap_or(usize::MAX);
+		let dispatched_tasks_counter = dispatched_tasks.clone();
 		Box::new(
 			PendingTasksIterator {
 				pending: VecDeque::new(),
-				range: 0..std::usize::MAX,
+				range: 0..self.pending_range_end(TaskKind::ServerKeyGeneration),
+				throttle: self.context.throttle.clone(),
+				task_kind: TaskKind::ServerKeyGeneration,
+				pending_requests: self.context.pending_requests.clone(),
+				pending_requests_count: 0,
+				escalation: self.context.escalation.clone(),
+				has_failed_pages: false,
+				budget: self.budget,
+				deferred_work: self.context.deferred_work.clone(),
+				dispatched_tasks: dispatched_tasks.clone(),
+				max_dispatched_tasks,
+				#[cfg(feature = "metrics")]
+				metrics: self.task_metrics(),
 				get_pending_tasks: server_key_generation_tasks,
 			}.chain(PendingTasksIterator {
 				pending: VecDeque::new(),
-				range: 0..std::usize::MAX,
+				range: 0..self.pending_range_end(TaskKind::ServerKeyRetrieval),
+				throttle: self.context.throttle.clone(),
+				task_kind: TaskKind::ServerKeyRetrieval,
+				pending_requests: self.context.pending_requests.clone(),
+				pending_requests_count: 0,
+				escalation: self.context.escalation.clone(),
+				has_failed_pages: false,
+				budget: self.budget,
+				deferred_work: self.context.deferred_work.clone(),
+				dispatched_tasks: dispatched_tasks.clone(),
+				max_dispatched_tasks,
+				#[cfg(feature = "metrics")]
+				metrics: self.task_metrics(),
 				get_pending_tasks: server_key_retrieval_tasks,
 			}).chain(PendingTasksIterator {
 				pending: VecDeque::new(),
-				range: 0..std::usize::MAX,
+				range: 0..self.pending_range_end(TaskKind::DocumentKeyStore),
+				throttle: self.context.throttle.clone(),
+				task_kind: TaskKind::DocumentKeyStore,
+				pending_requests: self.context.pending_requests.clone(),
+				pending_requests_count: 0,
+				escalation: self.context.escalation.clone(),
+				has_failed_pages: false,
+				budget: self.budget,
+				deferred_work: self.context.deferred_work.clone(),
+				dispatched_tasks: dispatched_tasks.clone(),
+				max_dispatched_tasks,
+				#[cfg(feature = "metrics")]
+				metrics: self.task_metrics(),
 				get_pending_tasks: document_key_store_tasks,
 			}).chain(PendingTasksIterator {
 				pending: VecDeque::new(),
-				range: 0..std::usize::MAX,
+				range: 0..self.pending_range_end(TaskKind::DocumentKeyShadowRetrieval),
+				throttle: self.context.throttle.clone(),
+				task_kind: TaskKind::DocumentKeyShadowRetrieval,
+				pending_requests: self.context.pending_requests.clone(),
+				pending_requests_count: 0,
+				escalation: self.context.escalation.clone(),
+				has_failed_pages: false,
+				budget: self.budget,
+				deferred_work: self.context.deferred_work.clone(),
+				dispatched_tasks: dispatched_tasks.clone(),
+				max_dispatched_tasks,
+				#[cfg(feature = "metrics")]
+				metrics: self.task_metrics(),
 				get_pending_tasks: document_key_shadow_retrieval_tasks,
 			})
+			.inspect(move |_| summary.on_pending_task_read())
+			.filter(self.accept_task())
+			.filter(move |task| !confirmations_context.confirmations.is_deferred(route, task))
+			.filter(self.accept_session())
+			.filter_map(move |task| apply_task_layers(&layers_context.task_layers, task))
+			.inspect(track_seen_task(self.context.sla.clone()))
+			.inspect(self.watch_dispatched_task())
+			.inspect(move |_| { dispatched_tasks_counter.fetch_add(1, Ordering::Relaxed); })
 		)
 	}
 
 	fn current_key_servers_set(&mut self) -> BTreeSet<KeyServerId> {
-		self.blockchain.current_key_servers_set()
+		self.context.blockchain.current_key_servers_set()
+	}
+}
+
+impl<B: Blockchain> SubstrateBlock<B> {
+	/// Defer new tasks that require confirmations. Returns tasks that need to be started
+	/// immediately and previously deferred tasks that have got enough confirmations.
+	#[allow(clippy::type_complexity)]
+	fn confirm_tasks(
+		&self,
+		new_tasks: Vec<(usize, BlockchainServiceTask)>,
+	) -> (Vec<(usize, BlockchainServiceTask)>, Vec<(TaskOriginBlock<B::BlockHash>, BlockchainServiceTask)>) {
+		let confirmations = &self.context.confirmations;
+		if !confirmations.is_enabled() {
+			return (new_tasks, Vec::new());
+		}
+
+		let block_number = match self.context.blockchain.block_number(self.block.block_hash.clone()) {
+			Ok(block_number) => block_number,
+			Err(error) => {
+				error!(
+					target: "secretstore",
+					"Failed to read number of the block: {}. Ignoring tasks that require confirmations",
+					error,
+				);
+
+				let new_tasks = new_tasks
+					.into_iter()
+					.filter(|(_, task)| confirmations.confirmation_depth(task) == 0)
+					.collect();
+				return (new_tasks, Vec::new());
+			},
+		};
+
+		let confirmed_tasks = confirmations.release(&*self.context.blockchain, self.route, block_number);
+		let new_tasks = new_tasks
+			.into_iter()
+			.filter_map(|(event_index, task)| match confirmations.confirmation_depth(&task) {
+				0 => Some((event_index, task)),
+				_ => {
+					confirmations.defer(
+						self.route,
+						TaskOriginBlock {
+							block_hash: self.block.block_hash.clone(),
+							block_number: Some(block_number),
+							event_index,
+						},
+						task,
+					);
+					None
+				},
+			})
+			.collect();
+
+		(new_tasks, confirmed_tasks)
+	}
+
+	/// Decode all events of the block in single pass.
+	///
+	/// Responses of this key server are reported to trackers, unknown events and
+	/// announcements are reported to handlers. Returns tasks along with their
+	/// indices within the block.
+	fn decode_block_events(&self, events: B::BlockEvents) -> Vec<(usize, BlockchainServiceTask)> {
+		let events = events.into_iter();
+		let mut tasks = Vec::with_capacity(events.size_hint().0);
+		// every route sees the same events => report unknown events (and announcements, and
+		// responses of other key servers) once
+		let report_unknown_events = self.route == Some(0);
+		let mut events_seen = 0;
+		for (event_index, event) in events.enumerate() {
+			events_seen += 1;
+			if let Some(response) = event.as_secret_store_response() {
+				if response.key_server == self.key_server_address {
+					self.context.sla.on_request_completed(response.call.task_kind(), response.call.key_id());
+					self.context.watchdog.on_request_completed(response.call.task_kind(), response.call.key_id());
+					if let Some(ref submitted_responses) = self.context.submitted_responses {
+						submitted_responses.on_response_accepted(response.origin, &response.call);
+					}
+					self.context.reconciler.on_response_accepted(self.key_server_address, response.origin, &response.call);
+				}
+				if report_unknown_events {
+					self.context.withholding.on_response_accepted(response.key_server, response.origin, &response.call);
+				}
+			}
+
+			if let Some(request) = event.as_completed_request() {
+				if report_unknown_events && self.context.withholding.is_enabled() {
+					let key_servers = self.context.blockchain.current_key_servers_set();
+					self.context.withholding.on_request_completed(request, &key_servers);
+				}
+				self.on_request_completed(request);
+			}
+
+			if report_unknown_events {
+				let is_unknown_event_reported = self.context.unknown_event_handler.is_some()
+					|| self.context.poison.is_enabled();
+				if is_unknown_event_reported {
+					if let Some(raw_event) = event.as_unknown_secret_store_event() {
+						if let Some(ref unknown_event_handler) = self.context.unknown_event_handler {
+							unknown_event_handler(&raw_event);
+						}
+						self.context.poison.on_decode_failure(
+							self.context.blockchain.block_number(self.block.block_hash.clone()).ok(),
+							format!(
+								"{} event (module {}, event {}, {} bytes)",
+								raw_event.name.as_deref().unwrap_or("unknown"),
+								raw_event.module_index,
+								raw_event.event_index,
+								raw_event.data.len(),
+							),
+						);
+					}
+				}
+
+				if let Some(scheduled_request) = event.as_scheduled_request() {
+					self.context.scheduled_requests.on_request_announced(scheduled_request);
+				}
+			}
+
+			if let Some(task) = event.as_secret_store_event() {
+				tasks.push((event_index, task));
+			}
+		}
+
+		self.summary.on_events_decoded(events_seen, tasks.len());
+		tasks
 	}
+
+	/// Close local state of the request that has been completed. Queued responses
+	/// are dropped and running session is aborted.
+	fn on_request_completed(&self, request: ServedRequest) {
+		let dropped_responses = self.context.submission_queue.remove_request(&request);
+		if dropped_responses != 0 {
+			trace!(
+				target: "secretstore",
+				"{:?} request has been completed. Dropped {} queued responses",
+				request.task_kind,
+				dropped_responses,
+			);
+		}
+		self.context.sla.on_request_completed(request.task_kind, request.key_id);
+		self.context.watchdog.on_request_completed(request.task_kind, request.key_id);
+		if let Some(ref submitted_responses) = self.context.submitted_responses {
+			submitted_responses.on_request_completed(&request);
+		}
+		if let Some(ref speculative) = self.context.speculative {
+			speculative.forget(&request);
+		}
+		self.context.reconciler.on_request_completed(self.key_server_address, request);
+		self.context.safe_mode.on_request_completed(&request);
+		self.context.session_limiter.on_session_completed(&request);
+		self.context.poison.on_request_completed(request.task_kind, request.key_id);
+		self.context.completed_requests.on_request_completed(request);
+		if request.task_kind == TaskKind::ServerKeyGeneration {
+			self.context.generation_fan_out.on_request_completed(request.key_id);
+		}
+	}
+
+	/// Returns function that starts watching sessions of tasks that are dispatched to
+	/// primary key servers.
+	fn watch_dispatched_task(&self) -> impl Fn(&BlockchainServiceTask) {
+		let (context, route, summary) = (self.context.clone(), self.route, self.summary.clone());
+		move |task| {
+			summary.on_task_dispatched();
+			if route.is_some() {
+				if let Some((task_kind, key_id)) = task_kind_and_key_id(task) {
+					context.watchdog.on_task_dispatched(task_kind, key_id);
+				}
+				if let Some(request) = ServedRequest::from_task(task) {
+					context.safe_mode.on_task_dispatched(request);
+					context.session_limiter.on_task_dispatched(request);
+				}
+				#[cfg(feature = "metrics")]
+				context.metrics.on_session_started();
+			}
+		}
+	}
+
+	/// Returns end of the pending tasks range of given kind. If blockchain knows number
+	/// of pending tasks, the scan stops at the last task instead of reading the empty page.
+	fn pending_range_end(&self, task_kind: TaskKind) -> usize {
+		match self.context.blockchain.pending_tasks_count(&self.block.block_hash, task_kind) {
+			Ok(Some(pending_tasks_count)) => {
+				// empty scan is never started => report it here
+				if pending_tasks_count == 0 {
+					self.context.pending_requests.on_scan_completed(task_kind, 0);
+				}
+				pending_tasks_count
+			},
+			Ok(None) => usize::MAX,
+			Err(error) => {
+				trace!(
+					target: "secretstore",
+					"Failed to read number of pending {:?} tasks: {}",
+					task_kind,
+					error,
+				);
+				usize::MAX
+			},
+		}
+	}
+
+	/// Returns metrics that are tracking seen tasks. Every route sees the same tasks =>
+	/// tasks are counted by the first route only.
+	#[cfg(feature = "metrics")]
+	fn task_metrics(&self) -> Option<Arc<Metrics>> {
+		match self.route == Some(0) {
+			true => Some(self.context.metrics.clone()),
+			false => None,
+		}
+	}
+
+	/// Returns function that filters out pending tasks that are over the running sessions
+	/// limit. Shadow key server isn't limited.
+	fn accept_session(&self) -> impl Fn(&BlockchainServiceTask) -> bool {
+		let (context, route, summary) = (self.context.clone(), self.route, self.summary.clone());
+		move |task| {
+			let accepts_session = route.is_none() || context.session_limiter.accepts_task(task);
+			if !accepts_session {
+				summary.on_task_skipped(SkipReason::SessionLimit);
+			}
+			accepts_session
+		}
+	}
+
+	/// Returns function that filters out tasks that are not served by this key server.
+	fn accept_task(&self) -> impl Fn(&BlockchainServiceTask) -> bool {
+		let (context, route) = (self.context.clone(), self.route);
+		let (tenant_quotas, summary) = (self.block.tenant_quotas.clone(), self.summary.clone());
+		move |task| match task_skip_reason(&context, route, &tenant_quotas, task) {
+			Some(skip_reason) => {
+				summary.on_task_skipped(skip_reason);
+				false
+			},
+			None => true,
+		}
+	}
+}
+
+/// Returns reason why task isn't served by the key server, or `None` if task is served.
+fn task_skip_reason<B: Blockchain>(
+	context: &ServiceContext<B>,
+	route: Option<KeyServerHandle>,
+	tenant_quotas: &TenantQuotas,
+	task: &BlockchainServiceTask,
+) -> Option<SkipReason> {
+	let is_routed_here = route
+		.and_then(|route| context.router.as_ref().map(|router| router(task) == route))
+		.unwrap_or(true);
+	if !is_routed_here {
+		return Some(SkipReason::RoutedElsewhere);
+	}
+	let is_quarantined = ServedRequest::from_task(task)
+		.map(|request| context.safe_mode.is_quarantined(&request))
+		.unwrap_or(false);
+	let is_poisoned = task_kind_and_key_id(task)
+		.map(|(task_kind, key_id)| context.poison.is_quarantined(task_kind, &key_id))
+		.unwrap_or(false);
+	if is_quarantined || is_poisoned {
+		return Some(SkipReason::Quarantined);
+	}
+	if !context.key_id_filter.accepts_task(task) {
+		return Some(SkipReason::KeyIdFilter);
+	}
+	if reject_policy_violation(context, route, task) {
+		return Some(SkipReason::PolicyViolation);
+	}
+	// shadow key server executes all tasks, even if primary has already responded
+	let is_served = route.is_some() && context.submitted_responses
+		.as_ref()
+		.map(|submitted_responses| submitted_responses.is_task_served(task))
+		.unwrap_or(false);
+	if is_served {
+		return Some(SkipReason::AlreadyServed);
+	}
+	if context.completed_requests.is_task_completed(task) {
+		return Some(SkipReason::AlreadyCompleted);
+	}
+	if !context.tenants.accepts_task(tenant_quotas, task) {
+		return Some(SkipReason::TenantPolicy);
+	}
+
+	// tasks that are skipped by the following checks don't consume tenant quota
+	let skip_reason = if !context.capacity.accepts_task(task) {
+		Some(SkipReason::NoCapacity)
+	// shadow key server executes all tasks, even if they're shared by several origins
+	} else if route.is_some() && !context.generation_fan_out.accepts_task(task) {
+		Some(SkipReason::SharedGeneration)
+	} else {
+		None
+	};
+	if skip_reason.is_some() {
+		context.tenants.on_task_skipped(tenant_quotas, task);
+	}
+
+	skip_reason
 }
 
-struct PendingTasksIterator<F> {
+/// Build (and emit, if it hasn't been emitted yet) service shutdown report.
+fn report_shutdown<B: Blockchain>(context: &ServiceContext<B>, reason: ShutdownReason) -> ShutdownReport {
+	context.shutdown.report(
+		reason,
+		&context.watchdog,
+		&context.submission_queue,
+		context.submitted_responses.as_deref(),
+	)
+}
+
+/// Reject task that violates policy of its origin. Error response is only submitted
+/// by primary key server. Returns true if task has been rejected.
+fn reject_policy_violation<B: Blockchain>(
+	context: &ServiceContext<B>,
+	route: Option<KeyServerHandle>,
+	task: &BlockchainServiceTask,
+) -> bool {
+	let violation = context.tenants.threshold_policy_violation(
+		task,
+		|| context.blockchain.current_key_servers_set().len(),
+	);
+	let violation = match violation {
+		Some(violation) => violation,
+		None => return false,
+	};
+
+	let request = match ServedRequest::from_task(task) {
+		Some(request) => request,
+		None => return true,
+	};
+	if route.is_some() && !context.completed_requests.is_task_completed(task) {
+		warn!(
+			target: "secretstore",
+			"Rejecting {:?} request: {}",
+			request.task_kind,
+			violation,
+		);
+
+		// request stays pending until error response is accepted => don't reject it again
+		context.completed_requests.on_request_completed(request);
+		let _ = context.policy_rejections.unbounded_send(SecretStoreCall::ServerKeyGenerationError(request.key_id));
+	}
+
+	true
+}
+
+/// Bind persisted state of the service to the chain with given genesis hash.
+fn bind_persistence_to_genesis(service_config: &mut ServiceConfiguration, genesis_hash: &[u8]) -> Result<(), String> {
+	let bind = |persistence: &Arc<dyn Persistence>| -> Result<Arc<dyn Persistence>, String> {
+		Ok(Arc::new(GenesisBoundPersistence::new(persistence.clone(), genesis_hash)?))
+	};
+
+	if let Some(ref mut checkpoint) = service_config.checkpoint {
+		*checkpoint = bind(checkpoint)?;
+	}
+	if let Some(ref mut submitted_responses) = service_config.submitted_responses {
+		*submitted_responses = Arc::new(submitted_responses.bound_to_genesis(genesis_hash)?);
+	}
+	if let Some(ref mut safe_mode) = service_config.safe_mode {
+		safe_mode.persistence = bind(&safe_mode.persistence)?;
+	}
+	if let Some(ref mut poison_quarantine) = service_config.poison_quarantine {
+		poison_quarantine.persistence = bind(&poison_quarantine.persistence)?;
+	}
+	if let Some(ref mut persistence) = service_config.unrequired_responses.persistence {
+		*persistence = bind(persistence)?;
+	}
+	Ok(())
+}
+
+/// Returns number of the block to resume processing from. If recently processed blocks
+/// have been retracted while service has been down, processing is resumed from the last
+/// processed block that is still canonical.
+fn resume_block_number<B: Blockchain>(blockchain: &B, recent_blocks: &[CheckpointedBlock]) -> Option<u64> {
+	let last_processed_block = recent_blocks.first()?;
+	for processed_block in recent_blocks {
+		// if block can't be checked, we assume it is canonical
+		if processed_block.hash.is_empty() {
+			return Some(processed_block.number);
+		}
+		let canonical_hash = match blockchain.block_hash(processed_block.number) {
+			Ok(Some(block_hash)) => blockchain.encode_block_hash(&block_hash),
+			Ok(None) | Err(_) => None,
+		};
+		if canonical_hash.map(|hash| hash != processed_block.hash).unwrap_or(false) {
+			continue;
+		}
+
+		if processed_block.number != last_processed_block.number {
+			warn!(
+				target: "secretstore",
+				"Last processed block {} has been retracted. Resuming from block {}",
+				last_processed_block.number,
+				processed_block.number,
+			);
+		}
+		return Some(processed_block.number);
+	}
+
+	// the first block after start is always scanning pending tasks => requests of retracted
+	// blocks that are still pending on the new fork are not missed
+	let oldest_processed_block = recent_blocks.last()?;
+	warn!(
+		target: "secretstore",
+		"All recently processed blocks ({}..={}) have been retracted. Resuming from block {}",
+		oldest_processed_block.number,
+		last_processed_block.number,
+		oldest_processed_block.number.saturating_sub(1),
+	);
+	Some(oldest_processed_block.number.saturating_sub(1))
+}
+
+/// Returns submission pipeline of the route. Canary key server may use its own pipeline.
+fn route_pipeline(
+	pipeline: &Option<PipelineConfiguration>,
+	canary: Option<&CanaryConfiguration>,
+	route: Option<KeyServerHandle>,
+) -> Option<PipelineConfiguration> {
+	match canary {
+		Some(canary) if route == Some(canary.canary_route) && canary.canary_pipeline.is_some() =>
+			canary.canary_pipeline.clone(),
+		_ => pipeline.clone(),
+	}
+}
+
+/// Returns suffix that is appended to service-level log messages.
+fn instance_suffix(instance_label: &Option<String>) -> String {
+	instance_label
+		.as_ref()
+		.map(|instance_label| format!(" ({})", instance_label))
+		.unwrap_or_default()
+}
+
+/// Returns function that starts SLA tracking of seen tasks.
+fn track_seen_task(sla: Arc<SlaTracker>) -> impl Fn(&BlockchainServiceTask) {
+	move |task| if let Some((task_kind, key_id)) = task_kind_and_key_id(task) {
+		sla.on_request_seen(task_kind, key_id);
+	}
+}
+
+/// Iterator over new tasks that defers remaining tasks to the next block when block
+/// processing budget is exhausted.
+struct BudgetedTasks<Hash> {
+	/// Tasks that are not yet processed. Flag is true for tasks of the current block.
+	tasks: std::vec::IntoIter<(bool, TaskOriginBlock<Hash>, BlockchainServiceTask)>,
+	/// Block processing budget.
+	budget: BlockBudget,
+	/// Deferred work queue.
+	deferred_work: Arc<DeferredWork<Hash>>,
+	/// Key server route that is processing the block.
+	route: Option<KeyServerHandle>,
+}
+
+impl<Hash> Iterator for BudgetedTasks<Hash> {
+	type Item = (bool, TaskOriginBlock<Hash>, BlockchainServiceTask);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.tasks.len() != 0 && self.budget.is_exhausted() {
+			let remaining_tasks = self.tasks.by_ref().map(|(_, task_origin_block, task)| (task_origin_block, task));
+			self.deferred_work.defer(self.route, remaining_tasks);
+			return None;
+		}
+
+		self.tasks.next()
+	}
+}
+
+struct PendingTasksIterator<Hash, F> {
 	pending: VecDeque<BlockchainServiceTask>,
 	range: Range<usize>,
+	throttle: Arc<ScanThrottle>,
+	task_kind: TaskKind,
+	pending_requests: Arc<PendingRequests>,
+	pending_requests_count: usize,
+	escalation: Arc<Escalation>,
+	has_failed_pages: bool,
+	budget: BlockBudget,
+	deferred_work: Arc<DeferredWork<Hash>>,
+	dispatched_tasks: Arc<AtomicUsize>,
+	max_dispatched_tasks: usize,
+	#[cfg(feature = "metrics")]
+	metrics: Option<Arc<Metrics>>,
 	get_pending_tasks: F,
 }
 
-impl<F> Iterator for PendingTasksIterator<F>
+impl<Hash, F> Iterator for PendingTasksIterator<Hash, F>
 	where
-		F: Fn(&mut VecDeque<BlockchainServiceTask>, Range<usize>) -> Result<(), String>,
+		F: Fn(&mut VecDeque<BlockchainServiceTask>, Range<usize>) -> Result<(), ServiceError>,
 {
 	type Item = BlockchainServiceTask;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		const PENDING_RANGE_LENGTH: usize = 16;
-
 		loop {
 			if let Some(pending_task) = self.pending.pop_front() {
 				return Some(pending_task);
@@ -310,20 +2686,77 @@ impl<F> Iterator for PendingTasksIterator<F>
 				return None;
 			}
 
-			let next_range_start = self.range.start + PENDING_RANGE_LENGTH;
+			// tasks are counted after filtering, so reading stops once enough tasks have been
+			// dispatched; remaining pending tasks are dispatched by the next scans
+			if self.dispatched_tasks.load(Ordering::Relaxed) >= self.max_dispatched_tasks {
+				self.range = self.range.end..self.range.end;
+				return None;
+			}
+
+			// remaining pending tasks will be read by the next scan
+			if self.budget.is_exhausted() {
+				self.range = self.range.end..self.range.end;
+				self.deferred_work.on_scan_interrupted();
+				return None;
+			}
+
+			let range_length = self.throttle.page_size();
+			let next_range_start = std::cmp::min(self.range.start.saturating_add(range_length), self.range.end);
 			let pending_range = self.range.start..next_range_start;
-			if let Err(error) = (self.get_pending_tasks)(&mut self.pending, pending_range) {
-				error!(
-					target: "secretstore",
-					"Failed to read pending tasks: {}",
-					error,
-				);
+			let query_start = Instant::now();
+			let query_result = (self.get_pending_tasks)(&mut self.pending, pending_range);
+			self.throttle.on_query_completed(query_start.elapsed());
+			match query_result {
+				Ok(()) => (),
+				// remaining pending tasks will be read by the next scan
+				Err(ref error) if error.is_transient() => {
+					warn!(
+						target: "secretstore",
+						"Failed to read pending {:?} tasks: {}. Retrying at next scan",
+						self.task_kind,
+						error,
+					);
+
+					self.escalation.on_query_error(error);
+					self.range = self.range.end..self.range.end;
+					return None;
+				},
+				// retrying won't help => skip the page and read the rest
+				Err(ref error) => {
+					error!(
+						target: "secretstore",
+						"Failed to read pending {:?} tasks at {:?}: {}. Skipping",
+						self.task_kind,
+						self.range.start..next_range_start,
+						error,
+					);
+
+					self.escalation.on_query_error(error);
+					self.has_failed_pages = true;
+					self.pending.clear();
+					match next_range_start != self.range.end {
+						true => self.range = next_range_start..self.range.end,
+						false => self.range = self.range.end..self.range.end,
+					}
+					continue;
+				},
+			}
+			#[cfg(feature = "metrics")]
+			if let Some(ref metrics) = self.metrics {
+				for task in &self.pending {
+					metrics.on_task_seen(task, TaskSource::PendingScan);
+				}
 			}
 
-			if self.pending.len() == PENDING_RANGE_LENGTH {
+			self.pending_requests_count += self.pending.len();
+			if self.pending.len() == range_length && next_range_start != self.range.end {
 				self.range = next_range_start..self.range.end;
 			} else {
 				self.range = self.range.end..self.range.end;
+				// failed scan tells nothing about number of pending requests
+				if !self.has_failed_pages {
+					self.pending_requests.on_scan_completed(self.task_kind, self.pending_requests_count);
+				}
 			}
 		}
 	}
@@ -0,0 +1,245 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Metrics of tasks processing and transactions submission.
+//!
+//! Metrics are rendered in Prometheus text exposition format, so they could be
+//! served by any HTTP endpoint of the embedder. Every key server route sees the
+//! same tasks, so seen tasks are counted by the first route only. Sessions and
+//! transactions are counted by every key server. If service has instance label,
+//! it is added to all metrics as `instance` label.
+
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+};
+use crate::{
+	BlockchainServiceTask, KeyServerHandle, TaskKind, task_kind_and_key_id,
+	canary::CanaryStats,
+	pending::PendingRequests,
+	queue::SubmissionQueue,
+};
+
+/// Source of the task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TaskSource {
+	/// Task has been read from the new block events.
+	NewBlock,
+	/// Task has been read by pending tasks scan.
+	PendingScan,
+}
+
+/// Service metrics registry.
+pub struct Metrics {
+	/// Service instance label.
+	instance_label: Option<String>,
+	/// Number of requests that are pending on chain.
+	pending_requests: Arc<PendingRequests>,
+	/// Responses that are queued, but not yet submitted.
+	submission_queue: Arc<SubmissionQueue>,
+	/// Canary rollout submission statistics.
+	canary_stats: Option<Arc<CanaryStats>>,
+	/// Number of seen tasks by kind and source.
+	tasks_seen: Mutex<BTreeMap<(TaskKind, TaskSource), u64>>,
+	/// Number of tasks that have been dispatched to key servers.
+	sessions_started: AtomicU64,
+	/// Number of sessions that have completed with success response.
+	sessions_completed: AtomicU64,
+	/// Number of sessions that have completed with error response.
+	sessions_failed: AtomicU64,
+	/// Number of submitted transactions.
+	transactions_submitted: AtomicU64,
+	/// Number of transactions that have failed to submit.
+	transactions_failed: AtomicU64,
+	/// Number of blocks received from the new blocks stream.
+	blocks_received: AtomicU64,
+	/// Number of blocks processed by every key server route.
+	blocks_processed: Mutex<BTreeMap<Option<KeyServerHandle>, u64>>,
+}
+
+impl Metrics {
+	/// Create new metrics registry.
+	pub fn new(
+		instance_label: Option<String>,
+		pending_requests: Arc<PendingRequests>,
+		submission_queue: Arc<SubmissionQueue>,
+		canary_stats: Option<Arc<CanaryStats>>,
+	) -> Self {
+		Metrics {
+			instance_label,
+			pending_requests,
+			submission_queue,
+			canary_stats,
+			tasks_seen: Mutex::new(BTreeMap::new()),
+			sessions_started: AtomicU64::new(0),
+			sessions_completed: AtomicU64::new(0),
+			sessions_failed: AtomicU64::new(0),
+			transactions_submitted: AtomicU64::new(0),
+			transactions_failed: AtomicU64::new(0),
+			blocks_received: AtomicU64::new(0),
+			blocks_processed: Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	/// Called when task is seen.
+	pub fn on_task_seen(&self, task: &BlockchainServiceTask, source: TaskSource) {
+		if let Some((task_kind, _)) = task_kind_and_key_id(task) {
+			*self.tasks_seen
+				.lock()
+				.expect("never panics under lock; qed")
+				.entry((task_kind, source))
+				.or_default() += 1;
+		}
+	}
+
+	/// Called when task is dispatched to the key server.
+	pub fn on_session_started(&self) {
+		self.sessions_started.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Called when session is completed.
+	pub fn on_session_completed(&self, is_failed: bool) {
+		match is_failed {
+			true => self.sessions_failed.fetch_add(1, Ordering::Relaxed),
+			false => self.sessions_completed.fetch_add(1, Ordering::Relaxed),
+		};
+	}
+
+	/// Called when transaction is submitted (or has failed to submit).
+	pub fn on_transaction_submitted(&self, is_failed: bool) {
+		match is_failed {
+			true => self.transactions_failed.fetch_add(1, Ordering::Relaxed),
+			false => self.transactions_submitted.fetch_add(1, Ordering::Relaxed),
+		};
+	}
+
+	/// Called when block is received from the new blocks stream.
+	pub fn on_block_received(&self) {
+		self.blocks_received.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Called when block processing is started by given key server route.
+	pub fn on_block_processed(&self, route: Option<KeyServerHandle>) {
+		*self.blocks_processed.lock().expect("never panics under lock; qed").entry(route).or_default() += 1;
+	}
+
+	/// Render all metrics in Prometheus text exposition format.
+	pub fn render(&self) -> String {
+		let mut output = String::new();
+
+		write_header(&mut output, "secretstore_tasks_seen_total", "counter", "Number of seen tasks.");
+		for ((task_kind, source), count) in self.tasks_seen.lock().expect("never panics under lock; qed").iter() {
+			let source = match *source {
+				TaskSource::NewBlock => "block",
+				TaskSource::PendingScan => "pending",
+			};
+			let labels = self.labels(&[("kind", format!("{:?}", task_kind)), ("source", source.into())]);
+			let _ = writeln!(output, "secretstore_tasks_seen_total{} {}", labels, count);
+		}
+
+		let counters = [
+			("secretstore_sessions_started_total", "Number of tasks dispatched to key servers.", &self.sessions_started),
+			("secretstore_sessions_completed_total", "Number of sessions completed with success.", &self.sessions_completed),
+			("secretstore_sessions_failed_total", "Number of sessions completed with error.", &self.sessions_failed),
+			("secretstore_transactions_submitted_total", "Number of submitted transactions.", &self.transactions_submitted),
+			("secretstore_transactions_failed_total", "Number of failed transaction submissions.", &self.transactions_failed),
+		];
+		for (name, help, counter) in counters.iter() {
+			write_header(&mut output, name, "counter", help);
+			let _ = writeln!(output, "{}{} {}", name, self.labels(&[]), counter.load(Ordering::Relaxed));
+		}
+
+		write_header(&mut output, "secretstore_pending_requests", "gauge", "Number of requests pending on chain.");
+		for (task_kind, count) in self.pending_requests.snapshot() {
+			let labels = self.labels(&[("kind", format!("{:?}", task_kind))]);
+			let _ = writeln!(output, "secretstore_pending_requests{} {}", labels, count);
+		}
+
+		write_header(&mut output, "secretstore_queued_responses", "gauge", "Number of queued responses.");
+		let _ = writeln!(
+			output,
+			"secretstore_queued_responses{} {}",
+			self.labels(&[]),
+			self.submission_queue.snapshot().len(),
+		);
+
+		write_header(
+			&mut output,
+			"secretstore_block_processing_lag",
+			"gauge",
+			"Number of received blocks that are not yet processed by key server.",
+		);
+		let blocks_received = self.blocks_received.load(Ordering::Relaxed);
+		for (route, blocks_processed) in self.blocks_processed.lock().expect("never panics under lock; qed").iter() {
+			let route = route.map(|route| route.to_string()).unwrap_or_else(|| "shadow".into());
+			let _ = writeln!(
+				output,
+				"secretstore_block_processing_lag{} {}",
+				self.labels(&[("route", route)]),
+				blocks_received.saturating_sub(*blocks_processed),
+			);
+		}
+
+		if let Some(ref canary_stats) = self.canary_stats {
+			let counters = [
+				(
+					"secretstore_canary_responses_submitted_total",
+					"Number of responses submitted using canary rollout path.",
+					[("stable", &canary_stats.stable_submitted), ("canary", &canary_stats.canary_submitted)],
+				),
+				(
+					"secretstore_canary_responses_failed_total",
+					"Number of responses that have failed to submit using canary rollout path.",
+					[("stable", &canary_stats.stable_failed), ("canary", &canary_stats.canary_failed)],
+				),
+			];
+			for (name, help, paths) in counters.iter() {
+				write_header(&mut output, name, "counter", help);
+				for (path, counter) in paths.iter() {
+					let labels = self.labels(&[("path", (*path).into())]);
+					let _ = writeln!(output, "{}{} {}", name, labels, counter.load(Ordering::Relaxed));
+				}
+			}
+		}
+
+		output
+	}
+
+	/// Format metric labels, including instance label.
+	fn labels(&self, labels: &[(&str, String)]) -> String {
+		let labels = labels
+			.iter()
+			.map(|(name, value)| (*name, value.as_str()))
+			.chain(self.instance_label.as_deref().map(|instance_label| ("instance", instance_label)))
+			.map(|(name, value)| format!(
+				"{}=\"{}\"",
+				name,
+				value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"),
+			))
+			.collect::<Vec<_>>();
+		match labels.is_empty() {
+			true => String::new(),
+			false => format!("{{{}}}", labels.join(",")),
+		}
+	}
+}
+
+/// Write metric header.
+fn write_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+	let _ = writeln!(output, "# HELP {} {}", name, help);
+	let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
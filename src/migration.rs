@@ -0,0 +1,94 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Automatic key servers set migration.
+//!
+//! Key shares must be redistributed when operators add or remove key servers. The
+//! blockchain service has no task for that, so the on-chain key servers set is
+//! checked at every block and the migration (servers set change) session is started
+//! by the embedder when the set changes.
+
+use std::{
+	collections::BTreeSet,
+	sync::{Arc, Mutex},
+};
+use log::{error, info};
+use parity_secretstore_primitives::KeyServerId;
+use crate::Blockchain;
+
+/// Key servers set migration driver.
+pub trait KeyServersSetMigration: Send + Sync + 'static {
+	/// Start migration from old key servers set to the new set. If migration can't be
+	/// started, it'll be retried at next block.
+	fn start_migration(
+		&self,
+		old_set: &BTreeSet<KeyServerId>,
+		new_set: &BTreeSet<KeyServerId>,
+	) -> Result<(), String>;
+}
+
+/// Key servers set changes monitor.
+pub struct KeyServersSetMonitor {
+	/// Migration driver. If `None`, key servers set isn't monitored.
+	migration: Option<Arc<dyn KeyServersSetMigration>>,
+	/// Key servers set that shares are distributed among.
+	current_set: Mutex<Option<BTreeSet<KeyServerId>>>,
+}
+
+impl KeyServersSetMonitor {
+	/// Create new monitor.
+	pub fn new(migration: Option<Arc<dyn KeyServersSetMigration>>) -> Self {
+		KeyServersSetMonitor {
+			migration,
+			current_set: Mutex::new(None),
+		}
+	}
+
+	/// Called when new block is processed. Starts migration if key servers set has changed.
+	pub fn on_new_block<B: Blockchain>(&self, blockchain: &B) {
+		let migration = match self.migration {
+			Some(ref migration) => migration,
+			None => return,
+		};
+
+		let new_set = blockchain.current_key_servers_set();
+		let mut current_set = self.current_set.lock().expect("never panics under lock; qed");
+		let old_set = match *current_set {
+			Some(ref old_set) if *old_set != new_set => old_set,
+			Some(_) => return,
+			None => {
+				*current_set = Some(new_set);
+				return;
+			},
+		};
+
+		info!(
+			target: "secretstore",
+			"Key servers set has changed from {:?} to {:?}. Starting migration",
+			old_set,
+			new_set,
+		);
+
+		match migration.start_migration(old_set, &new_set) {
+			Ok(()) => *current_set = Some(new_set),
+			Err(error) => error!(
+				target: "secretstore",
+				"Failed to start key servers set migration: {}. Will retry at next block",
+				error,
+			),
+		}
+	}
+}
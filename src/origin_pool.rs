@@ -0,0 +1,130 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-origin transaction pools.
+//!
+//! Requests may come from different origins that expect responses to be submitted
+//! differently (e.g. one pallet accepts signed extrinsics, while EVM contract needs
+//! `evm.call`). This pool selects transaction pool by origin of the request.
+
+use std::{
+	collections::BTreeMap,
+	sync::Arc,
+};
+use parity_secretstore_primitives::Address;
+use crate::{
+	DroppedTransaction, SecretStoreCall, SubmissionPriority, SubmitError, TransactionPool,
+	health::HealthReport,
+	identity::AccountId32,
+};
+
+/// Transaction pool that is used to submit responses.
+pub type DynTransactionPool<Hash> = Arc<dyn TransactionPool<TransactionHash = Hash>>;
+
+/// Transaction pool that selects actual pool by origin of the request.
+pub struct OriginTransactionPool<Hash> {
+	/// Pool that is used for origins without custom pool and for external calls.
+	default: DynTransactionPool<Hash>,
+	/// Custom pools by origin.
+	pools: BTreeMap<Address, DynTransactionPool<Hash>>,
+}
+
+impl<Hash> OriginTransactionPool<Hash> {
+	/// Create new pool with given default pool.
+	pub fn new(default: DynTransactionPool<Hash>) -> Self {
+		OriginTransactionPool {
+			default,
+			pools: BTreeMap::new(),
+		}
+	}
+
+	/// Use given pool to submit responses to requests of given origin.
+	pub fn with_origin_pool(mut self, origin: Address, pool: DynTransactionPool<Hash>) -> Self {
+		self.pools.insert(origin, pool);
+		self
+	}
+
+	/// Returns pool that submits responses to requests of given origin.
+	fn pool(&self, origin: Option<&Address>) -> &DynTransactionPool<Hash> {
+		origin
+			.and_then(|origin| self.pools.get(origin))
+			.unwrap_or(&self.default)
+	}
+}
+
+impl<Hash: std::fmt::Display + 'static> TransactionPool for OriginTransactionPool<Hash> {
+	type TransactionHash = Hash;
+
+	fn submit_transaction(&self, call: SecretStoreCall) -> Result<Self::TransactionHash, SubmitError> {
+		self.default.submit_transaction(call)
+	}
+
+	fn submit_transaction_from(
+		&self,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		self.default.submit_transaction_from(submitter, call)
+	}
+
+	fn submit_transaction_with_priority(
+		&self,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+		priority: SubmissionPriority,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		self.default.submit_transaction_with_priority(submitter, call, priority)
+	}
+
+	fn submit_transaction_for_origin(
+		&self,
+		origin: Option<&Address>,
+		submitter: Option<&AccountId32>,
+		call: SecretStoreCall,
+		priority: SubmissionPriority,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		self.pool(origin).submit_transaction_for_origin(origin, submitter, call, priority)
+	}
+
+	fn submit_batch_for_origin(
+		&self,
+		origin: Option<&Address>,
+		submitter: Option<&AccountId32>,
+		calls: Vec<SecretStoreCall>,
+	) -> Result<Self::TransactionHash, SubmitError> {
+		self.pool(origin).submit_batch_for_origin(origin, submitter, calls)
+	}
+
+	fn routes_by_origin(&self) -> bool {
+		!self.pools.is_empty() || self.default.routes_by_origin()
+	}
+
+	fn submitter_account(&self) -> Option<AccountId32> {
+		self.default.submitter_account()
+	}
+
+	fn submit_health_report(&self, report: &HealthReport) -> Result<Self::TransactionHash, SubmitError> {
+		self.default.submit_health_report(report)
+	}
+
+	fn take_dropped_transactions(&self) -> Vec<DroppedTransaction> {
+		let mut dropped_transactions = self.default.take_dropped_transactions();
+		for pool in self.pools.values() {
+			dropped_transactions.extend(pool.take_dropped_transactions());
+		}
+		dropped_transactions
+	}
+}
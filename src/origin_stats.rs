@@ -0,0 +1,88 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::BTreeMap,
+	sync::Mutex,
+};
+use parity_secretstore_primitives::Address;
+
+/// Label of origins that are not tracked individually.
+pub const OTHER_ORIGINS_LABEL: &str = "other";
+
+/// Counters of single origin.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OriginCounters {
+	/// Number of new tasks seen.
+	pub tasks: u64,
+	/// Number of submitted responses (including error responses).
+	pub responses: u64,
+	/// Number of submitted error responses.
+	pub error_responses: u64,
+}
+
+/// Per-origin tasks and responses statistics.
+///
+/// To keep cardinality of labels bounded, only first `max_origins` origins are
+/// tracked individually. All other origins are counted under the `OTHER_ORIGINS_LABEL`.
+pub struct OriginStatistics {
+	/// Max number of individually tracked origins.
+	max_origins: usize,
+	/// Counters by origin label.
+	counters: Mutex<BTreeMap<String, OriginCounters>>,
+}
+
+impl OriginStatistics {
+	/// Create new statistics.
+	pub fn new(max_origins: usize) -> Self {
+		OriginStatistics {
+			max_origins,
+			counters: Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	/// Called when new task of given origin is seen.
+	pub fn on_task_seen(&self, origin: &Address) {
+		self.update(origin, |counters| counters.tasks += 1);
+	}
+
+	/// Called when response to task of given origin is submitted.
+	pub fn on_response_submitted(&self, origin: &Address, is_error: bool) {
+		self.update(origin, |counters| {
+			counters.responses += 1;
+			if is_error {
+				counters.error_responses += 1;
+			}
+		});
+	}
+
+	/// Returns current counters by origin label.
+	pub fn snapshot(&self) -> BTreeMap<String, OriginCounters> {
+		self.counters.lock().expect("never panics under lock; qed").clone()
+	}
+
+	/// Update counters of given origin.
+	fn update(&self, origin: &Address, update: impl FnOnce(&mut OriginCounters)) {
+		let mut counters = self.counters.lock().expect("never panics under lock; qed");
+		let origin_label = format!("{:?}", origin);
+		let tracked_origins = counters.len() - counters.contains_key(OTHER_ORIGINS_LABEL) as usize;
+		let label = match counters.contains_key(&origin_label) || tracked_origins < self.max_origins {
+			true => origin_label,
+			false => OTHER_ORIGINS_LABEL.into(),
+		};
+		update(counters.entry(label).or_default());
+	}
+}
@@ -0,0 +1,147 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::{BTreeMap, VecDeque},
+	sync::{Arc, Mutex},
+};
+use futures::{StreamExt, stream::BoxStream};
+use log::error;
+use parity_secretstore_blockchain_service::BlockchainServiceTask;
+use crate::{Blockchain, MaybeSecretStoreEvent, TaskKind, capabilities::ALL_TASK_KINDS, error::ServiceError};
+
+/// Number of requests that are pending on chain, sampled by pending tasks scans.
+#[derive(Default)]
+pub struct PendingRequests {
+	/// Number of pending requests by task kind, read by the last completed scan.
+	counts: Mutex<BTreeMap<TaskKind, usize>>,
+}
+
+impl PendingRequests {
+	/// Called when pending tasks scan of given kind is completed.
+	pub fn on_scan_completed(&self, task_kind: TaskKind, pending_requests: usize) {
+		self.counts.lock().expect("never panics under lock; qed").insert(task_kind, pending_requests);
+	}
+
+	/// Returns number of pending requests by task kind. Kinds that have never been
+	/// scanned are missing.
+	pub fn snapshot(&self) -> BTreeMap<TaskKind, usize> {
+		self.counts.lock().expect("never panics under lock; qed").clone()
+	}
+}
+
+/// Returns stream of all tasks that are pending at given block.
+///
+/// Pending tasks are read lazily, page by page, so next page is only read when the
+/// consumer has handled all tasks of the previous page. Queries of the same kind are
+/// stopped on first error.
+pub fn pending_task_stream<B>(
+	blockchain: Arc<B>,
+	block_hash: B::BlockHash,
+	page_size: usize,
+) -> BoxStream<'static, BlockchainServiceTask>
+	where
+		B: Blockchain,
+		B::BlockHash: 'static,
+{
+	futures::stream::iter(pending_tasks(blockchain, block_hash, page_size)).boxed()
+}
+
+/// Returns iterator over all tasks that are pending at given block. Pending tasks are
+/// read lazily, page by page.
+pub fn pending_tasks<B: Blockchain>(
+	blockchain: Arc<B>,
+	block_hash: B::BlockHash,
+	page_size: usize,
+) -> impl Iterator<Item = BlockchainServiceTask> {
+	PendingTaskPages {
+		blockchain,
+		block_hash,
+		page_size: std::cmp::max(page_size, 1),
+		task_kind_index: 0,
+		next_index: 0,
+		page: VecDeque::new(),
+	}
+}
+
+/// Iterator over pending tasks pages of all kinds.
+struct PendingTaskPages<B: Blockchain> {
+	/// Blockchain reference.
+	blockchain: Arc<B>,
+	/// Block where pending tasks are read.
+	block_hash: B::BlockHash,
+	/// Max number of tasks that are read by single query.
+	page_size: usize,
+	/// Index of currently read task kind within `ALL_TASK_KINDS`.
+	task_kind_index: usize,
+	/// Index of the first task of the next page.
+	next_index: usize,
+	/// Tasks of current page that are not yet yielded.
+	page: VecDeque<BlockchainServiceTask>,
+}
+
+impl<B: Blockchain> PendingTaskPages<B> {
+	/// Read page of pending tasks of given kind.
+	fn read_page(&self, task_kind: TaskKind) -> Result<VecDeque<BlockchainServiceTask>, ServiceError> {
+		let range = self.next_index..self.next_index.saturating_add(self.page_size);
+		let events = match task_kind {
+			TaskKind::ServerKeyGeneration => self.blockchain.server_key_generation_tasks(&self.block_hash, range)?,
+			TaskKind::ServerKeyRetrieval => self.blockchain.server_key_retrieval_tasks(&self.block_hash, range)?,
+			TaskKind::DocumentKeyStore => self.blockchain.document_key_store_tasks(&self.block_hash, range)?,
+			TaskKind::DocumentKeyShadowRetrieval =>
+				self.blockchain.document_key_shadow_retrieval_tasks(&self.block_hash, range)?,
+		};
+
+		Ok(events.into_iter().filter_map(MaybeSecretStoreEvent::as_secret_store_event).collect())
+	}
+}
+
+impl<B: Blockchain> Iterator for PendingTaskPages<B> {
+	type Item = BlockchainServiceTask;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some(pending_task) = self.page.pop_front() {
+				return Some(pending_task);
+			}
+
+			let task_kind = *ALL_TASK_KINDS.get(self.task_kind_index)?;
+			match self.read_page(task_kind) {
+				Ok(page) => {
+					let is_last_page = page.len() < self.page_size;
+					self.page = page;
+					if is_last_page {
+						self.task_kind_index += 1;
+						self.next_index = 0;
+					} else {
+						self.next_index += self.page_size;
+					}
+				},
+				Err(error) => {
+					error!(
+						target: "secretstore",
+						"Failed to read pending {:?} tasks: {}",
+						task_kind,
+						error,
+					);
+
+					self.task_kind_index += 1;
+					self.next_index = 0;
+				},
+			}
+		}
+	}
+}
@@ -0,0 +1,469 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+};
+use log::warn;
+
+/// Key of the genesis hash that the persisted state is bound to.
+const GENESIS_HASH_KEY: &[u8] = b"secretstore:genesis_hash";
+/// Current version of state snapshot.
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// Key-value storage used to persist service state (checkpoints, queues, ...).
+pub trait Persistence: Send + Sync + 'static {
+	/// Read value.
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+	/// Insert or update value.
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), String>;
+	/// Remove value.
+	fn remove(&self, key: &[u8]) -> Result<(), String>;
+	/// Remove all values.
+	fn clear(&self) -> Result<(), String>;
+	/// Returns keys of all stored values. Only used to export state.
+	fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+		Err("listing keys is not supported by the persistence".into())
+	}
+}
+
+/// Persistence that is bound to the chain with given genesis hash.
+///
+/// If the chain has been re-genesised (or we're connected to another chain), the
+/// state of the old chain must not be replayed. So the whole state is dropped when
+/// genesis hash changes. State that isn't yet bound to any chain is adopted.
+///
+/// The service binds its own stores (checkpoint, submitted responses, safe mode,
+/// poison quarantine and unrequired responses) to the chain when it is started.
+pub struct GenesisBoundPersistence<P> {
+	/// Underlying persistence.
+	persistence: P,
+	/// Genesis hash of the chain.
+	genesis_hash: Vec<u8>,
+}
+
+/// In-memory persistence. State is lost on restart.
+#[derive(Default)]
+pub struct InMemoryPersistence {
+	/// Stored values.
+	values: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+/// File-based persistence. All values are kept in memory and the whole file is
+/// rewritten (atomically) on every update, so it is only suitable for small states.
+pub struct FilePersistence {
+	/// Path to the file.
+	path: PathBuf,
+	/// Stored values.
+	values: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl<P: Persistence> GenesisBoundPersistence<P> {
+	/// Bind persistence to the chain with given genesis hash.
+	pub fn new(persistence: P, genesis_hash: &[u8]) -> Result<Self, String> {
+		match persistence.get(GENESIS_HASH_KEY)? {
+			Some(ref stored_genesis_hash) if stored_genesis_hash.as_slice() == genesis_hash => (),
+			Some(stored_genesis_hash) => {
+				warn!(
+					target: "secretstore",
+					"Genesis hash has changed: {:?} -> {:?}. Dropping all persisted state of the old chain",
+					stored_genesis_hash,
+					genesis_hash,
+				);
+
+				persistence.clear()?;
+				persistence.put(GENESIS_HASH_KEY, genesis_hash.to_vec())?;
+			},
+			None => persistence.put(GENESIS_HASH_KEY, genesis_hash.to_vec())?,
+		}
+
+		Ok(GenesisBoundPersistence {
+			persistence,
+			genesis_hash: genesis_hash.to_vec(),
+		})
+	}
+}
+
+impl<P: Persistence> Persistence for GenesisBoundPersistence<P> {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+		self.persistence.get(key)
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), String> {
+		self.persistence.put(key, value)
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<(), String> {
+		self.persistence.remove(key)
+	}
+
+	fn clear(&self) -> Result<(), String> {
+		self.persistence.clear()?;
+		self.persistence.put(GENESIS_HASH_KEY, self.genesis_hash.clone())
+	}
+
+	fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+		// genesis hash is not the part of exported state
+		Ok(self.persistence.keys()?.into_iter().filter(|key| !is_internal_key(key)).collect())
+	}
+}
+
+impl<P: Persistence + ?Sized> Persistence for Arc<P> {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+		(**self).get(key)
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), String> {
+		(**self).put(key, value)
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<(), String> {
+		(**self).remove(key)
+	}
+
+	fn clear(&self) -> Result<(), String> {
+		(**self).clear()
+	}
+
+	fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+		(**self).keys()
+	}
+}
+
+impl Persistence for InMemoryPersistence {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+		Ok(self.values.read().map_err(|_| String::from("poisoned lock"))?.get(key).cloned())
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), String> {
+		self.values.write().map_err(|_| String::from("poisoned lock"))?.insert(key.to_vec(), value);
+		Ok(())
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<(), String> {
+		self.values.write().map_err(|_| String::from("poisoned lock"))?.remove(key);
+		Ok(())
+	}
+
+	fn clear(&self) -> Result<(), String> {
+		self.values.write().map_err(|_| String::from("poisoned lock"))?.clear();
+		Ok(())
+	}
+
+	fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+		Ok(self.values.read().map_err(|_| String::from("poisoned lock"))?.keys().cloned().collect())
+	}
+}
+
+impl FilePersistence {
+	/// Open persistence, stored in given file. The file is created on first update.
+	pub fn open(path: &Path) -> Result<Self, String> {
+		let values = match std::fs::read(path) {
+			Ok(contents) => decode_values(&contents)
+				.ok_or_else(|| format!("persistence file {} is corrupted", path.display()))?,
+			Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+			Err(error) => return Err(format!("failed to read persistence file {}: {}", path.display(), error)),
+		};
+
+		Ok(FilePersistence {
+			path: path.to_path_buf(),
+			values: RwLock::new(values),
+		})
+	}
+
+	/// Update values and write them to the file.
+	fn update(&self, update: impl FnOnce(&mut BTreeMap<Vec<u8>, Vec<u8>>)) -> Result<(), String> {
+		let mut values = self.values.write().map_err(|_| String::from("poisoned lock"))?;
+		let mut new_values = values.clone();
+		update(&mut new_values);
+
+		let mut temp_path = self.path.clone().into_os_string();
+		temp_path.push(".tmp");
+		std::fs::write(&temp_path, encode_values(&new_values))
+			.and_then(|_| std::fs::rename(&temp_path, &self.path))
+			.map_err(|error| format!("failed to write persistence file {}: {}", self.path.display(), error))?;
+
+		*values = new_values;
+		Ok(())
+	}
+}
+
+impl Persistence for FilePersistence {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+		Ok(self.values.read().map_err(|_| String::from("poisoned lock"))?.get(key).cloned())
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), String> {
+		self.update(|values| { values.insert(key.to_vec(), value); })
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<(), String> {
+		self.update(|values| { values.remove(key); })
+	}
+
+	fn clear(&self) -> Result<(), String> {
+		self.update(|values| values.clear())
+	}
+
+	fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+		Ok(self.values.read().map_err(|_| String::from("poisoned lock"))?.keys().cloned().collect())
+	}
+}
+
+/// Export all persisted state (checkpoints, queued responses, dedup records, ...) to
+/// versioned snapshot, that could be imported at other machine. Snapshot holds
+/// decrypted values, so it must be protected the same way the keys are.
+pub fn export_state<P: Persistence + ?Sized>(persistence: &P) -> Result<Vec<u8>, String> {
+	let mut snapshot = vec![SNAPSHOT_VERSION];
+	snapshot.extend(encode_values(&read_values(persistence)?));
+	Ok(snapshot)
+}
+
+/// Replace all persisted state with state from the snapshot. Values that are missing
+/// from the snapshot are removed after all snapshot values are written. If import
+/// fails, previous state is restored.
+pub fn import_state<P: Persistence + ?Sized>(persistence: &P, snapshot: &[u8]) -> Result<(), String> {
+	match snapshot.first() {
+		Some(&SNAPSHOT_VERSION) => (),
+		Some(version) => return Err(format!("unsupported state snapshot version: {}", version)),
+		None => return Err("empty state snapshot".into()),
+	}
+
+	let mut values = decode_values(&snapshot[1..]).ok_or_else(|| String::from("state snapshot is corrupted"))?;
+	values.retain(|key, _| !is_internal_key(key));
+	let previous_values = read_values(persistence)?;
+	replace_values(persistence, &previous_values, &values).map_err(|error| {
+		match replace_values(persistence, &values, &previous_values) {
+			Ok(()) => format!("failed to import state: {}. Previous state is restored", error),
+			Err(restore_error) => format!(
+				"failed to import state: {}. Failed to restore previous state: {}",
+				error,
+				restore_error,
+			),
+		}
+	})
+}
+
+/// Returns true if value with given key is internal to persistence and isn't the part
+/// of the service state.
+fn is_internal_key(key: &[u8]) -> bool {
+	key == GENESIS_HASH_KEY
+}
+
+/// Read all values, except for internal values.
+fn read_values<P: Persistence + ?Sized>(persistence: &P) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, String> {
+	let mut values = BTreeMap::new();
+	for key in persistence.keys()?.into_iter().filter(|key| !is_internal_key(key)) {
+		// value could have been removed while we were reading
+		if let Some(value) = persistence.get(&key)? {
+			values.insert(key, value);
+		}
+	}
+	Ok(values)
+}
+
+/// Write new values and remove old values that are missing from new values.
+fn replace_values<P: Persistence + ?Sized>(
+	persistence: &P,
+	old_values: &BTreeMap<Vec<u8>, Vec<u8>>,
+	new_values: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<(), String> {
+	for (key, value) in new_values {
+		persistence.put(key, value.clone())?;
+	}
+	for key in old_values.keys().filter(|key| !new_values.contains_key(*key)) {
+		persistence.remove(key)?;
+	}
+	Ok(())
+}
+
+/// Encode values as sequence of length-prefixed keys and values.
+fn encode_values(values: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+	let mut encoded = Vec::new();
+	for (key, value) in values {
+		encoded.extend_from_slice(&(key.len() as u32).to_be_bytes());
+		encoded.extend_from_slice(key);
+		encoded.extend_from_slice(&(value.len() as u32).to_be_bytes());
+		encoded.extend_from_slice(value);
+	}
+	encoded
+}
+
+/// Decode values, encoded with `encode_values`.
+fn decode_values(mut encoded: &[u8]) -> Option<BTreeMap<Vec<u8>, Vec<u8>>> {
+	fn decode_item<'a>(encoded: &mut &'a [u8]) -> Option<&'a [u8]> {
+		if encoded.len() < 4 {
+			return None;
+		}
+		let mut len = [0u8; 4];
+		len.copy_from_slice(&encoded[..4]);
+		let len = u32::from_be_bytes(len) as usize;
+		if encoded.len() < 4 + len {
+			return None;
+		}
+		let item = &encoded[4..4 + len];
+		*encoded = &encoded[4 + len..];
+		Some(item)
+	}
+
+	let mut values = BTreeMap::new();
+	while !encoded.is_empty() {
+		let key = decode_item(&mut encoded)?;
+		let value = decode_item(&mut encoded)?;
+		values.insert(key.to_vec(), value.to_vec());
+	}
+	Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unbound_persistence_is_adopted() {
+		let persistence = InMemoryPersistence::default();
+		persistence.put(b"key", b"value".to_vec()).unwrap();
+
+		let persistence = GenesisBoundPersistence::new(persistence, b"genesis").unwrap();
+		assert_eq!(persistence.get(b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(persistence.get(GENESIS_HASH_KEY).unwrap(), Some(b"genesis".to_vec()));
+	}
+
+	#[test]
+	fn persistence_is_preserved_when_genesis_is_the_same() {
+		let persistence = Arc::new(InMemoryPersistence::default());
+		GenesisBoundPersistence::new(persistence.clone(), b"genesis").unwrap()
+			.put(b"key", b"value".to_vec()).unwrap();
+
+		let persistence = GenesisBoundPersistence::new(persistence, b"genesis").unwrap();
+		assert_eq!(persistence.get(b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn persistence_is_cleared_when_genesis_changes() {
+		let persistence = Arc::new(InMemoryPersistence::default());
+		GenesisBoundPersistence::new(persistence.clone(), b"genesis").unwrap()
+			.put(b"key", b"value".to_vec()).unwrap();
+
+		let persistence = GenesisBoundPersistence::new(persistence, b"other-genesis").unwrap();
+		assert_eq!(persistence.get(b"key").unwrap(), None);
+		assert_eq!(persistence.get(GENESIS_HASH_KEY).unwrap(), Some(b"other-genesis".to_vec()));
+	}
+
+	#[test]
+	fn genesis_hash_survives_clear() {
+		let persistence = GenesisBoundPersistence::new(InMemoryPersistence::default(), b"genesis").unwrap();
+		persistence.put(b"key", b"value".to_vec()).unwrap();
+		persistence.clear().unwrap();
+
+		assert_eq!(persistence.keys().unwrap(), Vec::<Vec<u8>>::new());
+		assert_eq!(persistence.get(GENESIS_HASH_KEY).unwrap(), Some(b"genesis".to_vec()));
+	}
+
+	/// Persistence that fails to write given key.
+	struct FailingPersistence {
+		/// Underlying persistence.
+		persistence: InMemoryPersistence,
+		/// Key that can't be written.
+		failing_key: Vec<u8>,
+	}
+
+	impl Persistence for FailingPersistence {
+		fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+			self.persistence.get(key)
+		}
+
+		fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), String> {
+			match key == self.failing_key.as_slice() {
+				true => Err("write failed".into()),
+				false => self.persistence.put(key, value),
+			}
+		}
+
+		fn remove(&self, key: &[u8]) -> Result<(), String> {
+			self.persistence.remove(key)
+		}
+
+		fn clear(&self) -> Result<(), String> {
+			self.persistence.clear()
+		}
+
+		fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+			self.persistence.keys()
+		}
+	}
+
+	#[test]
+	fn values_encoding_round_trip() {
+		let mut values = BTreeMap::new();
+		values.insert(b"key1".to_vec(), b"value1".to_vec());
+		values.insert(b"key2".to_vec(), Vec::new());
+		values.insert(Vec::new(), b"value3".to_vec());
+
+		assert_eq!(decode_values(&encode_values(&values)), Some(values.clone()));
+		assert_eq!(decode_values(&encode_values(&BTreeMap::new())), Some(BTreeMap::new()));
+
+		let encoded = encode_values(&values);
+		assert_eq!(decode_values(&encoded[..encoded.len() - 1]), None);
+	}
+
+	#[test]
+	fn state_is_exported_and_imported() {
+		let source = GenesisBoundPersistence::new(InMemoryPersistence::default(), b"genesis").unwrap();
+		source.put(b"key1", b"value1".to_vec()).unwrap();
+		source.put(b"key2", b"value2".to_vec()).unwrap();
+		let snapshot = export_state(&source).unwrap();
+
+		let target = GenesisBoundPersistence::new(InMemoryPersistence::default(), b"other-genesis").unwrap();
+		target.put(b"stale", b"value".to_vec()).unwrap();
+		import_state(&target, &snapshot).unwrap();
+
+		assert_eq!(target.keys().unwrap(), vec![b"key1".to_vec(), b"key2".to_vec()]);
+		assert_eq!(target.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(target.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+		assert_eq!(target.get(GENESIS_HASH_KEY).unwrap(), Some(b"other-genesis".to_vec()));
+	}
+
+	#[test]
+	fn invalid_snapshot_is_rejected() {
+		let persistence = InMemoryPersistence::default();
+		assert!(import_state(&persistence, &[]).is_err());
+		assert!(import_state(&persistence, &[SNAPSHOT_VERSION + 1]).is_err());
+		assert!(import_state(&persistence, &[SNAPSHOT_VERSION, 0, 0, 0, 1]).is_err());
+	}
+
+	#[test]
+	fn previous_state_is_restored_when_import_fails() {
+		let source = InMemoryPersistence::default();
+		source.put(b"key1", b"new-value1".to_vec()).unwrap();
+		source.put(b"key2", b"new-value2".to_vec()).unwrap();
+		let snapshot = export_state(&source).unwrap();
+
+		let target = FailingPersistence {
+			persistence: InMemoryPersistence::default(),
+			failing_key: b"key2".to_vec(),
+		};
+		target.put(b"key1", b"value1".to_vec()).unwrap();
+		target.put(b"key3", b"value3".to_vec()).unwrap();
+		assert!(import_state(&target, &snapshot).is_err());
+
+		assert_eq!(target.keys().unwrap(), vec![b"key1".to_vec(), b"key3".to_vec()]);
+		assert_eq!(target.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(target.get(b"key3").unwrap(), Some(b"value3".to_vec()));
+	}
+}
@@ -0,0 +1,207 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Pipelined responses submission.
+//!
+//! Blocks are processed by the blockchain service one-by-one and responses are
+//! submitted from the threads that are completing sessions. Submission involves
+//! several chain queries and transaction pool calls, so it may delay processing of
+//! the next blocks. When pipeline is enabled, responses are handed over to the
+//! pool of submission workers via bounded queues. All responses for the same key
+//! are submitted by the same worker, so their relative order is preserved.
+
+use std::{
+	collections::hash_map::DefaultHasher,
+	hash::{Hash, Hasher},
+	sync::{Arc, mpsc::{SyncSender, sync_channel}},
+};
+use log::error;
+use parity_secretstore_blockchain_service::TransactionPool as BlockchainServiceTransactionPool;
+use parity_secretstore_primitives::{
+	Address, ServerKeyId,
+	key_server::{
+		DocumentKeyCommonRetrievalArtifacts, DocumentKeyShadowRetrievalArtifacts,
+		ServerKeyGenerationArtifacts, ServerKeyRetrievalArtifacts,
+	},
+	requester::Requester,
+};
+
+/// Submission job.
+type Job<T> = Box<dyn FnOnce(&T) + Send>;
+
+/// Responses submission pipeline configuration.
+#[derive(Debug, Clone)]
+pub struct PipelineConfiguration {
+	/// Number of submission workers.
+	pub submit_workers: usize,
+	/// Max number of responses queued by every worker. When the queue is full,
+	/// session thread is blocked until there's a room for the response.
+	pub queue_size: usize,
+}
+
+/// Transaction pool that is (optionally) submitting responses from worker threads.
+pub struct PipelinedTransactionPool<T> {
+	/// Inner transaction pool.
+	pool: Arc<T>,
+	/// Workers queues. Empty if pipeline is disabled.
+	workers: Vec<SyncSender<Job<T>>>,
+}
+
+impl Default for PipelineConfiguration {
+	fn default() -> Self {
+		PipelineConfiguration {
+			submit_workers: 4,
+			queue_size: 1_024,
+		}
+	}
+}
+
+impl<T: BlockchainServiceTransactionPool> PipelinedTransactionPool<T> {
+	/// Create new transaction pool and start submission workers. If configuration
+	/// is `None`, responses are submitted right away by the calling thread.
+	pub fn new(
+		pool: Arc<T>,
+		config: Option<PipelineConfiguration>,
+		instance_label: Option<&str>,
+	) -> Result<Self, String> {
+		let config = match config {
+			Some(config) => config,
+			None => return Ok(PipelinedTransactionPool { pool, workers: Vec::new() }),
+		};
+
+		let mut workers = Vec::with_capacity(config.submit_workers);
+		for worker_index in 0..std::cmp::max(config.submit_workers, 1) {
+			let (sender, receiver) = sync_channel::<Job<T>>(config.queue_size);
+			let worker_pool = pool.clone();
+			std::thread::Builder::new()
+				.name(match instance_label {
+					Some(instance_label) => format!("secretstore-{}-submit-{}", instance_label, worker_index),
+					None => format!("secretstore-submit-{}", worker_index),
+				})
+				.spawn(move || for job in receiver {
+					job(&*worker_pool);
+				})
+				.map_err(|error| format!("failed to start submission worker: {}", error))?;
+			workers.push(sender);
+		}
+
+		Ok(PipelinedTransactionPool { pool, workers })
+	}
+
+	/// Submit response for given key, either right away, or using one of workers.
+	fn dispatch(&self, key_id: &ServerKeyId, job: impl FnOnce(&T) + Send + 'static) {
+		if self.workers.is_empty() {
+			return job(&*self.pool);
+		}
+
+		let mut hasher = DefaultHasher::new();
+		key_id.hash(&mut hasher);
+		let worker_index = (hasher.finish() % self.workers.len() as u64) as usize;
+		if self.workers[worker_index].send(Box::new(job)).is_err() {
+			error!(
+				target: "secretstore",
+				"Submission worker {} has stopped. Response is dropped",
+				worker_index,
+			);
+		}
+	}
+}
+
+impl<T: BlockchainServiceTransactionPool> BlockchainServiceTransactionPool for PipelinedTransactionPool<T> {
+	fn publish_generated_server_key(
+		&self,
+		origin: Address,
+		key_id: ServerKeyId,
+		artifacts: ServerKeyGenerationArtifacts,
+	) {
+		self.dispatch(&key_id, move |pool| pool.publish_generated_server_key(origin, key_id, artifacts))
+	}
+
+	fn publish_server_key_generation_error(&self, origin: Address, key_id: ServerKeyId) {
+		self.dispatch(&key_id, move |pool| pool.publish_server_key_generation_error(origin, key_id))
+	}
+
+	fn publish_retrieved_server_key(
+		&self,
+		origin: Address,
+		key_id: ServerKeyId,
+		artifacts: ServerKeyRetrievalArtifacts,
+	) {
+		self.dispatch(&key_id, move |pool| pool.publish_retrieved_server_key(origin, key_id, artifacts))
+	}
+
+	fn publish_server_key_retrieval_error(&self, origin: Address, key_id: ServerKeyId) {
+		self.dispatch(&key_id, move |pool| pool.publish_server_key_retrieval_error(origin, key_id))
+	}
+
+	fn publish_stored_document_key(&self, origin: Address, key_id: ServerKeyId) {
+		self.dispatch(&key_id, move |pool| pool.publish_stored_document_key(origin, key_id))
+	}
+
+	fn publish_document_key_store_error(&self, origin: Address, key_id: ServerKeyId) {
+		self.dispatch(&key_id, move |pool| pool.publish_document_key_store_error(origin, key_id))
+	}
+
+	fn publish_retrieved_document_key_common(
+		&self,
+		origin: Address,
+		key_id: ServerKeyId,
+		requester: Requester,
+		artifacts: DocumentKeyCommonRetrievalArtifacts,
+	) {
+		self.dispatch(
+			&key_id,
+			move |pool| pool.publish_retrieved_document_key_common(origin, key_id, requester, artifacts),
+		)
+	}
+
+	fn publish_document_key_common_retrieval_error(
+		&self,
+		origin: Address,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) {
+		self.dispatch(
+			&key_id,
+			move |pool| pool.publish_document_key_common_retrieval_error(origin, key_id, requester),
+		)
+	}
+
+	fn publish_retrieved_document_key_personal(
+		&self,
+		origin: Address,
+		key_id: ServerKeyId,
+		requester: Requester,
+		artifacts: DocumentKeyShadowRetrievalArtifacts,
+	) {
+		self.dispatch(
+			&key_id,
+			move |pool| pool.publish_retrieved_document_key_personal(origin, key_id, requester, artifacts),
+		)
+	}
+
+	fn publish_document_key_personal_retrieval_error(
+		&self,
+		origin: Address,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) {
+		self.dispatch(
+			&key_id,
+			move |pool| pool.publish_document_key_personal_retrieval_error(origin, key_id, requester),
+		)
+	}
+}
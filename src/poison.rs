@@ -0,0 +1,432 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Quarantine of poison tasks.
+//!
+//! Some tasks fail every time they're processed: event can't be decoded by this
+//! version of the service, session is wedged every time it is started, or response
+//! transaction is rejected by the pool. Such tasks are retried forever, wasting
+//! resources and flooding logs. So tasks that have failed too many times are moved
+//! to the (persisted) quarantine and skipped. Operator may inspect quarantined
+//! items and retry or discard them once the fix is deployed. Tasks that are
+//! quarantined by the safe mode (because they have crashed the service) are
+//! reported here too.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	convert::TryInto,
+	sync::{Arc, Mutex},
+};
+use log::{error, warn};
+use parity_secretstore_primitives::ServerKeyId;
+use crate::{
+	TaskKind,
+	capabilities::ALL_TASK_KINDS,
+	persistence::Persistence,
+};
+
+/// Key of the quarantined items record.
+const ITEMS_KEY: &[u8] = b"secretstore:poison:items";
+/// Key of the discarded tasks record.
+const DISCARDED_TASKS_KEY: &[u8] = b"secretstore:poison:discarded";
+/// Size of the fixed part of single encoded item.
+const ENCODED_ITEM_HEADER_SIZE: usize = 8 + 1 + 1 + 1 + 32 + 1 + 8 + 4 + 4;
+/// Size of single encoded discarded task.
+const ENCODED_TASK_SIZE: usize = 1 + 32;
+
+/// Id of the quarantined item.
+pub type PoisonedItemId = u64;
+
+/// Called when item is quarantined.
+pub type PoisonHandler = Arc<dyn Fn(PoisonedItemId, &PoisonedItem) + Send + Sync>;
+
+/// Poison tasks quarantine configuration.
+#[derive(Clone)]
+pub struct PoisonQuarantineConfiguration {
+	/// Persistence of quarantined items.
+	pub persistence: Arc<dyn Persistence>,
+	/// Task is quarantined after it has failed this number of times at the same stage.
+	/// Events that can't be decoded are quarantined immediately.
+	pub max_failures: u32,
+	/// Quarantine alert handler.
+	pub handler: Option<PoisonHandler>,
+}
+
+/// Processing stage where item has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureStage {
+	/// Event can't be decoded.
+	Decode,
+	/// Key server session has been wedged.
+	Execute,
+	/// Response transaction has been rejected.
+	Submit,
+	/// Service has crashed while processing the task.
+	Crash,
+}
+
+/// Item that has been quarantined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoisonedItem {
+	/// Stage where item has failed.
+	pub stage: FailureStage,
+	/// Kind and key id of the task. `None` for events that can't be decoded.
+	pub task: Option<(TaskKind, ServerKeyId)>,
+	/// Number of the block where undecodable event has been found.
+	pub block_number: Option<u64>,
+	/// Number of failures.
+	pub failures: u32,
+	/// Description of the last failure.
+	pub last_error: String,
+}
+
+/// Poison tasks quarantine.
+pub struct PoisonQuarantine {
+	/// Configuration. If `None`, failures are not tracked.
+	config: Option<PoisonQuarantineConfiguration>,
+	/// Quarantine state.
+	state: Mutex<PoisonQuarantineState>,
+}
+
+/// Poison tasks quarantine state.
+#[derive(Default)]
+struct PoisonQuarantineState {
+	/// Id of the next quarantined item.
+	next_id: PoisonedItemId,
+	/// Number of failures of tasks that are not yet quarantined.
+	failures: BTreeMap<(FailureStage, TaskKind, ServerKeyId), u32>,
+	/// Quarantined items.
+	items: BTreeMap<PoisonedItemId, PoisonedItem>,
+	/// Tasks that are skipped until request is completed.
+	discarded: BTreeSet<(TaskKind, ServerKeyId)>,
+}
+
+impl PoisonQuarantine {
+	/// Create quarantine, restoring items that have been quarantined before restart.
+	pub fn new(config: Option<PoisonQuarantineConfiguration>) -> Self {
+		let mut state = PoisonQuarantineState::default();
+		if let Some(ref config) = config {
+			let read = |key: &[u8]| config.persistence.get(key).map_err(|error| warn!(
+				target: "secretstore",
+				"Failed to read quarantined items: {}",
+				error,
+			)).ok().flatten().unwrap_or_default();
+			state.items = decode_items(&read(ITEMS_KEY));
+			state.discarded = decode_discarded_tasks(&read(DISCARDED_TASKS_KEY));
+			state.next_id = state.items.keys().next_back().map(|id| id + 1).unwrap_or(0);
+		}
+
+		PoisonQuarantine {
+			config,
+			state: Mutex::new(state),
+		}
+	}
+
+	/// Returns true if failures are tracked.
+	pub fn is_enabled(&self) -> bool {
+		self.config.is_some()
+	}
+
+	/// Returns true if task is quarantined (or discarded).
+	pub fn is_quarantined(&self, task_kind: TaskKind, key_id: &ServerKeyId) -> bool {
+		if self.config.is_none() {
+			return false;
+		}
+
+		let state = self.state.lock().expect("never panics under lock; qed");
+		state.discarded.contains(&(task_kind, *key_id))
+			|| state.items.values().any(|item| item.task == Some((task_kind, *key_id)))
+	}
+
+	/// Called when SecretStore runtime module event can't be decoded.
+	pub fn on_decode_failure(&self, block_number: Option<u64>, description: String) {
+		if self.config.is_none() {
+			return;
+		}
+
+		self.quarantine(PoisonedItem {
+			stage: FailureStage::Decode,
+			task: None,
+			block_number,
+			failures: 1,
+			last_error: description,
+		});
+	}
+
+	/// Called when task has failed at given stage.
+	pub fn on_task_failure(&self, stage: FailureStage, task_kind: TaskKind, key_id: ServerKeyId, error: &str) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+
+		let failures = {
+			let mut state = self.state.lock().expect("never panics under lock; qed");
+			let failures = state.failures.entry((stage, task_kind, key_id)).or_insert(0);
+			*failures += 1;
+			match *failures >= config.max_failures {
+				true => state.failures.remove(&(stage, task_kind, key_id)),
+				false => None,
+			}
+		};
+
+		if let Some(failures) = failures {
+			self.quarantine(PoisonedItem {
+				stage,
+				task: Some((task_kind, key_id)),
+				block_number: None,
+				failures,
+				last_error: error.into(),
+			});
+		}
+	}
+
+	/// Called when task is quarantined by the safe mode, because it has crashed the
+	/// service given number of times. Task that is already quarantined after previous
+	/// crashes isn't quarantined again.
+	pub fn on_crash(&self, task_kind: TaskKind, key_id: ServerKeyId, crashes: u32) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let is_quarantined = self.state
+			.lock()
+			.expect("never panics under lock; qed")
+			.items
+			.values()
+			.any(|item| item.stage == FailureStage::Crash && item.task == Some((task_kind, key_id)));
+		if is_quarantined {
+			return;
+		}
+
+		self.quarantine(PoisonedItem {
+			stage: FailureStage::Crash,
+			task: Some((task_kind, key_id)),
+			block_number: None,
+			failures: crashes,
+			last_error: "service has crashed while processing the task".into(),
+		});
+	}
+
+	/// Called when request is completed. Task is released from quarantine.
+	pub fn on_request_completed(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		state.failures.retain(|(_, failed_task_kind, failed_key_id), _|
+			*failed_task_kind != task_kind || *failed_key_id != key_id);
+		let items_count = state.items.len();
+		state.items.retain(|_, item| item.task != Some((task_kind, key_id)));
+		let is_discarded = state.discarded.remove(&(task_kind, key_id));
+		if is_discarded || items_count != state.items.len() {
+			self.persist(&state);
+		}
+	}
+
+	/// Returns all quarantined items.
+	pub fn items(&self) -> BTreeMap<PoisonedItemId, PoisonedItem> {
+		self.state.lock().expect("never panics under lock; qed").items.clone()
+	}
+
+	/// Release item from quarantine, so that it is processed again.
+	pub fn retry(&self, id: PoisonedItemId) -> Option<PoisonedItem> {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let item = state.items.remove(&id)?;
+		self.persist(&state);
+		Some(item)
+	}
+
+	/// Remove item from quarantine. Discarded task is skipped until request is completed.
+	pub fn discard(&self, id: PoisonedItemId) -> Option<PoisonedItem> {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let item = state.items.remove(&id)?;
+		if let Some(task) = item.task {
+			state.discarded.insert(task);
+		}
+		self.persist(&state);
+		Some(item)
+	}
+
+	/// Put item to the quarantine.
+	fn quarantine(&self, item: PoisonedItem) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+
+		error!(
+			target: "secretstore",
+			"Quarantining item that has failed {} times at {:?} stage: {}",
+			item.failures,
+			item.stage,
+			item.last_error,
+		);
+
+		let id = {
+			let mut state = self.state.lock().expect("never panics under lock; qed");
+			let id = state.next_id;
+			state.next_id += 1;
+			state.items.insert(id, item.clone());
+			self.persist(&state);
+			id
+		};
+
+		if let Some(ref handler) = config.handler {
+			handler(id, &item);
+		}
+	}
+
+	/// Persist quarantined items and discarded tasks.
+	fn persist(&self, state: &PoisonQuarantineState) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+
+		let results = vec![
+			config.persistence.put(ITEMS_KEY, encode_items(&state.items)),
+			config.persistence.put(DISCARDED_TASKS_KEY, encode_discarded_tasks(&state.discarded)),
+		];
+		for error in results.into_iter().filter_map(Result::err) {
+			warn!(
+				target: "secretstore",
+				"Failed to write quarantined items: {}",
+				error,
+			);
+		}
+	}
+}
+
+/// Returns index of the task kind in `ALL_TASK_KINDS`.
+fn encode_task_kind(task_kind: TaskKind) -> u8 {
+	ALL_TASK_KINDS
+		.iter()
+		.position(|known_task_kind| *known_task_kind == task_kind)
+		.expect("ALL_TASK_KINDS contains all task kinds; qed") as u8
+}
+
+/// Encode quarantined items.
+fn encode_items(items: &BTreeMap<PoisonedItemId, PoisonedItem>) -> Vec<u8> {
+	let mut encoded = Vec::new();
+	for (id, item) in items {
+		let (task_kind, key_id) = item.task.unwrap_or((TaskKind::ServerKeyGeneration, ServerKeyId::default()));
+		encoded.extend_from_slice(&id.to_be_bytes());
+		encoded.push(item.stage as u8);
+		encoded.push(item.task.is_some() as u8);
+		encoded.push(encode_task_kind(task_kind));
+		encoded.extend_from_slice(key_id.as_bytes());
+		encoded.push(item.block_number.is_some() as u8);
+		encoded.extend_from_slice(&item.block_number.unwrap_or_default().to_be_bytes());
+		encoded.extend_from_slice(&item.failures.to_be_bytes());
+		encoded.extend_from_slice(&(item.last_error.len() as u32).to_be_bytes());
+		encoded.extend_from_slice(item.last_error.as_bytes());
+	}
+	encoded
+}
+
+/// Decode quarantined items. Decoding stops at the first malformed item.
+fn decode_items(mut encoded: &[u8]) -> BTreeMap<PoisonedItemId, PoisonedItem> {
+	let mut items = BTreeMap::new();
+	while !encoded.is_empty() {
+		match decode_item(encoded) {
+			Some((id, item, item_size)) => {
+				items.insert(id, item);
+				encoded = &encoded[item_size..];
+			},
+			None => {
+				warn!(
+					target: "secretstore",
+					"Ignoring {} bytes of malformed quarantined items record",
+					encoded.len(),
+				);
+				break;
+			},
+		}
+	}
+	items
+}
+
+/// Decode single quarantined item. Returns item along with its encoded size.
+fn decode_item(encoded: &[u8]) -> Option<(PoisonedItemId, PoisonedItem, usize)> {
+	let header = encoded.get(..ENCODED_ITEM_HEADER_SIZE)?;
+	let id = u64::from_be_bytes(header[0..8].try_into().ok()?);
+	let stage = match header[8] {
+		0 => FailureStage::Decode,
+		1 => FailureStage::Execute,
+		2 => FailureStage::Submit,
+		3 => FailureStage::Crash,
+		_ => return None,
+	};
+	let task_kind = *ALL_TASK_KINDS.get(header[10] as usize)?;
+	let mut key_id = ServerKeyId::default();
+	key_id.as_bytes_mut().copy_from_slice(&header[11..43]);
+	let block_number = u64::from_be_bytes(header[44..52].try_into().ok()?);
+	let failures = u32::from_be_bytes(header[52..56].try_into().ok()?);
+	let error_len = u32::from_be_bytes(header[56..60].try_into().ok()?) as usize;
+	let item_size = ENCODED_ITEM_HEADER_SIZE.checked_add(error_len)?;
+	let last_error = encoded.get(ENCODED_ITEM_HEADER_SIZE..item_size)?;
+	Some((
+		id,
+		PoisonedItem {
+			stage,
+			task: match header[9] != 0 {
+				true => Some((task_kind, key_id)),
+				false => None,
+			},
+			block_number: match header[43] != 0 {
+				true => Some(block_number),
+				false => None,
+			},
+			failures,
+			last_error: String::from_utf8_lossy(last_error).into_owned(),
+		},
+		item_size,
+	))
+}
+
+/// Encode discarded tasks.
+fn encode_discarded_tasks(discarded: &BTreeSet<(TaskKind, ServerKeyId)>) -> Vec<u8> {
+	let mut encoded = Vec::with_capacity(discarded.len() * ENCODED_TASK_SIZE);
+	for (task_kind, key_id) in discarded {
+		encoded.push(encode_task_kind(*task_kind));
+		encoded.extend_from_slice(key_id.as_bytes());
+	}
+	encoded
+}
+
+/// Decode discarded tasks. Malformed records are ignored.
+fn decode_discarded_tasks(encoded: &[u8]) -> BTreeSet<(TaskKind, ServerKeyId)> {
+	let records = encoded.chunks_exact(ENCODED_TASK_SIZE);
+	if !records.remainder().is_empty() {
+		warn!(
+			target: "secretstore",
+			"Ignoring discarded tasks record of invalid length {}",
+			encoded.len(),
+		);
+		return BTreeSet::new();
+	}
+
+	records
+		.filter_map(|encoded| {
+			let task_kind = *ALL_TASK_KINDS.get(encoded[0] as usize)?;
+			let mut key_id = ServerKeyId::default();
+			key_id.as_bytes_mut().copy_from_slice(&encoded[1..33]);
+			Some((task_kind, key_id))
+		})
+		.collect()
+}
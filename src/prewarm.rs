@@ -0,0 +1,135 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Pre-warming of sessions for scheduled requests.
+//!
+//! Some runtimes are generating requests at known block heights (e.g. on-chain
+//! lottery is generating server key every N blocks) and are announcing them in
+//! advance. When such announcement is seen, the key server is pre-warmed (version
+//! negotiation, connectivity checks, ...) shortly before the request is generated,
+//! so that the actual session completes within one block of the trigger.
+
+use std::sync::{Arc, Mutex};
+use log::{error, trace};
+use parity_secretstore_primitives::ServerKeyId;
+use crate::{TaskKind, confidential::Redactor};
+
+/// Request that will be generated by the runtime at given block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledRequest {
+	/// Number of the block where request is generated.
+	pub block_number: u64,
+	/// Kind of the request.
+	pub task_kind: TaskKind,
+	/// Key id of the request.
+	pub key_id: ServerKeyId,
+}
+
+/// Prepares key server for the session that will be started soon.
+pub trait SessionPrewarmer: Send + Sync + 'static {
+	/// Pre-warm key server for the scheduled request.
+	fn prewarm(&self, request: &ScheduledRequest) -> Result<(), String>;
+}
+
+/// Pre-warming configuration.
+#[derive(Clone)]
+pub struct PrewarmConfiguration {
+	/// Session pre-warmer.
+	pub prewarmer: Arc<dyn SessionPrewarmer>,
+	/// Key server is pre-warmed `lead_blocks` blocks before the request is generated.
+	pub lead_blocks: u64,
+}
+
+/// Scheduled requests that are waiting for pre-warming.
+pub struct ScheduledRequests {
+	/// Configuration. If `None`, announcements are ignored.
+	config: Option<PrewarmConfiguration>,
+	/// Redactor of identifying data.
+	redactor: Redactor,
+	/// Requests that are not yet pre-warmed.
+	requests: Mutex<Vec<ScheduledRequest>>,
+}
+
+impl ScheduledRequests {
+	/// Create new scheduled requests queue.
+	pub fn new(config: Option<PrewarmConfiguration>, redactor: Redactor) -> Self {
+		ScheduledRequests {
+			config,
+			redactor,
+			requests: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Returns true if announcements are processed.
+	pub fn is_enabled(&self) -> bool {
+		self.config.is_some()
+	}
+
+	/// Called when scheduled request announcement is seen.
+	pub fn on_request_announced(&self, request: ScheduledRequest) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let mut requests = self.requests.lock().expect("never panics under lock; qed");
+		if !requests.contains(&request) {
+			requests.push(request);
+		}
+	}
+
+	/// Called when new block is processed. Pre-warms key server for requests that
+	/// will be generated soon.
+	pub fn on_new_block(&self, block_number: u64) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+
+		let due_requests = {
+			let mut requests = self.requests.lock().expect("never panics under lock; qed");
+			let (due_requests, future_requests) = requests
+				.drain(..)
+				.partition::<Vec<_>, _>(|request| request.block_number <= block_number.saturating_add(config.lead_blocks));
+			*requests = future_requests;
+			due_requests
+		};
+
+		for request in due_requests {
+			// request has been already generated => no sense to pre-warm
+			if request.block_number < block_number {
+				continue;
+			}
+
+			match config.prewarmer.prewarm(&request) {
+				Ok(()) => trace!(
+					target: "secretstore",
+					"Pre-warmed key server for {:?} request {} scheduled at block {}",
+					request.task_kind,
+					self.redactor.redact(&request.key_id),
+					request.block_number,
+				),
+				Err(error) => error!(
+					target: "secretstore",
+					"Failed to pre-warm key server for {:?} request {} scheduled at block {}: {}",
+					request.task_kind,
+					self.redactor.redact(&request.key_id),
+					request.block_number,
+					error,
+				),
+			}
+		}
+	}
+}
@@ -0,0 +1,112 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Responses that are queued, but not yet submitted.
+//!
+//! Some responses are not submitted immediately (e.g. when they're waiting for
+//! origin block finalization). Operators may inspect these responses and drop
+//! those that are known to fail forever, without restarting the service.
+
+use std::{
+	collections::BTreeMap,
+	sync::Mutex,
+};
+use parity_secretstore_primitives::Address;
+use crate::{SecretStoreCall, dedup::ServedRequest};
+
+/// Id of the queued response.
+pub type QueuedResponseId = u64;
+
+/// Why response is queued.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueReason {
+	/// Response is waiting for origin block finalization.
+	OriginBlockNotFinalized,
+	/// Error response is buffered while key server cluster is unavailable.
+	ClusterUnavailable,
+	/// Response is waiting to be submitted in batch.
+	Batched,
+}
+
+/// Response that is queued, but not yet submitted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedResponse {
+	/// Origin of the request.
+	pub origin: Address,
+	/// Address of the key server that has produced the response.
+	pub key_server: Address,
+	/// Why response is queued.
+	pub reason: QueueReason,
+	/// Request description.
+	pub description: String,
+	/// The response itself.
+	pub call: SecretStoreCall,
+}
+
+/// Responses that are queued by all key servers.
+#[derive(Default)]
+pub struct SubmissionQueue {
+	/// Queue state.
+	state: Mutex<SubmissionQueueState>,
+}
+
+/// Submission queue state.
+#[derive(Default)]
+struct SubmissionQueueState {
+	/// Id of the next queued response.
+	next_id: QueuedResponseId,
+	/// Queued responses by id.
+	responses: BTreeMap<QueuedResponseId, QueuedResponse>,
+}
+
+impl SubmissionQueue {
+	/// Called when response is queued.
+	pub fn on_response_queued(&self, response: QueuedResponse) -> QueuedResponseId {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let id = state.next_id;
+		state.next_id += 1;
+		state.responses.insert(id, response);
+		id
+	}
+
+	/// Called when response leaves the queue (it is either submitted or dropped).
+	pub fn on_response_dequeued(&self, id: QueuedResponseId) {
+		self.state.lock().expect("never panics under lock; qed").responses.remove(&id);
+	}
+
+	/// Returns true if response is still queued (i.e. it hasn't been dropped manually).
+	pub fn contains(&self, id: QueuedResponseId) -> bool {
+		self.state.lock().expect("never panics under lock; qed").responses.contains_key(&id)
+	}
+
+	/// Returns all queued responses.
+	pub fn snapshot(&self) -> BTreeMap<QueuedResponseId, QueuedResponse> {
+		self.state.lock().expect("never panics under lock; qed").responses.clone()
+	}
+
+	/// Drop all queued responses to given request. Returns number of dropped responses.
+	pub fn remove_request(&self, request: &ServedRequest) -> usize {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let responses_count = state.responses.len();
+		state.responses.retain(|_, response| !ServedRequest::from_accepted_call(Some(response.origin), &response.call).contains(request));
+		responses_count - state.responses.len()
+	}
+
+	/// Drop queued response. Returns dropped response.
+	pub fn remove(&self, id: QueuedResponseId) -> Option<QueuedResponse> {
+		self.state.lock().expect("never panics under lock; qed").responses.remove(&id)
+	}
+}
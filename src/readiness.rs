@@ -0,0 +1,136 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{
+	Arc, Mutex,
+	atomic::{AtomicBool, Ordering},
+};
+use log::{info, warn};
+use parity_secretstore_primitives::service::ServiceTask;
+use crate::{BlockchainServiceTask, degraded::ClusterHealth};
+
+/// Key server cluster connectivity probe.
+pub trait ClusterConnectivity: Send + Sync + 'static {
+	/// Returns number of key servers (including this one) that are currently reachable.
+	fn connected_key_servers(&self) -> usize;
+}
+
+/// Capacity of the key server cluster, as seen by server key generation tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CapacityStatus {
+	/// Number of reachable key servers, read by the last check.
+	pub connected_key_servers: Option<usize>,
+	/// True if the last checked task has been deferred because of lack of key servers.
+	pub is_degraded: bool,
+	/// Number of server key generation tasks that have been deferred.
+	pub deferred_tasks: u64,
+}
+
+/// Gate that defers server key generation tasks that can't be completed with
+/// currently reachable key servers.
+///
+/// Generation session with threshold `t` requires at least `t + 1` key servers. If
+/// less key servers are reachable, the session is guaranteed to fail. So the task
+/// is not started. It stays pending on chain and is picked up by one of the next
+/// pending tasks scans.
+pub struct CapacityGate {
+	/// Connectivity probe. If `None`, all tasks are accepted.
+	probe: Option<Arc<dyn ClusterConnectivity>>,
+	/// Current capacity status.
+	status: Mutex<CapacityStatus>,
+}
+
+/// Startup gate that holds blocks processing until key server cluster is ready.
+///
+/// Right after boot, cluster sessions subsystem isn't ready yet and all sessions
+/// would fail. So blocks are ignored until the cluster is ready. Once the gate is
+/// open, it stays open. Requests from ignored blocks are picked up by the first
+/// pending tasks scan.
+pub struct ReadinessGate {
+	/// Cluster readiness probe. If `None`, the gate is always open.
+	probe: Option<Arc<dyn ClusterHealth>>,
+	/// True if the gate has been opened.
+	is_open: AtomicBool,
+}
+
+impl CapacityGate {
+	/// Create new gate.
+	pub fn new(probe: Option<Arc<dyn ClusterConnectivity>>) -> Self {
+		CapacityGate {
+			probe,
+			status: Mutex::new(CapacityStatus::default()),
+		}
+	}
+
+	/// Returns current capacity status.
+	pub fn status(&self) -> CapacityStatus {
+		*self.status.lock().expect("never panics under lock; qed")
+	}
+
+	/// Returns true if task could be started now.
+	pub fn accepts_task(&self, task: &BlockchainServiceTask) -> bool {
+		let (probe, threshold) = match (self.probe.as_ref(), task) {
+			(Some(probe), BlockchainServiceTask::Regular(_, ServiceTask::GenerateServerKey(_, _, threshold))) =>
+				(probe, *threshold),
+			_ => return true,
+		};
+
+		let connected_key_servers = probe.connected_key_servers();
+		let is_degraded = connected_key_servers <= threshold;
+		let mut status = self.status.lock().expect("never panics under lock; qed");
+		status.connected_key_servers = Some(connected_key_servers);
+		status.is_degraded = is_degraded;
+		if is_degraded {
+			status.deferred_tasks += 1;
+			warn!(
+				target: "secretstore",
+				"Deferring server key generation with threshold {}: only {} key servers are reachable",
+				threshold,
+				connected_key_servers,
+			);
+		}
+
+		!is_degraded
+	}
+}
+
+impl ReadinessGate {
+	/// Create new gate.
+	pub fn new(probe: Option<Arc<dyn ClusterHealth>>) -> Self {
+		let is_open = probe.is_none();
+		ReadinessGate {
+			probe,
+			is_open: AtomicBool::new(is_open),
+		}
+	}
+
+	/// Returns true if blocks could be processed.
+	pub fn is_open(&self) -> bool {
+		if self.is_open.load(Ordering::Acquire) {
+			return true;
+		}
+
+		let is_ready = self.probe.as_ref().map(|probe| probe.is_available()).unwrap_or(true);
+		if is_ready && !self.is_open.swap(true, Ordering::AcqRel) {
+			info!(
+				target: "secretstore",
+				"Key server cluster is ready. Starting to process blocks",
+			);
+		}
+
+		is_ready
+	}
+}
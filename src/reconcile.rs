@@ -0,0 +1,418 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+};
+use log::{info, trace, warn};
+use parity_secretstore_primitives::Address;
+use crate::{
+	Blockchain, SecretStoreCall, TaskKind, TransactionPool,
+	submit_call,
+	confidential::Redactor,
+	dedup::ServedRequest,
+	error::ServiceError,
+	identity::AccountId32,
+};
+
+/// Called when reconciliation round is completed.
+pub type ReconciliationHandler = Arc<dyn Fn(&ReconciliationReport) + Send + Sync>;
+
+/// Reconciliation configuration.
+#[derive(Debug, Clone)]
+pub struct ReconciliationConfiguration {
+	/// Reconciliation round is started every `interval` blocks.
+	pub interval: u32,
+	/// Response that isn't accepted by the runtime within this number of blocks is
+	/// considered missing from the chain.
+	pub confirmation_timeout: u64,
+	/// Max number of times the same response is resubmitted.
+	pub max_resubmissions: u32,
+}
+
+/// Drift between local state and chain state, found at single reconciliation round.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconciliationReport {
+	/// Number of responses that are waiting for confirmation after the round.
+	pub in_flight: usize,
+	/// Number of responses that are no longer required (probably because request has
+	/// been satisfied by other key servers) or which status can't be checked.
+	pub dropped: usize,
+	/// Number of responses that are already on chain, but the request is still waiting
+	/// for responses of other key servers.
+	pub confirmed: usize,
+	/// Number of responses that have been missing from chain and were resubmitted.
+	pub resubmitted: usize,
+	/// Number of responses that have been missing from chain and were not resubmitted,
+	/// because resubmissions limit has been reached.
+	pub abandoned: usize,
+	/// Number of responses that were not reconciled because of errors.
+	pub failed: usize,
+	/// Requests which responses have been abandoned at this round. They're still
+	/// pending on chain, so they could be served again.
+	pub abandoned_requests: Vec<ServedRequest>,
+}
+
+/// Compares local view of submitted responses with on-chain state and fixes
+/// discrepancies.
+pub struct Reconciler {
+	/// Configuration. If `None`, reconciliation is disabled.
+	config: Option<ReconciliationConfiguration>,
+	/// Reconciliation report handler.
+	handler: Option<ReconciliationHandler>,
+	/// Identifying data formatter.
+	redactor: Redactor,
+	/// Reconciler state.
+	state: Mutex<ReconcilerState>,
+}
+
+/// Reconciler state.
+#[derive(Default)]
+struct ReconcilerState {
+	/// Index of the current block.
+	current_block: u64,
+	/// Number of blocks till next reconciliation round.
+	blocks_till_round: u32,
+	/// Responses that have been submitted, but not yet accepted by the runtime.
+	in_flight: BTreeMap<(Address, ServedRequest), InFlightResponse>,
+}
+
+/// Response that has been submitted, but not yet accepted by the runtime.
+#[derive(Clone)]
+struct InFlightResponse {
+	/// Origin of the request. `None` for externally produced calls.
+	origin: Option<Address>,
+	/// Account that has submitted the response.
+	submitter: Option<AccountId32>,
+	/// The response itself.
+	call: SecretStoreCall,
+	/// Block where response has been (re)submitted.
+	submitted_at: u64,
+	/// Number of resubmissions.
+	resubmissions: u32,
+	/// True if transaction pool has dropped the response.
+	is_dropped: bool,
+}
+
+impl Default for ReconciliationConfiguration {
+	fn default() -> Self {
+		ReconciliationConfiguration {
+			interval: 10,
+			confirmation_timeout: 20,
+			max_resubmissions: 3,
+		}
+	}
+}
+
+impl Reconciler {
+	/// Create new reconciler.
+	pub fn new(
+		config: Option<ReconciliationConfiguration>,
+		handler: Option<ReconciliationHandler>,
+		redactor: Redactor,
+	) -> Self {
+		Reconciler {
+			config,
+			handler,
+			redactor,
+			state: Mutex::new(ReconcilerState::default()),
+		}
+	}
+
+	/// Called when response has been submitted by given key server.
+	pub fn on_response_submitted(
+		&self,
+		key_server: Address,
+		request: ServedRequest,
+		origin: Option<Address>,
+		submitter: Option<AccountId32>,
+		call: SecretStoreCall,
+	) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("reconciler never panics under lock; qed");
+		let current_block = state.current_block;
+		state.in_flight.insert((key_server, request), InFlightResponse {
+			origin,
+			submitter,
+			call,
+			submitted_at: current_block,
+			resubmissions: 0,
+			is_dropped: false,
+		});
+	}
+
+	/// Called when transaction pool has dropped the response. The response is checked
+	/// (and resubmitted) at the next round, without waiting for confirmation timeout.
+	pub fn on_response_dropped(&self, call: &SecretStoreCall) {
+		let mut state = self.state.lock().expect("reconciler never panics under lock; qed");
+		state.in_flight
+			.values_mut()
+			.filter(|response| response.call == *call)
+			.for_each(|response| response.is_dropped = true);
+	}
+
+	/// Called when request no longer requires response of given key server.
+	pub fn on_request_completed(&self, key_server: Address, request: ServedRequest) {
+		let mut state = self.state.lock().expect("reconciler never panics under lock; qed");
+		state.in_flight.remove(&(key_server, request));
+	}
+
+	/// Called when response of given key server has been accepted by the runtime.
+	pub fn on_response_accepted(&self, key_server: Address, origin: Address, call: &SecretStoreCall) {
+		let mut state = self.state.lock().expect("reconciler never panics under lock; qed");
+		for request in ServedRequest::from_accepted_call(Some(origin), call) {
+			state.in_flight.remove(&(key_server, request));
+		}
+	}
+
+	/// Forget responses that have been (re)submitted more than `retention` blocks ago.
+	/// Returns number of forgotten responses.
+	pub fn collect_garbage(&self, retention: u64) -> usize {
+		let mut state = self.state.lock().expect("reconciler never panics under lock; qed");
+		let current_block = state.current_block;
+		let responses_count = state.in_flight.len();
+		state.in_flight.retain(|_, response| current_block - response.submitted_at <= retention);
+		responses_count - state.in_flight.len()
+	}
+
+	/// Called when new block is processed. Starts reconciliation round if required.
+	/// Returns report of the round, if it has been started.
+	pub fn on_new_block<B: Blockchain, TP: TransactionPool>(
+		&self,
+		blockchain: &B,
+		transaction_pool: &TP,
+	) -> Option<ReconciliationReport> {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return None,
+		};
+
+		// select responses that are missing from chain for too long
+		let (current_block, missing_responses) = {
+			let mut state = self.state.lock().expect("reconciler never panics under lock; qed");
+			state.current_block += 1;
+			if state.blocks_till_round != 0 {
+				state.blocks_till_round -= 1;
+				return None;
+			}
+			state.blocks_till_round = std::cmp::max(config.interval, 1) - 1;
+
+			let current_block = state.current_block;
+			let missing_responses = state.in_flight
+				.iter()
+				.filter(|(_, response)| response.is_dropped
+					|| current_block - response.submitted_at > config.confirmation_timeout)
+				.map(|(key, response)| (*key, response.clone()))
+				.collect::<Vec<_>>();
+			(current_block, missing_responses)
+		};
+
+		// chain is queried without holding the lock
+		let mut report = ReconciliationReport::default();
+		let mut updates = Vec::with_capacity(missing_responses.len());
+		for ((key_server, request), mut response) in missing_responses {
+			// if blockchain doesn't support responses queries, we assume that response is missing
+			let is_response_missing = || has_response(blockchain, &request, key_server)
+				.map(|has_response| !has_response)
+				.unwrap_or(true);
+			match is_response_required(blockchain, &request, key_server) {
+				Ok(true) if !is_response_missing() => {
+					report.confirmed += 1;
+					updates.push(((key_server, request), None));
+				},
+				Ok(true) if response.resubmissions < config.max_resubmissions => {
+					let submit_result = submit_call(
+						transaction_pool,
+						response.origin.as_ref(),
+						response.submitter.as_ref(),
+						response.call.clone(),
+					);
+					match submit_result {
+						Ok(transaction_hash) => {
+							trace!(
+								target: "secretstore",
+								"Resubmitted {:?} response {}: {}",
+								request.task_kind,
+								self.redactor.redact(&request.key_id),
+								transaction_hash,
+							);
+
+							report.resubmitted += 1;
+							response.submitted_at = current_block;
+							response.resubmissions += 1;
+							response.is_dropped = false;
+							updates.push(((key_server, request), Some(response)));
+						},
+						Err(ref error) if !error.is_retryable() => {
+							warn!(
+								target: "secretstore",
+								"Abandoning {:?} response {}: resubmission has failed with fatal error: {}",
+								request.task_kind,
+								self.redactor.redact(&request.key_id),
+								error,
+							);
+
+							report.abandoned += 1;
+							report.abandoned_requests.push(request);
+							updates.push(((key_server, request), None));
+						},
+						Err(error) => {
+							warn!(
+								target: "secretstore",
+								"Failed to resubmit {:?} response {}: {}",
+								request.task_kind,
+								self.redactor.redact(&request.key_id),
+								error,
+							);
+
+							report.failed += 1;
+						},
+					}
+				},
+				Ok(true) => {
+					warn!(
+						target: "secretstore",
+						"Abandoning {:?} response {}: it is still missing from chain after {} resubmissions",
+						request.task_kind,
+						self.redactor.redact(&request.key_id),
+						response.resubmissions,
+					);
+
+					report.abandoned += 1;
+					report.abandoned_requests.push(request);
+					updates.push(((key_server, request), None));
+				},
+				Ok(false) => {
+					report.dropped += 1;
+					updates.push(((key_server, request), None));
+				},
+				// retrying check won't help => stop tracking the response
+				Err(ref error) if !error.is_transient() => {
+					warn!(
+						target: "secretstore",
+						"Forgetting {:?} response {}: failed to check if it is still required: {}",
+						request.task_kind,
+						self.redactor.redact(&request.key_id),
+						error,
+					);
+
+					report.dropped += 1;
+					updates.push(((key_server, request), None));
+				},
+				Err(error) => {
+					warn!(
+						target: "secretstore",
+						"Failed to check if {:?} response {} is still required: {}",
+						request.task_kind,
+						self.redactor.redact(&request.key_id),
+						error,
+					);
+
+					report.failed += 1;
+				},
+			}
+		}
+
+		{
+			let mut state = self.state.lock().expect("reconciler never panics under lock; qed");
+			for (key, response) in updates {
+				// response could have been accepted while we were reconciling
+				match response {
+					Some(response) => if let Some(in_flight) = state.in_flight.get_mut(&key) {
+						*in_flight = response;
+					},
+					None => {
+						state.in_flight.remove(&key);
+					},
+				}
+			}
+			report.in_flight = state.in_flight.len();
+		}
+
+		if report.dropped != 0 || report.confirmed != 0 || report.resubmitted != 0
+			|| report.abandoned != 0 || report.failed != 0
+		{
+			info!(
+				target: "secretstore",
+				"Reconciled local state with chain: {} responses dropped, {} confirmed, {} resubmitted, {} abandoned, {} failed, {} in flight",
+				report.dropped,
+				report.confirmed,
+				report.resubmitted,
+				report.abandoned,
+				report.failed,
+				report.in_flight,
+			);
+		}
+
+		if let Some(ref handler) = self.handler {
+			handler(&report);
+		}
+
+		Some(report)
+	}
+}
+
+/// Returns true if response of given key server to the request is still required.
+pub fn is_response_required<B: Blockchain>(
+	blockchain: &B,
+	request: &ServedRequest,
+	key_server: Address,
+) -> Result<bool, ServiceError> {
+	match request.task_kind {
+		TaskKind::ServerKeyGeneration =>
+			blockchain.is_server_key_generation_response_required(request.key_id, key_server),
+		TaskKind::ServerKeyRetrieval =>
+			blockchain.is_server_key_retrieval_response_required(request.key_id, key_server),
+		TaskKind::DocumentKeyStore =>
+			blockchain.is_document_key_store_response_required(request.key_id, key_server),
+		TaskKind::DocumentKeyShadowRetrieval => match request.requester {
+			Some(requester) => blockchain.is_document_key_shadow_retrieval_response_required(
+				request.key_id,
+				requester,
+				key_server,
+			),
+			None => Err(ServiceError::permanent("document key shadow retrieval request without requester")),
+		},
+	}
+}
+
+/// Returns true if response of given key server to the request is already on chain.
+fn has_response<B: Blockchain>(
+	blockchain: &B,
+	request: &ServedRequest,
+	key_server: Address,
+) -> Result<bool, ServiceError> {
+	match request.task_kind {
+		TaskKind::ServerKeyGeneration =>
+			blockchain.has_server_key_generation_response(request.key_id, key_server),
+		TaskKind::ServerKeyRetrieval =>
+			blockchain.has_server_key_retrieval_response(request.key_id, key_server),
+		TaskKind::DocumentKeyStore =>
+			blockchain.has_document_key_store_response(request.key_id, key_server),
+		TaskKind::DocumentKeyShadowRetrieval => match request.requester {
+			Some(requester) => blockchain.has_document_key_shadow_retrieval_response(
+				request.key_id,
+				requester,
+				key_server,
+			),
+			None => Err(ServiceError::permanent("document key shadow retrieval request without requester")),
+		},
+	}
+}
@@ -0,0 +1,232 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detection of chain reorganizations.
+//!
+//! The new blocks stream may yield blocks of different forks. When previously seen
+//! (not yet finalized) block is retracted from the canonical chain, requests from the
+//! new canonical blocks could be missed. So after reorg, canonical blocks starting
+//! from the fork point are replayed and pending tasks are scanned. Responses to
+//! requests from retracted blocks are prevented by confirmation depths (or by
+//! speculative processing), which only start tasks from blocks that are deep enough.
+
+use std::{
+	collections::BTreeMap,
+	sync::Mutex,
+};
+use log::{error, warn};
+use crate::{Blockchain, error::ServiceError, replay::MissedBlocks, speculative::BlockFinality};
+
+/// Reorgs tracking configuration.
+#[derive(Debug, Clone)]
+pub struct ReorgConfiguration {
+	/// Max number of recent non-finalized blocks that are checked for retraction.
+	pub tracked_blocks: usize,
+	/// Max number of canonical blocks that are replayed after reorg. If more blocks
+	/// are enacted, they are skipped and pending tasks are scanned instead.
+	pub max_replayed_blocks: u64,
+}
+
+/// Reorgs tracking statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReorgStatistics {
+	/// Number of the best block seen so far.
+	pub best_block_number: Option<u64>,
+	/// Number of the best finalized block seen so far.
+	pub finalized_block_number: Option<u64>,
+	/// Number of detected reorgs.
+	pub reorgs: u64,
+	/// Total number of retracted blocks.
+	pub retracted_blocks: u64,
+	/// Max number of blocks retracted by single reorg.
+	pub max_reorg_depth: u64,
+}
+
+/// Tracks recent blocks and detects reorgs.
+pub struct ReorgTracker<Hash> {
+	/// Configuration. If `None`, reorgs are not tracked.
+	config: Option<ReorgConfiguration>,
+	/// Tracker state.
+	state: Mutex<ReorgTrackerState<Hash>>,
+}
+
+/// Tracker state.
+struct ReorgTrackerState<Hash> {
+	/// Recent non-finalized blocks, by number.
+	blocks: BTreeMap<u64, Hash>,
+	/// Statistics.
+	statistics: ReorgStatistics,
+}
+
+impl Default for ReorgConfiguration {
+	fn default() -> Self {
+		ReorgConfiguration {
+			tracked_blocks: 64,
+			max_replayed_blocks: 64,
+		}
+	}
+}
+
+impl<Hash: Clone + PartialEq> ReorgTracker<Hash> {
+	/// Create new reorgs tracker.
+	pub fn new(config: Option<ReorgConfiguration>) -> Self {
+		ReorgTracker {
+			config,
+			state: Mutex::new(ReorgTrackerState {
+				blocks: BTreeMap::new(),
+				statistics: ReorgStatistics::default(),
+			}),
+		}
+	}
+
+	/// Returns reorgs tracking statistics.
+	pub fn statistics(&self) -> ReorgStatistics {
+		self.state.lock().expect("never panics under lock; qed").statistics
+	}
+
+	/// Called when new block is yielded by the new blocks stream. Returns canonical
+	/// blocks that need to be replayed because of reorg. Blocks above the best block
+	/// that has been seen before are not returned - they're missed blocks.
+	pub fn on_new_block<B: Blockchain<BlockHash = Hash>>(
+		&self,
+		blockchain: &B,
+		block_hash: &Hash,
+	) -> MissedBlocks<Hash> {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return MissedBlocks::default(),
+		};
+
+		let block_number = match blockchain.block_number(block_hash.clone()) {
+			Ok(block_number) => block_number,
+			Err(error) => {
+				error!(
+					target: "secretstore",
+					"Failed to read number of the block: {}. Reorgs are not tracked",
+					error,
+				);
+
+				return MissedBlocks::default();
+			},
+		};
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let previous_best_block_number = state.statistics.best_block_number;
+
+		// find blocks that are no longer canonical, starting from the most recent one
+		let mut fork_block_number = None;
+		for (tracked_block_number, tracked_block_hash) in state.blocks.iter().rev() {
+			let canonical_block_hash = match *tracked_block_number {
+				tracked_block_number if tracked_block_number == block_number => Some(block_hash.clone()),
+				tracked_block_number => match blockchain.block_hash(tracked_block_number) {
+					Ok(canonical_block_hash) => canonical_block_hash,
+					Err(error) => {
+						error!(
+							target: "secretstore",
+							"Failed to read hash of the canonical block: {}. Reorgs are not detected",
+							error,
+						);
+
+						break;
+					},
+				},
+			};
+
+			if canonical_block_hash.as_ref() == Some(tracked_block_hash) {
+				break;
+			}
+
+			fork_block_number = Some(*tracked_block_number);
+		}
+
+		// forget retracted blocks and remember the new one
+		let mut missed_blocks = MissedBlocks::default();
+		if let Some(fork_block_number) = fork_block_number {
+			let retracted_blocks = state.blocks.split_off(&fork_block_number).len() as u64;
+			state.statistics.reorgs += 1;
+			state.statistics.retracted_blocks += retracted_blocks;
+			state.statistics.max_reorg_depth = std::cmp::max(state.statistics.max_reorg_depth, retracted_blocks);
+
+			// blocks above previous best block are replayed as missed blocks
+			let enacted_blocks_end = std::cmp::min(
+				previous_best_block_number.map(|number| number + 1).unwrap_or(block_number),
+				block_number,
+			);
+			let enacted_blocks = enacted_blocks_end.saturating_sub(fork_block_number);
+			warn!(
+				target: "secretstore",
+				"Chain reorganization: {} blocks starting from {} have been retracted. Replaying {} blocks",
+				retracted_blocks,
+				fork_block_number,
+				enacted_blocks,
+			);
+
+			let enacted = match enacted_blocks > config.max_replayed_blocks {
+				true => None,
+				false => (fork_block_number..enacted_blocks_end)
+					.map(|enacted_block_number| blockchain.block_hash(enacted_block_number)
+						.and_then(|enacted_block_hash| enacted_block_hash.ok_or_else(||
+							ServiceError::transient(format!("block {} is unknown", enacted_block_number))
+						))
+					)
+					.collect::<Result<Vec<_>, _>>()
+					.map_err(|error| error!(
+						target: "secretstore",
+						"Failed to read hash of the enacted block: {}",
+						error,
+					))
+					.ok(),
+			};
+
+			// pending tasks scan recovers requests from blocks that haven't been replayed
+			missed_blocks.is_pending_scan_required = true;
+			if let Some(enacted) = enacted {
+				for (enacted_block_number, enacted_block_hash) in (fork_block_number..).zip(enacted.iter()) {
+					state.blocks.insert(enacted_block_number, enacted_block_hash.clone());
+				}
+				missed_blocks.replayed = enacted;
+			}
+		}
+		state.blocks.insert(block_number, block_hash.clone());
+		state.statistics.best_block_number = Some(std::cmp::max(
+			previous_best_block_number.unwrap_or(block_number),
+			block_number,
+		));
+
+		// finalized blocks are never retracted
+		match blockchain.block_finality(block_hash.clone()) {
+			Ok(BlockFinality::Finalized) => {
+				state.blocks = state.blocks.split_off(&block_number);
+				state.statistics.finalized_block_number = Some(std::cmp::max(
+					state.statistics.finalized_block_number.unwrap_or(block_number),
+					block_number,
+				));
+			},
+			Ok(_) => (),
+			Err(error) => error!(
+				target: "secretstore",
+				"Failed to read finality of the block: {}",
+				error,
+			),
+		}
+		while state.blocks.len() > config.tracked_blocks {
+			let oldest_block_number = *state.blocks.keys().next().expect("blocks are not empty; qed");
+			state.blocks.remove(&oldest_block_number);
+		}
+
+		missed_blocks
+	}
+}
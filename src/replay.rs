@@ -0,0 +1,254 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Replay of blocks that have been missed by the new blocks stream.
+//!
+//! When connection to the node drops for a while, the new blocks stream silently
+//! skips blocks that have been imported meanwhile. Requests from recently missed
+//! blocks are recovered by replaying these blocks. When too many blocks have been
+//! missed, replaying is more expensive than reading pending requests, so these
+//! blocks are skipped and the pending tasks scan is started instead.
+
+use std::sync::Mutex;
+use log::{error, info, warn};
+use crate::{Blockchain, error::ServiceError};
+
+/// Missed blocks replay configuration.
+#[derive(Debug, Clone)]
+pub struct ReplayConfiguration {
+	/// Max number of missed blocks that are replayed. If more blocks are missed, they
+	/// are skipped and pending tasks are scanned instead.
+	pub max_replayed_blocks: u64,
+}
+
+/// Missed blocks replay statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayStatistics {
+	/// Number of missed blocks that have been replayed.
+	pub replayed_blocks: u64,
+	/// Number of missed blocks that have been skipped.
+	pub skipped_blocks: u64,
+}
+
+/// Blocks that have been missed before new block.
+pub struct MissedBlocks<Hash> {
+	/// Hashes of missed blocks that need to be replayed, in order.
+	pub replayed: Vec<Hash>,
+	/// True if some blocks have been skipped and pending tasks need to be scanned.
+	pub is_pending_scan_required: bool,
+}
+
+/// Detects and replays missed blocks.
+pub struct BlockReplay {
+	/// Configuration. If `None`, missed blocks are never replayed.
+	config: Option<ReplayConfiguration>,
+	/// Replay state.
+	state: Mutex<BlockReplayState>,
+}
+
+/// Replay state.
+struct BlockReplayState {
+	/// Number of the best block seen so far.
+	best_block_number: Option<u64>,
+	/// Replay statistics.
+	statistics: ReplayStatistics,
+	/// Numbers of blocks that are replayed on demand.
+	requested_blocks: Vec<u64>,
+}
+
+impl Default for ReplayConfiguration {
+	fn default() -> Self {
+		ReplayConfiguration {
+			max_replayed_blocks: 64,
+		}
+	}
+}
+
+impl<Hash> Default for MissedBlocks<Hash> {
+	fn default() -> Self {
+		MissedBlocks {
+			replayed: Vec::new(),
+			is_pending_scan_required: false,
+		}
+	}
+}
+
+impl BlockReplay {
+	/// Create new blocks replay.
+	pub fn new(config: Option<ReplayConfiguration>) -> Self {
+		BlockReplay {
+			config,
+			state: Mutex::new(BlockReplayState {
+				best_block_number: None,
+				statistics: ReplayStatistics::default(),
+				requested_blocks: Vec::new(),
+			}),
+		}
+	}
+
+	/// Returns replay statistics.
+	pub fn statistics(&self) -> ReplayStatistics {
+		self.state.lock().expect("never panics under lock; qed").statistics
+	}
+
+	/// Resume from given block (e.g. the last block processed before restart). Blocks
+	/// that follow it are treated as missed blocks. Ignored if some block has already
+	/// been seen.
+	pub fn resume_from(&self, block_number: u64) {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		if state.best_block_number.is_none() {
+			state.best_block_number = Some(block_number);
+		}
+	}
+
+	/// Request replay of given past block. Block is replayed when next block is yielded
+	/// by the new blocks stream, even if missed blocks replay is disabled.
+	pub fn request_replay(&self, block_number: u64) {
+		self.state.lock().expect("never panics under lock; qed").requested_blocks.push(block_number);
+	}
+
+	/// Returns hashes of blocks that have been requested to be replayed.
+	pub fn take_requested_blocks<B: Blockchain>(&self, blockchain: &B) -> Vec<B::BlockHash> {
+		let requested_blocks = std::mem::take(
+			&mut self.state.lock().expect("never panics under lock; qed").requested_blocks,
+		);
+		requested_blocks
+			.into_iter()
+			.filter_map(|block_number| match blockchain.block_hash(block_number) {
+				Ok(Some(block_hash)) => {
+					info!(
+						target: "secretstore",
+						"Replaying block {} on demand",
+						block_number,
+					);
+
+					Some(block_hash)
+				},
+				Ok(None) => {
+					warn!(
+						target: "secretstore",
+						"Failed to replay block {}: block is unknown",
+						block_number,
+					);
+
+					None
+				},
+				Err(error) => {
+					error!(
+						target: "secretstore",
+						"Failed to replay block {}: {}",
+						block_number,
+						error,
+					);
+
+					None
+				},
+			})
+			.collect()
+	}
+
+	/// Called when new block is yielded by the new blocks stream. Returns blocks that
+	/// have been missed since previous block.
+	pub fn on_new_block<B: Blockchain>(
+		&self,
+		blockchain: &B,
+		block_hash: &B::BlockHash,
+	) -> MissedBlocks<B::BlockHash> {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return MissedBlocks::default(),
+		};
+
+		let block_number = match blockchain.block_number(block_hash.clone()) {
+			Ok(block_number) => block_number,
+			Err(error) => {
+				error!(
+					target: "secretstore",
+					"Failed to read number of the block: {}. Missed blocks are not replayed",
+					error,
+				);
+
+				return MissedBlocks::default();
+			},
+		};
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let previous_block_number = match state.best_block_number {
+			Some(best_block_number) if best_block_number >= block_number => return MissedBlocks::default(),
+			Some(best_block_number) => best_block_number,
+			None => {
+				state.best_block_number = Some(block_number);
+				return MissedBlocks::default();
+			},
+		};
+		state.best_block_number = Some(block_number);
+
+		let missed_blocks = block_number - previous_block_number - 1;
+		if missed_blocks == 0 {
+			return MissedBlocks::default();
+		}
+
+		let replayed = match missed_blocks > config.max_replayed_blocks {
+			true => None,
+			false => (previous_block_number + 1..block_number)
+				.map(|missed_block_number| blockchain.block_hash(missed_block_number)
+					.and_then(|missed_block_hash| missed_block_hash.ok_or_else(||
+						ServiceError::transient(format!("block {} is unknown", missed_block_number))
+					))
+				)
+				.collect::<Result<Vec<_>, _>>()
+				.map_err(|error| error!(
+					target: "secretstore",
+					"Failed to read hash of the missed block: {}",
+					error,
+				))
+				.ok(),
+		};
+
+		match replayed {
+			Some(replayed) => {
+				state.statistics.replayed_blocks += missed_blocks;
+				info!(
+					target: "secretstore",
+					"Replaying {} missed blocks {}..{}",
+					missed_blocks,
+					previous_block_number + 1,
+					block_number,
+				);
+
+				MissedBlocks {
+					replayed,
+					is_pending_scan_required: false,
+				}
+			},
+			None => {
+				state.statistics.skipped_blocks += missed_blocks;
+				warn!(
+					target: "secretstore",
+					"Skipping {} missed blocks {}..{}. Pending tasks will be scanned instead",
+					missed_blocks,
+					previous_block_number + 1,
+					block_number,
+				);
+
+				MissedBlocks {
+					replayed: Vec::new(),
+					is_pending_scan_required: true,
+				}
+			},
+		}
+	}
+}
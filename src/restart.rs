@@ -0,0 +1,72 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex, RwLock};
+use parity_secretstore_primitives::service::{
+	ServiceTask, ServiceTasksListener, ServiceTasksListenerRegistrar,
+};
+
+/// Listener registrar that survives service restarts.
+///
+/// Key server can't unregister listeners. So the inner registrar only gets single
+/// forwarding listener and every (re)started service just replaces forwarding target.
+pub struct RestartableListenerRegistrar {
+	/// Inner registrar.
+	registrar: Arc<dyn ServiceTasksListenerRegistrar>,
+	/// Forwarding listener. `None` if it hasn't been registered yet.
+	forwarder: Mutex<Option<Arc<ForwardingListener>>>,
+}
+
+/// Listener that forwards tasks to the listener of the current service.
+struct ForwardingListener {
+	/// Current listener.
+	target: RwLock<Arc<dyn ServiceTasksListener>>,
+}
+
+impl RestartableListenerRegistrar {
+	/// Create new registrar.
+	pub fn new(registrar: Arc<dyn ServiceTasksListenerRegistrar>) -> Self {
+		RestartableListenerRegistrar {
+			registrar,
+			forwarder: Mutex::new(None),
+		}
+	}
+}
+
+impl ServiceTasksListenerRegistrar for RestartableListenerRegistrar {
+	fn register_listener(&self, listener: Arc<dyn ServiceTasksListener>) {
+		let mut forwarder = self.forwarder.lock().expect("never panics under lock; qed");
+		match *forwarder {
+			Some(ref forwarder) => {
+				*forwarder.target.write().expect("never panics under lock; qed") = listener;
+			},
+			None => {
+				let new_forwarder = Arc::new(ForwardingListener {
+					target: RwLock::new(listener),
+				});
+				self.registrar.register_listener(new_forwarder.clone());
+				*forwarder = Some(new_forwarder);
+			},
+		}
+	}
+}
+
+impl ServiceTasksListener for ForwardingListener {
+	fn process_task(&self, task: ServiceTask) {
+		let target = self.target.read().expect("never panics under lock; qed").clone();
+		target.process_task(task)
+	}
+}
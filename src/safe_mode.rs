@@ -0,0 +1,282 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Safe mode that breaks crash loops.
+//!
+//! Tasks that are being processed are recorded in the persistence. If service
+//! crashes while processing the task, the record survives restart and the crash is
+//! counted. Tasks that have crashed the service too many times are quarantined:
+//! they're skipped (and reported) until request is completed, so the rest of tasks
+//! are still processed. Quarantined tasks are also reported to the poison tasks
+//! quarantine, so that operator could retry them.
+
+use std::{
+	collections::{BTreeMap, BTreeSet, btree_map::Entry},
+	convert::TryInto,
+	sync::{Arc, Mutex},
+};
+use log::{error, warn};
+use parity_secretstore_primitives::{Address, ServerKeyId};
+use crate::{
+	TaskKind,
+	capabilities::ALL_TASK_KINDS,
+	confidential::Redactor,
+	dedup::ServedRequest,
+	persistence::Persistence,
+	poison::PoisonQuarantine,
+};
+
+/// Key of the in-flight tasks record.
+const IN_FLIGHT_TASKS_KEY: &[u8] = b"secretstore:safe_mode:in_flight";
+/// Size of single encoded in-flight task.
+const ENCODED_TASK_SIZE: usize = 4 + 1 + 32 + 1 + 1 + 20 + 1 + 20;
+
+/// Called when task is quarantined.
+pub type QuarantineHandler = Arc<dyn Fn(&QuarantinedTask) + Send + Sync>;
+
+/// Safe mode configuration.
+#[derive(Clone)]
+pub struct SafeModeConfiguration {
+	/// Persistence of in-flight tasks.
+	pub persistence: Arc<dyn Persistence>,
+	/// Task is quarantined after it has crashed the service this number of times.
+	pub max_crashes: u32,
+	/// Quarantine alert handler.
+	pub handler: Option<QuarantineHandler>,
+}
+
+/// Task that has been quarantined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedTask {
+	/// Request of the task.
+	pub request: ServedRequest,
+	/// Number of crashes while processing the task.
+	pub crashes: u32,
+}
+
+/// Safe mode.
+pub struct SafeMode {
+	/// Configuration. If `None`, tasks are never quarantined.
+	config: Option<SafeModeConfiguration>,
+	/// Safe mode state.
+	state: Mutex<SafeModeState>,
+}
+
+/// Safe mode state.
+#[derive(Default)]
+struct SafeModeState {
+	/// Tasks that are being processed, mapped to number of crashes while processing them.
+	in_flight: BTreeMap<ServedRequest, u32>,
+	/// Quarantined tasks.
+	quarantined: BTreeSet<ServedRequest>,
+}
+
+impl SafeMode {
+	/// Create safe mode. Tasks that have been in flight when service has crashed
+	/// are counted and quarantined if they've crashed service too many times.
+	pub fn new(config: Option<SafeModeConfiguration>, redactor: &Redactor, poison: &PoisonQuarantine) -> Self {
+		let mut state = SafeModeState::default();
+		if let Some(ref config) = config {
+			let encoded = config.persistence.get(IN_FLIGHT_TASKS_KEY).map_err(|error| warn!(
+				target: "secretstore",
+				"Failed to read in-flight tasks: {}. Safe mode is disabled until restart",
+				error,
+			));
+			for (request, crashes) in decode_in_flight_tasks(&encoded.ok().flatten().unwrap_or_default()) {
+				let crashes = crashes.saturating_add(1);
+				if crashes >= config.max_crashes {
+					error!(
+						target: "secretstore",
+						"Service has crashed {} times while processing {:?} task {}. Quarantining task",
+						crashes,
+						request.task_kind,
+						redactor.redact(&request.key_id),
+					);
+
+					let quarantined_task = QuarantinedTask { request, crashes };
+
+					if let Some(ref handler) = config.handler {
+						handler(&quarantined_task);
+					}
+					poison.on_crash(request.task_kind, request.key_id, crashes);
+					state.quarantined.insert(request);
+				}
+				state.in_flight.insert(request, crashes);
+			}
+		}
+
+		let safe_mode = SafeMode {
+			config,
+			state: Mutex::new(state),
+		};
+		safe_mode.persist(&safe_mode.state.lock().expect("never panics under lock; qed"));
+		safe_mode
+	}
+
+	/// Returns true if task is quarantined.
+	pub fn is_quarantined(&self, request: &ServedRequest) -> bool {
+		self.config.is_some()
+			&& self.state.lock().expect("never panics under lock; qed").quarantined.contains(request)
+	}
+
+	/// Called when task is dispatched to the key server.
+	pub fn on_task_dispatched(&self, request: ServedRequest) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		if let Entry::Vacant(entry) = state.in_flight.entry(request) {
+			entry.insert(0);
+			self.persist(&state);
+		}
+	}
+
+	/// Called when task session has completed. Quarantined task stays quarantined.
+	pub fn on_task_completed(&self, request: &ServedRequest) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		if !state.quarantined.contains(request) && state.in_flight.remove(request).is_some() {
+			self.persist(&state);
+		}
+	}
+
+	/// Called when request is completed. Task is released from quarantine.
+	pub fn on_request_completed(&self, request: &ServedRequest) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		state.quarantined.remove(request);
+		if state.in_flight.remove(request).is_some() {
+			self.persist(&state);
+		}
+	}
+
+	/// Release task from quarantine (e.g. when it is retried by operator). Crashes
+	/// counter of the task is reset.
+	pub fn release(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let is_released_request = |request: &ServedRequest| request.task_kind == task_kind && request.key_id == key_id;
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		state.quarantined.retain(|request| !is_released_request(request));
+		let in_flight_count = state.in_flight.len();
+		state.in_flight.retain(|request, _| !is_released_request(request));
+		if in_flight_count != state.in_flight.len() {
+			self.persist(&state);
+		}
+	}
+
+	/// Called when service is stopped gracefully. Tasks that are in flight haven't
+	/// crashed the service, so they're forgotten.
+	pub fn on_graceful_shutdown(&self) {
+		if self.config.is_none() {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let quarantined = state.quarantined.clone();
+		state.in_flight.retain(|request, _| quarantined.contains(request));
+		self.persist(&state);
+	}
+
+	/// Persist in-flight tasks.
+	fn persist(&self, state: &SafeModeState) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+
+		if let Err(error) = config.persistence.put(IN_FLIGHT_TASKS_KEY, encode_in_flight_tasks(&state.in_flight)) {
+			warn!(
+				target: "secretstore",
+				"Failed to write in-flight tasks: {}",
+				error,
+			);
+		}
+	}
+}
+
+/// Encode in-flight tasks.
+fn encode_in_flight_tasks(in_flight: &BTreeMap<ServedRequest, u32>) -> Vec<u8> {
+	let mut encoded = Vec::with_capacity(in_flight.len() * ENCODED_TASK_SIZE);
+	for (request, crashes) in in_flight {
+		encoded.extend_from_slice(&crashes.to_be_bytes());
+		encoded.push(
+			ALL_TASK_KINDS
+				.iter()
+				.position(|task_kind| *task_kind == request.task_kind)
+				.expect("ALL_TASK_KINDS contains all task kinds; qed") as u8,
+		);
+		encoded.extend_from_slice(request.key_id.as_bytes());
+		encoded.push(request.is_personal as u8);
+		encoded.push(request.requester.is_some() as u8);
+		encoded.extend_from_slice(request.requester.unwrap_or_default().as_bytes());
+		encoded.push(request.origin.is_some() as u8);
+		encoded.extend_from_slice(request.origin.unwrap_or_default().as_bytes());
+	}
+	encoded
+}
+
+/// Decode in-flight tasks. Malformed records are ignored.
+fn decode_in_flight_tasks(encoded: &[u8]) -> Vec<(ServedRequest, u32)> {
+	let records = encoded.chunks_exact(ENCODED_TASK_SIZE);
+	if !records.remainder().is_empty() {
+		warn!(
+			target: "secretstore",
+			"Ignoring in-flight tasks record of invalid length {}",
+			encoded.len(),
+		);
+		return Vec::new();
+	}
+
+	records
+		.filter_map(|encoded| {
+			let crashes = u32::from_be_bytes(encoded[0..4].try_into().ok()?);
+			let task_kind = *ALL_TASK_KINDS.get(encoded[4] as usize)?;
+			let mut key_id = ServerKeyId::default();
+			key_id.as_bytes_mut().copy_from_slice(&encoded[5..37]);
+			let is_personal = encoded[37] != 0;
+			let mut requester = Address::default();
+			requester.as_bytes_mut().copy_from_slice(&encoded[39..59]);
+			let mut origin = Address::default();
+			origin.as_bytes_mut().copy_from_slice(&encoded[60..80]);
+			Some((
+				ServedRequest {
+					origin: match encoded[59] != 0 {
+						true => Some(origin),
+						false => None,
+					},
+					task_kind,
+					key_id,
+					requester: match encoded[38] != 0 {
+						true => Some(requester),
+						false => None,
+					},
+					is_personal,
+				},
+				crashes,
+			))
+		})
+		.collect()
+}
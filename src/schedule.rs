@@ -0,0 +1,105 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	cmp::Reverse,
+	collections::{BTreeMap, VecDeque},
+};
+use parity_secretstore_primitives::Address;
+use crate::{
+	BlockchainServiceTask, task_origin,
+	dedup::ServedRequest,
+	tenant::Tenants,
+};
+
+/// Tasks of single origin, split into per-requester queues.
+struct OriginQueue<T> {
+	/// Origin of tasks.
+	origin: Address,
+	/// Per-requester queues, in order of first requester task.
+	requesters: VecDeque<(Option<Address>, VecDeque<T>)>,
+}
+
+/// Order tasks so that single busy origin (or requester) can't starve others.
+///
+/// Tasks of origins with larger priority go first. Origins with the same priority
+/// are served using weighted round-robin: at every round, up to `weight` tasks of
+/// every origin are taken. Within single origin, requesters are served using plain
+/// round-robin. Relative order of tasks of the same requester is preserved.
+pub fn fair_order(tasks: Vec<BlockchainServiceTask>, tenants: &Tenants) -> Vec<BlockchainServiceTask> {
+	fair_order_by(tasks, tenants, |task| task)
+}
+
+/// Same as `fair_order`, but for items that are wrapping tasks.
+pub fn fair_order_by<T>(
+	tasks: Vec<T>,
+	tenants: &Tenants,
+	task_of: impl Fn(&T) -> &BlockchainServiceTask,
+) -> Vec<T> {
+	let tasks_count = tasks.len();
+	let mut priority_groups: BTreeMap<Reverse<i32>, Vec<OriginQueue<T>>> = BTreeMap::new();
+	for task in tasks {
+		let origin = task_origin(task_of(&task));
+		let requester = ServedRequest::from_task(task_of(&task)).and_then(|request| request.requester);
+		let origin_queues = priority_groups.entry(Reverse(tenants.tenant(&origin).priority)).or_default();
+		let origin_queue_index = match origin_queues.iter().position(|queue| queue.origin == origin) {
+			Some(origin_queue_index) => origin_queue_index,
+			None => {
+				origin_queues.push(OriginQueue {
+					origin,
+					requesters: VecDeque::new(),
+				});
+				origin_queues.len() - 1
+			},
+		};
+
+		let requester_queues = &mut origin_queues[origin_queue_index].requesters;
+		match requester_queues.iter_mut().find(|(queue_requester, _)| *queue_requester == requester) {
+			Some((_, requester_queue)) => requester_queue.push_back(task),
+			None => requester_queues.push_back((requester, vec![task].into())),
+		}
+	}
+
+	let mut ordered_tasks = Vec::with_capacity(tasks_count);
+	for (_, mut origin_queues) in priority_groups {
+		while !origin_queues.is_empty() {
+			for origin_queue in &mut origin_queues {
+				for _ in 0..tenants.weight(&origin_queue.origin) {
+					match origin_queue.next_task() {
+						Some(task) => ordered_tasks.push(task),
+						None => break,
+					}
+				}
+			}
+
+			origin_queues.retain(|origin_queue| !origin_queue.requesters.is_empty());
+		}
+	}
+
+	ordered_tasks
+}
+
+impl<T> OriginQueue<T> {
+	/// Take next task, switching to the next requester.
+	fn next_task(&mut self) -> Option<T> {
+		let (requester, mut requester_queue) = self.requesters.pop_front()?;
+		let task = requester_queue.pop_front();
+		if !requester_queue.is_empty() {
+			self.requesters.push_back((requester, requester_queue));
+		}
+		task
+	}
+}
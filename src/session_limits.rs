@@ -0,0 +1,213 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Limits of concurrently running key server sessions.
+//!
+//! Every dispatched task starts key server session. When a burst of requests is
+//! seen on chain, starting all sessions at once overloads the key server cluster.
+//! So the number of sessions of every task kind that are running at the same time
+//! is limited. Tasks from new blocks that are over the limit are queued and started
+//! by the next blocks, when running sessions complete. Pending tasks that are over
+//! the limit are skipped - they're still pending on chain, so they're read again by
+//! the next pending tasks scan.
+//!
+//! Session occupies the slot until it has produced response, or request has been
+//! completed. If neither happens (e.g. session has been wedged), the slot is released
+//! when the session timeout expires.
+
+use std::{
+	collections::{BTreeMap, VecDeque},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+use log::trace;
+use parity_secretstore_blockchain_service::BlockchainServiceTask;
+use crate::{KeyServerHandle, TaskKind, TaskOriginBlock, dedup::ServedRequest};
+
+/// Sessions limits configuration.
+#[derive(Debug, Clone)]
+pub struct SessionLimitsConfiguration {
+	/// Max number of sessions of given kind that are running at the same time. Sessions
+	/// of kinds that are missing from the map are not limited.
+	pub max_running_sessions: BTreeMap<TaskKind, usize>,
+	/// Max number of tasks that are queued by every key server. When the queue is full,
+	/// tasks from new blocks are skipped and read later by the pending tasks scan.
+	pub max_queued_tasks: usize,
+	/// Session slot is released after this time, even if session hasn't completed.
+	pub session_timeout: Duration,
+}
+
+impl Default for SessionLimitsConfiguration {
+	fn default() -> Self {
+		SessionLimitsConfiguration {
+			max_running_sessions: BTreeMap::new(),
+			max_queued_tasks: 1024,
+			session_timeout: Duration::from_secs(600),
+		}
+	}
+}
+
+/// Sessions limiter.
+pub struct SessionLimiter<Hash> {
+	/// Configuration. If `None`, sessions are not limited.
+	config: Option<SessionLimitsConfiguration>,
+	/// Limiter state.
+	state: Mutex<SessionLimiterState<Hash>>,
+}
+
+/// Sessions limiter state.
+struct SessionLimiterState<Hash> {
+	/// Queued tasks of every key server route.
+	queued_tasks: BTreeMap<KeyServerHandle, VecDeque<(TaskOriginBlock<Hash>, BlockchainServiceTask)>>,
+	/// Running sessions of limited kinds, mapped to the time when they have been started.
+	running_sessions: BTreeMap<ServedRequest, Instant>,
+}
+
+impl<Hash> SessionLimiter<Hash> {
+	/// Create new limiter.
+	pub fn new(config: Option<SessionLimitsConfiguration>) -> Self {
+		SessionLimiter {
+			config,
+			state: Mutex::new(SessionLimiterState {
+				queued_tasks: BTreeMap::new(),
+				running_sessions: BTreeMap::new(),
+			}),
+		}
+	}
+
+	/// Returns true if sessions are limited.
+	pub fn is_enabled(&self) -> bool {
+		self.config.is_some()
+	}
+
+	/// Returns true if session for the task could be started now. The task is counted
+	/// as running once it is dispatched.
+	pub fn accepts_task(&self, task: &BlockchainServiceTask) -> bool {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return true,
+		};
+		let request = match ServedRequest::from_task(task) {
+			Some(request) => request,
+			None => return true,
+		};
+		let max_running_sessions = match config.max_running_sessions.get(&request.task_kind) {
+			Some(max_running_sessions) => *max_running_sessions,
+			None => return true,
+		};
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		state.running_sessions.retain(|running_request, started_at| {
+			let is_expired = started_at.elapsed() >= config.session_timeout;
+			if is_expired {
+				trace!(
+					target: "secretstore",
+					"{:?} session has timed out. Releasing its slot",
+					running_request.task_kind,
+				);
+			}
+			!is_expired
+		});
+
+		// task of already running session doesn't start new session
+		let running_sessions = state.running_sessions
+			.keys()
+			.filter(|running_request| running_request.task_kind == request.task_kind)
+			.count();
+		state.running_sessions.contains_key(&request) || running_sessions < max_running_sessions
+	}
+
+	/// Called when task is dispatched to the key server.
+	pub fn on_task_dispatched(&self, request: ServedRequest) {
+		let is_limited = self.config
+			.as_ref()
+			.map(|config| config.max_running_sessions.contains_key(&request.task_kind))
+			.unwrap_or(false);
+		if !is_limited {
+			return;
+		}
+
+		self.state
+			.lock()
+			.expect("never panics under lock; qed")
+			.running_sessions
+			.entry(request)
+			.or_insert_with(Instant::now);
+	}
+
+	/// Called when session has completed, or request no longer requires our response.
+	/// Session slot is released.
+	pub fn on_session_completed(&self, request: &ServedRequest) {
+		if self.config.is_none() {
+			return;
+		}
+
+		self.state.lock().expect("never panics under lock; qed").running_sessions.remove(request);
+	}
+
+	/// Queue task that is over the limit. Returns false if queue is full and the task
+	/// has been dropped.
+	pub fn queue(
+		&self,
+		route: KeyServerHandle,
+		task_origin_block: TaskOriginBlock<Hash>,
+		task: BlockchainServiceTask,
+	) -> bool {
+		let max_queued_tasks = match self.config {
+			Some(ref config) => config.max_queued_tasks,
+			None => return false,
+		};
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let route_tasks = state.queued_tasks.entry(route).or_default();
+		if route_tasks.len() >= max_queued_tasks {
+			trace!(
+				target: "secretstore",
+				"Sessions queue of key server {} is full. Task will be read by pending tasks scan",
+				route,
+			);
+
+			return false;
+		}
+
+		route_tasks.push_back((task_origin_block, task));
+		true
+	}
+
+	/// Take tasks that have been queued by given route.
+	pub fn take(&self, route: Option<KeyServerHandle>) -> VecDeque<(TaskOriginBlock<Hash>, BlockchainServiceTask)> {
+		match route {
+			Some(route) => self.state
+				.lock()
+				.expect("never panics under lock; qed")
+				.queued_tasks
+				.remove(&route)
+				.unwrap_or_default(),
+			None => VecDeque::new(),
+		}
+	}
+
+	/// Returns number of tasks that are currently queued.
+	pub fn queued_tasks(&self) -> usize {
+		self.state
+			.lock()
+			.expect("never panics under lock; qed")
+			.queued_tasks
+			.values()
+			.map(VecDeque::len)
+			.sum()
+	}
+}
@@ -0,0 +1,184 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::{BTreeMap, VecDeque},
+	sync::{Arc, Mutex},
+};
+use log::{trace, warn};
+use parity_secretstore_primitives::{Address, ServerKeyId};
+use crate::{SecretStoreCall, TaskKind, confidential::Redactor};
+
+/// Max number of responses that are waiting for the response of other key server.
+const MAX_UNMATCHED_RESPONSES: usize = 1024;
+
+/// Called when primary and candidate key servers have produced different responses.
+pub type ShadowMismatchHandler = Arc<dyn Fn(&ShadowMismatch) + Send + Sync>;
+
+/// Role of the key server in shadow mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadowRole {
+	/// Primary key server: responses are published.
+	Primary,
+	/// Candidate key server: responses are only compared with responses of primary.
+	Candidate,
+}
+
+/// Responses of primary and candidate key servers that do not match.
+#[derive(Debug, Clone)]
+pub struct ShadowMismatch {
+	/// Response of primary key server.
+	pub primary: Result<SecretStoreCall, String>,
+	/// Response of candidate key server.
+	pub candidate: Result<SecretStoreCall, String>,
+}
+
+/// Compares responses of primary and candidate key servers.
+pub struct ShadowComparator {
+	/// Mismatch handler.
+	handler: Option<ShadowMismatchHandler>,
+	/// Identifying data formatter.
+	redactor: Redactor,
+	/// Responses waiting for the response of other key server.
+	unmatched: Mutex<UnmatchedResponses>,
+}
+
+/// Key of the request, response to which is compared.
+type RequestKey = (TaskKind, ServerKeyId, Option<Address>, bool);
+
+/// Responses waiting for the response of other key server.
+#[derive(Default)]
+struct UnmatchedResponses {
+	/// Index of the current block.
+	current_block: u64,
+	/// Responses by request, along with the block where response has been recorded.
+	responses: BTreeMap<RequestKey, (ShadowRole, Result<SecretStoreCall, String>, u64)>,
+	/// Requests in order of insertion.
+	order: VecDeque<RequestKey>,
+}
+
+impl ShadowComparator {
+	/// Create new comparator.
+	pub fn new(handler: Option<ShadowMismatchHandler>, redactor: Redactor) -> Self {
+		ShadowComparator {
+			handler,
+			redactor,
+			unmatched: Mutex::new(UnmatchedResponses::default()),
+		}
+	}
+
+	/// Called when new block is processed.
+	pub fn on_new_block(&self) {
+		self.unmatched.lock().expect("never panics under lock; qed").current_block += 1;
+	}
+
+	/// Forget responses that are waiting for the response of other key server for
+	/// more than `retention` blocks. Returns number of forgotten responses.
+	pub fn collect_garbage(&self, retention: u64) -> usize {
+		let mut unmatched = self.unmatched.lock().expect("never panics under lock; qed");
+		let current_block = unmatched.current_block;
+		let responses_count = unmatched.responses.len();
+		unmatched.responses.retain(|_, (_, _, recorded_at)| current_block - *recorded_at <= retention);
+
+		let UnmatchedResponses { ref responses, ref mut order, .. } = *unmatched;
+		order.retain(|key| responses.contains_key(key));
+		responses_count - responses.len()
+	}
+
+	/// Record response of key server to the request. Request is identified by kind, key id,
+	/// requester and phase (common or personal) of document key shadow retrieval. If
+	/// response of other key server is already known, responses are compared.
+	pub fn on_response(
+		&self,
+		role: ShadowRole,
+		task_kind: TaskKind,
+		key_id: ServerKeyId,
+		requester: Option<Address>,
+		is_personal: bool,
+		response: Result<SecretStoreCall, String>,
+	) {
+		let request_key = (task_kind, key_id, requester, is_personal);
+		let other_response = {
+			let mut unmatched = self.unmatched.lock().expect("never panics under lock; qed");
+			match unmatched.responses.remove(&request_key) {
+				Some((other_role, other_response, _)) if other_role != role => {
+					unmatched.order.retain(|key| *key != request_key);
+					other_response
+				},
+				_ => {
+					let current_block = unmatched.current_block;
+					if unmatched.responses.insert(request_key, (role, response, current_block)).is_none() {
+						unmatched.order.push_back(request_key);
+					}
+					while unmatched.order.len() > MAX_UNMATCHED_RESPONSES {
+						if let Some(oldest_key) = unmatched.order.pop_front() {
+							unmatched.responses.remove(&oldest_key);
+						}
+					}
+					return;
+				},
+			}
+		};
+
+		let (primary, candidate) = match role {
+			ShadowRole::Primary => (response, other_response),
+			ShadowRole::Candidate => (other_response, response),
+		};
+		if responses_match(&primary, &candidate) {
+			trace!(
+				target: "secretstore",
+				"Shadow key server response to {:?}({}) matches primary response",
+				task_kind,
+				self.redactor.redact(&key_id),
+			);
+			return;
+		}
+
+		warn!(
+			target: "secretstore",
+			"Shadow key server response to {:?}({}) differs from primary response",
+			task_kind,
+			self.redactor.redact(&key_id),
+		);
+		if let Some(ref handler) = self.handler {
+			handler(&ShadowMismatch { primary, candidate });
+		}
+	}
+}
+
+/// Returns true if responses of primary and candidate key servers match. Only
+/// deterministic parts of responses are compared.
+fn responses_match(
+	primary: &Result<SecretStoreCall, String>,
+	candidate: &Result<SecretStoreCall, String>,
+) -> bool {
+	let (primary, candidate) = match (primary, candidate) {
+		(Ok(primary), Ok(candidate)) => (primary, candidate),
+		(Err(_), Err(_)) => return true,
+		_ => return false,
+	};
+
+	match (primary, candidate) {
+		// generated keys are random
+		(SecretStoreCall::ServerKeyGenerated(..), SecretStoreCall::ServerKeyGenerated(..)) => true,
+		// encrypted document key and coefficients are encrypted with random nonce
+		(
+			SecretStoreCall::DocumentKeyPersonalRetrieved(_, _, primary_participants, _, _),
+			SecretStoreCall::DocumentKeyPersonalRetrieved(_, _, candidate_participants, _, _),
+		) => primary_participants == candidate_participants,
+		_ => primary == candidate,
+	}
+}
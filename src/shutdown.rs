@@ -0,0 +1,173 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Structured report of the service shutdown.
+//!
+//! When service is stopped by the embedder or terminated because of error, the
+//! report of work that has been interrupted is emitted. Requests of abandoned tasks
+//! and unsubmitted responses are still pending on chain, so they're served again
+//! after restart, unless the response is recorded as already submitted.
+
+use std::{
+	fmt,
+	sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+};
+use log::info;
+use parity_secretstore_primitives::{Address, ServerKeyId};
+use crate::{
+	TaskKind,
+	dedup::{ServedRequest, SubmittedResponses},
+	escalation::ErrorClass,
+	queue::{QueueReason, SubmissionQueue},
+	watchdog::Watchdog,
+};
+
+/// Why service has been shut down.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShutdownReason {
+	/// Service has been stopped by the embedder.
+	Graceful,
+	/// Service has been terminated because of given error.
+	Fatal(ErrorClass, String),
+}
+
+/// Response that has been left unsubmitted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsubmittedResponse {
+	/// Address of the key server that has produced the response.
+	pub key_server: Address,
+	/// Why response has been queued.
+	pub reason: QueueReason,
+	/// Request description.
+	pub description: String,
+	/// True if response to the request is in the persistent record of submitted
+	/// responses, so the request isn't served again after restart until the record
+	/// expires.
+	pub is_persisted: bool,
+}
+
+/// Service shutdown report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownReport {
+	/// Why service has been shut down.
+	pub reason: ShutdownReason,
+	/// Tasks which sessions have been running at shutdown.
+	pub abandoned_tasks: Vec<(TaskKind, ServerKeyId)>,
+	/// Responses that have been queued, but not submitted.
+	pub unsubmitted_responses: Vec<UnsubmittedResponse>,
+	/// Number of the last processed block, if known. Blocks are only tracked if
+	/// shutdown handler or checkpoint is configured.
+	pub last_processed_block: Option<u64>,
+}
+
+/// Called when service is shut down.
+pub type ShutdownHandler = Arc<dyn Fn(&ShutdownReport) + Send + Sync>;
+
+/// Service shutdown reporter.
+pub struct ShutdownReporter {
+	/// Shutdown handler.
+	handler: Option<ShutdownHandler>,
+	/// Number of the last processed block.
+	last_processed_block: Mutex<Option<u64>>,
+	/// True if report has been already emitted.
+	is_reported: AtomicBool,
+}
+
+impl ShutdownReporter {
+	/// Create new shutdown reporter.
+	pub fn new(handler: Option<ShutdownHandler>) -> Self {
+		ShutdownReporter {
+			handler,
+			last_processed_block: Mutex::new(None),
+			is_reported: AtomicBool::new(false),
+		}
+	}
+
+	/// Returns true if the last processed block needs to be tracked, i.e. if shutdown
+	/// handler is configured.
+	pub fn is_tracking_blocks(&self) -> bool {
+		self.handler.is_some()
+	}
+
+	/// Called when block is processed.
+	pub fn on_block_processed(&self, block_number: u64) {
+		*self.last_processed_block.lock().expect("never panics under lock; qed") = Some(block_number);
+	}
+
+	/// Build shutdown report. Report is logged and passed to the handler only once.
+	pub fn report(
+		&self,
+		reason: ShutdownReason,
+		watchdog: &Watchdog,
+		submission_queue: &SubmissionQueue,
+		submitted_responses: Option<&SubmittedResponses>,
+	) -> ShutdownReport {
+		let report = ShutdownReport {
+			reason,
+			abandoned_tasks: watchdog.running_sessions(),
+			unsubmitted_responses: submission_queue
+				.snapshot()
+				.into_values()
+				.map(|response| UnsubmittedResponse {
+					is_persisted: submitted_responses
+						.map(|submitted_responses| ServedRequest::from_accepted_call(Some(response.origin), &response.call)
+							.iter()
+							.any(|request| submitted_responses.submitted_response(request).is_some())
+						)
+						.unwrap_or(false),
+					key_server: response.key_server,
+					reason: response.reason,
+					description: response.description,
+				})
+				.collect(),
+			last_processed_block: *self.last_processed_block.lock().expect("never panics under lock; qed"),
+		};
+
+		if !self.is_reported.swap(true, Ordering::SeqCst) {
+			info!(
+				target: "secretstore",
+				"Secret Store service shutdown: {}",
+				report,
+			);
+
+			if let Some(ref handler) = self.handler {
+				handler(&report);
+			}
+		}
+
+		report
+	}
+}
+
+impl fmt::Display for ShutdownReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.reason {
+			ShutdownReason::Graceful => write!(f, "graceful")?,
+			ShutdownReason::Fatal(class, ref error) => write!(f, "fatal {:?} error: {}", class, error)?,
+		}
+		write!(
+			f,
+			", {} abandoned tasks, {} unsubmitted responses ({} persisted)",
+			self.abandoned_tasks.len(),
+			self.unsubmitted_responses.len(),
+			self.unsubmitted_responses.iter().filter(|response| response.is_persisted).count(),
+		)?;
+		match self.last_processed_block {
+			Some(block_number) => write!(f, ", last processed block: {}", block_number),
+			None => write!(f, ", last processed block: unknown"),
+		}
+	}
+}
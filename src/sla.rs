@@ -0,0 +1,196 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+};
+use log::warn;
+use parity_secretstore_primitives::ServerKeyId;
+use crate::{TaskKind, confidential::Redactor};
+
+/// Called when SLA violation is detected.
+pub type SlaViolationHandler = Arc<dyn Fn(&SlaViolation) + Send + Sync>;
+
+/// Stage of request processing where time has been lost.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlaStage {
+	/// Response has not been submitted yet (task is waiting for dispatch or the session
+	/// is still running).
+	Session,
+	/// Response has been submitted, but it has not been accepted by the runtime yet.
+	Inclusion,
+}
+
+/// SLA violation.
+#[derive(Debug, Clone)]
+pub struct SlaViolation {
+	/// Kind of the task.
+	pub task_kind: TaskKind,
+	/// Key id of the task.
+	pub key_id: ServerKeyId,
+	/// Target latency (in blocks).
+	pub target: u64,
+	/// Number of blocks passed since the request has been seen.
+	pub elapsed: u64,
+	/// Number of blocks spent at the session stage.
+	pub session_blocks: u64,
+	/// Stage at which request is at the moment of violation.
+	pub stage: SlaStage,
+}
+
+/// Tracks latency of requests that this key server is responsible for.
+pub struct SlaTracker {
+	/// Target latency (in blocks) for every task kind. Tasks of other kinds are not tracked.
+	targets: BTreeMap<TaskKind, u64>,
+	/// Violation handler.
+	handler: Option<SlaViolationHandler>,
+	/// Identifying data formatter.
+	redactor: Redactor,
+	/// Tracker state.
+	state: Mutex<SlaTrackerState>,
+}
+
+/// SLA tracker state.
+#[derive(Default)]
+struct SlaTrackerState {
+	/// Index of the current block.
+	current_block: u64,
+	/// Requests that are being tracked.
+	requests: BTreeMap<(TaskKind, ServerKeyId), TrackedRequest>,
+}
+
+/// Tracked request.
+struct TrackedRequest {
+	/// Block where request has been seen first.
+	seen_at: u64,
+	/// Block where response has been submitted.
+	submitted_at: Option<u64>,
+	/// True if violation has already been reported.
+	is_reported: bool,
+}
+
+impl SlaTracker {
+	/// Create new tracker.
+	pub fn new(
+		targets: BTreeMap<TaskKind, u64>,
+		handler: Option<SlaViolationHandler>,
+		redactor: Redactor,
+	) -> Self {
+		SlaTracker {
+			targets,
+			handler,
+			redactor,
+			state: Mutex::new(SlaTrackerState::default()),
+		}
+	}
+
+	/// Called when new block is processed. Reports all new violations.
+	pub fn on_new_block(&self) {
+		if self.targets.is_empty() {
+			return;
+		}
+
+		let mut violations = Vec::new();
+		{
+			let mut state = self.state.lock().expect("SLA tracker never panics under lock; qed");
+			state.current_block += 1;
+
+			let current_block = state.current_block;
+			for ((task_kind, key_id), request) in state.requests.iter_mut() {
+				let target = match self.targets.get(task_kind) {
+					Some(target) => *target,
+					None => continue,
+				};
+				let elapsed = current_block - request.seen_at;
+				if request.is_reported || elapsed <= target {
+					continue;
+				}
+
+				request.is_reported = true;
+				violations.push(SlaViolation {
+					task_kind: *task_kind,
+					key_id: *key_id,
+					target,
+					elapsed,
+					session_blocks: request.submitted_at.unwrap_or(current_block) - request.seen_at,
+					stage: match request.submitted_at {
+						Some(_) => SlaStage::Inclusion,
+						None => SlaStage::Session,
+					},
+				});
+			}
+		}
+
+		for violation in violations {
+			warn!(
+				target: "secretstore",
+				"SLA violation: {:?} request {} is not answered within {} blocks ({} blocks passed, {} at session stage, now at {:?} stage)",
+				violation.task_kind,
+				self.redactor.redact(&violation.key_id),
+				violation.target,
+				violation.elapsed,
+				violation.session_blocks,
+				violation.stage,
+			);
+
+			if let Some(ref handler) = self.handler {
+				handler(&violation);
+			}
+		}
+	}
+
+	/// Called when request is seen (either in new or in pending tasks).
+	pub fn on_request_seen(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		if !self.targets.contains_key(&task_kind) {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("SLA tracker never panics under lock; qed");
+		let current_block = state.current_block;
+		state.requests.entry((task_kind, key_id)).or_insert_with(|| TrackedRequest {
+			seen_at: current_block,
+			submitted_at: None,
+			is_reported: false,
+		});
+	}
+
+	/// Called when response to the request has been submitted.
+	pub fn on_response_submitted(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		let mut state = self.state.lock().expect("SLA tracker never panics under lock; qed");
+		let current_block = state.current_block;
+		if let Some(request) = state.requests.get_mut(&(task_kind, key_id)) {
+			request.submitted_at.get_or_insert(current_block);
+		}
+	}
+
+	/// Stop tracking requests that have been seen more than `retention` blocks ago
+	/// and have already been reported. Returns number of dropped requests.
+	pub fn collect_garbage(&self, retention: u64) -> usize {
+		let mut state = self.state.lock().expect("SLA tracker never panics under lock; qed");
+		let current_block = state.current_block;
+		let requests_count = state.requests.len();
+		state.requests.retain(|_, request| !request.is_reported || current_block - request.seen_at <= retention);
+		requests_count - state.requests.len()
+	}
+
+	/// Called when request no longer requires our response (it has been accepted or
+	/// the request is completed by other key servers).
+	pub fn on_request_completed(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		let mut state = self.state.lock().expect("SLA tracker never panics under lock; qed");
+		state.requests.remove(&(task_kind, key_id));
+	}
+}
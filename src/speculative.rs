@@ -0,0 +1,102 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Speculative processing of requests from non-finalized blocks.
+//!
+//! When blockchain is feeding the service with best (not yet finalized) blocks,
+//! sessions are started immediately, but responses are held until the block where
+//! request has been seen is finalized. If the block is retracted, held responses
+//! are dropped. So we get the latency of best blocks processing without paying
+//! fees for responses to requests that have never made it to the canonical chain.
+
+use std::{
+	collections::{BTreeMap, VecDeque},
+	sync::Mutex,
+};
+use crate::dedup::ServedRequest;
+
+/// Max number of speculatively started tasks that are tracked.
+const MAX_TRACKED_TASKS: usize = 4096;
+
+/// Finality status of the block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockFinality {
+	/// Block is finalized.
+	Finalized,
+	/// Block is not yet finalized, but it is still on the best chain.
+	NotFinalized,
+	/// Block has been retracted from the best chain.
+	Retracted,
+}
+
+/// Blocks where speculatively started tasks have been seen.
+pub struct SpeculativeTasks<Hash> {
+	/// Tasks state.
+	state: Mutex<SpeculativeTasksState<Hash>>,
+}
+
+/// Speculatively started tasks state.
+struct SpeculativeTasksState<Hash> {
+	/// Origin blocks by request.
+	origin_blocks: BTreeMap<ServedRequest, Hash>,
+	/// Requests in order of insertion.
+	order: VecDeque<ServedRequest>,
+}
+
+impl<Hash: Clone> SpeculativeTasks<Hash> {
+	/// Create new tasks tracker.
+	pub fn new() -> Self {
+		SpeculativeTasks {
+			state: Mutex::new(SpeculativeTasksState {
+				origin_blocks: BTreeMap::new(),
+				order: VecDeque::new(),
+			}),
+		}
+	}
+
+	/// Called when task is seen at given (not yet finalized) block.
+	pub fn on_task_seen(&self, request: ServedRequest, block_hash: Hash) {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		if state.origin_blocks.insert(request, block_hash).is_none() {
+			state.order.push_back(request);
+		}
+
+		while state.order.len() > MAX_TRACKED_TASKS {
+			if let Some(oldest_request) = state.order.pop_front() {
+				state.origin_blocks.remove(&oldest_request);
+			}
+		}
+	}
+
+	/// Returns block where task has been seen.
+	pub fn origin_block(&self, request: &ServedRequest) -> Option<Hash> {
+		self.state.lock().expect("never panics under lock; qed").origin_blocks.get(request).cloned()
+	}
+
+	/// Stop tracking the task.
+	pub fn forget(&self, request: &ServedRequest) {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		if state.origin_blocks.remove(request).is_some() {
+			state.order.retain(|tracked_request| tracked_request != request);
+		}
+	}
+}
+
+impl<Hash: Clone> Default for SpeculativeTasks<Hash> {
+	fn default() -> Self {
+		SpeculativeTasks::new()
+	}
+}
@@ -0,0 +1,183 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-block processing summary.
+//!
+//! Blocks where nothing visible has happened are otherwise silent in logs. The
+//! summary is logged (at debug level) when the block processing is completed, so it
+//! is easy to find out why some request hasn't been picked up. Compact summary of
+//! the last block is also available for inclusion in the node informant line.
+
+use std::{
+	collections::BTreeMap,
+	fmt,
+	sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+};
+use log::debug;
+use crate::KeyServerHandle;
+
+/// Why task has been skipped by the key server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+	/// Task is routed to other key server.
+	RoutedElsewhere,
+	/// Task has crashed the service (or failed) several times.
+	Quarantined,
+	/// Key id of the task isn't served.
+	KeyIdFilter,
+	/// Task violates policy of its origin.
+	PolicyViolation,
+	/// Response to the task has been already submitted.
+	AlreadyServed,
+	/// Task isn't served for its origin (or origin quota is exhausted).
+	TenantPolicy,
+	/// Request has been recently completed.
+	AlreadyCompleted,
+	/// Key server cluster has no capacity to serve the task.
+	NoCapacity,
+	/// Task is served by session, started for other origin.
+	SharedGeneration,
+	/// Max number of running sessions of task kind is reached.
+	SessionLimit,
+}
+
+/// Compact summary of the last processed block, designed for inclusion in the node
+/// informant (status) line.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InformantSummary {
+	/// Number of tasks dispatched to key servers.
+	pub tasks_dispatched: usize,
+	/// Number of tasks skipped by key servers.
+	pub tasks_skipped: usize,
+	/// Number of responses submitted since previous block.
+	pub responses_submitted: usize,
+}
+
+/// Last block summaries of all key servers.
+#[derive(Default)]
+pub struct Informant {
+	/// Summary of the last block, processed by every key server route.
+	summaries: Mutex<BTreeMap<Option<KeyServerHandle>, InformantSummary>>,
+}
+
+/// Summary of the block processing by single key server.
+pub struct BlockSummary {
+	/// Key server route that is processing the block.
+	route: Option<KeyServerHandle>,
+	/// Informant that receives compact summary when processing is completed.
+	informant: Arc<Informant>,
+	/// Number of the SecretStore runtime module events in the block.
+	events_seen: AtomicUsize,
+	/// Number of tasks decoded from the block events.
+	tasks_decoded: AtomicUsize,
+	/// Number of tasks read by the pending tasks scan.
+	pending_tasks_read: AtomicUsize,
+	/// Number of tasks dispatched to the key server.
+	tasks_dispatched: AtomicUsize,
+	/// Number of skipped tasks by reason.
+	tasks_skipped: Mutex<BTreeMap<SkipReason, usize>>,
+	/// Number of responses submitted since previous block.
+	responses_submitted: usize,
+}
+
+impl BlockSummary {
+	/// Create new block summary.
+	pub fn new(route: Option<KeyServerHandle>, informant: Arc<Informant>, responses_submitted: usize) -> Self {
+		BlockSummary {
+			route,
+			informant,
+			events_seen: AtomicUsize::new(0),
+			tasks_decoded: AtomicUsize::new(0),
+			pending_tasks_read: AtomicUsize::new(0),
+			tasks_dispatched: AtomicUsize::new(0),
+			tasks_skipped: Mutex::new(BTreeMap::new()),
+			responses_submitted,
+		}
+	}
+
+	/// Called when block events are decoded.
+	pub fn on_events_decoded(&self, events_seen: usize, tasks_decoded: usize) {
+		self.events_seen.fetch_add(events_seen, Ordering::Relaxed);
+		self.tasks_decoded.fetch_add(tasks_decoded, Ordering::Relaxed);
+	}
+
+	/// Called when task is read by the pending tasks scan.
+	pub fn on_pending_task_read(&self) {
+		self.pending_tasks_read.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Called when task is dispatched to the key server.
+	pub fn on_task_dispatched(&self) {
+		self.tasks_dispatched.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Called when task is skipped.
+	pub fn on_task_skipped(&self, reason: SkipReason) {
+		*self.tasks_skipped.lock().expect("never panics under lock; qed").entry(reason).or_default() += 1;
+	}
+}
+
+impl Informant {
+	/// Called when block has been processed by the key server.
+	pub fn on_block_processed(&self, route: Option<KeyServerHandle>, summary: InformantSummary) {
+		self.summaries.lock().expect("never panics under lock; qed").insert(route, summary);
+	}
+
+	/// Returns summary of the last block, aggregated over all primary key servers.
+	pub fn summary(&self) -> InformantSummary {
+		self.summaries
+			.lock()
+			.expect("never panics under lock; qed")
+			.iter()
+			.filter(|(route, _)| route.is_some())
+			.fold(InformantSummary::default(), |total, (_, summary)| InformantSummary {
+				tasks_dispatched: total.tasks_dispatched + summary.tasks_dispatched,
+				tasks_skipped: total.tasks_skipped + summary.tasks_skipped,
+				responses_submitted: total.responses_submitted + summary.responses_submitted,
+			})
+	}
+}
+
+impl fmt::Display for InformantSummary {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} SS tasks, {} responses submitted", self.tasks_dispatched, self.responses_submitted)
+	}
+}
+
+impl Drop for BlockSummary {
+	fn drop(&mut self) {
+		let tasks_skipped = self.tasks_skipped.lock().expect("never panics under lock; qed");
+		self.informant.on_block_processed(self.route, InformantSummary {
+			tasks_dispatched: self.tasks_dispatched.load(Ordering::Relaxed),
+			tasks_skipped: tasks_skipped.values().sum(),
+			responses_submitted: self.responses_submitted,
+		});
+
+		let route = self.route.map(|route| route.to_string()).unwrap_or_else(|| "shadow".into());
+		debug!(
+			target: "secretstore",
+			"Block processed by key server {}: {} events, {} decoded tasks, {} pending tasks, \
+				{} dispatched tasks, skipped tasks: {:?}, {} responses submitted since previous block",
+			route,
+			self.events_seen.load(Ordering::Relaxed),
+			self.tasks_decoded.load(Ordering::Relaxed),
+			self.pending_tasks_read.load(Ordering::Relaxed),
+			self.tasks_dispatched.load(Ordering::Relaxed),
+			tasks_skipped,
+			self.responses_submitted,
+		);
+	}
+}
@@ -0,0 +1,166 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	sync::Mutex,
+};
+use parity_secretstore_primitives::{Address, service::ServiceTask};
+use crate::{
+	BlockchainServiceTask, TaskKind, task_kind_and_key_id, task_origin,
+	identity::AccountId32,
+};
+
+/// Policy of serving requests coming from single origin (tenant).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TenantConfiguration {
+	/// Kinds of tasks that are served. If `None`, all tasks are served.
+	pub enabled_task_kinds: Option<BTreeSet<TaskKind>>,
+	/// Max number of tasks that are started at single block. If `None`, there's no limit.
+	pub max_tasks_per_block: Option<usize>,
+	/// Priority of tasks. Tasks of tenants with larger priority are started first.
+	pub priority: i32,
+	/// Weight of the tenant in fair scheduling among tenants with the same priority:
+	/// at every scheduling round, up to `weight` tasks of the tenant are started. Zero
+	/// weight is treated as 1.
+	pub weight: u32,
+	/// Account that submits responses. If `None`, default account is used.
+	pub submitter_account: Option<AccountId32>,
+	/// Policy on thresholds of server key generation requests. Requests that are
+	/// violating the policy are rejected with error response. If `None`, all
+	/// thresholds are accepted.
+	pub threshold_policy: Option<ThresholdPolicy>,
+}
+
+/// Policy on thresholds of server key generation requests.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ThresholdPolicy {
+	/// Min acceptable threshold.
+	pub min_threshold: usize,
+	/// Max acceptable threshold. If `None`, there's no upper limit.
+	pub max_threshold: Option<usize>,
+	/// If true, threshold must be less than the size of the current key servers set,
+	/// so that at least `threshold + 1` key servers could participate in session.
+	pub fits_key_servers_set: bool,
+}
+
+/// Per-origin serving policies.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tenants {
+	/// Policy of origins that are missing from `tenants`.
+	pub default: TenantConfiguration,
+	/// Policies of known origins.
+	pub tenants: BTreeMap<Address, TenantConfiguration>,
+}
+
+/// Number of tasks that have been accepted from every origin at single block.
+#[derive(Default)]
+pub struct TenantQuotas {
+	/// Number of accepted tasks by origin.
+	accepted_tasks: Mutex<BTreeMap<Address, usize>>,
+}
+
+impl Tenants {
+	/// Returns policy of given origin.
+	pub fn tenant(&self, origin: &Address) -> &TenantConfiguration {
+		self.tenants.get(origin).unwrap_or(&self.default)
+	}
+
+	/// Returns priority of the task.
+	pub fn task_priority(&self, task: &BlockchainServiceTask) -> i32 {
+		self.tenant(&task_origin(task)).priority
+	}
+
+	/// Returns fair scheduling weight of given origin.
+	pub fn weight(&self, origin: &Address) -> u32 {
+		std::cmp::max(self.tenant(origin).weight, 1)
+	}
+
+	/// Returns account that should submit responses to given origin.
+	pub fn submitter_account(&self, origin: &Address) -> Option<&AccountId32> {
+		self.tenant(origin).submitter_account.as_ref()
+	}
+
+	/// Returns reason why server key generation task violates threshold policy of its
+	/// origin. Other tasks never violate the policy.
+	pub fn threshold_policy_violation(
+		&self,
+		task: &BlockchainServiceTask,
+		key_servers_count: impl FnOnce() -> usize,
+	) -> Option<String> {
+		let threshold = match *task {
+			BlockchainServiceTask::Regular(_, ServiceTask::GenerateServerKey(_, _, threshold)) => threshold,
+			_ => return None,
+		};
+		let policy = self.tenant(&task_origin(task)).threshold_policy.as_ref()?;
+
+		if threshold < policy.min_threshold {
+			return Some(format!("threshold {} is less than min threshold {}", threshold, policy.min_threshold));
+		}
+		if let Some(max_threshold) = policy.max_threshold {
+			if threshold > max_threshold {
+				return Some(format!("threshold {} is larger than max threshold {}", threshold, max_threshold));
+			}
+		}
+		if policy.fits_key_servers_set {
+			let key_servers_count = key_servers_count();
+			if threshold >= key_servers_count {
+				return Some(format!("threshold {} requires more than {} key servers", threshold, key_servers_count));
+			}
+		}
+
+		None
+	}
+
+	/// Returns true if task is allowed by the tenant policy and tenant quota at
+	/// current block is not yet exhausted.
+	pub fn accepts_task(&self, quotas: &TenantQuotas, task: &BlockchainServiceTask) -> bool {
+		let origin = task_origin(task);
+		let tenant = self.tenant(&origin);
+		if let Some(ref enabled_task_kinds) = tenant.enabled_task_kinds {
+			match task_kind_and_key_id(task) {
+				Some((task_kind, _)) if enabled_task_kinds.contains(&task_kind) => (),
+				_ => return false,
+			}
+		}
+
+		if let Some(max_tasks_per_block) = tenant.max_tasks_per_block {
+			let mut accepted_tasks = quotas.accepted_tasks.lock().expect("never panics under lock; qed");
+			let accepted_tasks = accepted_tasks.entry(origin).or_default();
+			if *accepted_tasks >= max_tasks_per_block {
+				return false;
+			}
+
+			*accepted_tasks += 1;
+		}
+
+		true
+	}
+
+	/// Called when task, accepted by `accepts_task`, is skipped for other reason, so it
+	/// doesn't consume quota of its tenant.
+	pub fn on_task_skipped(&self, quotas: &TenantQuotas, task: &BlockchainServiceTask) {
+		let origin = task_origin(task);
+		if self.tenant(&origin).max_tasks_per_block.is_none() {
+			return;
+		}
+
+		let mut accepted_tasks = quotas.accepted_tasks.lock().expect("never panics under lock; qed");
+		if let Some(accepted_tasks) = accepted_tasks.get_mut(&origin) {
+			*accepted_tasks = accepted_tasks.saturating_sub(1);
+		}
+	}
+}
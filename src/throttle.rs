@@ -0,0 +1,138 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	sync::Mutex,
+	time::Duration,
+};
+use log::{info, warn};
+
+/// Adaptive pending scan throttling configuration.
+#[derive(Debug, Clone)]
+pub struct ThrottleConfiguration {
+	/// When average pending query latency exceeds this value, scans are throttled.
+	pub slow_query_latency: Duration,
+	/// When average pending query latency drops below this value, throttling is relaxed.
+	pub recovered_query_latency: Duration,
+	/// Min number of pending tasks read by single query.
+	pub min_page_size: usize,
+	/// Max multiplier of the pending scan interval.
+	pub max_interval_multiplier: u32,
+}
+
+/// Throttles pending scans when the node is slow to answer pending tasks queries.
+pub struct ScanThrottle {
+	/// Configuration. If `None`, scans are never throttled.
+	config: Option<ThrottleConfiguration>,
+	/// Default number of pending tasks read by single query.
+	default_page_size: usize,
+	/// Throttle state.
+	state: Mutex<ThrottleState>,
+}
+
+/// Throttle state.
+struct ThrottleState {
+	/// Average query latency.
+	average_latency: Option<Duration>,
+	/// Current number of pending tasks read by single query.
+	page_size: usize,
+	/// Current multiplier of pending scan interval.
+	interval_multiplier: u32,
+}
+
+impl Default for ThrottleConfiguration {
+	fn default() -> Self {
+		ThrottleConfiguration {
+			slow_query_latency: Duration::from_millis(500),
+			recovered_query_latency: Duration::from_millis(100),
+			min_page_size: 2,
+			max_interval_multiplier: 16,
+		}
+	}
+}
+
+impl ScanThrottle {
+	/// Create new throttle.
+	pub fn new(config: Option<ThrottleConfiguration>, default_page_size: usize) -> Self {
+		ScanThrottle {
+			config,
+			default_page_size,
+			state: Mutex::new(ThrottleState {
+				average_latency: None,
+				page_size: default_page_size,
+				interval_multiplier: 1,
+			}),
+		}
+	}
+
+	/// Returns number of pending tasks that should be read by single query.
+	pub fn page_size(&self) -> usize {
+		self.state.lock().expect("throttle never panics under lock; qed").page_size
+	}
+
+	/// Returns pending scan interval, given base scan interval.
+	pub fn scan_interval(&self, base_interval: u32) -> u32 {
+		let interval_multiplier = self.state.lock().expect("throttle never panics under lock; qed").interval_multiplier;
+		base_interval.saturating_mul(interval_multiplier)
+	}
+
+	/// Record latency of pending tasks query.
+	pub fn on_query_completed(&self, latency: Duration) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+
+		let mut state = self.state.lock().expect("throttle never panics under lock; qed");
+		let average_latency = match state.average_latency {
+			Some(average_latency) => (average_latency * 3 + latency) / 4,
+			None => latency,
+		};
+		state.average_latency = Some(average_latency);
+
+		if average_latency > config.slow_query_latency {
+			let page_size = std::cmp::max(state.page_size / 2, config.min_page_size);
+			let interval_multiplier = std::cmp::min(state.interval_multiplier * 2, config.max_interval_multiplier);
+			if page_size != state.page_size || interval_multiplier != state.interval_multiplier {
+				warn!(
+					target: "secretstore",
+					"Pending tasks queries are slow ({:?}). Throttling pending scans: page size {}, interval x{}",
+					average_latency,
+					page_size,
+					interval_multiplier,
+				);
+			}
+
+			state.page_size = page_size;
+			state.interval_multiplier = interval_multiplier;
+		} else if average_latency < config.recovered_query_latency {
+			let page_size = std::cmp::min(state.page_size * 2, self.default_page_size);
+			let interval_multiplier = std::cmp::max(state.interval_multiplier / 2, 1);
+			if page_size != state.page_size || interval_multiplier != state.interval_multiplier {
+				info!(
+					target: "secretstore",
+					"Pending tasks queries have recovered ({:?}). Relaxing pending scans throttling: page size {}, interval x{}",
+					average_latency,
+					page_size,
+					interval_multiplier,
+				);
+			}
+
+			state.page_size = page_size;
+			state.interval_multiplier = interval_multiplier;
+		}
+	}
+}
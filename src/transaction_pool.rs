@@ -14,8 +14,13 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::sync::Arc;
-use log::{error, trace};
+use std::{
+	cmp::Reverse,
+	collections::BTreeMap,
+	sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+	time::Instant,
+};
+use log::{error, info, trace, warn};
 use parity_secretstore_primitives::{
 	Address, ServerKeyId,
 	key_server::{
@@ -25,17 +30,150 @@ use parity_secretstore_primitives::{
 	requester::Requester,
 };
 use crate::{
-	Blockchain, SecretStoreCall, TransactionPool,
+	Blockchain, ResponseOrdering, SecondaryPublisher, SecretStoreCall, ServiceContext, SubmitError, TaskKind,
+	TransactionPool,
+	submit_call,
+	batch::split_into_batches,
+	confidential::Redactor,
+	dedup::ServedRequest,
+	error::ServiceError,
+	escalation::ErrorClass,
+	identity::{AccountId32, requester_address},
+	layer::apply_response_layers,
+	poison::FailureStage,
+	queue::{QueueReason, QueuedResponse, QueuedResponseId},
+	reconcile::is_response_required,
+	shadow::{ShadowComparator, ShadowRole},
+	speculative::BlockFinality,
 };
 
 /// Substrate transction pool.
-pub struct SubstrateTransactionPool<B, P> {
-	/// Shared blockchain reference.
-	blockchain: Arc<B>,
+pub struct SubstrateTransactionPool<B: Blockchain, P> {
+	/// Shared service state.
+	context: Arc<ServiceContext<B>>,
 	/// Shared reference to actual transaction pool.
 	transaction_pool: Arc<P>,
 	/// This key server address.
 	key_server_address: Address,
+	/// Identifying data formatter.
+	redactor: Redactor,
+	/// Secondary publication target.
+	secondary_publisher: Option<Arc<dyn SecondaryPublisher>>,
+	/// Shadow mode comparator and role of this key server.
+	shadow: Option<(Arc<ShadowComparator>, ShadowRole)>,
+	/// Responses to speculatively started tasks that are waiting for origin block finalization.
+	held_responses: Mutex<Vec<HeldResponse<B::BlockHash>>>,
+	/// Error responses that are buffered while key server cluster is unavailable.
+	buffered_errors: Mutex<Vec<BufferedError>>,
+	/// Responses that are waiting to be submitted in batch.
+	batched_responses: Mutex<Vec<BatchedResponse>>,
+	/// Number of responses submitted since previous block.
+	submitted_responses_count: AtomicUsize,
+}
+
+/// Error response that is buffered while key server cluster is unavailable.
+struct BufferedError {
+	/// Request that is responded.
+	request: ResponseRequest,
+	/// Request description.
+	description: String,
+	/// The response itself.
+	call: SecretStoreCall,
+	/// Time when response has been buffered.
+	buffered_at: Instant,
+	/// Id of the response in the submission queue.
+	queue_id: QueuedResponseId,
+}
+
+/// Deferred response that is ready to be submitted.
+struct ReleasedResponse {
+	/// Request that is responded.
+	request: ResponseRequest,
+	/// Request description.
+	description: String,
+	/// The response itself.
+	call: SecretStoreCall,
+}
+
+/// Response that is waiting to be submitted in batch.
+struct BatchedResponse {
+	/// Request that is responded.
+	request: ResponseRequest,
+	/// Request description.
+	description: String,
+	/// The response itself.
+	call: SecretStoreCall,
+	/// Id of the response in the submission queue.
+	queue_id: QueuedResponseId,
+}
+
+/// Response that is waiting for origin block finalization.
+struct HeldResponse<Hash> {
+	/// Request that is responded.
+	request: ResponseRequest,
+	/// Request description.
+	description: String,
+	/// The response itself.
+	call: SecretStoreCall,
+	/// Block where request has been seen.
+	origin_block: Hash,
+	/// Id of the response in the submission queue.
+	queue_id: QueuedResponseId,
+}
+
+/// Request that is being responded.
+#[derive(Clone, Copy)]
+struct ResponseRequest {
+	/// Origin of the request.
+	origin: Address,
+	/// Kind of the task.
+	task_kind: TaskKind,
+	/// Key id.
+	key_id: ServerKeyId,
+	/// Requester address (document key shadow retrieval only).
+	requester: Option<Address>,
+	/// True if this is personal part of document key shadow retrieval.
+	is_personal: bool,
+}
+
+impl ResponseRequest {
+	/// Create request that is not bound to requester.
+	fn new(origin: Address, task_kind: TaskKind, key_id: ServerKeyId) -> Self {
+		ResponseRequest {
+			origin,
+			task_kind,
+			key_id,
+			requester: None,
+			is_personal: false,
+		}
+	}
+
+	/// Create document key shadow retrieval request.
+	fn document_key_shadow_retrieval(
+		origin: Address,
+		key_id: ServerKeyId,
+		requester: &Requester,
+		is_personal: bool,
+	) -> Self {
+		ResponseRequest {
+			origin,
+			task_kind: TaskKind::DocumentKeyShadowRetrieval,
+			key_id,
+			requester: requester_address(requester, &key_id).ok(),
+			is_personal,
+		}
+	}
+
+	/// Returns request in the form that is recorded by submitted responses record.
+	fn served(&self) -> ServedRequest {
+		ServedRequest {
+			origin: Some(self.origin),
+			task_kind: self.task_kind,
+			key_id: self.key_id,
+			requester: self.requester,
+			is_personal: self.is_personal,
+		}
+	}
 }
 
 impl<B, P> SubstrateTransactionPool<B, P>
@@ -45,27 +183,207 @@ impl<B, P> SubstrateTransactionPool<B, P>
 {
 	/// Create new transaction pool.
 	pub fn new(
-		blockchain: Arc<B>,
+		context: Arc<ServiceContext<B>>,
 		transaction_pool: Arc<P>,
 		key_server_address: Address,
+		redactor: Redactor,
+		secondary_publisher: Option<Arc<dyn SecondaryPublisher>>,
+		shadow: Option<(Arc<ShadowComparator>, ShadowRole)>,
 	) -> Self {
 		SubstrateTransactionPool {
-			blockchain,
+			context,
 			transaction_pool,
 			key_server_address,
+			redactor,
+			secondary_publisher,
+			shadow,
+			held_responses: Mutex::new(Vec::new()),
+			buffered_errors: Mutex::new(Vec::new()),
+			batched_responses: Mutex::new(Vec::new()),
+			submitted_responses_count: AtomicUsize::new(0),
+		}
+	}
+
+	/// Returns number of responses submitted since previous call.
+	pub fn take_submitted_responses_count(&self) -> usize {
+		self.submitted_responses_count.swap(0, Ordering::Relaxed)
+	}
+
+	/// Called when new block is processed.
+	pub fn on_new_block(&self) {
+		// released responses are joining the batch, so they're submitted right away
+		let mut released_responses = self.release_held_responses();
+		released_responses.extend(self.release_buffered_errors());
+		order_responses(&mut released_responses, self.context.response_ordering);
+		for released_response in released_responses {
+			if !self.is_response_still_required(&released_response) {
+				continue;
+			}
+
+			let description = released_response.description;
+			self.submit_prepared_response(
+				released_response.request,
+				|| description.clone(),
+				Ok(released_response.call),
+			);
+		}
+
+		// responses collected while previous block has been processed
+		self.submit_batched_responses();
+	}
+
+	/// Drop buffered errors if key server cluster has recovered (so requests are retried)
+	/// and return errors which buffer period has expired.
+	fn release_buffered_errors(&self) -> Vec<ReleasedResponse> {
+		let degraded_mode = match self.context.degraded_mode {
+			Some(ref degraded_mode) => degraded_mode,
+			None => return Vec::new(),
+		};
+
+		let buffered_errors = self.take_queued_responses(&self.buffered_errors, |error| error.queue_id);
+		if buffered_errors.is_empty() {
+			return Vec::new();
+		}
+
+		// requests are still pending on chain => they'll be retried by pending scans
+		if degraded_mode.health.is_available() {
+			info!(
+				target: "secretstore",
+				"Key server cluster has recovered. Dropping {} buffered error responses",
+				buffered_errors.len(),
+			);
+			for buffered_error in buffered_errors {
+				self.context.submission_queue.on_response_dequeued(buffered_error.queue_id);
+			}
+			return Vec::new();
+		}
+
+		let (mut released_errors, mut still_buffered_errors) = (Vec::new(), Vec::new());
+		for buffered_error in buffered_errors {
+			if buffered_error.buffered_at.elapsed() < degraded_mode.buffer_period {
+				still_buffered_errors.push(buffered_error);
+				continue;
+			}
+
+			self.context.submission_queue.on_response_dequeued(buffered_error.queue_id);
+			released_errors.push(ReleasedResponse {
+				request: buffered_error.request,
+				description: buffered_error.description,
+				call: buffered_error.call,
+			});
+		}
+
+		self.buffered_errors
+			.lock()
+			.expect("never panics under lock; qed")
+			.extend(still_buffered_errors);
+
+		released_errors
+	}
+
+	/// Take all responses from the local queue, except for those that have been dropped
+	/// from the submission queue manually.
+	fn take_queued_responses<T>(
+		&self,
+		responses: &Mutex<Vec<T>>,
+		queue_id: impl Fn(&T) -> QueuedResponseId,
+	) -> Vec<T> {
+		let responses = std::mem::take(&mut *responses.lock().expect("never panics under lock; qed"));
+		responses
+			.into_iter()
+			.filter(|response| self.context.submission_queue.contains(queue_id(response)))
+			.collect()
+	}
+
+	/// Return held responses which origin blocks have been finalized and drop responses
+	/// which origin blocks have been retracted.
+	fn release_held_responses(&self) -> Vec<ReleasedResponse> {
+		let held_responses = self.take_queued_responses(&self.held_responses, |response| response.queue_id);
+		if held_responses.is_empty() {
+			return Vec::new();
 		}
+
+		let (mut released_responses, mut still_held_responses) = (Vec::new(), Vec::new());
+		for held_response in held_responses {
+			match self.context.blockchain.block_finality(held_response.origin_block.clone()) {
+				Ok(BlockFinality::Finalized) => {
+					self.context.submission_queue.on_response_dequeued(held_response.queue_id);
+					self.forget_speculative_task(&held_response.request);
+					released_responses.push(ReleasedResponse {
+						request: held_response.request,
+						description: held_response.description,
+						call: held_response.call,
+					});
+				},
+				Ok(BlockFinality::NotFinalized) => still_held_responses.push(held_response),
+				Ok(BlockFinality::Retracted) => {
+					trace!(
+						target: "secretstore",
+						"Dropping response {}: origin block has been retracted",
+						held_response.description,
+					);
+
+					self.context.submission_queue.on_response_dequeued(held_response.queue_id);
+					self.forget_speculative_task(&held_response.request);
+					self.on_request_completed(&held_response.request);
+				},
+				Err(error) => {
+					error!(
+						target: "secretstore",
+						"Failed to read finality of response {} origin block: {}",
+						held_response.description,
+						error,
+					);
+
+					still_held_responses.push(held_response);
+				},
+			}
+		}
+
+		self.held_responses
+			.lock()
+			.expect("never panics under lock; qed")
+			.extend(still_held_responses);
+
+		released_responses
 	}
 
 	/// Send response transaction if required.
 	fn submit_response_transaction(
 		&self,
+		request: ResponseRequest,
 		format_request: impl Fn() -> String,
-		is_response_required: impl FnOnce() -> Result<bool, String>,
+		is_response_required: impl FnOnce() -> Result<bool, ServiceError>,
 		prepare_response: impl FnOnce() -> Result<SecretStoreCall, String>,
 	) {
+		if let Some((ref shadow, ShadowRole::Candidate)) = self.shadow {
+			shadow.on_response(
+				ShadowRole::Candidate,
+				request.task_kind,
+				request.key_id,
+				request.requester,
+				request.is_personal,
+				prepare_response(),
+			);
+			return;
+		}
+
+		self.context.watchdog.on_session_completed(request.task_kind, request.key_id);
+		self.context.safe_mode.on_task_completed(&request.served());
+		self.context.session_limiter.on_session_completed(&request.served());
 		match is_response_required() {
 			Ok(true) => (),
-			Ok(false) => return,
+			Ok(false) => {
+				if self.context.unrequired_responses.is_enabled() {
+					if let Ok(call) = prepare_response() {
+						self.context.unrequired_responses.on_unrequired_response(request.origin, &call);
+					}
+				}
+
+				self.forget_speculative_task(&request);
+				self.on_request_completed(&request);
+				return;
+			},
 			Err(error) => error!(
 				target: "secretstore",
 				"Failed to check if response {} is required: {}",
@@ -74,22 +392,458 @@ impl<B, P> SubstrateTransactionPool<B, P>
 			),
 		}
 
-		let submit_result = prepare_response()
-			.and_then(|transaction| self
-				.transaction_pool
-				.submit_transaction(transaction)
+		let response = prepare_response();
+		#[cfg(feature = "metrics")]
+		self.context.metrics.on_session_completed(response.as_ref().map(SecretStoreCall::is_error).unwrap_or(true));
+		if let Some((ref shadow, ShadowRole::Primary)) = self.shadow {
+			shadow.on_response(
+				ShadowRole::Primary,
+				request.task_kind,
+				request.key_id,
+				request.requester,
+				request.is_personal,
+				response.clone(),
+			);
+		}
+
+		let response = response.map(|call| self.limit_response_size(call, &format_request));
+		let response = match response {
+			Ok(call) => match apply_response_layers(&self.context.response_layers, call) {
+				Some(call) => Ok(call),
+				None => {
+					trace!(
+						target: "secretstore",
+						"Response {} has been dropped by response layer",
+						format_request(),
+					);
+					return;
+				},
+			},
+			Err(error) => Err(error),
+		};
+		if let Ok(ref call) = response {
+			if let Err(error) = self.verify_artifacts(call) {
+				error!(
+					target: "secretstore",
+					"Refusing to publish response {}: artifacts verification has failed: {}",
+					format_request(),
+					error,
+				);
+
+				self.context.escalation.on_error(ErrorClass::ArtifactsVerification, &error);
+				return;
+			}
+
+			if call.is_error() && self.is_cluster_unavailable() {
+				warn!(
+					target: "secretstore",
+					"Key server cluster is unavailable. Buffering error response {}",
+					format_request(),
+				);
+
+				let queue_id = self.queue_response(
+					request.origin,
+					QueueReason::ClusterUnavailable,
+					format_request(),
+					call.clone(),
+				);
+				self.buffered_errors.lock().expect("never panics under lock; qed").push(BufferedError {
+					request,
+					description: format_request(),
+					call: call.clone(),
+					buffered_at: Instant::now(),
+					queue_id,
+				});
+				return;
+			}
+
+			if let Some(origin_block) = self.non_finalized_origin_block(&request) {
+				trace!(
+					target: "secretstore",
+					"Holding response {} until origin block is finalized",
+					format_request(),
+				);
+
+				let queue_id = self.queue_response(
+					request.origin,
+					QueueReason::OriginBlockNotFinalized,
+					format_request(),
+					call.clone(),
+				);
+				self.held_responses.lock().expect("never panics under lock; qed").push(HeldResponse {
+					request,
+					description: format_request(),
+					call: call.clone(),
+					origin_block,
+					queue_id,
+				});
+				return;
+			}
+		}
+
+		self.submit_prepared_response(request, format_request, response)
+	}
+
+	/// Register response in the submission queue.
+	fn queue_response(
+		&self,
+		origin: Address,
+		reason: QueueReason,
+		description: String,
+		call: SecretStoreCall,
+	) -> QueuedResponseId {
+		self.context.submission_queue.on_response_queued(QueuedResponse {
+			origin,
+			key_server: self.key_server_address,
+			reason,
+			description,
+			call,
+		})
+	}
+
+	/// Submit prepared response transaction. If batching is enabled, response is
+	/// added to the current batch.
+	fn submit_prepared_response(
+		&self,
+		request: ResponseRequest,
+		format_request: impl Fn() -> String,
+		response: Result<SecretStoreCall, String>,
+	) {
+		let (batching, call) = match (self.context.response_batching.as_ref(), response) {
+			(Some(batching), Ok(call)) => (batching, call),
+			(_, response) => return self.submit_single_response(request, format_request, response),
+		};
+
+		let queue_id = self.queue_response(request.origin, QueueReason::Batched, format_request(), call.clone());
+		let is_batch_full = {
+			let mut batched_responses = self.batched_responses.lock().expect("never panics under lock; qed");
+			batched_responses.push(BatchedResponse {
+				request,
+				description: format_request(),
+				call,
+				queue_id,
+			});
+			batched_responses.len() >= batching.max_batch_size
+		};
+
+		if is_batch_full {
+			self.submit_batched_responses();
+		}
+	}
+
+	/// Submit all batched responses.
+	fn submit_batched_responses(&self) {
+		let batching = match self.context.response_batching {
+			Some(ref batching) => batching,
+			None => return,
+		};
+
+		let mut batched_responses = self.take_queued_responses(&self.batched_responses, |response| response.queue_id)
+			.into_iter()
+			.map(|batched_response| {
+				self.context.submission_queue.on_response_dequeued(batched_response.queue_id);
+				ReleasedResponse {
+					request: batched_response.request,
+					description: batched_response.description,
+					call: batched_response.call,
+				}
+			})
+			.filter(|released_response| self.is_response_still_required(released_response))
+			.collect::<Vec<_>>();
+		order_responses(&mut batched_responses, self.context.response_ordering);
+
+		// high priority responses are submitted first and never share batch with routine
+		// responses. Canary and stable responses are submitted by different pools
+		let mut groups = BTreeMap::<_, Vec<_>>::new();
+		for response in batched_responses {
+			let is_canary = self.context.canary_rollout
+				.map(|rollout| rollout.is_canary(&response.call.key_id()))
+				.unwrap_or(false);
+			groups.entry((Reverse(response.call.priority()), is_canary)).or_default().push(response);
+		}
+		let batches = groups.into_values().flat_map(|responses| split_into_batches(
+			responses,
+			batching.max_batch_size,
+			|response| (
+				response.request.origin,
+				self.context.tenants.submitter_account(&response.request.origin).cloned(),
+			),
+		));
+		for batch in batches {
+			if batch.len() == 1 {
+				for response in batch {
+					let description = response.description;
+					self.submit_single_response(response.request, || description.clone(), Ok(response.call));
+				}
+				continue;
+			}
+
+			let origin = batch[0].request.origin;
+			let submitter = self.context.tenants.submitter_account(&origin);
+			let calls = batch.iter().map(|response| response.call.clone()).collect();
+			match self.transaction_pool.submit_batch_for_origin(Some(&origin), submitter, calls) {
+				Ok(transaction_hash) => {
+					#[cfg(feature = "metrics")]
+					self.context.metrics.on_transaction_submitted(false);
+					trace!(
+						target: "secretstore",
+						"Submitted batch of {} responses: {}",
+						batch.len(),
+						transaction_hash,
+					);
+
+					for response in batch {
+						let description = response.description;
+						self.on_response_submitted(
+							&response.request,
+							|| description.clone(),
+							submitter,
+							response.call,
+							&transaction_hash,
+						);
+					}
+				},
+				Err(error) => {
+					#[cfg(feature = "metrics")]
+					self.context.metrics.on_transaction_submitted(true);
+					trace!(
+						target: "secretstore",
+						"Failed to submit batch of {} responses: {}. Submitting responses one by one",
+						batch.len(),
+						error,
+					);
+
+					for response in batch {
+						let description = response.description;
+						self.submit_single_response(response.request, || description.clone(), Ok(response.call));
+					}
+				},
+			}
+		}
+	}
+
+	/// Submit response transaction.
+	fn submit_single_response(
+		&self,
+		request: ResponseRequest,
+		format_request: impl Fn() -> String,
+		response: Result<SecretStoreCall, String>,
+	) {
+		let submitter = self.context.tenants.submitter_account(&request.origin);
+		let submit_result = response
+			.map_err(SubmitError::Invalid)
+			.and_then(|transaction| submit_call(
+				&*self.transaction_pool,
+				Some(&request.origin),
+				submitter,
+				transaction.clone(),
+			)
+				.map(|transaction_hash| (transaction, transaction_hash))
 			);
 
 		match submit_result {
+			Ok((transaction, transaction_hash)) => {
+				#[cfg(feature = "metrics")]
+				self.context.metrics.on_transaction_submitted(false);
+				trace!(
+					target: "secretstore",
+					"Submitted response {}: {}",
+					format_request(),
+					transaction_hash,
+				);
+
+				self.on_response_submitted(&request, format_request, submitter, transaction, &transaction_hash);
+			},
+			Err(error) => {
+				#[cfg(feature = "metrics")]
+				self.context.metrics.on_transaction_submitted(true);
+				error!(
+					target: "secretstore",
+					"Failed to submit response {}: {}",
+					format_request(),
+					error,
+				);
+
+				if let SubmitError::Invalid(ref error) = error {
+					self.context.escalation.on_error(ErrorClass::InvalidTransaction, error);
+					self.context.poison.on_task_failure(FailureStage::Submit, request.task_kind, request.key_id, error);
+				}
+			},
+		}
+	}
+
+	/// Returns origins that are waiting for result of server key generation session,
+	/// started by request of given origin (including this origin).
+	fn generation_origins(&self, origin: Address, key_id: ServerKeyId) -> Vec<Address> {
+		let mut origins = vec![origin];
+		// shadow key server doesn't share sessions
+		if let Some((_, ShadowRole::Candidate)) = self.shadow {
+			return origins;
+		}
+
+		origins.extend(self.context.generation_fan_out.on_generation_completed(origin, key_id));
+		origins
+	}
+
+	/// Called when response (or batch containing response) has been submitted.
+	fn on_response_submitted(
+		&self,
+		request: &ResponseRequest,
+		format_request: impl Fn() -> String,
+		submitter: Option<&AccountId32>,
+		transaction: SecretStoreCall,
+		transaction_hash: &P::TransactionHash,
+	) {
+		self.submitted_responses_count.fetch_add(1, Ordering::Relaxed);
+		self.context.sla.on_response_submitted(request.task_kind, request.key_id);
+		self.context.origin_statistics.on_response_submitted(&request.origin, transaction.is_error());
+		if let Some(ref submitted_responses) = self.context.submitted_responses {
+			submitted_responses.on_response_submitted(&request.served(), transaction_hash.to_string());
+		}
+		self.context.reconciler.on_response_submitted(
+			self.key_server_address,
+			request.served(),
+			Some(request.origin),
+			submitter.cloned(),
+			transaction.clone(),
+		);
+		self.publish_to_secondary(&format_request, transaction);
+	}
+
+	/// Returns origin block of speculatively started task, if it is not yet finalized.
+	fn non_finalized_origin_block(&self, request: &ResponseRequest) -> Option<B::BlockHash> {
+		let speculative = self.context.speculative.as_ref()?;
+		let origin_block = speculative.origin_block(&request.served())?;
+		match self.context.blockchain.block_finality(origin_block.clone()) {
+			Ok(BlockFinality::Finalized) => {
+				speculative.forget(&request.served());
+				None
+			},
+			// retracted blocks are handled when held responses are released
+			Ok(_) => Some(origin_block),
+			Err(error) => {
+				error!(
+					target: "secretstore",
+					"Failed to read finality of block: {}. Holding response",
+					error,
+				);
+				Some(origin_block)
+			},
+		}
+	}
+
+	/// Returns true if degraded mode is enabled and key server cluster is unavailable.
+	fn is_cluster_unavailable(&self) -> bool {
+		self.context.degraded_mode
+			.as_ref()
+			.map(|degraded_mode| !degraded_mode.health.is_available())
+			.unwrap_or(false)
+	}
+
+	/// Check deferred response against the latest chain state before submitting it.
+	/// Responses that are not required anymore (e.g. request has been answered by other
+	/// key servers while response has been deferred) are dropped or passed to the
+	/// unrequired responses sink.
+	fn is_response_still_required(&self, response: &ReleasedResponse) -> bool {
+		let request = &response.request;
+		match is_response_required(&*self.context.blockchain, &request.served(), self.key_server_address) {
+			Ok(true) => true,
+			Ok(false) => {
+				trace!(
+					target: "secretstore",
+					"Dropping deferred response {}: it is not required anymore",
+					response.description,
+				);
+
+				self.context.unrequired_responses.on_unrequired_response(request.origin, &response.call);
+				self.forget_speculative_task(request);
+				self.on_request_completed(request);
+				false
+			},
+			// we'll know that response is stale only after submission
+			Err(_) => true,
+		}
+	}
+
+	/// Verify session artifacts, if verification is enabled.
+	fn verify_artifacts(&self, call: &SecretStoreCall) -> Result<(), String> {
+		let artifacts_verification = match self.context.artifacts_verification {
+			Some(ref artifacts_verification) => artifacts_verification,
+			None => return Ok(()),
+		};
+
+		let verification_result = artifacts_verification.verify(call);
+		if let Err(ref error) = verification_result {
+			if let Some(ref mismatch_handler) = artifacts_verification.mismatch_handler {
+				mismatch_handler(call, error);
+			}
+		}
+
+		verification_result
+	}
+
+	/// Replace response with error response if it exceeds configured size limit.
+	fn limit_response_size(&self, call: SecretStoreCall, format_request: impl Fn() -> String) -> SecretStoreCall {
+		let artifacts_verification = match self.context.artifacts_verification {
+			Some(ref artifacts_verification) => artifacts_verification,
+			None => return call,
+		};
+
+		match artifacts_verification.verify_size(&call) {
+			Ok(()) => call,
+			Err(error) => {
+				error!(
+					target: "secretstore",
+					"Replacing response {} with error response: {}",
+					format_request(),
+					error,
+				);
+
+				if let Some(ref mismatch_handler) = artifacts_verification.mismatch_handler {
+					mismatch_handler(&call, &error);
+				}
+
+				call.to_error()
+			},
+		}
+	}
+
+	/// Stop tracking speculatively started task.
+	fn forget_speculative_task(&self, request: &ResponseRequest) {
+		if let Some(ref speculative) = self.context.speculative {
+			speculative.forget(&request.served());
+		}
+	}
+
+	/// Called when request no longer requires our response.
+	fn on_request_completed(&self, request: &ResponseRequest) {
+		self.context.sla.on_request_completed(request.task_kind, request.key_id);
+		self.context.watchdog.on_request_completed(request.task_kind, request.key_id);
+		if let Some(ref submitted_responses) = self.context.submitted_responses {
+			submitted_responses.on_request_completed(&request.served());
+		}
+		self.context.reconciler.on_request_completed(self.key_server_address, request.served());
+		self.context.session_limiter.on_session_completed(&request.served());
+		self.context.poison.on_request_completed(request.task_kind, request.key_id);
+	}
+
+	/// Mirror submitted response to the secondary publication target.
+	fn publish_to_secondary(&self, format_request: impl Fn() -> String, transaction: SecretStoreCall) {
+		let secondary_publisher = match self.secondary_publisher {
+			Some(ref secondary_publisher) => secondary_publisher,
+			None => return,
+		};
+
+		match secondary_publisher.publish(transaction) {
 			Ok(transaction_hash) => trace!(
 				target: "secretstore",
-				"Submitted response {}: {}",
+				"Published response {} to secondary target: {}",
 				format_request(),
 				transaction_hash,
 			),
 			Err(error) => error!(
 				target: "secretstore",
-				"Failed to submit response {}: {}",
+				"Failed to publish response {} to secondary target: {}",
 				format_request(),
 				error,
 			),
@@ -106,77 +860,91 @@ impl<B, P> parity_secretstore_blockchain_service::TransactionPool
 {
 	fn publish_generated_server_key(
 		&self,
-		_origin: Address,
+		origin: Address,
 		key_id: ServerKeyId,
 		artifacts: ServerKeyGenerationArtifacts,
 	) {
-		self.submit_response_transaction(
-			|| format!("ServerKeyGenerationSuccess({})", key_id),
-			|| self.blockchain.is_server_key_generation_response_required(key_id, self.key_server_address),
-			|| Ok(SecretStoreCall::ServerKeyGenerated(key_id, artifacts.key)),
-		)
+		for origin in self.generation_origins(origin, key_id) {
+			self.submit_response_transaction(
+				ResponseRequest::new(origin, TaskKind::ServerKeyGeneration, key_id),
+				|| format!("ServerKeyGenerationSuccess({})", self.redactor.redact(&key_id)),
+				|| self.context.blockchain.is_server_key_generation_response_required(key_id, self.key_server_address),
+				|| Ok(SecretStoreCall::ServerKeyGenerated(key_id, artifacts.key)),
+			)
+		}
 	}
 
-	fn publish_server_key_generation_error(&self, _origin: Address, key_id: ServerKeyId) {
-		self.submit_response_transaction(
-			|| format!("ServerKeyGenerationFailure({})", key_id),
-			|| self.blockchain.is_server_key_generation_response_required(key_id, self.key_server_address),
-			|| Ok(SecretStoreCall::ServerKeyGenerationError(key_id)),
-		)
+	fn publish_server_key_generation_error(&self, origin: Address, key_id: ServerKeyId) {
+		for origin in self.generation_origins(origin, key_id) {
+			self.submit_response_transaction(
+				ResponseRequest::new(origin, TaskKind::ServerKeyGeneration, key_id),
+				|| format!("ServerKeyGenerationFailure({})", self.redactor.redact(&key_id)),
+				|| self.context.blockchain.is_server_key_generation_response_required(key_id, self.key_server_address),
+				|| Ok(SecretStoreCall::ServerKeyGenerationError(key_id)),
+			)
+		}
 	}
 
 	fn publish_retrieved_server_key(
 		&self,
-		_origin: Address,
+		origin: Address,
 		key_id: ServerKeyId,
 		artifacts: ServerKeyRetrievalArtifacts,
 	) {
 		self.submit_response_transaction(
-			|| format!("ServerKeyRetrievalSuccess({})", key_id),
-			|| self.blockchain.is_server_key_retrieval_response_required(key_id, self.key_server_address),
+			ResponseRequest::new(origin, TaskKind::ServerKeyRetrieval, key_id),
+			|| format!("ServerKeyRetrievalSuccess({})", self.redactor.redact(&key_id)),
+			|| self.context.blockchain.is_server_key_retrieval_response_required(key_id, self.key_server_address),
 			|| serialize_threshold(artifacts.threshold)
 				.map(|threshold| SecretStoreCall::ServerKeyRetrieved(key_id, artifacts.key, threshold)),
 		)
 	}
 
-	fn publish_server_key_retrieval_error(&self, _origin: Address, key_id: ServerKeyId) {
+	fn publish_server_key_retrieval_error(&self, origin: Address, key_id: ServerKeyId) {
 		self.submit_response_transaction(
-			|| format!("ServerKeyRetrievalFailure({})", key_id),
-			|| self.blockchain.is_server_key_retrieval_response_required(key_id, self.key_server_address),
+			ResponseRequest::new(origin, TaskKind::ServerKeyRetrieval, key_id),
+			|| format!("ServerKeyRetrievalFailure({})", self.redactor.redact(&key_id)),
+			|| self.context.blockchain.is_server_key_retrieval_response_required(key_id, self.key_server_address),
 			|| Ok(SecretStoreCall::ServerKeyRetrievalError(key_id)),
 		)
 	}
 
-	fn publish_stored_document_key(&self, _origin: Address, key_id: ServerKeyId) {
+	fn publish_stored_document_key(&self, origin: Address, key_id: ServerKeyId) {
 		self.submit_response_transaction(
-			|| format!("DocumentKeyStoreSuccess({})", key_id),
-			|| self.blockchain.is_document_key_store_response_required(key_id, self.key_server_address),
+			ResponseRequest::new(origin, TaskKind::DocumentKeyStore, key_id),
+			|| format!("DocumentKeyStoreSuccess({})", self.redactor.redact(&key_id)),
+			|| self.context.blockchain.is_document_key_store_response_required(key_id, self.key_server_address),
 			|| Ok(SecretStoreCall::DocumentKeyStored(key_id)),
 		)
 	}
 
-	fn publish_document_key_store_error(&self, _origin: Address, key_id: ServerKeyId) {
+	fn publish_document_key_store_error(&self, origin: Address, key_id: ServerKeyId) {
 		self.submit_response_transaction(
-			|| format!("DocumentKeyStoreFailure({})", key_id),
-			|| self.blockchain.is_document_key_store_response_required(key_id, self.key_server_address),
+			ResponseRequest::new(origin, TaskKind::DocumentKeyStore, key_id),
+			|| format!("DocumentKeyStoreFailure({})", self.redactor.redact(&key_id)),
+			|| self.context.blockchain.is_document_key_store_response_required(key_id, self.key_server_address),
 			|| Ok(SecretStoreCall::DocumentKeyStoreError(key_id)),
 		)
 	}
 
 	fn publish_retrieved_document_key_common(
 		&self,
-		_origin: Address,
+		origin: Address,
 		key_id: ServerKeyId,
 		requester: Requester,
 		artifacts: DocumentKeyCommonRetrievalArtifacts,
 	) {
 		self.submit_response_transaction(
-			|| format!("DocumentKeyCommonRetrievalSuccess({}, {})", key_id, requester),
-			|| requester
-				.address(&key_id)
-				.map_err(Into::into)
+			ResponseRequest::document_key_shadow_retrieval(origin, key_id, &requester, false),
+			|| format!(
+				"DocumentKeyCommonRetrievalSuccess({}, {})",
+				self.redactor.redact(&key_id),
+				self.redactor.redact(&requester),
+			),
+			|| requester_address(&requester, &key_id)
+				.map_err(ServiceError::permanent)
 				.and_then(|requester|
-					self.blockchain
+					self.context.blockchain
 						.is_document_key_shadow_retrieval_response_required(
 							key_id,
 							requester,
@@ -185,9 +953,7 @@ impl<B, P> parity_secretstore_blockchain_service::TransactionPool
 				),
 			|| serialize_threshold(artifacts.threshold)
 				.and_then(|threshold|
-					requester
-						.address(&key_id)
-						.map_err(Into::into)
+					requester_address(&requester, &key_id)
 						.map(|requester| (threshold, requester))
 				)
 				.map(|(threshold, requester)| SecretStoreCall::DocumentKeyCommonRetrieved(
@@ -201,26 +967,28 @@ impl<B, P> parity_secretstore_blockchain_service::TransactionPool
 
 	fn publish_document_key_common_retrieval_error(
 		&self,
-		_origin: Address,
+		origin: Address,
 		key_id: ServerKeyId,
 		requester: Requester,
 	) {
 		self.submit_response_transaction(
-			|| format!("DocumentKeyCommonRetrievalFailure({}, {})", key_id, requester),
-			|| requester
-				.address(&key_id)
-				.map_err(Into::into)
+			ResponseRequest::document_key_shadow_retrieval(origin, key_id, &requester, false),
+			|| format!(
+				"DocumentKeyCommonRetrievalFailure({}, {})",
+				self.redactor.redact(&key_id),
+				self.redactor.redact(&requester),
+			),
+			|| requester_address(&requester, &key_id)
+				.map_err(ServiceError::permanent)
 				.and_then(|requester|
-					self.blockchain
+					self.context.blockchain
 						.is_document_key_shadow_retrieval_response_required(
 							key_id,
 							requester,
 							self.key_server_address,
 						)
 				),
-			|| requester
-				.address(&key_id)
-				.map_err(Into::into)
+			|| requester_address(&requester, &key_id)
 				.map(|requester| SecretStoreCall::DocumentKeyShadowRetrievalError(
 					key_id,
 					requester,
@@ -230,18 +998,22 @@ impl<B, P> parity_secretstore_blockchain_service::TransactionPool
 
 	fn publish_retrieved_document_key_personal(
 		&self,
-		_origin: Address,
+		origin: Address,
 		key_id: ServerKeyId,
 		requester: Requester,
 		artifacts: DocumentKeyShadowRetrievalArtifacts,
 	) {
 		self.submit_response_transaction(
-			|| format!("DocumentKeyPersonalRetrievalSuccess({}, {})", key_id, requester),
-			|| requester
-				.address(&key_id)
-				.map_err(Into::into)
+			ResponseRequest::document_key_shadow_retrieval(origin, key_id, &requester, true),
+			|| format!(
+				"DocumentKeyPersonalRetrievalSuccess({}, {})",
+				self.redactor.redact(&key_id),
+				self.redactor.redact(&requester),
+			),
+			|| requester_address(&requester, &key_id)
+				.map_err(ServiceError::permanent)
 				.and_then(|requester|
-					self.blockchain
+					self.context.blockchain
 						.is_document_key_shadow_retrieval_response_required(
 							key_id,
 							requester,
@@ -257,9 +1029,7 @@ impl<B, P> parity_secretstore_blockchain_service::TransactionPool
 						"DocumentKeyPersonalRetrieval session has completed without self coefficient",
 					))?;
 
-				requester
-					.address(&key_id)
-					.map_err(Into::into)
+				requester_address(&requester, &key_id)
 					.map(|requester| SecretStoreCall::DocumentKeyPersonalRetrieved(
 						key_id,
 						requester,
@@ -273,26 +1043,28 @@ impl<B, P> parity_secretstore_blockchain_service::TransactionPool
 
 	fn publish_document_key_personal_retrieval_error(
 		&self,
-		_origin: Address,
+		origin: Address,
 		key_id: ServerKeyId,
 		requester: Requester,
 	) {
 		self.submit_response_transaction(
-			|| format!("DocumentKeyPersonalRetrievalFailure({}, {})", key_id, requester),
-			|| requester
-				.address(&key_id)
-				.map_err(Into::into)
+			ResponseRequest::document_key_shadow_retrieval(origin, key_id, &requester, true),
+			|| format!(
+				"DocumentKeyPersonalRetrievalFailure({}, {})",
+				self.redactor.redact(&key_id),
+				self.redactor.redact(&requester),
+			),
+			|| requester_address(&requester, &key_id)
+				.map_err(ServiceError::permanent)
 				.and_then(|requester|
-					self.blockchain
+					self.context.blockchain
 						.is_document_key_shadow_retrieval_response_required(
 							key_id,
 							requester,
 							self.key_server_address,
 						)
 				),
-			|| requester
-				.address(&key_id)
-				.map_err(Into::into)
+			|| requester_address(&requester, &key_id)
 				.map(|requester| SecretStoreCall::DocumentKeyShadowRetrievalError(
 					key_id,
 					requester,
@@ -308,3 +1080,14 @@ pub fn serialize_threshold(threshold: usize) -> Result<u8, String> {
 	}
 	Ok(threshold as _)
 }
+
+/// Order released responses before submission.
+fn order_responses(responses: &mut [ReleasedResponse], ordering: ResponseOrdering) {
+	// sorts are stable => responses with equal keys are kept in FIFO order
+	match ordering {
+		ResponseOrdering::Fifo => (),
+		ResponseOrdering::ErrorsFirst => responses.sort_by_key(|response| !response.call.is_error()),
+		ResponseOrdering::SuccessesFirst => responses.sort_by_key(|response| response.call.is_error()),
+		ResponseOrdering::ByKeyId => responses.sort_by_key(|response| response.call.key_id()),
+	}
+}
@@ -0,0 +1,306 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Responses that have been computed, but are not required anymore.
+//!
+//! When request has been answered by other key servers before our session has
+//! completed, our response isn't published. By default it is dropped, but some
+//! deployments want the computed result anyway - it could be persisted locally or
+//! forwarded to the observer.
+//!
+//! Persisted responses are encoded as: version byte, call index byte and call
+//! fields. Fixed-size fields are stored as is, variable-size fields are prefixed
+//! with their big-endian u32 length.
+
+use std::{convert::TryInto, sync::Arc};
+use log::warn;
+use parity_secretstore_primitives::{Address, Public, ServerKeyId};
+use crate::{SecretStoreCall, dedup::ServedRequest, persistence::Persistence};
+
+/// Prefix of unrequired response records keys.
+const UNREQUIRED_RESPONSE_KEY_PREFIX: &[u8] = b"secretstore:unrequired:";
+/// Version of unrequired response records encoding.
+const UNREQUIRED_RESPONSE_VERSION: u8 = 1;
+/// Size of encoded key id.
+const KEY_ID_SIZE: usize = 32;
+/// Size of encoded public.
+const PUBLIC_SIZE: usize = 64;
+/// Size of encoded address.
+const ADDRESS_SIZE: usize = 20;
+
+/// Called when response is not required anymore.
+pub type UnrequiredResponseHandler = Arc<dyn Fn(&SecretStoreCall) + Send + Sync>;
+
+/// What to do with responses that are not required anymore.
+#[derive(Clone, Default)]
+pub struct UnrequiredResponsesConfiguration {
+	/// If set, encoded response (see `encode_response`) is stored here.
+	pub persistence: Option<Arc<dyn Persistence>>,
+	/// If set, response is forwarded to this observer.
+	pub handler: Option<UnrequiredResponseHandler>,
+}
+
+/// Responses that are not required anymore.
+#[derive(Default)]
+pub struct UnrequiredResponses {
+	/// Configuration.
+	config: UnrequiredResponsesConfiguration,
+}
+
+impl UnrequiredResponses {
+	/// Create new unrequired responses sink.
+	pub fn new(config: UnrequiredResponsesConfiguration) -> Self {
+		UnrequiredResponses { config }
+	}
+
+	/// Returns true if unrequired responses are not dropped.
+	pub fn is_enabled(&self) -> bool {
+		self.config.persistence.is_some() || self.config.handler.is_some()
+	}
+
+	/// Called when computed response is not required anymore. Error responses carry
+	/// no artifacts, so they're always dropped.
+	pub fn on_unrequired_response(&self, origin: Address, call: &SecretStoreCall) {
+		if call.is_error() {
+			return;
+		}
+
+		if let Some(ref persistence) = self.config.persistence {
+			for request in ServedRequest::from_accepted_call(Some(origin), call) {
+				let record_key = request.prefixed_key(UNREQUIRED_RESPONSE_KEY_PREFIX);
+				if let Err(error) = persistence.put(&record_key, encode_response(call)) {
+					warn!(
+						target: "secretstore",
+						"Failed to store unrequired response: {}",
+						error,
+					);
+				}
+			}
+		}
+
+		if let Some(ref handler) = self.config.handler {
+			handler(call);
+		}
+	}
+}
+
+/// Encode response for storing in the persistence.
+pub fn encode_response(call: &SecretStoreCall) -> Vec<u8> {
+	let mut encoded = vec![UNREQUIRED_RESPONSE_VERSION];
+	match *call {
+		SecretStoreCall::ServerKeyGenerated(ref key_id, ref key) => {
+			encoded.push(0);
+			encoded.extend_from_slice(key_id.as_bytes());
+			encoded.extend_from_slice(key.as_bytes());
+		},
+		SecretStoreCall::ServerKeyGenerationError(ref key_id) => {
+			encoded.push(1);
+			encoded.extend_from_slice(key_id.as_bytes());
+		},
+		SecretStoreCall::ServerKeyRetrieved(ref key_id, ref key, threshold) => {
+			encoded.push(2);
+			encoded.extend_from_slice(key_id.as_bytes());
+			encoded.extend_from_slice(key.as_bytes());
+			encoded.push(threshold);
+		},
+		SecretStoreCall::ServerKeyRetrievalError(ref key_id) => {
+			encoded.push(3);
+			encoded.extend_from_slice(key_id.as_bytes());
+		},
+		SecretStoreCall::DocumentKeyStored(ref key_id) => {
+			encoded.push(4);
+			encoded.extend_from_slice(key_id.as_bytes());
+		},
+		SecretStoreCall::DocumentKeyStoreError(ref key_id) => {
+			encoded.push(5);
+			encoded.extend_from_slice(key_id.as_bytes());
+		},
+		SecretStoreCall::DocumentKeyCommonRetrieved(ref key_id, ref requester, ref common_point, threshold) => {
+			encoded.push(6);
+			encoded.extend_from_slice(key_id.as_bytes());
+			encoded.extend_from_slice(requester.as_bytes());
+			encoded.extend_from_slice(common_point.as_bytes());
+			encoded.push(threshold);
+		},
+		SecretStoreCall::DocumentKeyPersonalRetrieved(
+			ref key_id,
+			ref requester,
+			ref participants,
+			ref decrypted_secret,
+			ref shadow,
+		) => {
+			encoded.push(7);
+			encoded.extend_from_slice(key_id.as_bytes());
+			encoded.extend_from_slice(requester.as_bytes());
+			encoded.extend_from_slice(&(participants.len() as u32).to_be_bytes());
+			for participant in participants {
+				encoded.extend_from_slice(participant.as_bytes());
+			}
+			encoded.extend_from_slice(decrypted_secret.as_bytes());
+			encoded.extend_from_slice(&(shadow.len() as u32).to_be_bytes());
+			encoded.extend_from_slice(shadow);
+		},
+		SecretStoreCall::DocumentKeyShadowRetrievalError(ref key_id, ref requester) => {
+			encoded.push(8);
+			encoded.extend_from_slice(key_id.as_bytes());
+			encoded.extend_from_slice(requester.as_bytes());
+		},
+	}
+	encoded
+}
+
+/// Decode response, previously encoded with `encode_response`.
+pub fn decode_response(mut encoded: &[u8]) -> Result<SecretStoreCall, String> {
+	let version = take(&mut encoded, 1)?[0];
+	if version != UNREQUIRED_RESPONSE_VERSION {
+		return Err(format!("unknown unrequired response record version: {}", version));
+	}
+
+	let call_index = take(&mut encoded, 1)?[0];
+	let call = match call_index {
+		0 => SecretStoreCall::ServerKeyGenerated(
+			take_key_id(&mut encoded)?,
+			take_public(&mut encoded)?,
+		),
+		1 => SecretStoreCall::ServerKeyGenerationError(take_key_id(&mut encoded)?),
+		2 => SecretStoreCall::ServerKeyRetrieved(
+			take_key_id(&mut encoded)?,
+			take_public(&mut encoded)?,
+			take(&mut encoded, 1)?[0],
+		),
+		3 => SecretStoreCall::ServerKeyRetrievalError(take_key_id(&mut encoded)?),
+		4 => SecretStoreCall::DocumentKeyStored(take_key_id(&mut encoded)?),
+		5 => SecretStoreCall::DocumentKeyStoreError(take_key_id(&mut encoded)?),
+		6 => SecretStoreCall::DocumentKeyCommonRetrieved(
+			take_key_id(&mut encoded)?,
+			take_address(&mut encoded)?,
+			take_public(&mut encoded)?,
+			take(&mut encoded, 1)?[0],
+		),
+		7 => {
+			let key_id = take_key_id(&mut encoded)?;
+			let requester = take_address(&mut encoded)?;
+			let participants_count = take_len(&mut encoded)?;
+			let participants = (0..participants_count)
+				.map(|_| take_address(&mut encoded))
+				.collect::<Result<Vec<_>, _>>()?;
+			let decrypted_secret = take_public(&mut encoded)?;
+			let shadow_len = take_len(&mut encoded)?;
+			let shadow = take(&mut encoded, shadow_len)?.to_vec();
+			SecretStoreCall::DocumentKeyPersonalRetrieved(key_id, requester, participants, decrypted_secret, shadow)
+		},
+		8 => SecretStoreCall::DocumentKeyShadowRetrievalError(
+			take_key_id(&mut encoded)?,
+			take_address(&mut encoded)?,
+		),
+		_ => return Err(format!("unknown unrequired response call index: {}", call_index)),
+	};
+
+	if !encoded.is_empty() {
+		return Err(format!("unrequired response record has {} trailing bytes", encoded.len()));
+	}
+
+	Ok(call)
+}
+
+/// Read given number of bytes from the encoded record.
+fn take<'a>(encoded: &mut &'a [u8], size: usize) -> Result<&'a [u8], String> {
+	if encoded.len() < size {
+		return Err("unrequired response record is too short".into());
+	}
+
+	let (taken, rest) = encoded.split_at(size);
+	*encoded = rest;
+	Ok(taken)
+}
+
+/// Read key id from the encoded record.
+fn take_key_id(encoded: &mut &[u8]) -> Result<ServerKeyId, String> {
+	take(encoded, KEY_ID_SIZE).map(ServerKeyId::from_slice)
+}
+
+/// Read public from the encoded record.
+fn take_public(encoded: &mut &[u8]) -> Result<Public, String> {
+	take(encoded, PUBLIC_SIZE).map(Public::from_slice)
+}
+
+/// Read address from the encoded record.
+fn take_address(encoded: &mut &[u8]) -> Result<Address, String> {
+	take(encoded, ADDRESS_SIZE).map(Address::from_slice)
+}
+
+/// Read length of variable-size field from the encoded record.
+fn take_len(encoded: &mut &[u8]) -> Result<usize, String> {
+	let len = take(encoded, 4)?;
+	Ok(u32::from_be_bytes(len.try_into().expect("take returns exactly 4 bytes; qed")) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn calls() -> Vec<SecretStoreCall> {
+		let key_id = ServerKeyId::repeat_byte(0x01);
+		let public = Public::repeat_byte(0x02);
+		let requester = Address::repeat_byte(0x03);
+		vec![
+			SecretStoreCall::ServerKeyGenerated(key_id, public),
+			SecretStoreCall::ServerKeyGenerationError(key_id),
+			SecretStoreCall::ServerKeyRetrieved(key_id, public, 1),
+			SecretStoreCall::ServerKeyRetrievalError(key_id),
+			SecretStoreCall::DocumentKeyStored(key_id),
+			SecretStoreCall::DocumentKeyStoreError(key_id),
+			SecretStoreCall::DocumentKeyCommonRetrieved(key_id, requester, public, 1),
+			SecretStoreCall::DocumentKeyPersonalRetrieved(
+				key_id,
+				requester,
+				vec![Address::repeat_byte(0x04), Address::repeat_byte(0x05)],
+				public,
+				vec![0x06, 0x07],
+			),
+			SecretStoreCall::DocumentKeyShadowRetrievalError(key_id, requester),
+		]
+	}
+
+	#[test]
+	fn responses_are_encoded_and_decoded() {
+		for call in calls() {
+			assert_eq!(decode_response(&encode_response(&call)), Ok(call));
+		}
+	}
+
+	#[test]
+	fn response_with_unknown_version_is_rejected() {
+		let mut encoded = encode_response(&calls()[0]);
+		encoded[0] = UNREQUIRED_RESPONSE_VERSION + 1;
+		assert!(decode_response(&encoded).is_err());
+	}
+
+	#[test]
+	fn truncated_response_is_rejected() {
+		for call in calls() {
+			let encoded = encode_response(&call);
+			assert!(decode_response(&encoded[..encoded.len() - 1]).is_err());
+		}
+	}
+
+	#[test]
+	fn response_with_trailing_bytes_is_rejected() {
+		let mut encoded = encode_response(&calls()[0]);
+		encoded.push(0);
+		assert!(decode_response(&encoded).is_err());
+	}
+}
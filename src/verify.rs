@@ -0,0 +1,148 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	sync::Arc,
+};
+use crate::{SecretStoreCall, TaskKind};
+
+/// Size of encoded call index (module index + call index).
+const CALL_INDEX_SIZE: usize = 2;
+/// Size of encoded key id.
+const KEY_ID_SIZE: usize = 32;
+/// Size of encoded public.
+const PUBLIC_SIZE: usize = 64;
+/// Size of encoded address.
+const ADDRESS_SIZE: usize = 20;
+
+/// Custom session artifacts verifier (e.g. checks that points are on the curve, or that
+/// signatures are valid).
+pub trait ArtifactsVerifier: Send + Sync + 'static {
+	/// Verify artifacts of the response. Error means that response must not be published.
+	fn verify(&self, call: &SecretStoreCall) -> Result<(), String>;
+}
+
+/// Called when response is not published because its artifacts have failed verification.
+pub type ArtifactsMismatchHandler = Arc<dyn Fn(&SecretStoreCall, &str) + Send + Sync>;
+
+/// Session artifacts verification configuration.
+#[derive(Clone)]
+pub struct ArtifactsVerification {
+	/// Custom verifier that is called after built-in checks have passed.
+	pub verifier: Option<Arc<dyn ArtifactsVerifier>>,
+	/// Called when verification fails.
+	pub mismatch_handler: Option<ArtifactsMismatchHandler>,
+	/// Max size of encoded response, by task kind. Responses that are larger are
+	/// replaced with error responses. Kinds that are missing from this map are not limited.
+	pub max_encoded_sizes: BTreeMap<TaskKind, usize>,
+	/// Max threshold of keys. If `None`, threshold isn't checked.
+	pub max_threshold: Option<u8>,
+}
+
+impl ArtifactsVerification {
+	/// Verify artifacts of the response.
+	pub fn verify(&self, call: &SecretStoreCall) -> Result<(), String> {
+		verify_call(call)?;
+		if let Some(max_threshold) = self.max_threshold {
+			match *call {
+				SecretStoreCall::ServerKeyRetrieved(_, _, threshold)
+					| SecretStoreCall::DocumentKeyCommonRetrieved(_, _, _, threshold) if threshold > max_threshold =>
+					return Err(format!("threshold {} exceeds max threshold {}", threshold, max_threshold)),
+				_ => (),
+			}
+		}
+		match self.verifier {
+			Some(ref verifier) => verifier.verify(call),
+			None => Ok(()),
+		}
+	}
+
+	/// Check that encoded response fits configured size limit.
+	pub fn verify_size(&self, call: &SecretStoreCall) -> Result<(), String> {
+		let max_encoded_size = match self.max_encoded_sizes.get(&call.task_kind()) {
+			Some(max_encoded_size) => *max_encoded_size,
+			None => return Ok(()),
+		};
+
+		let encoded_size = encoded_size(call);
+		if encoded_size > max_encoded_size {
+			return Err(format!(
+				"encoded response size {} exceeds limit {}",
+				encoded_size,
+				max_encoded_size,
+			));
+		}
+
+		Ok(())
+	}
+}
+
+/// Returns size of SCALE-encoded call.
+pub fn encoded_size(call: &SecretStoreCall) -> usize {
+	CALL_INDEX_SIZE + match *call {
+		SecretStoreCall::ServerKeyGenerated(..) => KEY_ID_SIZE + PUBLIC_SIZE,
+		SecretStoreCall::ServerKeyGenerationError(..) => KEY_ID_SIZE,
+		SecretStoreCall::ServerKeyRetrieved(..) => KEY_ID_SIZE + PUBLIC_SIZE + 1,
+		SecretStoreCall::ServerKeyRetrievalError(..) => KEY_ID_SIZE,
+		SecretStoreCall::DocumentKeyStored(..) => KEY_ID_SIZE,
+		SecretStoreCall::DocumentKeyStoreError(..) => KEY_ID_SIZE,
+		SecretStoreCall::DocumentKeyCommonRetrieved(..) => KEY_ID_SIZE + ADDRESS_SIZE + PUBLIC_SIZE + 1,
+		SecretStoreCall::DocumentKeyPersonalRetrieved(_, _, ref participants, _, ref shadow) =>
+			KEY_ID_SIZE + ADDRESS_SIZE
+				+ compact_size(participants.len()) + participants.len() * ADDRESS_SIZE
+				+ PUBLIC_SIZE
+				+ compact_size(shadow.len()) + shadow.len(),
+		SecretStoreCall::DocumentKeyShadowRetrievalError(..) => KEY_ID_SIZE + ADDRESS_SIZE,
+	}
+}
+
+/// Returns size of SCALE-encoded compact length.
+fn compact_size(len: usize) -> usize {
+	match len {
+		0..=0x3f => 1,
+		0x40..=0x3fff => 2,
+		0x4000..=0x3fff_ffff => 4,
+		_ => 5,
+	}
+}
+
+/// Check that artifacts of the response are internally consistent.
+pub fn verify_call(call: &SecretStoreCall) -> Result<(), String> {
+	match *call {
+		SecretStoreCall::ServerKeyGenerated(_, ref key)
+			| SecretStoreCall::ServerKeyRetrieved(_, ref key, _) if key.is_zero() =>
+			Err("server key is zero".into()),
+		SecretStoreCall::DocumentKeyCommonRetrieved(_, _, ref common_point, _) if common_point.is_zero() =>
+			Err("document key common point is zero".into()),
+		SecretStoreCall::DocumentKeyPersonalRetrieved(_, _, ref participants, ref decrypted_secret, ref shadow) => {
+			if participants.is_empty() {
+				return Err("document key shadow retrieval participants are empty".into());
+			}
+			if participants.iter().collect::<BTreeSet<_>>().len() != participants.len() {
+				return Err("document key shadow retrieval participants are duplicated".into());
+			}
+			if decrypted_secret.is_zero() {
+				return Err("document key decrypted secret is zero".into());
+			}
+			if shadow.is_empty() {
+				return Err("document key shadow is empty".into());
+			}
+			Ok(())
+		},
+		_ => Ok(()),
+	}
+}
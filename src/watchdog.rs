@@ -0,0 +1,222 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Watchdog of key server sessions.
+//!
+//! Session may neither complete nor fail (e.g. when some key server has dropped
+//! the session message). Such session is holding the request forever, because
+//! key server won't start another session for the same request. Watchdog asks
+//! key server about progress of sessions that are running for too long. Wedged
+//! sessions are aborted and their tasks are re-dispatched by the pending tasks
+//! scan (requests are still pending on chain).
+
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+};
+use log::{error, trace, warn};
+use parity_secretstore_primitives::ServerKeyId;
+use crate::{TaskKind, confidential::Redactor};
+
+/// Key server sessions probe.
+pub trait SessionProbe: Send + Sync + 'static {
+	/// Returns true if key server is still making progress on the session.
+	fn is_session_alive(&self, task_kind: TaskKind, key_id: &ServerKeyId) -> bool;
+	/// Force-abort the session.
+	fn abort_session(&self, task_kind: TaskKind, key_id: &ServerKeyId) -> Result<(), String>;
+}
+
+/// Called when session for the same request has been wedged `max_wedges` times.
+/// Arguments are task kind, key id and number of wedges.
+pub type WedgedSessionHandler = Arc<dyn Fn(TaskKind, &ServerKeyId, usize) + Send + Sync>;
+
+/// Sessions watchdog configuration.
+#[derive(Clone)]
+pub struct WatchdogConfiguration {
+	/// Sessions probe.
+	pub probe: Arc<dyn SessionProbe>,
+	/// Key server is asked about session progress after this number of blocks.
+	pub session_timeout: u64,
+	/// Alert is raised when session for the same request is wedged this number of times.
+	pub max_wedges: usize,
+	/// Alert handler.
+	pub handler: Option<WedgedSessionHandler>,
+}
+
+/// Sessions watchdog.
+pub struct Watchdog {
+	/// Configuration. If `None`, sessions are not watched.
+	config: Option<WatchdogConfiguration>,
+	/// Identifying data formatter.
+	redactor: Redactor,
+	/// Watchdog state.
+	state: Mutex<WatchdogState>,
+}
+
+/// Watchdog state.
+#[derive(Default)]
+struct WatchdogState {
+	/// Index of the current block.
+	current_block: u64,
+	/// Running sessions, mapped to the block where they've been (last) checked.
+	sessions: BTreeMap<(TaskKind, ServerKeyId), u64>,
+	/// Number of wedged sessions by request.
+	wedges: BTreeMap<(TaskKind, ServerKeyId), usize>,
+}
+
+impl Watchdog {
+	/// Create new watchdog.
+	pub fn new(config: Option<WatchdogConfiguration>, redactor: Redactor) -> Self {
+		Watchdog {
+			config,
+			redactor,
+			state: Mutex::new(WatchdogState::default()),
+		}
+	}
+
+	/// Called when task is dispatched to the key server. Sessions are tracked even if
+	/// watchdog is disabled, so that they could be reported on shutdown.
+	pub fn on_task_dispatched(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let current_block = state.current_block;
+		state.sessions.entry((task_kind, key_id)).or_insert(current_block);
+	}
+
+	/// Returns sessions that are currently running.
+	pub fn running_sessions(&self) -> Vec<(TaskKind, ServerKeyId)> {
+		self.state.lock().expect("never panics under lock; qed").sessions.keys().cloned().collect()
+	}
+
+	/// Called when session has produced response.
+	pub fn on_session_completed(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		self.state.lock().expect("never panics under lock; qed").sessions.remove(&(task_kind, key_id));
+	}
+
+	/// Called when request no longer requires our response. Session that is still
+	/// running is aborted.
+	pub fn on_request_completed(&self, task_kind: TaskKind, key_id: ServerKeyId) {
+		let is_session_running = {
+			let mut state = self.state.lock().expect("never panics under lock; qed");
+			state.wedges.remove(&(task_kind, key_id));
+			state.sessions.remove(&(task_kind, key_id)).is_some()
+		};
+
+		let config = match self.config {
+			Some(ref config) if is_session_running => config,
+			_ => return,
+		};
+
+		trace!(
+			target: "secretstore",
+			"{:?} request {} has been completed. Aborting running session",
+			task_kind,
+			self.redactor.redact(&key_id),
+		);
+
+		if let Err(error) = config.probe.abort_session(task_kind, &key_id) {
+			error!(
+				target: "secretstore",
+				"Failed to abort {:?} session {}: {}",
+				task_kind,
+				self.redactor.redact(&key_id),
+				error,
+			);
+		}
+	}
+
+	/// Called when new block is processed. Aborts wedged sessions. Returns sessions
+	/// that have been aborted - their tasks need to be re-dispatched.
+	pub fn on_new_block(&self) -> Vec<(TaskKind, ServerKeyId)> {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return Vec::new(),
+		};
+
+		let expired_sessions = {
+			let mut state = self.state.lock().expect("never panics under lock; qed");
+			state.current_block += 1;
+
+			let current_block = state.current_block;
+			let expired_sessions = state.sessions
+				.iter()
+				.filter(|(_, checked_at)| current_block - **checked_at >= config.session_timeout)
+				.map(|(request, _)| *request)
+				.collect::<Vec<_>>();
+			for request in &expired_sessions {
+				state.sessions.insert(*request, current_block);
+			}
+			expired_sessions
+		};
+
+		// probe is called without lock => new sessions could be dispatched meanwhile
+		let mut wedged_sessions = Vec::new();
+		for (task_kind, key_id) in expired_sessions {
+			if config.probe.is_session_alive(task_kind, &key_id) {
+				continue;
+			}
+
+			warn!(
+				target: "secretstore",
+				"{:?} session {} has been wedged. Aborting",
+				task_kind,
+				self.redactor.redact(&key_id),
+			);
+
+			if let Err(error) = config.probe.abort_session(task_kind, &key_id) {
+				error!(
+					target: "secretstore",
+					"Failed to abort {:?} session {}: {}",
+					task_kind,
+					self.redactor.redact(&key_id),
+					error,
+				);
+				continue;
+			}
+
+			wedged_sessions.push((task_kind, key_id));
+		}
+
+		let mut alerts = Vec::new();
+		{
+			let mut state = self.state.lock().expect("never panics under lock; qed");
+			for request in &wedged_sessions {
+				state.sessions.remove(request);
+
+				let wedges = state.wedges.entry(*request).or_insert(0);
+				*wedges += 1;
+				if *wedges >= config.max_wedges {
+					alerts.push((*request, *wedges));
+				}
+			}
+		}
+
+		for ((task_kind, key_id), wedges) in alerts {
+			error!(
+				target: "secretstore",
+				"{:?} session {} has been wedged {} times",
+				task_kind,
+				self.redactor.redact(&key_id),
+				wedges,
+			);
+
+			if let Some(ref handler) = config.handler {
+				handler(task_kind, &key_id, wedges);
+			}
+		}
+
+		wedged_sessions
+	}
+}
@@ -0,0 +1,188 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detection of key servers that are withholding responses.
+//!
+//! Some requests (server key generation, document key store) are only completed when
+//! every key server of the current set has responded. Key server that participates in
+//! sessions, but never submits responses, makes others pay for its work. We track which
+//! key servers have responded to every completed request and flag key servers that are
+//! systematically missing.
+
+use std::{
+	collections::{BTreeMap, BTreeSet, VecDeque},
+	sync::{Arc, Mutex},
+};
+use log::warn;
+use parity_secretstore_primitives::{Address, KeyServerId};
+use crate::{SecretStoreCall, TaskKind, dedup::ServedRequest};
+
+/// Max number of not yet completed requests that are tracked.
+const MAX_TRACKED_REQUESTS: usize = 4096;
+
+/// Called when key server is flagged for withholding responses.
+pub type WithholdingHandler = Arc<dyn Fn(&WithholdingReport) + Send + Sync>;
+
+/// Responses withholding detection configuration.
+#[derive(Clone)]
+pub struct WithholdingConfiguration {
+	/// Kinds of requests that every key server must respond to.
+	pub task_kinds: BTreeSet<TaskKind>,
+	/// Key server is never flagged before this number of requests has been completed.
+	pub min_requests: u64,
+	/// Key server is flagged when it has missed more than this percent of responses.
+	pub max_missed_percent: u64,
+	/// Called when key server is flagged.
+	pub handler: Option<WithholdingHandler>,
+}
+
+/// Responses of single key server.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WithholdingReport {
+	/// Key server.
+	pub key_server: KeyServerId,
+	/// Number of completed requests while key server has been in the current set.
+	pub completed_requests: u64,
+	/// Number of completed requests that key server hasn't responded to.
+	pub missed_responses: u64,
+	/// True if key server is flagged for withholding responses.
+	pub is_flagged: bool,
+}
+
+/// Tracks responses of all key servers.
+pub struct WithholdingMonitor {
+	/// Configuration. If `None`, responses are not tracked.
+	config: Option<WithholdingConfiguration>,
+	/// Monitor state.
+	state: Mutex<WithholdingMonitorState>,
+}
+
+/// Monitor state.
+#[derive(Default)]
+struct WithholdingMonitorState {
+	/// Key servers that have responded to not yet completed requests.
+	responders: BTreeMap<ServedRequest, BTreeSet<KeyServerId>>,
+	/// Tracked requests in order of insertion.
+	order: VecDeque<ServedRequest>,
+	/// Reports of all key servers.
+	reports: BTreeMap<KeyServerId, WithholdingReport>,
+}
+
+impl Default for WithholdingConfiguration {
+	fn default() -> Self {
+		WithholdingConfiguration {
+			task_kinds: vec![TaskKind::ServerKeyGeneration, TaskKind::DocumentKeyStore].into_iter().collect(),
+			min_requests: 16,
+			max_missed_percent: 50,
+			handler: None,
+		}
+	}
+}
+
+impl WithholdingMonitor {
+	/// Create new monitor.
+	pub fn new(config: Option<WithholdingConfiguration>) -> Self {
+		WithholdingMonitor {
+			config,
+			state: Mutex::new(WithholdingMonitorState::default()),
+		}
+	}
+
+	/// Returns true if responses are tracked.
+	pub fn is_enabled(&self) -> bool {
+		self.config.is_some()
+	}
+
+	/// Returns reports of all key servers.
+	pub fn reports(&self) -> Vec<WithholdingReport> {
+		self.state.lock().expect("never panics under lock; qed").reports.values().cloned().collect()
+	}
+
+	/// Called when response of any key server is accepted by the runtime module.
+	pub fn on_response_accepted(&self, key_server: KeyServerId, origin: Address, call: &SecretStoreCall) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+		if !config.task_kinds.contains(&call.task_kind()) {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		for request in ServedRequest::from_accepted_call(Some(origin), call) {
+			if !state.responders.contains_key(&request) {
+				state.order.push_back(request);
+			}
+			state.responders.entry(request).or_default().insert(key_server);
+		}
+
+		while state.order.len() > MAX_TRACKED_REQUESTS {
+			if let Some(oldest_request) = state.order.pop_front() {
+				state.responders.remove(&oldest_request);
+			}
+		}
+	}
+
+	/// Called when request is completed. Every key server of the current set is
+	/// expected to respond.
+	pub fn on_request_completed(&self, request: ServedRequest, key_servers: &BTreeSet<KeyServerId>) {
+		let config = match self.config {
+			Some(ref config) => config,
+			None => return,
+		};
+		if !config.task_kinds.contains(&request.task_kind) {
+			return;
+		}
+
+		let mut state = self.state.lock().expect("never panics under lock; qed");
+		let responders = state.responders.remove(&request).unwrap_or_default();
+		state.order.retain(|tracked_request| *tracked_request != request);
+
+		let mut flagged_reports = Vec::new();
+		for key_server in key_servers {
+			let report = state.reports.entry(*key_server).or_insert_with(|| WithholdingReport {
+				key_server: *key_server,
+				..Default::default()
+			});
+			report.completed_requests += 1;
+			if !responders.contains(key_server) {
+				report.missed_responses += 1;
+			}
+
+			let is_flagged = report.completed_requests >= config.min_requests
+				&& report.missed_responses * 100 > report.completed_requests * config.max_missed_percent;
+			if is_flagged && !report.is_flagged {
+				flagged_reports.push(*report);
+			}
+			report.is_flagged = is_flagged;
+		}
+		drop(state);
+
+		for flagged_report in flagged_reports {
+			warn!(
+				target: "secretstore",
+				"Key server {:?} has missed {} of {} responses",
+				flagged_report.key_server,
+				flagged_report.missed_responses,
+				flagged_report.completed_requests,
+			);
+
+			if let Some(ref handler) = config.handler {
+				handler(&flagged_report);
+			}
+		}
+	}
+}